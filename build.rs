@@ -0,0 +1,39 @@
+use std::env;
+use std::process::Command;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+// Build information reported by `GET /version` and `--version-json`:
+// IPTOASN_GIT_COMMIT and IPTOASN_BUILD_DATE can be set to override the
+// values found here, e.g. when building from a tarball without .git.
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-env-changed=IPTOASN_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=IPTOASN_BUILD_DATE");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let commit = env::var("IPTOASN_GIT_COMMIT").ok().filter(|commit| !commit.is_empty()).or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "--short=12", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!("cargo:rustc-env=IPTOASN_GIT_COMMIT={}", commit.as_deref().unwrap_or("unknown"));
+
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let date = env::var("IPTOASN_BUILD_DATE").ok().filter(|date| !date.is_empty()).unwrap_or_else(|| {
+        let timestamp = env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|epoch| epoch.parse::<i64>().ok())
+            .and_then(|epoch| OffsetDateTime::from_unix_timestamp(epoch).ok())
+            .unwrap_or_else(OffsetDateTime::now_utc)
+            .replace_nanosecond(0)
+            .unwrap();
+        timestamp.format(&Rfc3339).unwrap_or_default()
+    });
+    println!("cargo:rustc-env=IPTOASN_BUILD_DATE={}", date);
+    println!("cargo:rustc-env=IPTOASN_TARGET={}", env::var("TARGET").unwrap_or_default());
+}
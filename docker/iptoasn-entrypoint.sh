@@ -3,7 +3,7 @@
 DEFAULT_PORT='53661'
 DEFAULT_DBURL='https://iptoasn.com/data/ip2asn-combined.tsv.gz'
 
-if [ $IPTOASN_PORT ] || [ $IPTOASN_DBURL ]; then
+if [ $IPTOASN_PORT ] || [ $IPTOASN_DBURL ] || [ $# -eq 0 ]; then
   if ! [ $IPTOASN_PORT ]; then
     IPTOASN_PORT=$DEFAULT_PORT
   fi
@@ -12,7 +12,8 @@ if [ $IPTOASN_PORT ] || [ $IPTOASN_DBURL ]; then
     IPTOASN_DBURL=$DEFAULT_DBURL
   fi
 
-  exec /usr/bin/iptoasn-webservice --listen 0.0.0.0:"$IPTOASN_PORT" --dburl "$IPTOASN_DBURL"
+  # Clients reach the container through its network interface, not loopback
+  exec /usr/bin/iptoasn-webservice --listen-any="$IPTOASN_PORT" --dburl "$IPTOASN_DBURL"
 else
   exec /usr/bin/iptoasn-webservice "$@"
 fi
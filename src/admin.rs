@@ -0,0 +1,25 @@
+use crate::auth;
+use http::HeaderMap;
+use std::sync::OnceLock;
+
+// Secret of the admin routes (--admin-token)
+static TOKEN: OnceLock<String> = OnceLock::new();
+
+// Require this token on the admin routes, from any client. Must be called
+// before the server is started.
+pub fn set_token(token: &str) {
+    let _ = TOKEN.set(token.trim().to_string());
+}
+
+pub fn token_enabled() -> bool {
+    TOKEN.get().is_some_and(|token| !token.is_empty())
+}
+
+// Whether a request sends the token in an `X-Api-Key` or `Authorization:
+// Bearer` header. Without a token, the admin routes are disabled.
+pub fn authorized(headers: &HeaderMap) -> bool {
+    TOKEN
+        .get()
+        .filter(|token| !token.is_empty())
+        .is_some_and(|token| auth::sends_token(headers, token))
+}
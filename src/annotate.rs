@@ -0,0 +1,140 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::{IpAddr, Ipv6Addr};
+
+const NIL: usize = usize::MAX;
+
+// Cache key of an address: IPv6 addresses are grouped by their network of
+// `v6_prefix_len` bits when set (e.g. 64, as addresses with privacy
+// extensions change within their /64), other addresses are their own key.
+pub fn cache_key(ip: IpAddr, v6_prefix_len: Option<u8>) -> String {
+    match (ip, v6_prefix_len) {
+        (IpAddr::V6(v6), Some(len)) => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            format!("{}/{}", Ipv6Addr::from(u128::from(v6) & mask), len)
+        }
+        _ => ip.to_string(),
+    }
+}
+
+struct Entry<K, V> {
+    key: K,
+    value: V,
+    // Neighbours in recency order, NIL at the ends
+    prev: usize,
+    next: usize,
+}
+
+// Least recently used cache of lookup results, bounded to `capacity`
+// entries so that logs with millions of unique (e.g. spoofed) addresses
+// don't exhaust memory. Entries live in a vector linked by indices, and
+// evicted slots are reused in place.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    index: HashMap<K, usize>,
+    entries: Vec<Entry<K, V>>,
+    // Most and least recently used entries
+    head: usize,
+    tail: usize,
+    evictions: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruCache<K, V> {
+    // A capacity of 0 disables caching
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            index: HashMap::new(),
+            entries: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            evictions: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Number of entries dropped to make room for new ones
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    // Value of a key, marked as most recently used
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let i = *self.index.get(key)?;
+        self.unlink(i);
+        self.push_front(i);
+        Some(&self.entries[i].value)
+    }
+
+    // Insert or replace a value, evicting the least recently used entry if
+    // the cache is full
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(&i) = self.index.get(&key) {
+            self.entries[i].value = value;
+            self.unlink(i);
+            self.push_front(i);
+            return;
+        }
+        let i = if self.entries.len() < self.capacity {
+            self.entries.push(Entry {
+                key: key.clone(),
+                value,
+                prev: NIL,
+                next: NIL,
+            });
+            self.entries.len() - 1
+        } else {
+            let i = self.tail;
+            self.unlink(i);
+            let entry = &mut self.entries[i];
+            self.index.remove(&entry.key);
+            entry.key = key.clone();
+            entry.value = value;
+            self.evictions += 1;
+            i
+        };
+        self.index.insert(key, i);
+        self.push_front(i);
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.entries[i].prev, self.entries[i].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.entries[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.entries[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.entries[i].prev = NIL;
+        self.entries[i].next = self.head;
+        match self.head {
+            NIL => self.tail = i,
+            head => self.entries[head].prev = i,
+        }
+        self.head = i;
+    }
+}
@@ -0,0 +1,134 @@
+use std::borrow::Cow;
+
+// Transliterations of U+00C0 to U+00FF
+const LATIN_1: [&str; 64] = [
+    "A", "A", "A", "A", "A", "A", "AE", "C", "E", "E", "E", "E", "I", "I", "I", "I", //
+    "D", "N", "O", "O", "O", "O", "O", "x", "O", "U", "U", "U", "U", "Y", "TH", "ss", //
+    "a", "a", "a", "a", "a", "a", "ae", "c", "e", "e", "e", "e", "i", "i", "i", "i", //
+    "d", "n", "o", "o", "o", "o", "o", "/", "o", "u", "u", "u", "u", "y", "th", "y",
+];
+
+// Transliterations of U+0100 to U+017F (Latin Extended-A)
+const LATIN_EXTENDED_A: [&str; 128] = [
+    "A", "a", "A", "a", "A", "a", "C", "c", "C", "c", "C", "c", "C", "c", "D", "d", //
+    "D", "d", "E", "e", "E", "e", "E", "e", "E", "e", "E", "e", "G", "g", "G", "g", //
+    "G", "g", "G", "g", "H", "h", "H", "h", "I", "i", "I", "i", "I", "i", "I", "i", //
+    "I", "i", "IJ", "ij", "J", "j", "K", "k", "k", "L", "l", "L", "l", "L", "l", "L", //
+    "l", "L", "l", "N", "n", "N", "n", "N", "n", "'n", "N", "n", "O", "o", "O", "o", //
+    "O", "o", "OE", "oe", "R", "r", "R", "r", "R", "r", "S", "s", "S", "s", "S", "s", //
+    "S", "s", "T", "t", "T", "t", "T", "t", "U", "u", "U", "u", "U", "u", "U", "u", //
+    "U", "u", "U", "u", "W", "w", "Y", "y", "Y", "Z", "z", "Z", "z", "Z", "z", "s",
+];
+
+// Transliterations of U+0410 to U+044F (Russian Cyrillic)
+const CYRILLIC: [&str; 64] = [
+    "A", "B", "V", "G", "D", "E", "Zh", "Z", "I", "Y", "K", "L", "M", "N", "O", "P", //
+    "R", "S", "T", "U", "F", "Kh", "Ts", "Ch", "Sh", "Shch", "", "Y", "", "E", "Yu", "Ya", //
+    "a", "b", "v", "g", "d", "e", "zh", "z", "i", "y", "k", "l", "m", "n", "o", "p", //
+    "r", "s", "t", "u", "f", "kh", "ts", "ch", "sh", "shch", "", "y", "", "e", "yu", "ya",
+];
+
+// Transliterations of U+0391 to U+03A9 (Greek capitals); small letters are
+// 0x20 higher
+const GREEK: [&str; 25] = [
+    "A", "V", "G", "D", "E", "Z", "I", "Th", "I", "K", "L", "M", "N", "X", "O", "P", //
+    "R", "", "S", "T", "Y", "F", "Ch", "Ps", "O",
+];
+
+// ASCII transliteration of a character, None if there is none
+fn transliterate(c: char) -> Option<Cow<'static, str>> {
+    let lower = |s: &'static str| Cow::Owned(s.to_lowercase());
+    let s = match c as u32 {
+        0x00A0 | 0x2000..=0x200A | 0x202F => " ",
+        0x00A9 => "(c)",
+        0x00AB => "<<",
+        0x00AE => "(R)",
+        0x00B4 | 0x2018..=0x201B | 0x2032 => "'",
+        0x00B7 | 0x2022 => "*",
+        0x00BB => ">>",
+        c @ 0x00C0..=0x00FF => LATIN_1[c as usize - 0x00C0],
+        c @ 0x0100..=0x017F => LATIN_EXTENDED_A[c as usize - 0x0100],
+        0x01A0 => "O",
+        0x01A1 => "o",
+        0x01AF => "U",
+        0x01B0 => "u",
+        // Combining diacritical marks, left by decomposed text
+        0x0300..=0x036F => "",
+        0x0386 => "A",
+        0x0388 => "E",
+        0x0389 | 0x038A => "I",
+        0x038C => "O",
+        0x038E => "Y",
+        0x038F => "O",
+        c @ 0x0391..=0x03A9 => GREEK[c as usize - 0x0391],
+        0x03AC => "a",
+        0x03AD => "e",
+        0x03AE | 0x03AF => "i",
+        0x03C2 => "s",
+        c @ 0x03B1..=0x03C9 => return Some(lower(GREEK[c as usize - 0x03B1])),
+        0x03CC => "o",
+        0x03CD => "y",
+        0x03CE => "o",
+        0x0401 => "Yo",
+        0x0404 => "Ye",
+        0x0406 => "I",
+        0x0407 => "Yi",
+        0x040E => "U",
+        c @ 0x0410..=0x044F => CYRILLIC[c as usize - 0x0410],
+        0x0451 => "yo",
+        0x0454 => "ye",
+        0x0456 => "i",
+        0x0457 => "yi",
+        0x045E => "u",
+        0x0490 => "G",
+        0x0491 => "g",
+        // Vietnamese letters with two diacritics, capital then small
+        c @ 0x1EA0..=0x1EF9 => {
+            let base = match c {
+                0x1EA0..=0x1EB7 => "A",
+                0x1EB8..=0x1EC7 => "E",
+                0x1EC8..=0x1ECB => "I",
+                0x1ECC..=0x1EE3 => "O",
+                0x1EE4..=0x1EF1 => "U",
+                _ => "Y",
+            };
+            return Some(if c % 2 == 0 { Cow::Borrowed(base) } else { lower(base) });
+        }
+        0x2010..=0x2015 | 0x2212 => "-",
+        0x201C..=0x201F | 0x2033 => "\"",
+        0x2026 => "...",
+        0x20AC => "EUR",
+        0x2122 => "TM",
+        _ => return None,
+    };
+    Some(Cow::Borrowed(s))
+}
+
+// ASCII transliteration of a text: accented Latin letters lose their
+// diacritics, Cyrillic and Greek are romanized, typographic punctuation is
+// replaced with its ASCII counterpart, and other characters with '?'
+pub fn fold(s: &str) -> Cow<'_, str> {
+    if s.is_ascii() {
+        return Cow::Borrowed(s);
+    }
+    let mut folded = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c.is_ascii() {
+            folded.push(c);
+            continue;
+        }
+        match transliterate(c) {
+            Some(s) => folded.push_str(&s),
+            None => folded.push('?'),
+        }
+    }
+    Cow::Owned(folded)
+}
+
+// Transliteration of a text that isn't ASCII, None if it already is
+pub fn fold_non_ascii(s: &str) -> Option<String> {
+    match fold(s) {
+        Cow::Borrowed(_) => None,
+        Cow::Owned(folded) => Some(folded),
+    }
+}
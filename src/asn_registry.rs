@@ -0,0 +1,93 @@
+// IANA Special-Purpose AS Numbers registry
+// (https://www.iana.org/assignments/iana-as-numbers-special-registry), plus
+// the AS numbers IANA holds in reserve. These never belong to a network, so
+// they are labelled instead of being reported as unknown.
+pub struct SpecialAsn {
+    pub first: u32,
+    pub last: u32,
+    // Stable identifier for API clients
+    pub code: &'static str,
+    // Human-readable label, used as description
+    pub label: &'static str,
+    pub reference: &'static str,
+}
+
+const SPECIAL_ASNS: [SpecialAsn; 10] = [
+    SpecialAsn {
+        first: 0,
+        last: 0,
+        code: "reserved",
+        label: "Reserved",
+        reference: "RFC 7607",
+    },
+    SpecialAsn {
+        first: 112,
+        last: 112,
+        code: "as112",
+        label: "AS112 project",
+        reference: "RFC 7534",
+    },
+    SpecialAsn {
+        first: 23456,
+        last: 23456,
+        code: "as_trans",
+        label: "AS_TRANS",
+        reference: "RFC 6793",
+    },
+    SpecialAsn {
+        first: 64496,
+        last: 64511,
+        code: "documentation",
+        label: "Documentation",
+        reference: "RFC 5398",
+    },
+    SpecialAsn {
+        first: 64512,
+        last: 65534,
+        code: "private_use",
+        label: "Private use",
+        reference: "RFC 6996",
+    },
+    SpecialAsn {
+        first: 65535,
+        last: 65535,
+        code: "reserved",
+        label: "Reserved",
+        reference: "RFC 7300",
+    },
+    SpecialAsn {
+        first: 65536,
+        last: 65551,
+        code: "documentation",
+        label: "Documentation",
+        reference: "RFC 5398",
+    },
+    SpecialAsn {
+        first: 65552,
+        last: 131071,
+        code: "reserved",
+        label: "Reserved",
+        reference: "IANA",
+    },
+    SpecialAsn {
+        first: 4200000000,
+        last: 4294967294,
+        code: "private_use",
+        label: "Private use",
+        reference: "RFC 6996",
+    },
+    SpecialAsn {
+        first: 4294967295,
+        last: 4294967295,
+        code: "reserved",
+        label: "Reserved",
+        reference: "RFC 7300",
+    },
+];
+
+// Registry entry of a special-purpose or reserved AS number
+pub fn lookup(number: u32) -> Option<&'static SpecialAsn> {
+    SPECIAL_ASNS
+        .iter()
+        .find(|special| (special.first..=special.last).contains(&number))
+}
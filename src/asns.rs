@@ -1,16 +1,27 @@
+use crate::buckets::BucketIndex;
+use crate::cidr;
+use crate::country;
+use crate::mmap::{self, MmapBuilder, MmapRanges};
+use crate::normalize;
 use flate2::read::GzDecoder;
+use roaring::RoaringBitmap;
+use sha2::{Digest, Sha256};
 use log::{debug, error, info, warn};
+use std::borrow::Cow;
 use std::cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::prelude::*;
+use std::io::BufReader;
 use std::net::IpAddr;
-use std::ops::Bound::{Included, Unbounded};
+use std::ops::Bound::{Excluded, Included, Unbounded};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
 use std::{env, fs};
 use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Asn {
     pub first_ip: IpAddr,
     pub last_ip: IpAddr,
@@ -51,54 +62,460 @@ impl Asn {
     }
 }
 
+// Compressed IPv4 membership sets per country and per large ASN, for O(1)
+// "is this IP in country X / ASN Y" checks without scanning ranges.
+struct MembershipSets {
+    by_country: HashMap<Arc<str>, RoaringBitmap>,
+    by_asn: HashMap<u32, RoaringBitmap>,
+}
+
+// Download progress callback, called with the number of bytes received so far
+// and the expected total, if the server sent a Content-Length
+pub type Progress<'a> = &'a (dyn Fn(u64, Option<u64>) + Send + Sync);
+
+// Progress of the parse of a database
+#[derive(Clone, Copy, Debug)]
+pub struct ParseProgress {
+    // Lines parsed so far
+    pub lines: u64,
+    // Offset in the compressed database, and its size
+    pub parsed_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl ParseProgress {
+    pub fn percent(&self) -> u64 {
+        if self.total_bytes == 0 {
+            return 100;
+        }
+        self.parsed_bytes.min(self.total_bytes) * 100 / self.total_bytes
+    }
+}
+
+// Parse progress callback, called every PARSE_PROGRESS_LINES lines and once
+// the whole database is parsed
+pub type ParseProgressFn<'a> = &'a (dyn Fn(ParseProgress) + Send + Sync);
+
+// Progress callbacks of the download and the parse of a database
+#[derive(Clone, Copy)]
+pub struct LoadProgress<'a> {
+    pub download: Progress<'a>,
+    pub parse: ParseProgressFn<'a>,
+}
+
+const PARSE_PROGRESS_LINES: u64 = 50_000;
+
+// How long a cache update waits for another process updating the cache
+#[cfg(feature = "async")]
+const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Most of a download's Content-Length that is reserved upfront
+#[cfg(feature = "async")]
+const MAX_BODY_RESERVATION: u64 = 64 << 20;
+
+// Which path supplied the loaded database
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DbSource {
+    // Downloaded from an http(s) URL
+    Network,
+    // Read from the cache file, after all sources failed or while it is fresh
+    Cache,
+    // Read from a local file (path or file:// URL)
+    File,
+    // Replicated from a primary instance (--primary)
+    Replica,
+}
+
+impl DbSource {
+    pub const ALL: [DbSource; 4] = [DbSource::Network, DbSource::Cache, DbSource::File, DbSource::Replica];
+
+    fn of_url(url: &str) -> Self {
+        if url.starts_with("http://") || url.starts_with("https://") {
+            DbSource::Network
+        } else {
+            DbSource::File
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DbSource::Network => "network",
+            DbSource::Cache => "cache",
+            DbSource::File => "file",
+            DbSource::Replica => "replica",
+        }
+    }
+}
+
+// Where the ranges are kept: in memory, or in a memory-mapped binary
+// snapshot searched in place (--backend mmap)
+enum Ranges {
+    Memory(BTreeSet<Asn>),
+    Mmap(MmapRanges),
+    // In memory, with a /24 and /48 bucket table (--index-mode buckets)
+    Buckets(BucketIndex),
+}
+
+// Path of the binary snapshot when using the mmap backend
+static MMAP_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+// Whether in-memory ranges get a bucket table (--index-mode buckets)
+static BUCKET_INDEX: AtomicBool = AtomicBool::new(false);
+
+// Set on shutdown, to abort database parses (Asns::cancel_loads)
+static LOADS_CANCELLED: AtomicBool = AtomicBool::new(false);
+
+// How long replaced cache snapshots are kept, in seconds (--retain-snapshots)
+static SNAPSHOT_RETENTION: AtomicU64 = AtomicU64::new(0);
+
+// Counts of snapshot_stats by snapshot and subject. Snapshots are named after
+// their content, so the counts of one never change.
+static SNAPSHOT_COUNTS: Mutex<Option<HashMap<(PathBuf, StatsSubject), SnapshotCounts>>> = Mutex::new(None);
+
+// Ranges of the snapshots origin_history was asked about, decompressed once
+// each. Held while a snapshot is read, so that it is only read once.
+static SNAPSHOT_RANGES: Mutex<Option<HashMap<PathBuf, Arc<SnapshotRanges>>>> = Mutex::new(None);
+
+// Ranges of a retained snapshot, sorted by first address: (first, last, AS
+// number, index of its country and description in `meta`)
+#[derive(Default)]
+struct SnapshotRanges {
+    v4: Vec<(u32, u32, u32, u32)>,
+    v6: Vec<(u128, u128, u32, u32)>,
+    meta: Vec<(Box<str>, Box<str>)>,
+}
+
+impl SnapshotRanges {
+    fn read(path: &Path) -> std::io::Result<Self> {
+        let reader = BufReader::new(GzDecoder::new(fs::File::open(path)?));
+        let mut ranges = SnapshotRanges::default();
+        let mut meta_ids: HashMap<(String, String), u32> = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            let Some(record) = Asns::parse_line(&line) else { continue };
+            let key = (record.country.into_owned(), record.description.into_owned());
+            let meta = match meta_ids.get(&key) {
+                Some(&meta) => meta,
+                None => {
+                    let meta = ranges.meta.len() as u32;
+                    ranges.meta.push((key.0.as_str().into(), key.1.as_str().into()));
+                    meta_ids.insert(key, meta);
+                    meta
+                }
+            };
+            match (record.first_ip, record.last_ip) {
+                (IpAddr::V4(first), IpAddr::V4(last)) => {
+                    ranges.v4.push((first.into(), last.into(), record.number, meta));
+                }
+                (IpAddr::V6(first), IpAddr::V6(last)) => {
+                    ranges.v6.push((first.into(), last.into(), record.number, meta));
+                }
+                _ => {}
+            }
+        }
+        ranges.v4.sort_unstable();
+        ranges.v4.shrink_to_fit();
+        ranges.v6.sort_unstable();
+        ranges.v6.shrink_to_fit();
+        Ok(ranges)
+    }
+
+    // AS number, country and description of the range containing `ip`
+    fn get(&self, ip: IpAddr) -> Option<(u32, &str, &str)> {
+        let (number, meta) = match ip {
+            IpAddr::V4(ip) => {
+                let ip = u32::from(ip);
+                let i = self.v4.partition_point(|range| range.0 <= ip).checked_sub(1)?;
+                let (_, last, number, meta) = self.v4[i];
+                (ip <= last).then_some((number, meta))?
+            }
+            IpAddr::V6(ip) => {
+                let ip = u128::from(ip);
+                let i = self.v6.partition_point(|range| range.0 <= ip).checked_sub(1)?;
+                let (_, last, number, meta) = self.v6[i];
+                (ip <= last).then_some((number, meta))?
+            }
+        };
+        let (country, description) = &self.meta[meta as usize];
+        Some((number, country, description))
+    }
+}
+
+// Fields of a database line, before interning
+struct RawRecord<'a> {
+    first_ip: IpAddr,
+    last_ip: IpAddr,
+    number: u32,
+    country: Cow<'a, str>,
+    description: Cow<'a, str>,
+}
+
+impl RawRecord<'_> {
+    fn into_owned(self) -> RawRecord<'static> {
+        RawRecord {
+            country: Cow::Owned(self.country.into_owned()),
+            description: Cow::Owned(self.description.into_owned()),
+            ..self
+        }
+    }
+}
+
+// String interning pools to deduplicate country codes and descriptions
+#[derive(Default)]
+struct Interner {
+    countries: HashMap<String, Arc<str>>,
+    descriptions: HashMap<String, Arc<str>>,
+    // Country codes left unknown after corrections, with their number of ranges
+    unmapped: BTreeMap<Arc<str>, usize>,
+}
+
+impl Interner {
+    fn intern(&mut self, record: RawRecord<'_>) -> Asn {
+        // Intern country code, after normalization and corrections
+        let normalized = normalize::country(&record.country);
+        let country_str = country::correct(&normalized);
+        let country = self
+            .countries
+            .entry(country_str.to_owned())
+            .or_insert_with(|| Arc::from(country_str))
+            .clone();
+        if !country::is_known(country_str) {
+            *self.unmapped.entry(country.clone()).or_default() += 1;
+        }
+
+        // Intern description, after normalization
+        let description = match self.descriptions.get(record.description.as_ref()) {
+            Some(description) => description.clone(),
+            None => {
+                let description: Arc<str> = Arc::from(normalize::description(&record.description));
+                self.descriptions
+                    .insert(record.description.into_owned(), description.clone());
+                description
+            }
+        };
+
+        Asn {
+            first_ip: record.first_ip,
+            last_ip: record.last_ip,
+            number: record.number,
+            country,
+            description,
+        }
+    }
+}
+
+// Time taken by one stage of `Asns::bench_parse`, with the size of its output
+pub struct ParseStage {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    pub count: usize,
+    pub unit: &'static str,
+}
+
+// Ranges of an ASN registered in one country, and the number of CIDR
+// prefixes they make up
+#[derive(Clone, Debug)]
+pub struct CountryShare {
+    pub country: Arc<str>,
+    pub ranges: usize,
+    pub prefixes: usize,
+}
+
+// Origin of an address across consecutive retained cache snapshots, AS 0
+// when it wasn't announced
+pub struct OriginWindow {
+    // Oldest snapshot of the window: creation time, or last use where the
+    // filesystem doesn't record it
+    pub first_seen: SystemTime,
+    // Newest snapshot of the window: when it was last downloaded
+    pub last_seen: SystemTime,
+    pub number: u32,
+    pub country: String,
+    pub description: String,
+    // Number of snapshots in the window
+    pub snapshots: usize,
+}
+
+// Ranges of an AS number or of a country, counted by snapshot_stats
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum StatsSubject {
+    Asn(u32),
+    Country(String),
+}
+
+// Size of the ranges of a StatsSubject in one retained cache snapshot
+#[derive(Clone, Copy)]
+pub struct SnapshotStats {
+    // Creation time of the snapshot, or its last use where the filesystem
+    // doesn't record it
+    pub first_seen: SystemTime,
+    // When the snapshot was last downloaded
+    pub last_seen: SystemTime,
+    pub counts: SnapshotCounts,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct SnapshotCounts {
+    // CIDR prefixes the ranges make up
+    pub prefixes: u64,
+    pub ipv4_addresses: u64,
+    // IPv6 /64 networks the ranges span
+    pub ipv6_64s: u64,
+}
+
+// Ranges and indexes of a database being read, before it is assembled
+struct RangesCollector {
+    memory_ranges: BTreeSet<Asn>,
+    mmap_builder: Option<MmapBuilder>,
+    asn_meta: HashMap<u32, (Arc<str>, Arc<str>)>,
+    range_counts: HashMap<u32, usize>,
+    asn_countries: HashMap<u32, Vec<CountryShare>>,
+}
+
+impl RangesCollector {
+    fn new() -> Result<Self, &'static str> {
+        let mmap_builder = match MMAP_PATH.get() {
+            Some(path) => Some(MmapBuilder::create(path).map_err(Self::snapshot_error)?),
+            None => None,
+        };
+        Ok(Self {
+            memory_ranges: BTreeSet::new(),
+            mmap_builder,
+            asn_meta: HashMap::new(),
+            range_counts: HashMap::new(),
+            asn_countries: HashMap::new(),
+        })
+    }
+
+    fn push(&mut self, asn: Asn) -> Result<(), &'static str> {
+        Asns::index_meta(&asn, &mut self.asn_meta, &mut self.range_counts, &mut self.asn_countries);
+        match &mut self.mmap_builder {
+            Some(builder) => builder.push(&asn).map_err(Self::snapshot_error)?,
+            None => {
+                self.memory_ranges.insert(asn);
+            }
+        }
+        Ok(())
+    }
+
+    fn snapshot_error(e: std::io::Error) -> &'static str {
+        let path = MMAP_PATH.get().map_or(Cow::Borrowed(""), |path| path.to_string_lossy());
+        error!("Unable to write the ranges snapshot {}: {}", path, e);
+        "Unable to write the ranges snapshot"
+    }
+
+    fn finish(
+        self,
+        unmapped_countries: BTreeMap<Arc<str>, usize>,
+        source: String,
+        db_source: DbSource,
+        updated: SystemTime,
+        generation: String,
+    ) -> Result<Asns, &'static str> {
+        let ranges = match (self.mmap_builder, MMAP_PATH.get()) {
+            (Some(builder), Some(path)) => Ranges::Mmap(builder.write(path).map_err(Self::snapshot_error)?),
+            _ if BUCKET_INDEX.load(AtomicOrdering::Relaxed) => {
+                Ranges::Buckets(BucketIndex::new(self.memory_ranges.into_iter().collect()))
+            }
+            _ => Ranges::Memory(self.memory_ranges),
+        };
+        let country_asns = Asns::index_countries(&self.asn_meta);
+        Ok(Asns {
+            ranges,
+            asn_meta: self.asn_meta,
+            source,
+            db_source,
+            membership: OnceLock::new(),
+            unmapped_countries,
+            range_counts: self.range_counts,
+            asn_countries: self.asn_countries,
+            country_asns,
+            updated,
+            generation,
+        })
+    }
+}
+
 pub struct Asns {
-    asns: BTreeSet<Asn>,
+    ranges: Ranges,
     asn_meta: HashMap<u32, (Arc<str>, Arc<str>)>,
+    source: String,
+    db_source: DbSource,
+    membership: OnceLock<MembershipSets>,
+    // Country codes left unknown after corrections, with their number of ranges
+    unmapped_countries: BTreeMap<Arc<str>, usize>,
+    // Number of ranges announced by each ASN
+    range_counts: HashMap<u32, usize>,
+    // Registry countries of the ranges of each ASN
+    asn_countries: HashMap<u32, Vec<CountryShare>>,
+    // ASNs of each country code, sorted
+    country_asns: HashMap<Arc<str>, Vec<u32>>,
+    // When the data was downloaded, or last modified for local files
+    updated: SystemTime,
+    // Content hash of the compressed database, as in its snapshot name
+    generation: String,
 }
 
 impl Asns {
     const CACHE_FILE_NAME: &'static str = "ip2asn-combined.tsv.gz";
     const CACHE_SUBDIR: &'static str = "iptoasn";
+    // ASNs announcing at least that many IPv4 addresses get their own membership set
+    const LARGE_ASN_MIN_ADDRESSES: u64 = 65_536;
 
-    fn default_cache_file_path() -> Option<PathBuf> {
-        if let Ok(xdg_cache) = env::var("XDG_CACHE_HOME") {
-            return Some(PathBuf::from(xdg_cache)
-                .join(Self::CACHE_SUBDIR)
-                .join(Self::CACHE_FILE_NAME));
-        }
-        if let Some(home_dir) = home::home_dir() {
-            return Some(home_dir
-                .join(".cache")
-                .join(Self::CACHE_SUBDIR)
-                .join(Self::CACHE_FILE_NAME));
-        }
-        None
+    // Keep the ranges of databases loaded from now on in a memory-mapped
+    // binary snapshot at `path` instead of the heap. Lookups are slightly
+    // slower, but memory use stays minimal, for small edge devices.
+    pub fn use_mmap_backend(path: PathBuf) {
+        let _ = MMAP_PATH.set(path);
+    }
+
+    // Look up the ranges of databases loaded from now on through a table of
+    // their /24 (IPv4) and /48 (IPv6) buckets before the binary search. Uses
+    // a bit more memory, for faster lookups of hot prefixes. Ignored with
+    // the mmap backend.
+    pub fn use_bucket_index() {
+        BUCKET_INDEX.store(true, AtomicOrdering::Relaxed);
+    }
+
+    // Keep cache snapshots replaced by a newer download for `retention`
+    // instead of removing them, for the history of /v1/as/ip/<ip>/history
+    pub fn retain_snapshots(retention: Duration) {
+        SNAPSHOT_RETENTION.store(retention.as_secs(), AtomicOrdering::Relaxed);
+    }
+
+    // Abort the database parses running, and fail the ones started from now
+    // on, so that a shutdown during a refresh doesn't wait for the parse to
+    // complete. The database already loaded is still served.
+    pub fn cancel_loads() {
+        LOADS_CANCELLED.store(true, AtomicOrdering::Relaxed);
+    }
+
+    // $XDG_CACHE_HOME if set, on any platform, otherwise the cache directory
+    // of the platform: ~/.cache on Linux, ~/Library/Caches on macOS and
+    // %LOCALAPPDATA% on Windows
+    pub fn default_cache_file_path() -> Option<PathBuf> {
+        let cache_dir = env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(dirs::cache_dir)?;
+        Some(cache_dir.join(Self::CACHE_SUBDIR).join(Self::CACHE_FILE_NAME))
     }
 
-    fn try_load_fallback(cache_file: Option<&Path>) -> Result<Vec<u8>, &'static str> {
+    #[cfg(feature = "async")]
+    fn try_load_fallback(cache_file: Option<&Path>) -> Result<(Vec<u8>, String), &'static str> {
         // 1) CLI-provided cache path
         if let Some(cf) = cache_file {
-            match fs::read(cf) {
-                Ok(content) => {
-                    info!("Successfully loaded fallback data from: {}", cf.display());
-                    return Ok(content);
-                }
-                Err(_) => {
-                    debug!("Fallback file not found: {}", cf.display());
-                }
+            if let Some(content) = Self::read_cache_file(cf) {
+                info!("Successfully loaded fallback data from: {}", cf.display());
+                return Ok((content, cf.display().to_string()));
             }
         }
 
-        // 2) Default XDG-based cache path
+        // 2) Default per-user cache path
         if let Some(def) = Self::default_cache_file_path() {
-            match fs::read(&def) {
-                Ok(content) => {
-                    info!("Successfully loaded fallback data from: {}", def.display());
-                    return Ok(content);
-                }
-                Err(_) => {
-                    debug!("Fallback file not found: {}", def.display());
-                }
+            if let Some(content) = Self::read_cache_file(&def) {
+                info!("Successfully loaded fallback data from: {}", def.display());
+                return Ok((content, def.display().to_string()));
             }
         }
 
@@ -110,39 +527,200 @@ impl Asns {
         ];
 
         for path in &fallback_paths {
-            if let Ok(content) = fs::read(path) {
+            if let Some(content) = Self::read_cache_file(Path::new(path)) {
                 info!("Successfully loaded fallback data from: {}", path);
-                return Ok(content);
-            } else {
-                debug!("Fallback file not found: {}", path);
+                return Ok((content, path.to_string()));
             }
         }
 
         Err("No fallback data sources available")
     }
 
+    #[cfg(feature = "async")]
     pub async fn new(
         url: &str,
         http_client: Option<&reqwest::Client>,
         cache_file: Option<PathBuf>,
     ) -> Result<Self, &'static str> {
+        Self::from_urls(&[url.to_string()], http_client, cache_file, 0).await
+    }
+
+    // Load the database from a single URL (http, https or file), without any
+    // cache file, for library users in async contexts.
+    #[cfg(feature = "async")]
+    pub async fn load(url: &str) -> Result<Self, &'static str> {
+        let bytes = Self::fetch(url, None, 0, &|_, _| {}).await?;
+        let updated = Self::source_modified(url).unwrap_or_else(SystemTime::now);
+        Self::parse_data(&bytes, url.to_string(), DbSource::of_url(url), updated, &|_| {})
+    }
+
+    // Same as load, for sync contexts (e.g. rayon pipelines or FFI): local
+    // files (path or file:// URL) are read with std::fs, and http(s) URLs
+    // require the "blocking" feature. Must not be called from an async
+    // runtime thread.
+    pub fn load_blocking(url_or_path: &str) -> Result<Self, &'static str> {
+        let bytes = if url_or_path.starts_with("http://") || url_or_path.starts_with("https://") {
+            Self::fetch_blocking(url_or_path)?
+        } else {
+            let path = url_or_path.strip_prefix("file://").unwrap_or(url_or_path);
+            fs::read(path).map_err(|e| {
+                error!("Unable to read the database: {}", e);
+                "Unable to read the database"
+            })?
+        };
+        let updated = Self::source_modified(url_or_path).unwrap_or_else(SystemTime::now);
+        Self::parse_data(&bytes, url_or_path.to_string(), DbSource::of_url(url_or_path), updated, &|_| {})
+    }
+
+    // Parse a gzipped ip2asn TSV database already in memory
+    pub fn from_gzip_bytes(bytes: Vec<u8>, source: &str) -> Result<Self, &'static str> {
+        Self::parse_data(&bytes, source.to_string(), DbSource::of_url(source), SystemTime::now(), &|_| {})
+    }
+
+    #[cfg(feature = "blocking")]
+    fn fetch_blocking(url: &str) -> Result<Vec<u8>, &'static str> {
         info!("Loading the database from {}", url);
+        let res = reqwest::blocking::Client::new()
+            .get(url)
+            .header(
+                "User-Agent",
+                concat!("iptoasn-webservice/", env!("CARGO_PKG_VERSION")),
+            )
+            .send()
+            .map_err(|e| {
+                error!("Failed to send request: {}", e);
+                "Failed to send request"
+            })?;
+        if !res.status().is_success() {
+            error!("Unable to load the database, status: {}", res.status());
+            return Err("Unable to load the database");
+        }
+        res.bytes().map(|bytes| bytes.to_vec()).map_err(|e| {
+            error!("Unable to read response body: {}", e);
+            "Unable to read response body"
+        })
+    }
+
+    #[cfg(not(feature = "blocking"))]
+    fn fetch_blocking(_url: &str) -> Result<Vec<u8>, &'static str> {
+        Err("Loading from http(s) URLs without a runtime requires the \"blocking\" feature")
+    }
 
-        let bytes = if url.starts_with("file://") {
+    // Modification time of a local database (path or file:// URL)
+    fn source_modified(url_or_path: &str) -> Option<SystemTime> {
+        if url_or_path.starts_with("http://") || url_or_path.starts_with("https://") {
+            return None;
+        }
+        let path = url_or_path.strip_prefix("file://").unwrap_or(url_or_path);
+        Self::modified(Path::new(path))
+    }
+
+    // Try each URL of an ordered failover list in turn (e.g. primary mirror,
+    // secondary mirror, file:// fallback) and only resort to the cache file
+    // once all of them failed.
+    // `rate_limit` caps HTTP downloads to that many bytes per second (0 = unlimited).
+    #[cfg(feature = "async")]
+    pub async fn from_urls(
+        urls: &[String],
+        http_client: Option<&reqwest::Client>,
+        cache_file: Option<PathBuf>,
+        rate_limit: u64,
+    ) -> Result<Self, &'static str> {
+        Self::from_urls_with_progress(urls, http_client, cache_file, rate_limit, &|_, _| {}).await
+    }
+
+    // Same as from_urls, reporting the progress of HTTP downloads
+    #[cfg(feature = "async")]
+    pub async fn from_urls_with_progress(
+        urls: &[String],
+        http_client: Option<&reqwest::Client>,
+        cache_file: Option<PathBuf>,
+        rate_limit: u64,
+        progress: Progress<'_>,
+    ) -> Result<Self, &'static str> {
+        let progress = LoadProgress {
+            download: progress,
+            parse: &|_| {},
+        };
+        Self::from_urls_with_load_progress(urls, http_client, cache_file, rate_limit, progress).await
+    }
+
+    // Same as from_urls, reporting the progress of HTTP downloads and of
+    // the parse of the database
+    #[cfg(feature = "async")]
+    pub async fn from_urls_with_load_progress(
+        urls: &[String],
+        http_client: Option<&reqwest::Client>,
+        cache_file: Option<PathBuf>,
+        rate_limit: u64,
+        progress: LoadProgress<'_>,
+    ) -> Result<Self, &'static str> {
+        for url in urls {
+            match Self::fetch(url, http_client, rate_limit, progress.download).await {
+                Ok(bytes) => {
+                    // Local mirrors are as recent as their last update
+                    let updated = Self::source_modified(url).unwrap_or_else(SystemTime::now);
+                    // A mirror may answer with a captive portal page or a
+                    // truncated file: move on to the next source
+                    let asns = match Self::parse_data(&bytes, url.clone(), DbSource::of_url(url), updated, progress.parse) {
+                        Ok(asns) => asns,
+                        Err(e) if LOADS_CANCELLED.load(AtomicOrdering::Relaxed) => return Err(e),
+                        Err(e) => {
+                            warn!("Unable to parse the database from {}: {}", url, e);
+                            continue;
+                        }
+                    };
+                    // Only cache a download that parsed, off the async
+                    // workers: the lock may wait for another process
+                    if url.starts_with("http://") || url.starts_with("https://") {
+                        let cache_file = cache_file.clone();
+                        tokio::task::spawn_blocking(move || {
+                            Self::save_to_cache(&bytes, cache_file.as_deref());
+                        })
+                        .await
+                        .map_err(|_| "Unable to save the database to the cache")?;
+                    }
+                    return Ok(asns);
+                }
+                Err(e) => {
+                    warn!("Unable to load the database from {}: {}", url, e);
+                }
+            }
+        }
+
+        warn!("All database sources failed, attempting to use cached data");
+        match Self::try_load_fallback(cache_file.as_deref()) {
+            Ok((content, source)) => {
+                let updated = Self::modified(Path::new(&source)).unwrap_or_else(SystemTime::now);
+                Self::parse_data(&content, source, DbSource::Cache, updated, progress.parse)
+            }
+            Err(msg) => {
+                error!("{}", msg);
+                Err("Failed to load database from URL and all fallback sources")
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    async fn fetch(
+        url: &str,
+        http_client: Option<&reqwest::Client>,
+        rate_limit: u64,
+        progress: Progress<'_>,
+    ) -> Result<Vec<u8>, &'static str> {
+        info!("Loading the database from {}", url);
+
+        if url.starts_with("file://") {
             // Handle local file URL
             let path = url.trim_start_matches("file://");
-            info!("Loading the database from file://{}", path);
             match std::fs::read(path) {
-                Ok(content) => content,
+                Ok(content) => Ok(content),
                 Err(e) => {
                     error!("Unable to read the database: {}", e);
-                    return Err("Unable to read the database");
+                    Err("Unable to read the database")
                 }
             }
         } else if url.starts_with("http://") || url.starts_with("https://") {
-            // Handle HTTP or HTTPS URL
-            info!("Loading the database from {}", url);
-
             // Use provided client or create a new one
             let client;
             let client_ref = if let Some(provided_client) = http_client {
@@ -165,51 +743,66 @@ impl Asns {
                 Ok(res) => {
                     if !res.status().is_success() {
                         error!("Unable to load the database, status: {}", res.status());
-                        warn!("HTTP request failed, attempting to use cached data");
-
-                        return match Self::try_load_fallback(cache_file.as_deref()) {
-                            Ok(content) => Self::parse_data(content),
-                            Err(_) => {
-                                Err("Unable to load the database and no fallback data available")
-                            }
-                        };
+                        return Err("Unable to load the database");
                     }
 
-                    // Get response body as bytes
-                    match res.bytes().await {
-                        Ok(bytes) => bytes.to_vec(),
-                        Err(e) => {
-                            error!("Unable to read response body: {}", e);
-                            return Err("Unable to read response body");
-                        }
-                    }
+                    Self::read_body(res, rate_limit, progress).await
                 }
                 Err(e) => {
                     error!("Failed to send request: {}", e);
-                    warn!("Network request failed, attempting to use cached data");
-
-                    return match Self::try_load_fallback(cache_file.as_deref()) {
-                        Ok(content) => Self::parse_data(content),
-                        Err(msg) => {
-                            error!("{}", msg);
-                            Err("Failed to load database from URL and all fallback sources")
-                        }
-                    };
+                    Err("Failed to send request")
                 }
             }
         } else {
             error!("Unsupported URL scheme: {}", url);
-            return Err("Unsupported URL scheme");
-        };
-
-        // Save successful download to cache
-        if url.starts_with("http://") || url.starts_with("https://") {
-            Self::save_to_cache(&bytes, cache_file.as_deref());
+            Err("Unsupported URL scheme")
         }
+    }
 
-        Self::parse_data(bytes)
+    // Read the response body chunk by chunk. With a rate limit, the average
+    // rate since the start of the download is kept to `rate_limit` bytes per
+    // second: whenever more bytes have been received than that allows, sleep
+    // until the elapsed time caught up. There is no burst allowance.
+    #[cfg(feature = "async")]
+    async fn read_body(
+        mut res: reqwest::Response,
+        rate_limit: u64,
+        progress: Progress<'_>,
+    ) -> Result<Vec<u8>, &'static str> {
+        let total = res.content_length();
+        // The announced length is only a hint: a broken mirror may claim
+        // anything, so the reservation is capped and the rest grows as needed
+        let mut bytes = Vec::with_capacity(total.map_or(0, |t| t.min(MAX_BODY_RESERVATION)) as usize);
+        let start = Instant::now();
+        progress(0, total);
+        loop {
+            match res.chunk().await {
+                Ok(Some(chunk)) => {
+                    bytes.extend_from_slice(&chunk);
+                    progress(bytes.len() as u64, total);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Unable to read response body: {}", e);
+                    return Err("Unable to read response body");
+                }
+            }
+            if rate_limit > 0 {
+                let allowed = Duration::from_secs_f64(bytes.len() as f64 / rate_limit as f64);
+                let elapsed = start.elapsed();
+                if allowed > elapsed {
+                    tokio::time::sleep(allowed - elapsed).await;
+                }
+            }
+        }
+        Ok(bytes)
     }
 
+    // Cached downloads are stored as content-addressed snapshots next to the
+    // cache file (e.g. ip2asn-combined.<sha256 prefix>.tsv.gz), and the cache
+    // file itself is a symlink to the latest snapshot. If the upstream file
+    // didn't change, nothing is written at all.
+    #[cfg(feature = "async")]
     fn save_to_cache(bytes: &[u8], cache_file: Option<&Path>) {
         let target_path = cache_file
             .map(|p| p.to_path_buf())
@@ -219,118 +812,886 @@ impl Asns {
             return;
         };
 
-        if let Some(parent) = path.parent() {
-            if let Err(e) = fs::create_dir_all(parent) {
-                warn!("Failed to create cache directory {}: {}", parent.display(), e);
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if let Err(e) = fs::create_dir_all(&dir) {
+            warn!("Failed to create cache directory {}: {}", dir.display(), e);
+            return;
+        }
+
+        let _lock = match Self::lock_cache(&dir, &path) {
+            Ok(lock) => lock,
+            Err(e) => {
+                warn!("Failed to lock cache directory {}: {}", dir.display(), e);
                 return;
             }
+        };
+
+        let hash = Self::content_hash(bytes);
+        let (prefix, suffix) = Self::snapshot_name_parts(&path);
+        let snapshot_name = format!("{}{}{}", prefix, hash, suffix);
+        let snapshot = dir.join(&snapshot_name);
+
+        if fs::read_link(&path).is_ok_and(|target| target == Path::new(&snapshot_name))
+            && snapshot.is_file()
+        {
+            info!(
+                "Database unchanged, keeping cached snapshot {}",
+                snapshot.display()
+            );
+            Self::touch(&snapshot);
+            return;
+        }
+        if snapshot.is_file() {
+            // Back to a retained snapshot: it is the latest download again
+            Self::touch(&snapshot);
+        } else if let Err(e) = Self::write_atomically(&dir, &snapshot, bytes) {
+            warn!("Failed to cache database to {}: {}", snapshot.display(), e);
+            return;
         }
+        if let Err(e) = Self::point_cache_to(&path, &dir, &snapshot_name) {
+            warn!("Failed to update cache file {}: {}", path.display(), e);
+            return;
+        }
+        info!(
+            "Successfully cached database to {} ({})",
+            path.display(),
+            snapshot_name
+        );
 
-        match fs::write(&path, bytes) {
-            Ok(()) => info!("Successfully cached database to {}", path.display()),
-            Err(e) => warn!("Failed to cache database to {}: {}", path.display(), e),
+        // Remove the snapshots that are no longer referenced, unless they
+        // were in use within the retention period
+        let retention = Duration::from_secs(SNAPSHOT_RETENTION.load(AtomicOrdering::Relaxed));
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if name == snapshot_name || !Self::is_snapshot_name(&name, prefix, suffix) {
+                    continue;
+                }
+                let retained = Self::modified(&entry.path())
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age < retention);
+                if !retained {
+                    debug!("Removing old cache snapshot {}", name);
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
         }
     }
 
-    fn parse_data(bytes: Vec<u8>) -> Result<Self, &'static str> {
-        let mut data = String::new();
-        if GzDecoder::new(bytes.as_slice())
-            .read_to_string(&mut data)
-            .is_err()
+    // Short SHA-256 of a compressed database, naming its cache snapshot
+    fn content_hash(bytes: &[u8]) -> String {
+        Sha256::digest(bytes)[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // The modification time of a snapshot tells when it was last downloaded
+    #[cfg(feature = "async")]
+    fn touch(snapshot: &Path) {
+        if let Err(e) = fs::File::options()
+            .write(true)
+            .open(snapshot)
+            .and_then(|file| file.set_modified(SystemTime::now()))
         {
-            error!("Unable to decompress the database");
-            return Err("Unable to decompress the database");
+            debug!("Failed to touch {}: {}", snapshot.display(), e);
         }
+    }
 
-        // String interning pools to deduplicate country codes and descriptions
-        let mut country_pool: HashMap<String, Arc<str>> = HashMap::new();
-        let mut description_pool: HashMap<String, Arc<str>> = HashMap::new();
+    // Origin AS of `ip` in each snapshot retained next to the cache file,
+    // oldest first, with consecutive snapshots of the same AS merged into
+    // one window. Snapshots are only decompressed the first time they are
+    // asked about; their ranges are kept until they are removed.
+    pub fn origin_history(cache_file: &Path, ip: IpAddr) -> std::io::Result<Vec<OriginWindow>> {
+        let snapshots = Self::retained_snapshots(cache_file)?;
+        let mut cache = SNAPSHOT_RANGES.lock().unwrap_or_else(PoisonError::into_inner);
+        let cache = cache.get_or_insert_with(HashMap::new);
+        // Forget the snapshots removed since
+        cache.retain(|path, _| snapshots.iter().any(|(_, _, snapshot)| snapshot == path));
+        let mut windows: Vec<OriginWindow> = Vec::new();
+        for (first_seen, last_seen, path) in snapshots {
+            let ranges = match cache.get(&path) {
+                Some(ranges) => ranges.clone(),
+                None => match SnapshotRanges::read(&path) {
+                    Ok(ranges) => {
+                        let ranges = Arc::new(ranges);
+                        cache.insert(path, ranges.clone());
+                        ranges
+                    }
+                    Err(e) => {
+                        warn!("Skipping unreadable snapshot {}: {}", path.display(), e);
+                        continue;
+                    }
+                },
+            };
+            let (number, country, description) = ranges
+                .get(ip)
+                .map(|(number, country, description)| (number, country.to_string(), description.to_string()))
+                .unwrap_or_default();
+            // Content going back to a retained snapshot reuses it, and its
+            // creation time predates the windows since
+            let first_seen = match windows.last() {
+                Some(window) => first_seen.max(window.last_seen),
+                None => first_seen,
+            };
+            match windows.last_mut() {
+                Some(window) if window.number == number => {
+                    window.last_seen = last_seen;
+                    window.country = country;
+                    window.description = description;
+                    window.snapshots += 1;
+                }
+                _ => windows.push(OriginWindow {
+                    first_seen,
+                    last_seen,
+                    number,
+                    country,
+                    description,
+                    snapshots: 1,
+                }),
+            }
+        }
+        Ok(windows)
+    }
 
-        let mut asns = BTreeSet::new();
-        let mut asn_meta: HashMap<u32, (Arc<str>, Arc<str>)> = HashMap::new();
+    // Prefix count and address space of an AS number or a country in each
+    // snapshot retained next to the cache file, oldest first. Snapshots are
+    // only scanned the first time they are asked about for a subject.
+    pub fn snapshot_stats(cache_file: &Path, subject: &StatsSubject) -> std::io::Result<Vec<SnapshotStats>> {
+        let snapshots = Self::retained_snapshots(cache_file)?;
+        let mut stats = Vec::with_capacity(snapshots.len());
+        for (first_seen, last_seen, path) in &snapshots {
+            let key = (path.clone(), subject.clone());
+            let cached = SNAPSHOT_COUNTS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_ref()
+                .and_then(|counts| counts.get(&key).copied());
+            let counts = match cached {
+                Some(counts) => counts,
+                None => match Self::count_snapshot(path, subject) {
+                    Ok(counts) => {
+                        SNAPSHOT_COUNTS
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .get_or_insert_with(HashMap::new)
+                            .insert(key, counts);
+                        counts
+                    }
+                    Err(e) => {
+                        warn!("Skipping unreadable snapshot {}: {}", path.display(), e);
+                        continue;
+                    }
+                },
+            };
+            stats.push(SnapshotStats {
+                first_seen: *first_seen,
+                last_seen: *last_seen,
+                counts,
+            });
+        }
+        // Forget the snapshots removed since
+        if let Some(counts) = SNAPSHOT_COUNTS.lock().unwrap_or_else(PoisonError::into_inner).as_mut() {
+            counts.retain(|(path, _), _| snapshots.iter().any(|(_, _, snapshot)| snapshot == path));
+        }
+        Ok(stats)
+    }
 
-        for line in data.split_terminator('\n') {
-            if line.trim().is_empty() {
+    fn count_snapshot(path: &Path, subject: &StatsSubject) -> std::io::Result<SnapshotCounts> {
+        let reader = BufReader::new(GzDecoder::new(fs::File::open(path)?));
+        let mut counts = SnapshotCounts::default();
+        for line in reader.lines() {
+            let line = line?;
+            let Some(record) = Self::parse_line(&line) else { continue };
+            let matches = match subject {
+                StatsSubject::Asn(number) => record.number == *number,
+                // As corrected when the database is loaded
+                StatsSubject::Country(country) => {
+                    country::correct(&normalize::country(&record.country)).eq_ignore_ascii_case(country)
+                }
+            };
+            if !matches {
                 continue;
             }
-            let mut parts = line.split('\t');
-            let first_ip = match parts.next().and_then(|s| IpAddr::from_str(s).ok()) {
-                Some(ip) => ip,
-                None => {
-                    warn!("Invalid IP address in line: {}", line);
-                    continue;
+            counts.prefixes += cidr::prefix_count(record.first_ip, record.last_ip) as u64;
+            match (record.first_ip, record.last_ip) {
+                (IpAddr::V4(first), IpAddr::V4(last)) => {
+                    counts.ipv4_addresses += (u32::from(last) - u32::from(first)) as u64 + 1;
                 }
-            };
-            let last_ip = match parts.next().and_then(|s| IpAddr::from_str(s).ok()) {
-                Some(ip) => ip,
-                None => {
-                    warn!("Invalid IP address in line: {}", line);
-                    continue;
+                (IpAddr::V6(first), IpAddr::V6(last)) => {
+                    counts.ipv6_64s += ((u128::from(last) >> 64) - (u128::from(first) >> 64)) as u64 + 1;
                 }
-            };
-            let number = match parts.next().and_then(|s| u32::from_str(s).ok()) {
-                Some(num) => num,
-                None => {
-                    warn!("Invalid ASN number in line: {}", line);
-                    continue;
+                _ => {}
+            }
+        }
+        Ok(counts)
+    }
+
+    // Snapshots retained next to the cache file, oldest first, with the
+    // times they were first and last seen
+    fn retained_snapshots(cache_file: &Path) -> std::io::Result<Vec<(SystemTime, SystemTime, PathBuf)>> {
+        let dir = match cache_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let (prefix, suffix) = Self::snapshot_name_parts(cache_file);
+        let mut snapshots: Vec<(SystemTime, SystemTime, PathBuf)> = Vec::new();
+        for entry in fs::read_dir(&dir)?.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !Self::is_snapshot_name(&name, prefix, suffix) {
+                continue;
+            }
+            let Ok(meta) = entry.metadata() else { continue };
+            let Ok(last_seen) = meta.modified() else { continue };
+            let first_seen = meta.created().unwrap_or(last_seen).min(last_seen);
+            snapshots.push((first_seen, last_seen, entry.path()));
+        }
+        snapshots.sort_by_key(|(_, last_seen, _)| *last_seen);
+        Ok(snapshots)
+    }
+
+    // Load the cache file if it was downloaded less than `max_age` ago, so
+    // that repeated CLI runs skip the network entirely.
+    pub fn from_fresh_cache(cache_file: Option<&Path>, max_age: Duration) -> Option<Self> {
+        let path = cache_file
+            .map(|p| p.to_path_buf())
+            .or_else(Self::default_cache_file_path)?;
+        let modified = Self::modified(&path)?;
+        let age = SystemTime::now().duration_since(modified).ok()?;
+        if age > max_age {
+            debug!("Cache file {} is {} seconds old, refreshing", path.display(), age.as_secs());
+            return None;
+        }
+        let content = Self::read_cache_file(&path)?;
+        info!("Using cached database {} ({} seconds old)", path.display(), age.as_secs());
+        Self::parse_data(&content, path.display().to_string(), DbSource::Cache, modified, &|_| {}).ok()
+    }
+
+    // Remove control characters (including a stray \r of CRLF files) and
+    // bidirectional formatting characters, which could otherwise break plain
+    // text output or visually reorder surrounding text, and trim whitespace.
+    fn sanitize(field: &str) -> Cow<'_, str> {
+        let unsafe_char = |c: char| {
+            c.is_control() || matches!(c, '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}')
+        };
+        let trimmed = field.trim();
+        if trimmed.contains(unsafe_char) {
+            Cow::Owned(trimmed.chars().filter(|&c| !unsafe_char(c)).collect::<String>().trim().to_string())
+        } else {
+            Cow::Borrowed(trimmed)
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+
+    // Read a cache file, only trusting it if it is a complete gzip stream:
+    // a file truncated by a crash or a concurrent writer is skipped.
+    fn read_cache_file(path: &Path) -> Option<Vec<u8>> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(_) => {
+                debug!("Fallback file not found: {}", path.display());
+                return None;
+            }
+        };
+        if let Err(e) = std::io::copy(&mut GzDecoder::new(content.as_slice()), &mut std::io::sink()) {
+            warn!("Ignoring corrupt cache file {}: {}", path.display(), e);
+            return None;
+        }
+        Some(content)
+    }
+
+    // Advisory lock serializing cache updates between processes sharing the
+    // cache directory, e.g. the server and CLI runs. Released when dropped.
+    // Gives up after CACHE_LOCK_TIMEOUT, rather than waiting for a stuck
+    // process forever.
+    #[cfg(feature = "async")]
+    fn lock_cache(dir: &Path, path: &Path) -> std::io::Result<fs::File> {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(Self::CACHE_FILE_NAME);
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(format!(".{}.lock", name)))?;
+        let deadline = Instant::now() + CACHE_LOCK_TIMEOUT;
+        loop {
+            match lock.try_lock() {
+                Ok(()) => return Ok(lock),
+                Err(fs::TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(100))
                 }
-            };
+                Err(fs::TryLockError::WouldBlock) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "held by another process",
+                    ))
+                }
+                Err(fs::TryLockError::Error(e)) => return Err(e),
+            }
+        }
+    }
 
-            // Intern country code
-            let country_str = parts.next().unwrap_or("");
-            let country = country_pool
-                .entry(country_str.to_owned())
-                .or_insert_with(|| Arc::from(country_str))
-                .clone();
-
-            // Intern description
-            let description_str = parts.next().unwrap_or("");
-            let description = description_pool
-                .entry(description_str.to_owned())
-                .or_insert_with(|| Arc::from(description_str))
-                .clone();
-
-            let asn = Asn {
-                first_ip,
-                last_ip,
-                number,
-                country: country.clone(),
-                description: description.clone(),
-            };
-            asns.insert(asn);
+    // Write to a temporary file private to this process, then rename it
+    // into place, so that readers never see a partially written file
+    #[cfg(feature = "async")]
+    fn write_atomically(dir: &Path, target: &Path, bytes: &[u8]) -> std::io::Result<()> {
+        let name = target.file_name().and_then(|n| n.to_str()).unwrap_or("cache");
+        let tmp = dir.join(format!(".{}.{}.tmp", name, std::process::id()));
+        let result = fs::File::create(&tmp)
+            .and_then(|mut file| file.write_all(bytes).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&tmp, target));
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    // "dir/ip2asn-combined.tsv.gz" -> ("ip2asn-combined.", ".tsv.gz")
+    fn snapshot_name_parts(path: &Path) -> (&str, &str) {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(Self::CACHE_FILE_NAME);
+        match name.find('.') {
+            Some(i) => (&name[..=i], &name[i..]),
+            None => (name, ""),
+        }
+    }
+
+    fn is_snapshot_name(name: &str, prefix: &str, suffix: &str) -> bool {
+        name.strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix))
+            .is_some_and(|hash| hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+    }
+
+    // Atomically replace the cache file with a symlink to the snapshot
+    #[cfg(all(unix, feature = "async"))]
+    fn point_cache_to(path: &Path, dir: &Path, snapshot_name: &str) -> std::io::Result<()> {
+        let tmp = dir.join(format!(".{}.{}.link", snapshot_name, std::process::id()));
+        let _ = fs::remove_file(&tmp);
+        std::os::unix::fs::symlink(snapshot_name, &tmp)?;
+        fs::rename(&tmp, path)
+    }
 
-            // Store AS meta (country + description) if not already present
-            asn_meta.entry(number).or_insert_with(|| (country, description));
+    // Without symlinks, the cache file is a hard link to the latest snapshot,
+    // atomically replaced too. Filesystems without hard links get a copy.
+    #[cfg(all(not(unix), feature = "async"))]
+    fn point_cache_to(path: &Path, dir: &Path, snapshot_name: &str) -> std::io::Result<()> {
+        let snapshot = dir.join(snapshot_name);
+        let tmp = dir.join(format!(".{}.{}.link", snapshot_name, std::process::id()));
+        let _ = fs::remove_file(&tmp);
+        if let Err(e) = fs::hard_link(&snapshot, &tmp) {
+            debug!("Failed to hard link {}, copying it: {}", snapshot.display(), e);
+            let bytes = fs::read(&snapshot)?;
+            return Self::write_atomically(dir, path, &bytes);
         }
+        let result = fs::rename(&tmp, path);
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        result
+    }
+
+    // Parse a gzipped database as it is decompressed, line by line, so that
+    // the decompressed database is never held in memory as a whole
+    fn parse_data(
+        bytes: &[u8],
+        source: String,
+        db_source: DbSource,
+        updated: SystemTime,
+        progress: ParseProgressFn<'_>,
+    ) -> Result<Self, &'static str> {
+        let generation = Self::content_hash(bytes);
+        let total_bytes = bytes.len() as u64;
+        // The remaining input of the decoder tells the offset in the
+        // compressed database
+        let mut reader = BufReader::new(GzDecoder::new(bytes));
+        let position = |reader: &BufReader<GzDecoder<&[u8]>>, lines| ParseProgress {
+            lines,
+            parsed_bytes: total_bytes - reader.get_ref().get_ref().len() as u64,
+            total_bytes,
+        };
+
+        let mut interner = Interner::default();
+        let mut collector = RangesCollector::new()?;
+        let mut invalid_utf8_lines = 0;
+
+        let mut buf = Vec::new();
+        let mut lines = 0;
+        loop {
+            buf.clear();
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Unable to decompress the database: {}", e);
+                    return Err("Unable to decompress the database");
+                }
+            }
+            lines += 1;
+            if lines % PARSE_PROGRESS_LINES == 0 {
+                if LOADS_CANCELLED.load(AtomicOrdering::Relaxed) {
+                    warn!("Database parse cancelled after {} lines", lines);
+                    return Err("Database load cancelled");
+                }
+                progress(position(&reader, lines));
+            }
+            let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+            // A stray invalid byte only affects its own line
+            let line = String::from_utf8_lossy(line);
+            if matches!(line, Cow::Owned(_)) {
+                invalid_utf8_lines += 1;
+            }
+            let Some(record) = Self::parse_line(&line) else {
+                continue;
+            };
+            collector.push(interner.intern(record))?;
+        }
+        progress(position(&reader, lines));
+        let Interner {
+            countries: country_pool,
+            descriptions: description_pool,
+            unmapped: unmapped_countries,
+        } = interner;
+
+        let asns = collector.finish(unmapped_countries, source, db_source, updated, generation)?;
 
         info!(
-            "Database loaded with {} entries ({} unique countries, {} unique descriptions)",
+            "Database {} loaded with {} entries ({} unique countries, {} unique descriptions)",
+            asns.generation,
             asns.len(),
             country_pool.len(),
             description_pool.len()
         );
-        Ok(Self { asns, asn_meta })
+        if invalid_utf8_lines > 0 {
+            warn!(
+                "{} lines of the database contained invalid UTF-8, replaced with U+FFFD",
+                invalid_utf8_lines
+            );
+        }
+        if !asns.unmapped_countries.is_empty() {
+            warn!(
+                "Unknown country codes in the database: {}",
+                asns.unmapped_countries
+                    .iter()
+                    .map(|(code, count)| format!("{:?} ({} ranges)", code, count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        Ok(asns)
+    }
+
+    // The ranges in the binary snapshot format, to replicate the database to
+    // standby instances
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut builder = MmapBuilder::default();
+        for asn in self.iter_ranges() {
+            // Records kept in memory can't fail to be written
+            let _ = builder.push(&asn);
+        }
+        builder.into_bytes()
+    }
+
+    // A database replicated from another instance, keeping the source,
+    // update time and generation of the original
+    pub fn from_snapshot(
+        bytes: &[u8],
+        source: String,
+        updated: SystemTime,
+        generation: String,
+    ) -> Result<Self, &'static str> {
+        let ranges = mmap::decode(bytes).map_err(|e| {
+            error!("Unable to read the replicated snapshot: {}", e);
+            "Unable to read the replicated snapshot"
+        })?;
+        let mut collector = RangesCollector::new()?;
+        // Country codes were already corrected by the primary
+        let mut unmapped_countries: BTreeMap<Arc<str>, usize> = BTreeMap::new();
+        for asn in ranges {
+            if !country::is_known(&asn.country) {
+                *unmapped_countries.entry(asn.country.clone()).or_default() += 1;
+            }
+            collector.push(asn)?;
+        }
+        let asns = collector.finish(unmapped_countries, source, DbSource::Replica, updated, generation)?;
+        info!("Database {} replicated with {} entries", asns.generation, asns.len());
+        Ok(asns)
+    }
+
+    // Fields of a database line, or None for blank and invalid lines
+    fn parse_line(line: &str) -> Option<RawRecord<'_>> {
+        if line.trim().is_empty() {
+            return None;
+        }
+        let mut parts = line.split('\t');
+        let Some(first_ip) = parts.next().and_then(|s| IpAddr::from_str(s).ok()) else {
+            warn!("Invalid IP address in line: {}", line);
+            return None;
+        };
+        let Some(last_ip) = parts.next().and_then(|s| IpAddr::from_str(s).ok()) else {
+            warn!("Invalid IP address in line: {}", line);
+            return None;
+        };
+        let Some(number) = parts.next().and_then(|s| u32::from_str(s).ok()) else {
+            warn!("Invalid ASN number in line: {}", line);
+            return None;
+        };
+        Some(RawRecord {
+            first_ip,
+            last_ip,
+            number,
+            country: Self::sanitize(parts.next().unwrap_or("")),
+            description: Self::sanitize(parts.next().unwrap_or("")),
+        })
+    }
+
+    // Store AS meta (country + description) if not already present, and
+    // count the ranges and prefixes of the ASN, per country
+    fn index_meta(
+        asn: &Asn,
+        asn_meta: &mut HashMap<u32, (Arc<str>, Arc<str>)>,
+        range_counts: &mut HashMap<u32, usize>,
+        asn_countries: &mut HashMap<u32, Vec<CountryShare>>,
+    ) {
+        asn_meta
+            .entry(asn.number)
+            .or_insert_with(|| (asn.country.clone(), asn.description.clone()));
+        *range_counts.entry(asn.number).or_default() += 1;
+        // Most ASNs have a single country, so a list is searched faster than
+        // a map would be
+        let shares = asn_countries.entry(asn.number).or_default();
+        let share = match shares.iter().position(|share| share.country == asn.country) {
+            Some(i) => &mut shares[i],
+            None => {
+                shares.push(CountryShare {
+                    country: asn.country.clone(),
+                    ranges: 0,
+                    prefixes: 0,
+                });
+                shares.last_mut().unwrap()
+            }
+        };
+        share.ranges += 1;
+        share.prefixes += cidr::prefix_count(asn.first_ip, asn.last_ip);
+    }
+
+    // Group the ASNs by the country code of their metadata
+    fn index_countries(asn_meta: &HashMap<u32, (Arc<str>, Arc<str>)>) -> HashMap<Arc<str>, Vec<u32>> {
+        let mut country_asns: HashMap<Arc<str>, Vec<u32>> = HashMap::new();
+        for (&number, (country, _)) in asn_meta {
+            country_asns.entry(country.clone()).or_default().push(number);
+        }
+        for numbers in country_asns.values_mut() {
+            numbers.sort_unstable();
+        }
+        country_asns
+    }
+
+    // Run the stages of parse_data one after the other on a gzipped
+    // database, keeping the output of each stage, and report the time each
+    // one took, followed by lookups with both index modes. For evaluating
+    // parser and index optimizations on real data files.
+    pub fn bench_parse(bytes: &[u8], report: &mut dyn FnMut(ParseStage)) -> Result<(), &'static str> {
+        let start = Instant::now();
+        let mut data = Vec::new();
+        if GzDecoder::new(bytes).read_to_end(&mut data).is_err() {
+            return Err("Unable to decompress the database");
+        }
+        report(ParseStage {
+            name: "decompress",
+            elapsed: start.elapsed(),
+            count: data.len(),
+            unit: "bytes",
+        });
+
+        let start = Instant::now();
+        let records: Vec<RawRecord<'_>> = data
+            .split(|&b| b == b'\n')
+            .filter_map(|line| match String::from_utf8_lossy(line) {
+                Cow::Borrowed(line) => Self::parse_line(line),
+                Cow::Owned(line) => Self::parse_line(&line).map(RawRecord::into_owned),
+            })
+            .collect();
+        report(ParseStage {
+            name: "parse",
+            elapsed: start.elapsed(),
+            count: records.len(),
+            unit: "records",
+        });
+
+        let start = Instant::now();
+        let mut interner = Interner::default();
+        let asns: Vec<Asn> = records.into_iter().map(|record| interner.intern(record)).collect();
+        report(ParseStage {
+            name: "intern",
+            elapsed: start.elapsed(),
+            count: interner.countries.len() + interner.descriptions.len(),
+            unit: "unique strings",
+        });
+
+        let start = Instant::now();
+        let mut asn_meta = HashMap::new();
+        let mut range_counts = HashMap::new();
+        let mut asn_countries = HashMap::new();
+        let mut ranges = BTreeSet::new();
+        for asn in asns {
+            Self::index_meta(&asn, &mut asn_meta, &mut range_counts, &mut asn_countries);
+            ranges.insert(asn);
+        }
+        report(ParseStage {
+            name: "index",
+            elapsed: start.elapsed(),
+            count: ranges.len(),
+            unit: "ranges",
+        });
+
+        let start = Instant::now();
+        let country_asns = Self::index_countries(&asn_meta);
+        report(ParseStage {
+            name: "countries",
+            elapsed: start.elapsed(),
+            count: country_asns.len(),
+            unit: "country codes",
+        });
+
+        let start = Instant::now();
+        let buckets = BucketIndex::new(ranges.iter().cloned().collect());
+        report(ParseStage {
+            name: "buckets",
+            elapsed: start.elapsed(),
+            count: buckets.buckets(),
+            unit: "buckets",
+        });
+
+        // Both ends of every range, for comparing the lookups of both index modes
+        let probes: Vec<IpAddr> = ranges.iter().flat_map(|asn| [asn.first_ip, asn.last_ip]).collect();
+        let start = Instant::now();
+        for &ip in &probes {
+            let fasn = Asn::from_single_ip(ip);
+            std::hint::black_box(ranges.range((Unbounded, Included(&fasn))).next_back());
+        }
+        report(ParseStage {
+            name: "lookup",
+            elapsed: start.elapsed(),
+            count: probes.len(),
+            unit: "lookups (binary)",
+        });
+        let start = Instant::now();
+        for &ip in &probes {
+            std::hint::black_box(buckets.find(ip));
+        }
+        report(ParseStage {
+            name: "lookup",
+            elapsed: start.elapsed(),
+            count: probes.len(),
+            unit: "lookups (buckets)",
+        });
+        Ok(())
+    }
+
+    // Country codes that are neither ISO 3166-1 alpha-2 codes nor corrected
+    // to one, with their number of ranges
+    pub fn unmapped_country_codes(&self) -> &BTreeMap<Arc<str>, usize> {
+        &self.unmapped_countries
+    }
+
+    pub fn updated(&self) -> SystemTime {
+        self.updated
+    }
+
+    // Identifies the loaded database: the hash part of its snapshot name in
+    // the cache directory, e.g. ip2asn-combined.<generation>.tsv.gz
+    pub fn generation(&self) -> &str {
+        &self.generation
+    }
+
+    // Number of ranges announced by an ASN
+    pub fn range_count(&self, number: u32) -> usize {
+        self.range_counts.get(&number).copied().unwrap_or(0)
+    }
+
+    // Number of distinct ASNs announcing ranges
+    pub fn asn_count(&self) -> usize {
+        self.range_counts.keys().filter(|&&number| number > 0).count()
+    }
+
+    // Number of distinct country codes of announced ranges
+    pub fn country_count(&self) -> usize {
+        self.country_asns.len()
+    }
+
+    // URL or file path the database was loaded from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Whether the database was downloaded, read from a local file, or read
+    // from the cache file
+    pub fn db_source(&self) -> DbSource {
+        self.db_source
+    }
+
+    pub fn len(&self) -> usize {
+        match &self.ranges {
+            Ranges::Memory(ranges) => ranges.len(),
+            Ranges::Mmap(ranges) => ranges.len(),
+            Ranges::Buckets(ranges) => ranges.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // All ranges, ordered by first address
+    fn iter_ranges(&self) -> Box<dyn Iterator<Item = Cow<'_, Asn>> + '_> {
+        match &self.ranges {
+            Ranges::Memory(ranges) => Box::new(ranges.iter().map(Cow::Borrowed)),
+            Ranges::Mmap(ranges) => Box::new(ranges.iter().map(Cow::Owned)),
+            Ranges::Buckets(ranges) => Box::new(ranges.iter().map(Cow::Borrowed)),
+        }
+    }
+
+    // Membership sets are only built on first use, so that one-shot lookups
+    // don't pay for them.
+    fn membership(&self) -> &MembershipSets {
+        self.membership.get_or_init(|| {
+            let mut by_country: HashMap<Arc<str>, RoaringBitmap> = HashMap::new();
+            let mut asn_sizes: HashMap<u32, u64> = HashMap::new();
+            for a in self.iter_ranges() {
+                if let (IpAddr::V4(f), IpAddr::V4(l)) = (a.first_ip, a.last_ip) {
+                    if a.number == 0 {
+                        continue;
+                    }
+                    let (f, l) = (u32::from(f), u32::from(l));
+                    by_country
+                        .entry(a.country.clone())
+                        .or_default()
+                        .insert_range(f..=l);
+                    *asn_sizes.entry(a.number).or_default() += (l - f) as u64 + 1;
+                }
+            }
+
+            let mut by_asn: HashMap<u32, RoaringBitmap> = HashMap::new();
+            for a in self.iter_ranges() {
+                if let (IpAddr::V4(f), IpAddr::V4(l)) = (a.first_ip, a.last_ip) {
+                    if asn_sizes.get(&a.number).copied().unwrap_or(0) >= Self::LARGE_ASN_MIN_ADDRESSES {
+                        by_asn
+                            .entry(a.number)
+                            .or_default()
+                            .insert_range(u32::from(f)..=u32::from(l));
+                    }
+                }
+            }
+
+            for bitmap in by_country.values_mut().chain(by_asn.values_mut()) {
+                bitmap.optimize();
+            }
+            info!(
+                "Membership sets built ({} countries, {} large ASNs)",
+                by_country.len(),
+                by_asn.len()
+            );
+            MembershipSets { by_country, by_asn }
+        })
     }
 
-    pub fn lookup_by_ip(&self, ip: IpAddr) -> Option<&Asn> {
-        let fasn = Asn::from_single_ip(ip);
-        match self.asns.range((Unbounded, Included(&fasn))).next_back() {
-            Some(found) if ip <= found.last_ip && found.number > 0 => Some(found),
-            _ => None,
+    // Check whether an IP belongs to a given country. IPv4 addresses are
+    // answered from the membership sets, IPv6 addresses by a regular lookup.
+    pub fn ip_in_country(&self, ip: IpAddr, country_code: &str) -> bool {
+        match ip {
+            IpAddr::V4(v4) => self
+                .membership()
+                .by_country
+                .get(country_code.trim())
+                .is_some_and(|bitmap| bitmap.contains(u32::from(v4))),
+            IpAddr::V6(_) => self
+                .lookup_by_ip(ip)
+                .is_some_and(|found| found.country.as_ref() == country_code.trim()),
         }
     }
 
+    // Check whether an IP is announced by a given ASN. IPv4 addresses of large
+    // ASNs are answered from the membership sets, anything else by a regular lookup.
+    pub fn ip_in_asn(&self, ip: IpAddr, number: u32) -> bool {
+        if let IpAddr::V4(v4) = ip {
+            if let Some(bitmap) = self.membership().by_asn.get(&number) {
+                return bitmap.contains(u32::from(v4));
+            }
+        }
+        self.lookup_by_ip(ip).is_some_and(|found| found.number == number)
+    }
+
+    // Announced range of an IP address
+    pub fn lookup_by_ip(&self, ip: IpAddr) -> Option<Cow<'_, Asn>> {
+        self.lookup_row_by_ip(ip).filter(|found| found.number > 0)
+    }
+
+    // Row of the dataset covering an IP address, including the AS0 rows of
+    // space that is listed as not routed. None for space missing from the
+    // dataset altogether.
+    pub fn lookup_row_by_ip(&self, ip: IpAddr) -> Option<Cow<'_, Asn>> {
+        let found = match &self.ranges {
+            Ranges::Memory(ranges) => {
+                let fasn = Asn::from_single_ip(ip);
+                ranges
+                    .range((Unbounded, Included(&fasn)))
+                    .next_back()
+                    .map(Cow::Borrowed)
+            }
+            Ranges::Mmap(ranges) => ranges.find(ip).map(Cow::Owned),
+            Ranges::Buckets(ranges) => ranges.find(ip).map(Cow::Borrowed),
+        };
+        found.filter(|found| ip <= found.last_ip)
+    }
+
+    // Announced ranges overlapping [first, last], ordered by first address
+    pub fn ranges_overlapping(&self, first: IpAddr, last: IpAddr) -> Vec<Cow<'_, Asn>> {
+        let candidates: Box<dyn Iterator<Item = Cow<'_, Asn>>> = match &self.ranges {
+            Ranges::Memory(ranges) => {
+                let (first_key, last_key) = (Asn::from_single_ip(first), Asn::from_single_ip(last));
+                Box::new(
+                    ranges
+                        .range((Unbounded, Included(&first_key)))
+                        .next_back()
+                        .into_iter()
+                        .chain(ranges.range((Excluded(&first_key), Included(&last_key))))
+                        .map(Cow::Borrowed),
+                )
+            }
+            Ranges::Mmap(ranges) => Box::new(ranges.between(first, last).map(Cow::Owned)),
+            Ranges::Buckets(ranges) => Box::new(ranges.between(first, last).iter().map(Cow::Borrowed)),
+        };
+        candidates
+            .filter(|a| a.number > 0 && a.first_ip <= last && a.last_ip >= first)
+            .filter(|a| a.first_ip.is_ipv4() == first.is_ipv4())
+            .collect()
+    }
+
     pub fn lookup_meta_by_asn(&self, number: u32) -> Option<(Arc<str>, Arc<str>)> {
         self.asn_meta
             .get(&number)
             .map(|(cc, desc)| (cc.clone(), desc.clone()))
     }
 
-    // Build a temporary list of ranges for a given ASN by scanning the in-memory set.
+    // Registry countries of the ranges of an ASN, with the most prefixes
+    // first, from the index built when the database is loaded
+    pub fn countries_by_asn(&self, number: u32) -> Vec<CountryShare> {
+        let mut shares = self.asn_countries.get(&number).cloned().unwrap_or_default();
+        shares.sort_by(|a, b| b.prefixes.cmp(&a.prefixes).then_with(|| a.country.cmp(&b.country)));
+        shares
+    }
+
+    // Build a temporary list of ranges for a given ASN by scanning all ranges.
     // No persistent memory overhead; O(N) per call.
     pub fn collect_ranges_by_asn(&self, number: u32) -> Vec<(IpAddr, IpAddr)> {
-        self.asns
-            .iter()
+        self.iter_ranges()
             .filter(|a| a.number == number)
             .map(|a| (a.first_ip, a.last_ip))
             .collect()
@@ -347,27 +1708,150 @@ impl Asns {
         v
     }
 
-    // Enumerate all ASNs for a given country code, sorted by AS number.
-    pub fn enumerate_asns_by_country(&self, country_code: &str) -> Vec<u32> {
-        let cc = country_code.trim();
-        let mut v: Vec<u32> = self
+    // ASNs whose description contains `text`, case-insensitively, sorted by
+    // AS number.
+    pub fn search_asn_meta(&self, text: &str) -> Vec<(u32, Arc<str>, Arc<str>)> {
+        let needle = text.to_lowercase();
+        let mut v: Vec<(u32, Arc<str>, Arc<str>)> = self
             .asn_meta
             .iter()
-            .filter_map(|(&n, (c, _))| if c.as_ref() == cc { Some(n) } else { None })
+            .filter(|(&n, (_, desc))| n > 0 && desc.to_lowercase().contains(&needle))
+            .map(|(&n, (cc, desc))| (n, cc.clone(), desc.clone()))
             .collect();
-        v.sort_unstable();
-        v.dedup();
+        v.sort_unstable_by_key(|x| x.0);
         v
     }
 
-    // Collect all ranges for a given country code by scanning the in-memory set.
+    // Enumerate all ASNs for a given country code, sorted by AS number, from
+    // the country index built when the database is loaded.
+    pub fn enumerate_asns_by_country(&self, country_code: &str) -> Vec<u32> {
+        self.country_asns
+            .get(country_code.trim())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Collect all ranges for a given country code by scanning all ranges.
     // No persistent memory overhead; O(N) per call.
     pub fn collect_ranges_by_country(&self, country_code: &str) -> Vec<(IpAddr, IpAddr)> {
         let cc = country_code.trim();
-        self.asns
-            .iter()
+        self.iter_ranges()
             .filter(|a| a.country.as_ref() == cc && a.number > 0)
             .map(|a| (a.first_ip, a.last_ip))
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn database(lines: &[&[u8]]) -> Asns {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        for line in lines {
+            encoder.write_all(line).unwrap();
+            encoder.write_all(b"\n").unwrap();
+        }
+        Asns::from_gzip_bytes(encoder.finish().unwrap(), "test.tsv.gz").unwrap()
+    }
+
+    fn description(asns: &Asns, ip: &str) -> String {
+        asns.lookup_by_ip(ip.parse().unwrap()).unwrap().description.to_string()
+    }
+
+    #[test]
+    fn sanitize_strips_control_characters() {
+        assert_eq!(Asns::sanitize("EXAMPLE-AS\r"), "EXAMPLE-AS");
+        assert_eq!(Asns::sanitize("A\u{0}B\u{1b}[31mC\tD\u{7f}"), "AB[31mCD");
+        assert_eq!(Asns::sanitize(" \u{85}LINE\nBREAK \n"), "LINEBREAK");
+        assert_eq!(Asns::sanitize("\u{7}\u{8}"), "");
+    }
+
+    #[test]
+    fn sanitize_strips_bidi_formatting() {
+        assert_eq!(Asns::sanitize("EVIL\u{202e}gpj.exe"), "EVILgpj.exe");
+        assert_eq!(Asns::sanitize("\u{2066}ISOLATED\u{2069} \u{200f}"), "ISOLATED");
+    }
+
+    #[test]
+    fn sanitize_keeps_quotes_and_markup() {
+        // Escaping them is up to each output format
+        let field = r#"ACME "Networks" <script>alert('x')</script> & Co, =1+1"#;
+        assert!(matches!(Asns::sanitize(field), Cow::Borrowed(s) if s == field));
+        assert_eq!(Asns::sanitize("Société Générale – 中国电信"), "Société Générale – 中国电信");
+    }
+
+    #[test]
+    fn sanitize_long_fields() {
+        let long = format!("{}\u{0}{}", "A".repeat(100_000), "B".repeat(100_000));
+        let sanitized = Asns::sanitize(&long);
+        assert_eq!(sanitized.len(), 200_000);
+        assert!(!sanitized.contains('\u{0}'));
+    }
+
+    #[test]
+    fn parse_tolerates_adversarial_descriptions() {
+        let long = format!("1.0.2.0\t1.0.2.255\t64500\tUS\t{}\u{1b}", "X".repeat(65_536));
+        let asns = database(&[
+            b"1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUD\xff\xfeFLARE",
+            b"1.0.1.0\t1.0.1.255\t3215\tFR\tOrange \"S.A.\"\x1b[0m\r",
+            long.as_bytes(),
+            b"1.0.3.0\t1.0.3.255\t64501\tDE\t\xe2\x80\xaeevil\xe2\x80\xac",
+        ]);
+        assert_eq!(asns.len(), 4);
+        assert_eq!(description(&asns, "1.0.0.1"), "CLOUD\u{fffd}\u{fffd}FLARE");
+        assert_eq!(description(&asns, "1.0.1.1"), "Orange \"S.A.\"[0m");
+        assert_eq!(description(&asns, "1.0.2.1"), "X".repeat(65_536));
+        assert_eq!(description(&asns, "1.0.3.1"), "evil");
+    }
+
+    #[test]
+    fn snapshot_ranges_lookup() {
+        let path = env::temp_dir().join(format!("iptoasn-snapshot-ranges-{}.tsv.gz", std::process::id()));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder
+            .write_all(
+                b"2001:db8::\t2001:db8::ffff\t64502\tDE\tSIX\n\
+                  1.0.1.0\t1.0.1.255\t64501\tUS\tSECOND\n\
+                  1.0.0.0\t1.0.0.255\t64500\tUS\tFIRST\n\
+                  1.0.3.0\t1.0.3.255\t64503\tUS\tFIRST\n",
+            )
+            .unwrap();
+        fs::write(&path, encoder.finish().unwrap()).unwrap();
+        let ranges = SnapshotRanges::read(&path);
+        fs::remove_file(&path).unwrap();
+        let ranges = ranges.unwrap();
+
+        let get = |ip: &str| ranges.get(ip.parse().unwrap());
+        assert_eq!(get("1.0.0.0"), Some((64500, "US", "FIRST")));
+        assert_eq!(get("1.0.1.255"), Some((64501, "US", "SECOND")));
+        assert_eq!(get("1.0.3.7"), Some((64503, "US", "FIRST")));
+        assert_eq!(get("1.0.2.1"), None);
+        assert_eq!(get("0.255.255.255"), None);
+        assert_eq!(get("2001:db8::42"), Some((64502, "DE", "SIX")));
+        assert_eq!(get("2001:db8::1:0"), None);
+        assert_eq!(ranges.meta.len(), 3);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn unparsable_source_falls_through() {
+        let dir = env::temp_dir().join(format!("iptoasn-failover-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let bad = dir.join("portal.tsv.gz");
+        let good = dir.join("mirror.tsv.gz");
+        fs::write(&bad, b"<html>Please log in</html>").unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(b"1.0.0.0\t1.0.0.255\t64500\tUS\tMIRROR\n").unwrap();
+        fs::write(&good, encoder.finish().unwrap()).unwrap();
+
+        let urls = [format!("file://{}", bad.display()), format!("file://{}", good.display())];
+        let asns = Asns::from_urls(&urls, None, Some(dir.join("cache.tsv.gz")), 0).await;
+        fs::remove_dir_all(&dir).unwrap();
+        let asns = asns.unwrap();
+        assert_eq!(asns.source(), urls[1]);
+        assert_eq!(description(&asns, "1.0.0.1"), "MIRROR");
+    }
+}
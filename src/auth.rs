@@ -0,0 +1,46 @@
+use crate::usage;
+use http::HeaderMap;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+// API keys required by the routes that aren't public (set_keys)
+static KEYS: OnceLock<HashSet<String>> = OnceLock::new();
+
+// Set the accepted API keys, from --api-key and the api_keys of the config
+// file. Without keys, authentication is disabled. Must be called before the
+// server is started.
+pub fn set_keys(keys: impl IntoIterator<Item = String>) {
+    let keys: HashSet<String> = keys
+        .into_iter()
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty())
+        .collect();
+    let _ = KEYS.set(keys);
+}
+
+pub fn is_enabled() -> bool {
+    KEYS.get().is_some_and(|keys| !keys.is_empty())
+}
+
+// Whether a request sends an accepted key, or the key of a tenant, in an
+// `X-Api-Key` or `Authorization: Bearer` header
+pub fn authorized(headers: &HeaderMap) -> bool {
+    accepts_key(usage::api_key(headers))
+}
+
+// Whether a key is accepted, or the key of a tenant. Without keys, any
+// client is.
+pub(crate) fn accepts_key(key: Option<&str>) -> bool {
+    let Some(keys) = KEYS.get().filter(|keys| !keys.is_empty()) else {
+        return true;
+    };
+    key.is_some_and(|key| keys.contains(key) || usage::tenant_by_key(key).is_some())
+}
+
+// Whether a request sends this secret (the admin or replication token) in an
+// `X-Api-Key` or `Authorization: Bearer` header. Comparing digests keeps the
+// time taken independent of the secret.
+pub(crate) fn sends_token(headers: &HeaderMap, token: &str) -> bool {
+    usage::api_key(headers).is_some_and(|key| Sha256::digest(key.as_bytes()) == Sha256::digest(token.as_bytes()))
+}
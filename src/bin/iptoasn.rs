@@ -1,20 +1,41 @@
-use clap::{Arg, ArgAction, Command};
-use log::{error, info};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgGroup, Command};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info, warn};
 use mimalloc::MiMalloc;
-use regex::Regex;
-use reqwest::header::{ACCEPT, CONTENT_TYPE};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, Read, Write};
+use regex::bytes::{Captures, Match, Regex};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
 use std::net::IpAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-use iptoasn_webservice::asns::Asns;
+// Authorization header of the requests to the webservice (--api-key)
+static AUTHORIZATION_VALUE: OnceLock<HeaderValue> = OnceLock::new();
+
+use iptoasn_webservice::annotate::{self, LruCache};
+use iptoasn_webservice::asns::{Asns, LoadProgress};
+use iptoasn_webservice::build_info;
+use iptoasn_webservice::ascii;
+use iptoasn_webservice::cidr::{self, IpRangeSet};
+use iptoasn_webservice::cli_config::{CliConfig, CliOutput, CliSettings};
+use iptoasn_webservice::config::NormalizationConfig;
+use iptoasn_webservice::country;
+use iptoasn_webservice::doctor::Doctor;
+use iptoasn_webservice::selftest::SelfTest;
+use iptoasn_webservice::domains;
+use iptoasn_webservice::normalize;
+use iptoasn_webservice::update;
 use iptoasn_webservice::DEFAULT_DB_URL;
 
 const DEFAULT_SERVER_URL: &str = match option_env!("IPTOASN_SERVER_URL") {
@@ -28,6 +49,8 @@ async fn main() {
 
     let matches = Command::new("iptoasn")
         .version(env!("CARGO_PKG_VERSION"))
+        // Replaced by a flag of our own, printing build information with --json
+        .disable_version_flag(true)
         .author("Sven Mäder <maeder@phys.ethz.ch>")
         .about("Annotate IP addresses with ASN info using in-memory database. Subcommands query the iptoasn webservice")
         // Global switches for HTTP mode
@@ -37,7 +60,31 @@ async fn main() {
                 .value_name("url")
                 .help("Base URL of iptoasn webservice")
                 .env("IPTOASN_SERVER_URL")
-                .default_value(DEFAULT_SERVER_URL),
+                .default_value(DEFAULT_SERVER_URL)
+                .global(true),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("path")
+                .help("Configuration file of default options and profiles [default: ~/.config/iptoasn/config.toml]")
+                .env("IPTOASN_CONFIG"),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .value_name("name")
+                .help("Profile of the configuration file to use, e.g. work or home")
+                .env("IPTOASN_PROFILE"),
+        )
+        .arg(
+            Arg::new("api_key")
+                .long("api-key")
+                .value_name("key")
+                .help("API key sent to the webservice, for servers requiring one")
+                .env("IPTOASN_API_KEY")
+                .hide_env_values(true)
+                .global(true),
         )
         .arg(
             Arg::new("json")
@@ -46,6 +93,33 @@ async fn main() {
                 .help("Use JSON format for output of subcommands (Accept: application/json)")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .value_name("mode")
+                .help("Output of subcommands: tables on a terminal and plain text otherwise, JSON, or always plain text")
+                .value_parser(["auto", "json", "plain"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::new("flag")
+                .long("flag")
+                .help("Show the flag emoji of the country in the tables of the ip and asn subcommands")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("version")
+                .short('V')
+                .long("version")
+                .help("Print version, or the git commit, build date and features too with --json")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("check_update")
+                .long("check-update")
+                .help("Check whether a newer release is available on GitHub and exit")
+                .action(ArgAction::SetTrue),
+        )
         // Subcommands for HTTP API usage
         .subcommand(
             Command::new("ip")
@@ -65,6 +139,42 @@ async fn main() {
                         .value_name("file")
                         .help("Path to file with IPs; if not set, reads from stdin")
                         .required(false),
+                )
+                .arg(
+                    Arg::new("chunk_size")
+                        .long("chunk-size")
+                        .value_name("ips")
+                        .help("Send the addresses in requests of that many, 0 for a single request")
+                        .default_value("0")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("parallel")
+                        .long("parallel")
+                        .value_name("n")
+                        .help("Maximum number of chunks in flight at the same time")
+                        .default_value("4")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("pool_size")
+                        .long("pool-size")
+                        .value_name("n")
+                        .help("Maximum number of idle connections kept open to the server")
+                        .default_value("4")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("http2")
+                        .long("http2")
+                        .help("Use HTTP/2 without negotiation for http:// servers, to multiplex the chunks on one connection (https:// servers negotiate it)")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("compress")
+                        .long("compress")
+                        .help("Gzip the request bodies; the server must accept Content-Encoding: gzip")
+                        .action(ArgAction::SetTrue),
                 ),
         )
         .subcommand(
@@ -77,12 +187,40 @@ async fn main() {
                         .required(false),
                 )
                 .subcommand(
-                    Command::new("subnets").about("List subnets of an AS").arg(
-                        Arg::new("asn")
-                            .value_name("as number")
-                            .help("AS number (e.g., 15169 or AS15169)")
-                            .required(true),
-                    ),
+                    Command::new("subnets")
+                        .about("List subnets of an AS")
+                        .arg(
+                            Arg::new("asn")
+                                .value_name("as number")
+                                .help("AS number (e.g., 15169 or AS15169)")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("format")
+                                .long("format")
+                                .value_name("format")
+                                .help("Output format: CIDR prefixes, the reverse DNS zones covering them, or a router prefix-list")
+                                .value_parser(["cidr", "rdns-zones", "bird", "frr", "junos", "ios"])
+                                .default_value("cidr"),
+                        )
+                        .arg(
+                            Arg::new("sort")
+                                .long("sort")
+                                .value_name("order")
+                                .help("Order of the subnets: by address, or largest blocks first (IPv4 before IPv6 either way)")
+                                .value_parser(["prefix", "size"])
+                                .default_value("prefix"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("countries")
+                        .about("List the registry countries of the ranges of an AS, with their prefix counts")
+                        .arg(
+                            Arg::new("asn")
+                                .value_name("as number")
+                                .help("AS number (e.g., 15169 or AS15169)")
+                                .required(true),
+                        ),
                 ),
         )
         .subcommand(Command::new("asns").about("List all AS numbers via webservice"))
@@ -103,17 +241,132 @@ async fn main() {
                                 .value_name("country code")
                                 .help("2-letter country code (e.g., US)")
                                 .required(true),
+                        )
+                        .arg(
+                            Arg::new("sort")
+                                .long("sort")
+                                .value_name("order")
+                                .help("Order of the subnets: by address, or largest blocks first (IPv4 before IPv6 either way)")
+                                .value_parser(["prefix", "size"])
+                                .default_value("prefix"),
                         ),
                 ),
         )
+        .subcommand(
+            Command::new("doctor")
+                .about("Check the config file, database URLs, cache directory and webservice URL, then print a report")
+                .arg(
+                    Arg::new("head")
+                        .long("head")
+                        .help("Also send HEAD requests to the database and webservice URLs")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("selftest")
+                .about("Check the endpoints, output types, errors and headers of a running webservice (--server), then print a conformance report"),
+        )
+        .subcommand(
+            Command::new("bench-parse")
+                .about("Time the stages of loading a database file and report the memory used after each one")
+                .arg(
+                    Arg::new("file")
+                        .value_name("file.tsv.gz")
+                        .help("Gzipped ip2asn TSV database")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("domains")
+                .about("Resolve a list of hostnames, then look up the ASNs of their addresses via webservice")
+                .arg(
+                    Arg::new("file")
+                        .value_name("file")
+                        .help("Path to file with one hostname or URL per line; if not set or '-', reads from stdin")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("resolver")
+                        .long("resolver")
+                        .value_name("ip[:port]")
+                        .help("DNS server to query instead of the system resolver (repeatable)")
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("concurrency")
+                        .long("concurrency")
+                        .value_name("n")
+                        .help("Maximum number of hostnames resolved at the same time")
+                        .default_value("32")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("format")
+                        .help("Output format (--json implies json)")
+                        .value_parser(["csv", "json"])
+                        .default_value("csv"),
+                ),
+        )
+        .subcommand(
+            Command::new("sets")
+                .about("Intersect, subtract or union an IP/CIDR list with the prefixes of ASNs or countries, using the local database")
+                .arg(
+                    Arg::new("in_asn")
+                        .long("in-asn")
+                        .value_name("asn")
+                        .help("AS number whose prefixes form the reference set (repeatable)")
+                        .action(ArgAction::Append)
+                        .value_parser(clap::value_parser!(u32)),
+                )
+                .arg(
+                    Arg::new("in_country")
+                        .long("in-country")
+                        .value_name("country code")
+                        .help("Country whose prefixes form the reference set (repeatable)")
+                        .action(ArgAction::Append),
+                )
+                .group(
+                    ArgGroup::new("reference")
+                        .args(["in_asn", "in_country"])
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("intersect")
+                        .long("intersect")
+                        .value_name("file")
+                        .help("Print the parts of the list inside the reference set ('-' for stdin)"),
+                )
+                .arg(
+                    Arg::new("subtract")
+                        .long("subtract")
+                        .value_name("file")
+                        .help("Print the parts of the list outside the reference set ('-' for stdin)"),
+                )
+                .arg(
+                    Arg::new("union")
+                        .long("union")
+                        .value_name("file")
+                        .help("Print the list merged with the reference set ('-' for stdin)"),
+                )
+                .group(
+                    ArgGroup::new("operation")
+                        .args(["intersect", "subtract", "union"])
+                        .required(true),
+                ),
+        )
         // Original annotate-mode arguments (used when no HTTP subcommands are present)
         .arg(
             Arg::new("db_url")
                 .short('u')
                 .long("dburl")
                 .value_name("db_url")
-                .help("URL to download the in-memory database")
+                .help("URL to download the in-memory database; repeat or separate with commas for an ordered failover list")
                 .env("IPTOASN_DB_URL")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
                 .default_value(DEFAULT_DB_URL),
         )
         .arg(
@@ -121,7 +374,44 @@ async fn main() {
                 .short('c')
                 .long("cache-file")
                 .value_name("path")
-                .help("Override path to cache file [env: $XDG_CACHE_HOME/iptoasn/] [default: ~/.cache/iptoasn/]"),
+                .help("Override path to cache file [env: $XDG_CACHE_HOME/iptoasn/] [default: iptoasn/ in the user cache directory]"),
+        )
+        .arg(
+            Arg::new("country_map")
+                .long("country-map")
+                .value_name("from=to")
+                .help("Correct a country code of the database, e.g. EU=DE; repeat or separate with commas")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(parse_country_map),
+        )
+        .arg(
+            Arg::new("uppercase_countries")
+                .long("uppercase-countries")
+                .help("Convert the country codes of the database to uppercase, before corrections")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("strip_country_suffix")
+                .long("strip-country-suffix")
+                .help("Remove a trailing country code from AS descriptions, as in \"Example, US\"")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("max_description_length")
+                .long("max-description-length")
+                .value_name("chars")
+                .help("Truncate AS descriptions to that many characters")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max_cache_age")
+                .long("max-cache-age")
+                .value_name("hours")
+                .help("Use the cache file without downloading the database if it is younger than this; 0 to always download")
+                .env("IPTOASN_MAX_CACHE_AGE")
+                .default_value("24")
+                .value_parser(clap::value_parser!(u64)),
         )
         .arg(
             Arg::new("input")
@@ -137,6 +427,12 @@ async fn main() {
                 .help("Include AS description in annotations")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("ascii")
+                .long("ascii")
+                .help("Transliterate AS descriptions to ASCII in annotations, for pipelines rejecting other bytes")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("line_buffered")
                 .short('l')
@@ -171,22 +467,164 @@ async fn main() {
                 .value_parser(clap::value_parser!(usize))
                 .default_missing_value("1"),
         )
+        .arg(
+            Arg::new("ports")
+                .short('p')
+                .long("ports")
+                .help("Recognize ip:port and [IPv6]:port tokens: look up the address and append AS info after the port")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sample")
+                .long("sample")
+                .value_name("1/n")
+                .help("Only process every n-th input line, e.g. 1/100")
+                .value_parser(parse_sample),
+        )
+        .arg(
+            Arg::new("aggregate_v6")
+                .long("aggregate-v6")
+                .value_name("/len")
+                .help("Look up and count IPv6 addresses once per network of that prefix length, e.g. /64 for clients using privacy extensions")
+                .value_parser(parse_v6_prefix_len),
+        )
+        .arg(
+            Arg::new("lookup_cache_size")
+                .long("lookup-cache-size")
+                .value_name("entries")
+                .help("Maximum number of addresses whose annotation is kept for repeated lookups; 0 to disable")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("100000"),
+        )
+        .arg(
+            Arg::new("errors")
+                .long("errors")
+                .value_name("mode")
+                .help("What to do with lines that aren't valid UTF-8: pass them through unchanged, skip them, replace the invalid bytes with U+FFFD, or abort")
+                .value_parser(["pass", "skip", "replace", "abort"])
+                .default_value("pass"),
+        )
+        .arg(
+            Arg::new("errors_file")
+                .long("errors-file")
+                .value_name("path")
+                .help("Write the lines that aren't valid UTF-8 to this file, whatever --errors does with them, as <line number> TAB <error> TAB <original bytes>"),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry-run")
+                .help("Do not write annotated output; print matching and database coverage statistics instead")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("metrics_textfile")
+                .long("metrics-textfile")
+                .value_name("path")
+                .help("Write statistics of the annotation or ips run to this file, for the textfile collector of node_exporter (e.g. a .prom file in its --collector.textfile.directory)")
+                .global(true),
+        )
         .get_matches();
 
-    let server = matches.get_one::<String>("server").unwrap().to_string();
-    let use_json = matches.get_flag("json");
+    let settings = match cli_settings(&matches) {
+        Ok(settings) => settings,
+        // Reported by the doctor
+        Err(_) if matches.subcommand_matches("doctor").is_some() => CliSettings::default(),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    // Options given on the command line or in the environment take
+    // precedence over the configuration file
+    let explicit = |id| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    let server = match settings.server {
+        Some(server) if !explicit("server") => server,
+        _ => matches.get_one::<String>("server").unwrap().to_string(),
+    };
+    let api_key = matches.get_one::<String>("api_key").or(settings.api_key.as_ref());
+    if let Some(key) = api_key {
+        match HeaderValue::from_str(&format!("Bearer {}", key.trim())) {
+            Ok(mut value) => {
+                value.set_sensitive(true);
+                let _ = AUTHORIZATION_VALUE.set(value);
+            }
+            Err(_) => {
+                eprintln!("Invalid API key");
+                std::process::exit(2);
+            }
+        }
+    }
+    let output = match matches.get_one::<String>("output").unwrap().as_str() {
+        _ if matches.get_flag("json") => CliOutput::Json,
+        _ if !explicit("output") && settings.output.is_some() => settings.output.unwrap(),
+        "json" => CliOutput::Json,
+        "plain" => CliOutput::Plain,
+        _ => CliOutput::Auto,
+    };
+    let use_json = output == CliOutput::Json;
+    // Tables for humans, plain text for pipes and scripts
+    let table = (output == CliOutput::Auto && io::stdout().is_terminal()).then(|| TableStyle {
+        color: std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && settings.color != Some(false),
+        flag: matches.get_flag("flag") || settings.flag == Some(true),
+    });
+    let as_markers = match &settings.as_markers {
+        Some(as_markers) if !explicit("as_markers") => as_markers,
+        _ => matches.get_one::<String>("as_markers").unwrap(),
+    };
+    let as_sep = match &settings.as_sep {
+        Some(as_sep) if !explicit("as_sep") => as_sep,
+        _ => matches.get_one::<String>("as_sep").unwrap(),
+    };
+    country::set_corrections(
+        matches
+            .get_many::<(String, String)>("country_map")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+    );
+    normalize::set_options(NormalizationConfig {
+        uppercase_countries: matches.get_flag("uppercase_countries"),
+        strip_country_suffix: matches.get_flag("strip_country_suffix"),
+        max_description_length: matches.get_one::<usize>("max_description_length").copied(),
+    });
+
+    if matches.get_flag("version") {
+        if use_json {
+            println!("{}", serde_json::to_string_pretty(&build_info::build_info()).unwrap());
+        } else {
+            println!("iptoasn {}", env!("CARGO_PKG_VERSION"));
+        }
+        return;
+    }
+
+    if matches.get_flag("check_update") {
+        if let Err(code) = check_update().await {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    // Weekly new release notice, only shown to interactive users
+    if io::stderr().is_terminal() {
+        update::notify_if_due();
+    }
 
     // If an HTTP API subcommand is used, run HTTP mode and exit
     if let Some(sub_m) = matches.subcommand_matches("ip") {
         let ip_opt = sub_m.get_one::<String>("ip").cloned();
-        if let Err(code) = http_lookup_ip(&server, use_json, ip_opt.as_deref()).await {
+        if let Err(code) = http_lookup_ip(&server, use_json, table.as_ref(), ip_opt.as_deref()).await {
             std::process::exit(code);
         }
         return;
     }
     if let Some(sub_m) = matches.subcommand_matches("ips") {
         let file_opt = sub_m.get_one::<String>("file").cloned();
-        if let Err(code) = http_bulk_ips(&server, use_json, file_opt.as_deref()).await {
+        if let Err(code) = http_bulk_ips(&server, use_json, file_opt.as_deref(), sub_m).await {
             std::process::exit(code);
         }
         return;
@@ -200,7 +638,24 @@ async fn main() {
     if let Some(asn_m) = matches.subcommand_matches("asn") {
         if let Some(subnets_m) = asn_m.subcommand_matches("subnets") {
             let asn = subnets_m.get_one::<String>("asn").unwrap();
-            let path = format!("/v1/as/n/{}/subnets", asn);
+            let sort = subnets_m.get_one::<String>("sort").unwrap();
+            let path = format!("/v1/as/n/{}/subnets?sort={}", asn, sort);
+            let res = match subnets_m.get_one::<String>("format").map(String::as_str) {
+                Some("rdns-zones") => http_rdns_zones(&server, &path).await,
+                Some(format @ ("bird" | "frr" | "junos" | "ios")) => {
+                    let path = format!("{}&format={}", path, format);
+                    http_get_simple(&server, false, &path).await
+                }
+                _ => http_get_simple(&server, use_json, &path).await,
+            };
+            if let Err(code) = res {
+                std::process::exit(code);
+            }
+            return;
+        }
+        if let Some(countries_m) = asn_m.subcommand_matches("countries") {
+            let asn = countries_m.get_one::<String>("asn").unwrap();
+            let path = format!("/v1/as/n/{}/countries", asn);
             if let Err(code) = http_get_simple(&server, use_json, &path).await {
                 std::process::exit(code);
             }
@@ -208,7 +663,11 @@ async fn main() {
         }
         if let Some(asn) = asn_m.get_one::<String>("asn") {
             let path = format!("/v1/as/n/{}", asn);
-            if let Err(code) = http_get_simple(&server, use_json, &path).await {
+            let res = match &table {
+                Some(style) => http_get_table(&server, &path, style).await,
+                None => http_get_simple(&server, use_json, &path).await,
+            };
+            if let Err(code) = res {
                 std::process::exit(code);
             }
             return;
@@ -220,7 +679,8 @@ async fn main() {
     if let Some(cc_m) = matches.subcommand_matches("country") {
         if let Some(subnets_m) = cc_m.subcommand_matches("subnets") {
             let cc = subnets_m.get_one::<String>("cc").unwrap();
-            let path = format!("/v1/as/country/{}/subnets", cc);
+            let sort = subnets_m.get_one::<String>("sort").unwrap();
+            let path = format!("/v1/as/country/{}/subnets?sort={}", cc, sort);
             if let Err(code) = http_get_simple(&server, use_json, &path).await {
                 std::process::exit(code);
             }
@@ -238,12 +698,86 @@ async fn main() {
         }
     }
 
+    if let Some(doctor_m) = matches.subcommand_matches("doctor") {
+        if let Err(code) = doctor(&matches, &server, doctor_m.get_flag("head")).await {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("selftest").is_some() {
+        if let Err(code) = selftest(&server, use_json).await {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    if let Some(bench_m) = matches.subcommand_matches("bench-parse") {
+        if let Err(code) = bench_parse(bench_m.get_one::<String>("file").unwrap()) {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    if let Some(domains_m) = matches.subcommand_matches("domains") {
+        if let Err(code) = domains(&server, use_json, domains_m).await {
+            std::process::exit(code);
+        }
+        return;
+    }
+
+    if let Some(sets_m) = matches.subcommand_matches("sets") {
+        if let Err(code) = sets(&matches, sets_m).await {
+            std::process::exit(code);
+        }
+        return;
+    }
+
     // Otherwise, run original annotate mode
-    if let Err(code) = annotate_mode(&matches).await {
+    if let Err(code) = annotate_mode(&matches, as_markers, as_sep).await {
         std::process::exit(code);
     }
 }
 
+// Settings of the configuration file (--config), with those of the
+// selected profile (--profile)
+fn cli_settings(matches: &clap::ArgMatches) -> Result<CliSettings, String> {
+    let profile = matches.get_one::<String>("profile").map(String::as_str);
+    match cli_config_path(matches) {
+        Some(path) => CliConfig::load(&path)?.settings(profile),
+        None => CliConfig::default().settings(profile),
+    }
+}
+
+fn cli_config_path(matches: &clap::ArgMatches) -> Option<PathBuf> {
+    matches
+        .get_one::<String>("config")
+        .map(PathBuf::from)
+        .or_else(CliConfig::default_path)
+}
+
+async fn check_update() -> Result<(), i32> {
+    match update::check().await {
+        Ok(Some(update)) => {
+            println!(
+                "New version available: {} (running {}): {}",
+                update.version,
+                env!("CARGO_PKG_VERSION"),
+                update.url
+            );
+            Ok(())
+        }
+        Ok(None) => {
+            println!("iptoasn {} is up to date", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("Update check failed: {}", e);
+            Err(1)
+        }
+    }
+}
+
 fn join_url(base: &str, path: &str) -> String {
     let b = base.trim_end_matches('/');
     let p = path.trim_start_matches('/');
@@ -258,9 +792,28 @@ fn print_with_trailing_newline(s: &str) {
     }
 }
 
-async fn http_lookup_ip(server: &str, use_json: bool, ip: Option<&str>) -> Result<(), i32> {
-    let client = reqwest::Client::new();
-    let accept = if use_json {
+// Client for requests to the webservice, sending the API key if one was
+// given with --api-key
+fn webservice_client_builder() -> reqwest::ClientBuilder {
+    let mut headers = HeaderMap::new();
+    if let Some(value) = AUTHORIZATION_VALUE.get() {
+        headers.insert(AUTHORIZATION, value.clone());
+    }
+    reqwest::Client::builder().default_headers(headers)
+}
+
+fn webservice_client() -> reqwest::Client {
+    webservice_client_builder().build().unwrap_or_default()
+}
+
+async fn http_lookup_ip(
+    server: &str,
+    use_json: bool,
+    table: Option<&TableStyle>,
+    ip: Option<&str>,
+) -> Result<(), i32> {
+    let client = webservice_client();
+    let accept = if use_json || table.is_some() {
         "application/json"
     } else {
         "text/plain"
@@ -279,7 +832,10 @@ async fn http_lookup_ip(server: &str, use_json: bool, ip: Option<&str>) -> Resul
                 eprintln!("{}", body);
                 return Err(1);
             }
-            print_with_trailing_newline(&body);
+            match table {
+                Some(style) => print_table(&body, style),
+                None => print_with_trailing_newline(&body),
+            }
             Ok(())
         }
         Err(e) => {
@@ -290,12 +846,94 @@ async fn http_lookup_ip(server: &str, use_json: bool, ip: Option<&str>) -> Resul
 }
 
 async fn http_get_simple(server: &str, use_json: bool, path: &str) -> Result<(), i32> {
-    let client = reqwest::Client::new();
     let accept = if use_json {
         "application/json"
     } else {
         "text/plain"
     };
+    let body = http_get_text(server, accept, path).await?;
+    print_with_trailing_newline(&body);
+    Ok(())
+}
+
+// JSON response of a lookup, as a table
+async fn http_get_table(server: &str, path: &str, style: &TableStyle) -> Result<(), i32> {
+    let body = http_get_text(server, "application/json", path).await?;
+    print_table(&body, style);
+    Ok(())
+}
+
+struct TableStyle {
+    // ANSI colors, unless NO_COLOR is set
+    color: bool,
+    // Flag emoji next to the country code
+    flag: bool,
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+
+// Fields of an IP or AS lookup response as aligned key-value lines
+fn print_table(body: &str, style: &TableStyle) {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(body) else {
+        print_with_trailing_newline(body);
+        return;
+    };
+    let text = |name: &str| value.get(name).and_then(serde_json::Value::as_str);
+    let mut rows: Vec<(&str, String, &str)> = Vec::new();
+    if let Some(ip) = text("ip") {
+        rows.push(("IP", ip.to_string(), BOLD));
+    }
+    if let Some(announced) = value.get("announced").and_then(serde_json::Value::as_bool) {
+        let covered = value.get("covered").and_then(serde_json::Value::as_bool);
+        let (answer, color) = match (announced, covered) {
+            (true, _) => ("yes", GREEN),
+            (false, Some(true)) => ("no (listed as not routed)", RED),
+            (false, Some(false)) => ("no (not in the dataset)", RED),
+            (false, None) => ("no", RED),
+        };
+        rows.push(("Announced", answer.to_string(), color));
+    }
+    if let (Some(first_ip), Some(last_ip)) = (text("first_ip"), text("last_ip")) {
+        rows.push(("Range", format!("{} - {}", first_ip, last_ip), ""));
+    }
+    if let Some(number) = value.get("as_number").and_then(serde_json::Value::as_u64) {
+        rows.push(("AS number", format!("AS{}", number), CYAN));
+    }
+    if let Some(cc) = text("as_country_code") {
+        let country = match country::flag(cc).filter(|_| style.flag) {
+            Some(flag) => format!("{} {}", flag, cc),
+            None => cc.to_string(),
+        };
+        rows.push(("Country", country, ""));
+    }
+    if let Some(description) = text("as_description") {
+        rows.push(("Description", description.to_string(), ""));
+    }
+    let warnings = value.get("warnings").and_then(serde_json::Value::as_array);
+    for warning in warnings.into_iter().flatten() {
+        if let Some(message) = warning.get("message").and_then(serde_json::Value::as_str) {
+            rows.push(("Warning", message.to_string(), YELLOW));
+        }
+    }
+
+    let width = rows.iter().map(|(label, _, _)| label.len()).max().unwrap_or(0);
+    for (label, value, color) in rows {
+        if style.color {
+            println!("{DIM}{label:<width$}{RESET}  {color}{value}{RESET}");
+        } else {
+            println!("{label:<width$}  {value}");
+        }
+    }
+}
+
+async fn http_get_text(server: &str, accept: &str, path: &str) -> Result<String, i32> {
+    let client = webservice_client();
     let url = join_url(server, path);
     match client.get(&url).header(ACCEPT, accept).send().await {
         Ok(resp) => {
@@ -305,8 +943,7 @@ async fn http_get_simple(server: &str, use_json: bool, path: &str) -> Result<(),
                 eprintln!("{}", body);
                 return Err(1);
             }
-            print_with_trailing_newline(&body);
-            Ok(())
+            Ok(body)
         }
         Err(e) => {
             eprintln!("Request failed: {}", e);
@@ -315,15 +952,40 @@ async fn http_get_simple(server: &str, use_json: bool, path: &str) -> Result<(),
     }
 }
 
+// Fetch the subnets of an AS and print the reverse DNS zones covering them
+async fn http_rdns_zones(server: &str, path: &str) -> Result<(), i32> {
+    let body = http_get_text(server, "text/plain", path).await?;
+    let mut zones: Vec<String> = Vec::new();
+    for prefix in body.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match cidr::reverse_zones(prefix) {
+            Ok(mut z) => zones.append(&mut z),
+            Err(e) => {
+                eprintln!("{}", e);
+                return Err(1);
+            }
+        }
+    }
+    zones.dedup();
+    for zone in zones {
+        println!("{}", zone);
+    }
+    Ok(())
+}
+
 // Bulk IP PUT with auto-detected input content-type; output controlled by --json via Accept
-async fn http_bulk_ips(server: &str, use_json: bool, file: Option<&str>) -> Result<(), i32> {
-    let client = reqwest::Client::new();
+async fn http_bulk_ips(
+    server: &str,
+    use_json: bool,
+    file: Option<&str>,
+    ips_m: &clap::ArgMatches,
+) -> Result<(), i32> {
     let accept = if use_json {
         "application/json"
     } else {
         "text/plain"
     };
     let url = join_url(server, "/v1/as/ips");
+    let started = Instant::now();
 
     // Read input (file or stdin) as-is
     let text = if let Some(path) = file {
@@ -349,24 +1011,184 @@ async fn http_bulk_ips(server: &str, use_json: bool, file: Option<&str>) -> Resu
     } else {
         "text/plain"
     };
+    let chunk_size = *ips_m.get_one::<usize>("chunk_size").unwrap();
+    let metrics_textfile = ips_m.get_one::<String>("metrics_textfile");
+    // Input lines and distinct addresses, before the text is moved into the chunks
+    let input = metrics_textfile.map(|_| bulk_input_stats(&text, content_type == "application/json"));
+    let chunks = if chunk_size == 0 {
+        vec![(text, 0)]
+    } else {
+        bulk_chunks(&text, content_type == "application/json", chunk_size)?
+    };
 
-    match client
-        .put(&url)
-        .header(ACCEPT, accept)
-        .header(CONTENT_TYPE, content_type)
-        .body(text)
-        .send()
-        .await
-    {
+    // One client for all chunks, so that they share its connections
+    let mut builder = webservice_client_builder()
+        .pool_max_idle_per_host(*ips_m.get_one::<usize>("pool_size").unwrap());
+    if ips_m.get_flag("http2") {
+        builder = builder.http2_prior_knowledge();
+    }
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Unable to create the HTTP client: {}", e);
+            return Err(1);
+        }
+    };
+    let compress = ips_m.get_flag("compress");
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(
+        (*ips_m.get_one::<usize>("parallel").unwrap()).max(1),
+    ));
+    let mut tasks = tokio::task::JoinSet::new();
+    let offsets: Vec<usize> = chunks.iter().map(|(_, offset)| *offset).collect();
+    for (index, (body, _)) in chunks.into_iter().enumerate() {
+        let (client, url, semaphore) = (client.clone(), url.clone(), semaphore.clone());
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            let mut request = client
+                .put(&url)
+                .header(ACCEPT, accept)
+                .header(CONTENT_TYPE, content_type);
+            request = if compress {
+                request.header(CONTENT_ENCODING, "gzip").body(gzip(body.as_bytes()))
+            } else {
+                request.body(body)
+            };
+            (index, bulk_request(request).await)
+        });
+    }
+    let mut bodies = vec![String::new(); offsets.len()];
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((index, Ok(body))) => bodies[index] = body,
+            Ok((_, Err(code))) => return Err(code),
+            Err(e) => {
+                eprintln!("Request failed: {}", e);
+                return Err(1);
+            }
+        }
+    }
+
+    if let (Some(path), Some((lines, unique_ips))) = (metrics_textfile, input) {
+        let asns = bulk_asn_counts(&bodies, use_json);
+        let metrics = JobMetrics {
+            command: "ips",
+            lines,
+            ips: asns.values().sum(),
+            unique_ips,
+            asns: &asns,
+            duration: started.elapsed(),
+        };
+        if let Err(e) = metrics.write_textfile(path) {
+            eprintln!("Failed to write metrics textfile {}: {}", path, e);
+            return Err(1);
+        }
+    }
+
+    if bodies.len() == 1 || !use_json {
+        for body in &bodies {
+            print_with_trailing_newline(body);
+        }
+        return Ok(());
+    }
+    // One JSON array, with the positions in the whole input
+    let mut results = Vec::new();
+    for (body, offset) in bodies.iter().zip(offsets) {
+        let chunk = match serde_json::from_str::<serde_json::Value>(body) {
+            Ok(serde_json::Value::Array(chunk)) => chunk,
+            _ => {
+                eprintln!("Unexpected response from {}", server);
+                return Err(1);
+            }
+        };
+        for mut result in chunk {
+            if let Some(index) = result.get("index").and_then(serde_json::Value::as_u64) {
+                result["index"] = serde_json::Value::from(index + offset as u64);
+            }
+            results.push(result);
+        }
+    }
+    println!("{}", serde_json::to_string(&results).unwrap());
+    Ok(())
+}
+
+// Bodies of `chunk_size` addresses each, with the position of their first
+// address in the input
+fn bulk_chunks(text: &str, json: bool, chunk_size: usize) -> Result<Vec<(String, usize)>, i32> {
+    if json {
+        let items: Vec<serde_json::Value> = serde_json::from_str(text).map_err(|e| {
+            eprintln!("Invalid JSON input: {}", e);
+            2
+        })?;
+        return Ok(items
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| (serde_json::to_string(chunk).unwrap(), i * chunk_size))
+            .collect());
+    }
+    let lines: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).collect();
+    Ok(lines
+        .chunks(chunk_size)
+        .enumerate()
+        .map(|(i, chunk)| (chunk.join("\n"), i * chunk_size))
+        .collect())
+}
+
+// Lines of a bulk input, and its distinct addresses
+fn bulk_input_stats(text: &str, json: bool) -> (u64, u64) {
+    if json {
+        let items: Vec<serde_json::Value> = serde_json::from_str(text).unwrap_or_default();
+        let unique: HashSet<&str> = items.iter().filter_map(serde_json::Value::as_str).map(str::trim).collect();
+        return (items.len() as u64, unique.len() as u64);
+    }
+    let unique: HashSet<&str> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.eq_ignore_ascii_case("begin") && !line.eq_ignore_ascii_case("end"))
+        .collect();
+    (text.lines().count() as u64, unique.len() as u64)
+}
+
+// Results of the bulk responses per AS number, 0 for addresses that aren't
+// announced: the as_number of JSON results, or the first field of plain text
+// lines
+fn bulk_asn_counts(bodies: &[String], json: bool) -> HashMap<u32, u64> {
+    let mut asns: HashMap<u32, u64> = HashMap::new();
+    for body in bodies {
+        if json {
+            let results = match serde_json::from_str::<serde_json::Value>(body) {
+                Ok(serde_json::Value::Array(results)) => results,
+                _ => Vec::new(),
+            };
+            for result in results {
+                let number = result.get("as_number").and_then(serde_json::Value::as_u64).unwrap_or(0);
+                *asns.entry(number as u32).or_default() += 1;
+            }
+        } else {
+            for line in body.lines() {
+                if let Some(Ok(number)) = line.split('|').next().map(|field| u32::from_str(field.trim())) {
+                    *asns.entry(number).or_default() += 1;
+                }
+            }
+        }
+    }
+    asns
+}
+
+async fn bulk_request(request: reqwest::RequestBuilder) -> Result<String, i32> {
+    match request.send().await {
         Ok(resp) => {
             let status = resp.status();
+            // Cut short by the request deadline of the server
+            let truncated = resp.headers().contains_key("x-truncated");
             let body = resp.text().await.unwrap_or_default();
             if !status.is_success() {
                 eprintln!("{}", body);
                 return Err(1);
             }
-            print_with_trailing_newline(&body);
-            Ok(())
+            if truncated {
+                warn!("The server only looked up part of a chunk before its deadline");
+            }
+            Ok(body)
         }
         Err(e) => {
             eprintln!("Request failed: {}", e);
@@ -375,8 +1197,368 @@ async fn http_bulk_ips(server: &str, use_json: bool, file: Option<&str>) -> Resu
     }
 }
 
-async fn annotate_mode(matches: &clap::ArgMatches) -> Result<(), i32> {
-    let db_url = matches.get_one::<String>("db_url").unwrap();
+fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec can't fail
+    let _ = encoder.write_all(bytes);
+    encoder.finish().unwrap_or_default()
+}
+
+// Resolve hostnames, look up all their addresses with one bulk request, and
+// print a host, IP, ASN mapping
+async fn domains(server: &str, use_json: bool, domains_m: &clap::ArgMatches) -> Result<(), i32> {
+    let text = match domains_m.get_one::<String>("file").map(String::as_str) {
+        Some(path) if path != "-" => fs::read_to_string(path).map_err(|e| {
+            eprintln!("Failed to read file {}: {}", path, e);
+            2
+        })?,
+        _ => {
+            let mut s = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut s) {
+                eprintln!("Failed to read stdin: {}", e);
+                return Err(2);
+            }
+            s
+        }
+    };
+    let hosts: Vec<String> = text.lines().filter_map(domains::parse_host).collect();
+    let resolver = match domains_m.get_many::<String>("resolver") {
+        Some(addrs) => {
+            let addrs: Vec<String> = addrs.cloned().collect();
+            domains::Resolver::servers(&addrs).map_err(|e| {
+                eprintln!("{}", e);
+                2
+            })?
+        }
+        None => domains::Resolver::System,
+    };
+    let concurrency = *domains_m.get_one::<usize>("concurrency").unwrap();
+    let resolved = domains::resolve_all(hosts, Arc::new(resolver), concurrency).await;
+
+    // Each address is looked up once, however many hosts share it
+    let mut ips: Vec<String> = resolved
+        .iter()
+        .filter_map(|(_, result)| result.as_ref().ok())
+        .flatten()
+        .map(IpAddr::to_string)
+        .collect();
+    ips.sort();
+    ips.dedup();
+    let mut lookups: BTreeMap<String, serde_json::Value> = BTreeMap::new();
+    if !ips.is_empty() {
+        let body = serde_json::to_string(&ips).unwrap();
+        let response = webservice_client()
+            .put(join_url(server, "/v1/as/ips"))
+            .header(ACCEPT, "application/json")
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await;
+        let text = match response {
+            Ok(resp) if resp.status().is_success() => {
+                // Cut short by the request deadline of the server
+                if resp.headers().contains_key("x-truncated") {
+                    warn!("The server only looked up part of the addresses before its deadline");
+                }
+                resp.text().await.unwrap_or_default()
+            }
+            Ok(resp) => {
+                eprintln!("{}", resp.text().await.unwrap_or_default());
+                return Err(1);
+            }
+            Err(e) => {
+                eprintln!("Request failed: {}", e);
+                return Err(1);
+            }
+        };
+        let results = match serde_json::from_str::<serde_json::Value>(&text) {
+            Ok(serde_json::Value::Array(results)) => results,
+            _ => {
+                eprintln!("Unexpected response from {}", server);
+                return Err(1);
+            }
+        };
+        for mut result in results {
+            // Position in our own request, meaningless to the reader
+            if let Some(result) = result.as_object_mut() {
+                result.remove("index");
+            }
+            let ip = result.get("ip").and_then(serde_json::Value::as_str).map(str::to_string);
+            if let Some(ip) = ip {
+                lookups.insert(ip, result);
+            }
+        }
+    }
+
+    let mut out = io::stdout().lock();
+    let res = if use_json || domains_m.get_one::<String>("format").unwrap() == "json" {
+        let records: Vec<serde_json::Value> = resolved
+            .iter()
+            .map(|(host, result)| match result {
+                Ok(ips) => serde_json::json!({
+                    "host": host,
+                    "addresses": ips
+                        .iter()
+                        .map(|ip| lookups.get(&ip.to_string()).cloned().unwrap_or_else(|| serde_json::json!({ "ip": ip })))
+                        .collect::<Vec<_>>(),
+                }),
+                Err(e) => serde_json::json!({ "host": host, "error": e }),
+            })
+            .collect();
+        writeln!(out, "{}", serde_json::to_string(&records).unwrap())
+    } else {
+        write_domains_csv(&mut out, &resolved, &lookups)
+    };
+    res.map_err(|e| {
+        eprintln!("Failed to write output: {}", e);
+        1
+    })
+}
+
+fn write_domains_csv(
+    out: &mut impl Write,
+    resolved: &[(String, Result<Vec<IpAddr>, String>)],
+    lookups: &BTreeMap<String, serde_json::Value>,
+) -> io::Result<()> {
+    let field = |s: &str| {
+        if s.contains(['"', ',', '\r', '\n']) {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        } else {
+            s.to_string()
+        }
+    };
+    writeln!(out, "host,ip,as_number,as_country_code,as_description,error")?;
+    for (host, result) in resolved {
+        let ips = match result {
+            Ok(ips) => ips,
+            Err(e) => {
+                writeln!(out, "{},,,,,{}", field(host), field(e))?;
+                continue;
+            }
+        };
+        for ip in ips {
+            let ip = ip.to_string();
+            let lookup = lookups.get(&ip);
+            let text = |name: &str| {
+                lookup
+                    .and_then(|l| l.get(name))
+                    .map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string))
+                    .unwrap_or_default()
+            };
+            writeln!(
+                out,
+                "{},{},{},{},{},",
+                field(host),
+                ip,
+                text("as_number"),
+                field(&text("as_country_code")),
+                field(&text("as_description"))
+            )?;
+        }
+    }
+    Ok(())
+}
+
+async fn doctor(matches: &clap::ArgMatches, server: &str, head: bool) -> Result<(), i32> {
+    let mut doctor = Doctor::new();
+    if let Some(path) = cli_config_path(matches) {
+        doctor.check_cli_config(&path, cli_settings(matches).map(|_| ()));
+    }
+    for db_url in matches.get_many::<String>("db_url").unwrap() {
+        doctor.check_url("database URL", db_url, None, head).await;
+    }
+    match matches
+        .get_one::<String>("cache_file")
+        .map(PathBuf::from)
+        .or_else(Asns::default_cache_file_path)
+    {
+        Some(cache_file) => doctor.check_cache_file(&cache_file),
+        None => eprintln!("No cache path available; skipping cache check"),
+    }
+    doctor.check_url("webservice URL", server, None, head).await;
+    if doctor.print_report() {
+        Ok(())
+    } else {
+        Err(1)
+    }
+}
+
+async fn selftest(server: &str, use_json: bool) -> Result<(), i32> {
+    let mut selftest = SelfTest::new(webservice_client(), server);
+    selftest.run().await;
+    let passed = if use_json {
+        let report = serde_json::json!({
+            "server": server,
+            "passed": selftest.passed(),
+            "checks": selftest.checks(),
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        selftest.passed()
+    } else {
+        selftest.print_report()
+    };
+    if passed {
+        Ok(())
+    } else {
+        Err(1)
+    }
+}
+
+// Time decompression, parsing, interning and index building of a database
+// file separately, with the resident memory after each stage.
+fn bench_parse(path: &str) -> Result<(), i32> {
+    let mut previous_rss = resident_memory();
+    let start = Instant::now();
+    let bytes = fs::read(path).map_err(|e| {
+        eprintln!("Unable to read {}: {}", path, e);
+        1
+    })?;
+    println!("{:<12} {:>10} {:>12} {:>12}  output", "stage", "time", "rss", "rss delta");
+    let mut print_stage = |name: &str, elapsed: Duration, output: String| {
+        let rss = resident_memory();
+        let delta = match (rss, previous_rss) {
+            (Some(rss), Some(previous)) => format!("{:+.1} MiB", mib(rss as f64 - previous as f64)),
+            _ => "n/a".to_string(),
+        };
+        println!(
+            "{:<12} {:>10} {:>12} {:>12}  {}",
+            name,
+            format!("{:.1?}", elapsed),
+            rss.map_or_else(|| "n/a".to_string(), |rss| format!("{:.1} MiB", mib(rss as f64))),
+            delta,
+            output
+        );
+        previous_rss = rss;
+    };
+    print_stage("read", start.elapsed(), format!("{} bytes", bytes.len()));
+
+    let start = Instant::now();
+    let result = Asns::bench_parse(&bytes, &mut |stage| {
+        print_stage(stage.name, stage.elapsed, format!("{} {}", stage.count, stage.unit));
+    });
+    if let Err(e) = result {
+        eprintln!("{}: {}", path, e);
+        return Err(1);
+    }
+    println!("total: {:.1?}", start.elapsed());
+    if let Some(peak) = peak_resident_memory() {
+        println!("peak rss: {:.1} MiB", mib(peak as f64));
+    }
+    Ok(())
+}
+
+// Resident set size of the process in bytes (Linux only)
+fn resident_memory() -> Option<u64> {
+    proc_status_kb("VmRSS:")
+}
+
+// Highest resident set size of the process so far in bytes (Linux only)
+fn peak_resident_memory() -> Option<u64> {
+    proc_status_kb("VmHWM:")
+}
+
+fn proc_status_kb(field: &str) -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with(field))?;
+    let kb: u64 = line[field.len()..].trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kb * 1024)
+}
+
+fn mib(bytes: f64) -> f64 {
+    bytes / (1024.0 * 1024.0)
+}
+
+// Set operations between a user-supplied IP/CIDR list and the prefixes of
+// the given ASNs and countries; prints the resulting merged CIDRs.
+async fn sets(matches: &clap::ArgMatches, sets_m: &clap::ArgMatches) -> Result<(), i32> {
+    let (op, path) = ["intersect", "subtract", "union"]
+        .into_iter()
+        .find_map(|op| sets_m.get_one::<String>(op).map(|path| (op, path.as_str())))
+        .unwrap();
+
+    let text = if path == "-" {
+        let mut s = String::new();
+        if let Err(e) = io::stdin().read_to_string(&mut s) {
+            eprintln!("Failed to read stdin: {}", e);
+            return Err(2);
+        }
+        s
+    } else {
+        match std::fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to read file {}: {}", path, e);
+                return Err(2);
+            }
+        }
+    };
+    let mut list = IpRangeSet::new();
+    for (i, line) in text.lines().enumerate() {
+        let entry = line.split('#').next().unwrap_or("").trim();
+        if entry.is_empty() {
+            continue;
+        }
+        if let Err(e) = list.insert_str(entry) {
+            eprintln!("{}:{}: {}", path, i + 1, e);
+            return Err(2);
+        }
+    }
+
+    let db_urls: Vec<String> = matches
+        .get_many::<String>("db_url")
+        .unwrap()
+        .cloned()
+        .collect();
+    let cache_file: Option<PathBuf> = matches.get_one::<String>("cache_file").map(PathBuf::from);
+    let http_client = if db_urls
+        .iter()
+        .any(|u| u.starts_with("http://") || u.starts_with("https://"))
+    {
+        Some(reqwest::Client::new())
+    } else {
+        None
+    };
+    let asns = match get_asns(&db_urls, http_client.as_ref(), cache_file, max_cache_age(matches)).await {
+        Ok(asns) => asns,
+        Err(e) => {
+            error!("Failed to load database: {e}");
+            return Err(1);
+        }
+    };
+
+    let mut reference = IpRangeSet::new();
+    for &number in sets_m.get_many::<u32>("in_asn").into_iter().flatten() {
+        for (first, last) in asns.collect_ranges_by_asn(number) {
+            reference.insert_range(first, last);
+        }
+    }
+    for cc in sets_m.get_many::<String>("in_country").into_iter().flatten() {
+        for (first, last) in asns.collect_ranges_by_country(&cc.to_uppercase()) {
+            reference.insert_range(first, last);
+        }
+    }
+
+    let result = match op {
+        "intersect" => list.intersect(reference),
+        "subtract" => list.subtract(reference),
+        _ => list.union(reference),
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for cidr in result.to_cidrs() {
+        if writeln!(out, "{}", cidr).is_err() {
+            return Err(1);
+        }
+    }
+    Ok(())
+}
+
+async fn annotate_mode(matches: &clap::ArgMatches, as_markers: &str, as_sep: &str) -> Result<(), i32> {
+    let db_urls: Vec<String> = matches
+        .get_many::<String>("db_url")
+        .unwrap()
+        .cloned()
+        .collect();
     let include_description = matches.get_flag("description");
     let input_path = matches.get_one::<String>("input").map(String::as_str);
     let line_buffered = matches.get_flag("line_buffered");
@@ -387,7 +1569,6 @@ async fn annotate_mode(matches: &clap::ArgMatches) -> Result<(), i32> {
     let limit: usize = matches.get_one::<usize>("first").copied().unwrap_or(0);
 
     // Parse AS markers (must be exactly two Unicode characters)
-    let as_markers = matches.get_one::<String>("as_markers").unwrap();
     let mut chs = as_markers.chars();
     let (as_open, as_close) = match (chs.next(), chs.next(), chs.next()) {
         (Some(o), Some(c), None) => (o.to_string(), c.to_string()),
@@ -399,17 +1580,41 @@ async fn annotate_mode(matches: &clap::ArgMatches) -> Result<(), i32> {
             return Err(2);
         }
     };
-    let as_sep = matches.get_one::<String>("as_sep").unwrap();
+    let format = AnnotateFormat {
+        include_description,
+        ascii: matches.get_flag("ascii"),
+        aggregate_v6: matches.get_one::<u8>("aggregate_v6").copied(),
+        as_open: &as_open,
+        as_close: &as_close,
+        as_sep,
+    };
+    let sample = matches.get_one::<u64>("sample").copied().unwrap_or(1);
+    let dry_run = matches.get_flag("dry_run");
+    let started = Instant::now();
+    let errors = matches.get_one::<String>("errors").unwrap().as_str();
+    let mut errors_file = match matches.get_one::<String>("errors_file") {
+        Some(path) => match File::create(path) {
+            Ok(file) => Some(io::BufWriter::new(file)),
+            Err(e) => {
+                error!("Failed to create errors file {}: {}", path, e);
+                return Err(1);
+            }
+        },
+        None => None,
+    };
 
-    // Create HTTP client once if URL is HTTP/HTTPS (for DB download)
-    let http_client = if db_url.starts_with("http://") || db_url.starts_with("https://") {
+    // Create HTTP client once if any URL is HTTP/HTTPS (for DB download)
+    let http_client = if db_urls
+        .iter()
+        .any(|u| u.starts_with("http://") || u.starts_with("https://"))
+    {
         Some(reqwest::Client::new())
     } else {
         None
     };
 
     // Load ASN database
-    let asns = match get_asns(db_url, http_client.as_ref(), cache_file.clone()).await {
+    let asns = match get_asns(&db_urls, http_client.as_ref(), cache_file.clone(), max_cache_age(matches)).await {
         Ok(asns) => Arc::new(asns),
         Err(e) => {
             error!("Failed to load initial database: {e}");
@@ -420,7 +1625,7 @@ async fn annotate_mode(matches: &clap::ArgMatches) -> Result<(), i32> {
     let asns_arc = Arc::new(RwLock::new(asns));
 
     // Prepare input reader (file or stdin)
-    let reader: Box<dyn BufRead> = match input_path {
+    let mut reader: Box<dyn BufRead> = match input_path {
         Some(path) => {
             let file = match File::open(path) {
                 Ok(f) => f,
@@ -439,21 +1644,36 @@ async fn annotate_mode(matches: &clap::ArgMatches) -> Result<(), i32> {
     //  - mapped: the IPv4-mapped IPv6 prefix "::ffff:" (only the prefix; we leave the following IPv4
     //            to be matched by the IPv4 branch later in the same pass)
     //  - ip6: IPv6 token with custom boundaries (excluding "::ffff:..." by virtue of the 'mapped' alt)
-    let re_ip = Regex::new(
+    //
+    // With --ports, a port following an IPv4 address is part of its token,
+    // and bracketed IPv6 addresses are matched with their brackets and port,
+    // so that the AS info follows the whole token instead of splitting it.
+    let ports = matches.get_flag("ports");
+    let (port4, bracketed) = if ports {
+        (
+            r"(?P<port4> :\d{1,5} )?",
+            r"(?P<ip6b> \[ [0-9A-Fa-f.]* : [0-9A-Fa-f:.]* \] ) (?P<port6> :\d{1,5} \b )? |",
+        )
+    } else {
+        ("", "")
+    };
+    let re_ip = Regex::new(&format!(
         r"(?x)
-        # 1) IPv4 dotted-quad
-        \b (?P<ip4> (?:\d{1,3}\.){3}\d{1,3} ) \b
+        # 0) With --ports, bracketed IPv6 with an optional port
+        {bracketed}
+        # 1) IPv4 dotted-quad, with an optional port with --ports
+        \b (?P<ip4> (?:\d{{1,3}}\.){{3}}\d{{1,3}} ) {port4} \b
         |
         # 2) IPv4-mapped IPv6 prefix '::ffff:' (do not consume dotted-quad that follows)
         (?P<pre_mapped> ^ | [^0-9A-Fa-f:] )
-        (?P<mapped> :: [Ff]{4} : )
+        (?P<mapped> :: [Ff]{{4}} : )
         |
         # 3) IPv6 (preserve surrounding delimiters)
         (?P<pre> ^ | [^0-9A-Fa-f:] )
-        (?P<ip6> (?:[0-9A-Fa-f]{0,4}:){2,7}[0-9A-Fa-f]{0,4} | :: )
+        (?P<ip6> (?:[0-9A-Fa-f]{{0,4}}:){{2,7}}[0-9A-Fa-f]{{0,4}} | :: )
         (?P<post> [^0-9A-Fa-f:] | $ )
         ",
-    )
+    ))
     .unwrap();
 
     // Choose output writer: line-buffered for stdin when requested, else buffered
@@ -464,67 +1684,128 @@ async fn annotate_mode(matches: &clap::ArgMatches) -> Result<(), i32> {
         Box::new(io::BufWriter::new(stdout_raw))
     };
 
-    // Cache to avoid repeated lookups across the whole run
-    let mut cache: HashMap<(String, bool), Option<String>> = HashMap::new();
+    // Cache to avoid repeated lookups across the whole run, bounded so that
+    // logs with many unique addresses don't exhaust memory
+    let mut cache = LruCache::new(*matches.get_one::<usize>("lookup_cache_size").unwrap());
+    let mut stats = AnnotateStats::default();
 
-    for line_res in reader.lines() {
-        let line = match line_res {
-            Ok(l) => l,
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf) {
+            Ok(0) => break,
+            Ok(_) => {}
+            // The position in the stream is unknown after a read error, so
+            // it can't be skipped like an invalid line
             Err(e) => {
-                error!("Failed to read line: {}", e);
+                error!("Failed to read line {}: {}", stats.lines + 1, e);
                 return Err(1);
             }
+        }
+        stats.lines += 1;
+        if (stats.lines - 1) % sample != 0 {
+            continue;
+        }
+        stats.sampled_lines += 1;
+        let content = buf.strip_suffix(b"\n").unwrap_or(&buf);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        let ending = &buf[content.len()..];
+        // Lines are annotated as bytes: invalid UTF-8 is passed through
+        // unchanged by default, and only the IP tokens are rewritten
+        let replaced;
+        let content: &[u8] = match std::str::from_utf8(content) {
+            Ok(_) => content,
+            Err(e) => {
+                // Recorded whatever happens to the line
+                if let Some(errors_file) = errors_file.as_mut() {
+                    let written = write!(errors_file, "{}\t{}\t", stats.lines, e)
+                        .and_then(|_| errors_file.write_all(content))
+                        .and_then(|_| errors_file.write_all(b"\n"));
+                    if let Err(e) = written {
+                        error!("Failed to write errors file: {}", e);
+                        return Err(1);
+                    }
+                }
+                match errors {
+                    "pass" => content,
+                    "replace" => {
+                        stats.replaced_lines += 1;
+                        replaced = String::from_utf8_lossy(content).into_owned();
+                        replaced.as_bytes()
+                    }
+                    "skip" => {
+                        stats.skipped_lines += 1;
+                        continue;
+                    }
+                    _ => {
+                        error!(
+                            "Line {} is not valid UTF-8 ({}); use --errors pass, skip or replace to continue past such lines",
+                            stats.lines, e
+                        );
+                        return Err(1);
+                    }
+                }
+            }
         };
 
         // Single-pass replacement handling IPv4, IPv6, and IPv4-mapped IPv6 ::ffff: prefix
-        let line = re_ip
-            .replacen(&line, limit, |caps: &regex::Captures| {
-                // IPv4
-                if let Some(m) = caps.name("ip4") {
-                    return annotate_ip_token(
-                        m.as_str(),
-                        include_description,
-                        &asns_arc,
-                        &mut cache,
-                        &as_open,
-                        &as_close,
-                        as_sep,
-                    );
-                }
+        let line = re_ip.replacen(content, limit, |caps: &Captures| {
+            // Matched tokens are ASCII
+            let text = |m: Match| String::from_utf8_lossy(m.as_bytes()).into_owned();
 
-                // IPv4-mapped IPv6 prefix ::ffff: (return unchanged so that the following IPv4
-                // can be matched and annotated by the IPv4 branch in this same pass)
-                if let Some(m) = caps.name("mapped") {
-                    let pre = caps.name("pre_mapped").map(|m| m.as_str()).unwrap_or("");
-                    return format!("{}{}", pre, m.as_str());
-                }
+            // IPv4, with its port if any
+            if let Some(m) = caps.name("ip4") {
+                let token = text(caps.get(0).unwrap());
+                return annotate_ip_token(&text(m), &token, &format, &asns_arc, &mut cache, &mut stats)
+                    .into_bytes();
+            }
 
-                // IPv6 (preserve pre/post)
-                if let Some(m) = caps.name("ip6") {
-                    let pre = caps.name("pre").map(|m| m.as_str()).unwrap_or("");
-                    let post = caps.name("post").map(|m| m.as_str()).unwrap_or("");
-                    return format!(
-                        "{}{}{}",
-                        pre,
-                        annotate_ip_token(
-                            m.as_str(),
-                            include_description,
-                            &asns_arc,
-                            &mut cache,
-                            &as_open,
-                            &as_close,
-                            as_sep
-                        ),
-                        post
-                    );
-                }
+            // Bracketed IPv6, looked up without the brackets and port
+            if let Some(m) = caps.name("ip6b") {
+                let token = text(caps.get(0).unwrap());
+                let ip = text(m);
+                let ip = &ip[1..ip.len() - 1];
+                return annotate_ip_token(ip, &token, &format, &asns_arc, &mut cache, &mut stats)
+                    .into_bytes();
+            }
 
-                // Fallback: shouldn't happen, return original match
-                caps.get(0).map(|m| m.as_str()).unwrap_or("").to_string()
-            })
-            .to_string();
+            // IPv4-mapped IPv6 prefix ::ffff: (return unchanged so that the following IPv4
+            // can be matched and annotated by the IPv4 branch in this same pass)
+            if let Some(m) = caps.name("mapped") {
+                let mut out = caps.name("pre_mapped").map(|m| m.as_bytes().to_vec()).unwrap_or_default();
+                out.extend_from_slice(m.as_bytes());
+                return out;
+            }
 
-        if let Err(e) = writeln!(stdout, "{}", line) {
+            // IPv6 (preserve pre/post)
+            if let Some(m) = caps.name("ip6") {
+                let mut out = caps.name("pre").map(|m| m.as_bytes().to_vec()).unwrap_or_default();
+                let token = text(m);
+                out.extend_from_slice(
+                    annotate_ip_token(&token, &token, &format, &asns_arc, &mut cache, &mut stats).as_bytes(),
+                );
+                out.extend_from_slice(caps.name("post").map(|m| m.as_bytes()).unwrap_or_default());
+                return out;
+            }
+
+            // Fallback: shouldn't happen, return original match
+            caps.get(0).map(|m| m.as_bytes().to_vec()).unwrap_or_default()
+        });
+
+        if dry_run {
+            continue;
+        }
+        if let Err(e) = stdout.write_all(&line).and_then(|_| stdout.write_all(ending)) {
+            error!("Failed to write output: {}", e);
+            return Err(1);
+        }
+    }
+
+    if dry_run {
+        stats.unique_ips_approximate = cache.capacity() == 0 || cache.evictions() > 0;
+        stats.aggregate_v6 = format.aggregate_v6;
+        let asns = asns_arc.read().unwrap().clone();
+        if let Err(e) = stats.write_report(&mut stdout, asns.unmapped_country_codes()) {
             error!("Failed to write output: {}", e);
             return Err(1);
         }
@@ -534,82 +1815,336 @@ async fn annotate_mode(matches: &clap::ArgMatches) -> Result<(), i32> {
         error!("Failed to flush output: {}", e);
         return Err(1);
     }
+    if let Some(Err(e)) = errors_file.as_mut().map(Write::flush) {
+        error!("Failed to write errors file: {}", e);
+        return Err(1);
+    }
+    if stats.skipped_lines > 0 {
+        warn!("Skipped {} lines that weren't valid UTF-8", stats.skipped_lines);
+    }
+    if stats.replaced_lines > 0 {
+        warn!("Replaced invalid UTF-8 in {} lines", stats.replaced_lines);
+    }
+
+    if let Some(path) = matches.get_one::<String>("metrics_textfile") {
+        let metrics = JobMetrics {
+            command: "annotate",
+            lines: stats.lines,
+            ips: stats.announced + stats.not_announced,
+            unique_ips: stats.unique_ips,
+            asns: &stats.asns,
+            duration: started.elapsed(),
+        };
+        if let Err(e) = metrics.write_textfile(path) {
+            error!("Failed to write metrics textfile {}: {}", path, e);
+            return Err(1);
+        }
+    }
 
     Ok(())
 }
 
+fn max_cache_age(matches: &clap::ArgMatches) -> Option<Duration> {
+    match *matches.get_one::<u64>("max_cache_age").unwrap() {
+        0 => None,
+        hours => Some(Duration::from_secs(hours.saturating_mul(3600))),
+    }
+}
+
 async fn get_asns(
-    db_url: &str,
+    db_urls: &[String],
     http_client: Option<&reqwest::Client>,
     cache_file: Option<PathBuf>,
+    max_cache_age: Option<Duration>,
 ) -> Result<Asns, &'static str> {
+    if let Some(asns) =
+        max_cache_age.and_then(|max_age| Asns::from_fresh_cache(cache_file.as_deref(), max_age))
+    {
+        info!("ASNs loaded from {}", asns.source());
+        return Ok(asns);
+    }
     info!("Retrieving ASNs");
-    let asns = Asns::new(db_url, http_client, cache_file)
-        .await
-        .map_err(|_| "ASNs load failed")?;
+    // Progress bar on stderr, hidden when stderr isn't a terminal
+    let bar = if io::stderr().is_terminal() {
+        ProgressBar::no_length()
+    } else {
+        ProgressBar::hidden()
+    };
+    bar.set_style(
+        ProgressStyle::with_template(
+            "Downloading database {bar:40} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+        )
+        .unwrap(),
+    );
+    // The same bar shows the parse once the download is complete
+    let parsing = AtomicBool::new(false);
+    let progress = LoadProgress {
+        download: &|downloaded, total| {
+            if let Some(total) = total {
+                bar.set_length(total);
+            }
+            bar.set_position(downloaded);
+        },
+        parse: &|progress| {
+            if !parsing.swap(true, Ordering::Relaxed) {
+                bar.set_style(
+                    ProgressStyle::with_template("Parsing database {bar:40} {percent}% ({msg} lines)").unwrap(),
+                );
+                bar.set_length(progress.total_bytes);
+            }
+            bar.set_position(progress.parsed_bytes);
+            bar.set_message(progress.lines.to_string());
+        },
+    };
+    let asns = Asns::from_urls_with_load_progress(db_urls, http_client, cache_file, 0, progress).await;
+    bar.finish_and_clear();
+    let asns = asns.map_err(|_| "ASNs load failed")?;
     info!("ASNs loaded");
     Ok(asns)
 }
 
+// Annotation format shared by all tokens of a run.
+struct AnnotateFormat<'a> {
+    include_description: bool,
+    // Descriptions transliterated to ASCII (--ascii)
+    ascii: bool,
+    // IPv6 addresses are looked up once per network of that length
+    aggregate_v6: Option<u8>,
+    as_open: &'a str,
+    as_close: &'a str,
+    as_sep: &'a str,
+}
+
+// Counters reported by --dry-run.
+#[derive(Default)]
+struct AnnotateStats {
+    lines: u64,
+    sampled_lines: u64,
+    skipped_lines: u64,
+    replaced_lines: u64,
+    tokens: u64,
+    announced: u64,
+    not_announced: u64,
+    invalid: u64,
+    // Lookups not answered by the cache: unique addresses, unless entries
+    // were evicted from the cache
+    unique_ips: u64,
+    unique_ips_approximate: bool,
+    // IPv6 addresses counted once per network of that length (--aggregate-v6)
+    aggregate_v6: Option<u8>,
+    // Valid addresses per AS number, 0 for those not announced
+    asns: HashMap<u32, u64>,
+}
+
+impl AnnotateStats {
+    // Count a token: invalid, or a valid address and its AS number if it's
+    // announced
+    fn record(&mut self, result: Option<Option<u32>>) {
+        self.tokens += 1;
+        match result {
+            Some(Some(_)) => self.announced += 1,
+            Some(None) => self.not_announced += 1,
+            None => self.invalid += 1,
+        }
+        if let Some(number) = result {
+            *self.asns.entry(number.unwrap_or(0)).or_default() += 1;
+        }
+    }
+
+    fn write_report(
+        &self,
+        out: &mut dyn Write,
+        unmapped_countries: &BTreeMap<Arc<str>, usize>,
+    ) -> io::Result<()> {
+        let valid = self.announced + self.not_announced;
+        let coverage = if valid > 0 {
+            self.announced as f64 * 100.0 / valid as f64
+        } else {
+            0.0
+        };
+        writeln!(out, "lines read:        {}", self.lines)?;
+        writeln!(out, "lines sampled:     {}", self.sampled_lines)?;
+        writeln!(out, "  skipped:         {}", self.skipped_lines)?;
+        writeln!(out, "  replaced UTF-8:  {}", self.replaced_lines)?;
+        writeln!(out, "IP tokens matched: {}", self.tokens)?;
+        writeln!(out, "  announced:       {}", self.announced)?;
+        writeln!(out, "  not announced:   {}", self.not_announced)?;
+        writeln!(out, "  invalid:         {}", self.invalid)?;
+        let mut unique = self.unique_ips.to_string();
+        if self.unique_ips_approximate {
+            unique = format!("at most {} (the lookup cache was too small)", unique);
+        }
+        if let Some(len) = self.aggregate_v6 {
+            unique.push_str(&format!(" (IPv6 per /{})", len));
+        }
+        writeln!(out, "unique IPs:        {}", unique)?;
+        writeln!(out, "coverage:          {:.1}%", coverage)?;
+        for (code, count) in unmapped_countries {
+            writeln!(out, "unmapped country:  {:?} ({} ranges)", code, count)?;
+        }
+        out.flush()
+    }
+}
+
+// Number of AS numbers with the most addresses reported by --metrics-textfile
+const TOP_ASNS: usize = 10;
+
+// Statistics of a run, for the textfile collector of node_exporter, so that
+// enrichment jobs run from cron show up in the existing monitoring
+// (--metrics-textfile).
+struct JobMetrics<'a> {
+    // annotate or ips, the `command` label of the metrics
+    command: &'static str,
+    lines: u64,
+    ips: u64,
+    unique_ips: u64,
+    // Addresses per AS number, 0 for those not announced
+    asns: &'a HashMap<u32, u64>,
+    duration: Duration,
+}
+
+impl JobMetrics<'_> {
+    fn render(&self) -> String {
+        let command = self.command;
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, value: String| {
+            out.push_str(&format!("# HELP iptoasn_job_{name} {help}\n# TYPE iptoasn_job_{name} gauge\n"));
+            out.push_str(&format!("iptoasn_job_{name}{{command=\"{command}\"}} {value}\n"));
+        };
+        gauge("lines", "Input lines processed by the last run.", self.lines.to_string());
+        gauge("ips", "IP addresses looked up by the last run.", self.ips.to_string());
+        gauge("unique_ips", "Distinct IP addresses looked up by the last run.", self.unique_ips.to_string());
+        gauge(
+            "duration_seconds",
+            "Duration of the last run.",
+            format!("{:.3}", self.duration.as_secs_f64()),
+        );
+        let finished = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        gauge(
+            "last_success_timestamp_seconds",
+            "Unix time the last successful run finished.",
+            finished.as_secs().to_string(),
+        );
+
+        let mut top: Vec<(u32, u64)> = self.asns.iter().map(|(&number, &count)| (number, count)).collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        top.truncate(TOP_ASNS);
+        out.push_str(&format!(
+            "# HELP iptoasn_job_asn_ips IP addresses of the {TOP_ASNS} AS numbers with the most in the last run, AS0 for those not announced.\n"
+        ));
+        out.push_str("# TYPE iptoasn_job_asn_ips gauge\n");
+        for (number, count) in top {
+            out.push_str(&format!("iptoasn_job_asn_ips{{command=\"{command}\",asn=\"{number}\"}} {count}\n"));
+        }
+        out
+    }
+
+    // Written aside and renamed, so that the collector never reads a partial
+    // file. It only reads *.prom files, so the temporary file is ignored.
+    fn write_textfile(&self, path: &str) -> io::Result<()> {
+        let tmp = format!("{}.{}.tmp", path, std::process::id());
+        let written = fs::write(&tmp, self.render()).and_then(|_| fs::rename(&tmp, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written
+    }
+}
+
+// Parse a country code correction given as "from=to".
+fn parse_country_map(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() => Ok((from.to_string(), to.to_string())),
+        _ => Err(format!("invalid country code correction \"{}\", expected from=to", s)),
+    }
+}
+
+// Parse an IPv6 prefix length given as "/64" (or just "64").
+fn parse_v6_prefix_len(s: &str) -> Result<u8, String> {
+    let len = s.strip_prefix('/').unwrap_or(s);
+    match u8::from_str(len) {
+        Ok(len) if (1..=128).contains(&len) => Ok(len),
+        _ => Err(format!("invalid IPv6 prefix length \"{}\", expected /1 to /128", s)),
+    }
+}
+
+// Parse a sampling rate given as "1/n" (or just "n").
+fn parse_sample(s: &str) -> Result<u64, String> {
+    let n = s.strip_prefix("1/").unwrap_or(s);
+    match u64::from_str(n) {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid sampling rate \"{}\", expected 1/n with n > 0", s)),
+    }
+}
+
+// Annotate the address `ip_s` of a token, which is written with the AS info
+// appended. The token is the address itself, or includes its brackets and port
+// with --ports.
 fn annotate_ip_token(
     ip_s: &str,
-    include_description: bool,
+    token: &str,
+    format: &AnnotateFormat,
     asns_arc: &Arc<RwLock<Arc<Asns>>>,
-    cache: &mut HashMap<(String, bool), Option<String>>,
-    as_open: &str,
-    as_close: &str,
-    as_sep: &str,
+    cache: &mut LruCache<String, Option<(String, Option<u32>)>>,
+    stats: &mut AnnotateStats,
 ) -> String {
-    if let Some(cached) = cache.get(&(ip_s.to_string(), include_description)) {
+    // Bracketed IPv4-mapped addresses are looked up as IPv4
+    let ip = IpAddr::from_str(ip_s).ok().map(|ip| ip.to_canonical());
+    // The cache keeps the AS info appended to the address, so that the
+    // addresses of an aggregated IPv6 network share one entry
+    let key = match ip {
+        Some(ip) => annotate::cache_key(ip, format.aggregate_v6),
+        None => ip_s.to_string(),
+    };
+    if let Some(cached) = cache.get(&key) {
+        stats.record(cached.as_ref().map(|(_, number)| *number));
         return match cached {
-            Some(ann) => ann.clone(),
-            None => ip_s.to_string(),
+            Some((suffix, _)) => format!("{}{}", token, suffix),
+            None => token.to_string(),
         };
     }
 
-    let ip = match IpAddr::from_str(ip_s) {
-        Ok(ip) => ip,
-        Err(_) => {
-            // Not a valid IP token; leave unchanged
-            cache.insert((ip_s.to_string(), include_description), None);
-            return ip_s.to_string();
-        }
+    let Some(ip) = ip else {
+        // Not a valid IP token; leave unchanged
+        stats.record(None);
+        cache.insert(key, None);
+        return token.to_string();
     };
+    stats.unique_ips += 1;
 
     let asns = asns_arc.read().unwrap().clone();
 
-    let annot = if let Some(found) = asns.lookup_by_ip(ip) {
-        let mut s = String::new();
-        s.push_str(ip_s);
-        s.push(' ');
-        s.push_str(as_open);
-        s.push_str("AS");
-        s.push_str(&found.number.to_string());
-        s.push_str(as_sep);
-        s.push_str(&found.country);
-        if include_description {
-            s.push_str(as_sep);
-            s.push_str(&found.description);
-        }
-        s.push_str(as_close);
-        s
+    let found = asns.lookup_by_ip(ip);
+    let mut suffix = String::from(" ");
+    suffix.push_str(format.as_open);
+    if let Some(found) = &found {
+        suffix.push_str("AS");
+        suffix.push_str(&found.number.to_string());
+        suffix.push_str(format.as_sep);
+        suffix.push_str(&found.country);
+        if format.include_description {
+            suffix.push_str(format.as_sep);
+            if format.ascii {
+                suffix.push_str(&ascii::fold(&found.description));
+            } else {
+                suffix.push_str(&found.description);
+            }
+        }
     } else {
         // No ASN found (local/private or unrouted)
-        let mut s = String::new();
-        s.push_str(ip_s);
-        s.push(' ');
-        s.push_str(as_open);
-        s.push_str("AS0");
-        s.push_str(as_sep);
-        s.push_str("None");
-        if include_description {
-            s.push_str(as_sep);
-            s.push_str("Not announced");
-        }
-        s.push_str(as_close);
-        s
-    };
+        suffix.push_str("AS0");
+        suffix.push_str(format.as_sep);
+        suffix.push_str("None");
+        if format.include_description {
+            suffix.push_str(format.as_sep);
+            suffix.push_str("Not announced");
+        }
+    }
+    suffix.push_str(format.as_close);
 
-    cache.insert((ip_s.to_string(), include_description), Some(annot.clone()));
+    let number = found.as_ref().map(|found| found.number);
+    stats.record(Some(number));
+    let annot = format!("{}{}", token, suffix);
+    cache.insert(key, Some((suffix, number)));
     annot
 }
@@ -0,0 +1,85 @@
+use crate::asns::Asn;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+// Ranges sorted by first address, with a first-level table keyed on the /24
+// (IPv4) or /48 (IPv6) of an address (--index-mode buckets). Each bucket
+// holds the indices of the ranges starting in it, so that lookups of hot
+// prefixes only search a handful of neighbouring ranges. Buckets without
+// any range start (inside large ranges) fall back to a search of all
+// ranges, which keeps the table proportional to the number of ranges.
+pub(crate) struct BucketIndex {
+    ranges: Vec<Asn>,
+    // Bucket -> [first, end) indices of the ranges starting in it
+    v4: HashMap<u32, (u32, u32)>,
+    v6: HashMap<u64, (u32, u32)>,
+}
+
+enum Bucket {
+    V4(u32),
+    V6(u64),
+}
+
+fn bucket(ip: IpAddr) -> Bucket {
+    match ip {
+        IpAddr::V4(v4) => Bucket::V4(u32::from(v4) >> 8),
+        IpAddr::V6(v6) => Bucket::V6((u128::from(v6) >> 80) as u64),
+    }
+}
+
+impl BucketIndex {
+    // `ranges` must be sorted by first address
+    pub(crate) fn new(ranges: Vec<Asn>) -> Self {
+        let mut v4: HashMap<u32, (u32, u32)> = HashMap::new();
+        let mut v6: HashMap<u64, (u32, u32)> = HashMap::new();
+        for (i, asn) in ranges.iter().enumerate() {
+            let i = i as u32;
+            let entry = match bucket(asn.first_ip) {
+                Bucket::V4(key) => v4.entry(key).or_insert((i, i)),
+                Bucket::V6(key) => v6.entry(key).or_insert((i, i)),
+            };
+            entry.1 = i + 1;
+        }
+        BucketIndex { ranges, v4, v6 }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    // Number of non-empty buckets
+    pub(crate) fn buckets(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Asn> {
+        self.ranges.iter()
+    }
+
+    // Ranges that may overlap [first, last]: from the one containing or
+    // preceding `first` to the last one starting at or before `last`
+    pub(crate) fn between(&self, first: IpAddr, last: IpAddr) -> &[Asn] {
+        let start = self
+            .ranges
+            .partition_point(|asn| asn.first_ip <= first)
+            .saturating_sub(1);
+        let end = self.ranges.partition_point(|asn| asn.first_ip <= last);
+        &self.ranges[start..end.max(start)]
+    }
+
+    // Range with the greatest first address <= ip
+    pub(crate) fn find(&self, ip: IpAddr) -> Option<&Asn> {
+        let candidates = match bucket(ip) {
+            Bucket::V4(key) => self.v4.get(&key),
+            Bucket::V6(key) => self.v6.get(&key),
+        };
+        let (first, end) = match candidates {
+            Some(&(first, end)) => (first as usize, end as usize),
+            None => (0, self.ranges.len()),
+        };
+        let n = self.ranges[first..end].partition_point(|asn| asn.first_ip <= ip);
+        // Before the first range starting in the bucket, the previous range
+        // may still extend into it
+        (first + n).checked_sub(1).map(|i| &self.ranges[i])
+    }
+}
@@ -0,0 +1,38 @@
+use crate::mmap;
+use serde::Serialize;
+
+// What a binary was built from and with, for fleet audits (GET /version,
+// --version-json)
+#[derive(Serialize)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub build_date: &'static str,
+    pub target: &'static str,
+    // Cargo features compiled in
+    pub features: Vec<&'static str>,
+    // Layout version of the binary database snapshots (--backend mmap)
+    pub data_format_version: u32,
+}
+
+pub fn build_info() -> BuildInfo {
+    let features = [
+        ("async", cfg!(feature = "async")),
+        ("csv", cfg!(feature = "csv")),
+        ("msgpack", cfg!(feature = "msgpack")),
+        ("blocking", cfg!(feature = "blocking")),
+        ("grpc", cfg!(feature = "grpc")),
+    ];
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_commit: env!("IPTOASN_GIT_COMMIT"),
+        build_date: env!("IPTOASN_BUILD_DATE"),
+        target: env!("IPTOASN_TARGET"),
+        features: features
+            .into_iter()
+            .filter(|&(_, enabled)| enabled)
+            .map(|(name, _)| name)
+            .collect(),
+        data_format_version: mmap::FORMAT_VERSION,
+    }
+}
@@ -0,0 +1,446 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+// Set of IPv4 and IPv6 addresses kept as sorted, merged inclusive ranges,
+// supporting the set operations used by `iptoasn sets`.
+#[derive(Clone, Debug, Default)]
+pub struct IpRangeSet {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpRangeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add an IP address or a CIDR prefix
+    pub fn insert_str(&mut self, s: &str) -> Result<(), String> {
+        let (ip_s, prefix_len) = match s.split_once('/') {
+            Some((ip_s, len_s)) => {
+                let len = len_s
+                    .parse::<u8>()
+                    .map_err(|_| format!("Invalid prefix length in [{}]", s))?;
+                (ip_s, Some(len))
+            }
+            None => (s, None),
+        };
+        let ip = IpAddr::from_str(ip_s).map_err(|_| format!("Invalid IP address [{}]", s))?;
+        match ip {
+            IpAddr::V4(ip) => {
+                let len = prefix_len.unwrap_or(32);
+                if len > 32 {
+                    return Err(format!("Invalid prefix length in [{}]", s));
+                }
+                let mask = if len == 0 { 0 } else { u32::MAX << (32 - len) };
+                let first = u32::from(ip) & mask;
+                self.v4.push((first, first | !mask));
+            }
+            IpAddr::V6(ip) => {
+                let len = prefix_len.unwrap_or(128);
+                if len > 128 {
+                    return Err(format!("Invalid prefix length in [{}]", s));
+                }
+                let mask = if len == 0 { 0 } else { u128::MAX << (128 - len) };
+                let first = u128::from(ip) & mask;
+                self.v6.push((first, first | !mask));
+            }
+        }
+        Ok(())
+    }
+
+    // Add an inclusive range; both ends must be of the same address family
+    pub fn insert_range(&mut self, first: IpAddr, last: IpAddr) {
+        match (first, last) {
+            (IpAddr::V4(f), IpAddr::V4(l)) if f <= l => self.v4.push((f.into(), l.into())),
+            (IpAddr::V6(f), IpAddr::V6(l)) if f <= l => self.v6.push((f.into(), l.into())),
+            _ => {}
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let ip = u32::from(ip);
+                self.v4.iter().any(|&(first, last)| first <= ip && ip <= last)
+            }
+            IpAddr::V6(ip) => {
+                let ip = u128::from(ip);
+                self.v6.iter().any(|&(first, last)| first <= ip && ip <= last)
+            }
+        }
+    }
+
+    fn normalize(&mut self) {
+        self.v4 = merge_ranges_u32(&mut self.v4);
+        self.v6 = merge_ranges_u128(&mut self.v6);
+    }
+
+    pub fn union(mut self, mut other: IpRangeSet) -> IpRangeSet {
+        self.v4.append(&mut other.v4);
+        self.v6.append(&mut other.v6);
+        self.normalize();
+        self
+    }
+
+    pub fn intersect(mut self, mut other: IpRangeSet) -> IpRangeSet {
+        self.normalize();
+        other.normalize();
+        IpRangeSet {
+            v4: intersect_ranges(&self.v4, &other.v4),
+            v6: intersect_ranges(&self.v6, &other.v6),
+        }
+    }
+
+    pub fn subtract(mut self, mut other: IpRangeSet) -> IpRangeSet {
+        self.normalize();
+        other.normalize();
+        IpRangeSet {
+            v4: subtract_ranges(&self.v4, &other.v4),
+            v6: subtract_ranges(&self.v6, &other.v6),
+        }
+    }
+
+    // Minimal list of CIDR prefixes covering the set, IPv4 first
+    pub fn to_cidrs(mut self) -> Vec<String> {
+        self.normalize();
+        let v4 = self.v4.iter().flat_map(|&(f, l)| {
+            range_to_cidrs(&Ipv4Addr::from(f).to_string(), &Ipv4Addr::from(l).to_string())
+        });
+        let v6 = self.v6.iter().flat_map(|&(f, l)| {
+            range_to_cidrs(&Ipv6Addr::from(f).to_string(), &Ipv6Addr::from(l).to_string())
+        });
+        v4.chain(v6).collect()
+    }
+}
+
+// First and last address and prefix length of a CIDR prefix, e.g.
+// 192.0.2.0/24. Host bits are ignored, and a bare address is a /32 or /128.
+pub fn prefix_bounds(prefix: &str) -> Result<(IpAddr, IpAddr, u8), String> {
+    let (ip_s, len_s) = match prefix.trim().split_once('/') {
+        Some((ip_s, len_s)) => (ip_s, Some(len_s)),
+        None => (prefix.trim(), None),
+    };
+    let ip = IpAddr::from_str(ip_s).map_err(|_| format!("Invalid prefix [{}]", prefix))?;
+    let max_len = if ip.is_ipv4() { 32 } else { 128 };
+    let len = match len_s {
+        Some(len_s) => len_s
+            .parse::<u8>()
+            .ok()
+            .filter(|len| *len <= max_len)
+            .ok_or_else(|| format!("Invalid prefix length in [{}]", prefix))?,
+        None => max_len,
+    };
+    Ok(match ip {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            let first = u32::from(ip) & mask;
+            (Ipv4Addr::from(first).into(), Ipv4Addr::from(first | !mask).into(), len)
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            let first = u128::from(ip) & mask;
+            (Ipv6Addr::from(first).into(), Ipv6Addr::from(first | !mask).into(), len)
+        }
+    })
+}
+
+// Reverse DNS zones (in-addr.arpa / ip6.arpa) covering a CIDR prefix.
+// Prefixes that don't end on an octet (IPv4) or nibble (IPv6) boundary are
+// split into the zones of the next boundary; IPv4 prefixes longer than /24
+// map to their enclosing /24 zone, as they can only be delegated RFC 2317 style.
+pub fn reverse_zones(prefix: &str) -> Result<Vec<String>, String> {
+    let (ip_s, len_s) = prefix
+        .trim()
+        .split_once('/')
+        .ok_or_else(|| format!("Invalid prefix [{}]", prefix))?;
+    let len = len_s
+        .parse::<u32>()
+        .map_err(|_| format!("Invalid prefix length in [{}]", prefix))?;
+    match IpAddr::from_str(ip_s).map_err(|_| format!("Invalid prefix [{}]", prefix))? {
+        IpAddr::V4(ip) if len <= 32 => {
+            let zone_len = len.clamp(8, 24).div_ceil(8) * 8;
+            let mask = u32::MAX.checked_shl(32 - len.min(zone_len)).unwrap_or(0);
+            let first = u32::from(ip) & mask;
+            Ok((0..1u32 << zone_len.saturating_sub(len))
+                .map(|i| {
+                    let octets = (first + (i << (32 - zone_len))).to_be_bytes();
+                    let mut labels: Vec<String> = octets[..(zone_len / 8) as usize]
+                        .iter()
+                        .rev()
+                        .map(|o| o.to_string())
+                        .collect();
+                    labels.push("in-addr.arpa".to_string());
+                    labels.join(".")
+                })
+                .collect())
+        }
+        IpAddr::V6(ip) if len <= 128 => {
+            let zone_len = len.div_ceil(4).max(1) * 4;
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            let first = u128::from(ip) & mask;
+            Ok((0..1u128 << (zone_len - len))
+                .map(|i| {
+                    let addr = first + (i << (128 - zone_len));
+                    let mut labels: Vec<String> = (0..zone_len / 4)
+                        .rev()
+                        .map(|n| format!("{:x}", (addr >> (124 - 4 * n)) & 0xf))
+                        .collect();
+                    labels.push("ip6.arpa".to_string());
+                    labels.join(".")
+                })
+                .collect())
+        }
+        _ => Err(format!("Invalid prefix length in [{}]", prefix)),
+    }
+}
+
+// Intersection of two sorted, merged lists of inclusive ranges
+fn intersect_ranges<T: Copy + Ord>(a: &[(T, T)], b: &[(T, T)]) -> Vec<(T, T)> {
+    let (mut i, mut j) = (0, 0);
+    let mut out = Vec::new();
+    while i < a.len() && j < b.len() {
+        let start = a[i].0.max(b[j].0);
+        let end = a[i].1.min(b[j].1);
+        if start <= end {
+            out.push((start, end));
+        }
+        if a[i].1 < b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    out
+}
+
+// Ranges of `a` not covered by `b`, both sorted and merged
+fn subtract_ranges<T>(a: &[(T, T)], b: &[(T, T)]) -> Vec<(T, T)>
+where
+    T: Copy + Ord + std::ops::Add<Output = T> + std::ops::Sub<Output = T> + From<u8>,
+{
+    let one = T::from(1);
+    let mut out = Vec::new();
+    let mut j = 0;
+    for &(start, end) in a {
+        let mut cur = start;
+        while j < b.len() && b[j].1 < cur {
+            j += 1;
+        }
+        let mut k = j;
+        let mut done = false;
+        while k < b.len() && b[k].0 <= end {
+            if b[k].0 > cur {
+                out.push((cur, b[k].0 - one));
+            }
+            if b[k].1 >= end {
+                done = true;
+                break;
+            }
+            cur = b[k].1 + one;
+            k += 1;
+        }
+        if !done {
+            out.push((cur, end));
+        }
+    }
+    out
+}
+
+pub fn merge_ranges_u32(ranges: &mut [(u32, u32)]) -> Vec<(u32, u32)> {
+    if ranges.is_empty() {
+        return Vec::new();
+    }
+    ranges.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+    let mut out: Vec<(u32, u32)> = Vec::new();
+    let mut cur = ranges[0];
+
+    for &(s, e) in &ranges[1..] {
+        let adj = cur.1 != u32::MAX && s <= cur.1.saturating_add(1);
+        let ovl = s <= cur.1;
+        if ovl || adj {
+            cur.1 = cur.1.max(e);
+        } else {
+            out.push(cur);
+            cur = (s, e);
+        }
+    }
+    out.push(cur);
+    out
+}
+
+pub fn merge_ranges_u128(ranges: &mut [(u128, u128)]) -> Vec<(u128, u128)> {
+    if ranges.is_empty() {
+        return Vec::new();
+    }
+    ranges.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+    let mut out: Vec<(u128, u128)> = Vec::new();
+    let mut cur = ranges[0];
+
+    for &(s, e) in &ranges[1..] {
+        let adj = cur.1 != u128::MAX && s <= cur.1.saturating_add(1);
+        let ovl = s <= cur.1;
+        if ovl || adj {
+            cur.1 = cur.1.max(e);
+        } else {
+            out.push(cur);
+            cur = (s, e);
+        }
+    }
+    out.push(cur);
+    out
+}
+
+// Order of subnet lists (?sort=). Both orders list all IPv4 prefixes
+// before IPv6 ones, so that the output is stable and can be diffed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SubnetOrder {
+    // Numerically ascending by network address
+    #[default]
+    Prefix,
+    // Largest blocks first, then by network address
+    Size,
+}
+
+impl FromStr for SubnetOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefix" => Ok(SubnetOrder::Prefix),
+            "size" => Ok(SubnetOrder::Size),
+            _ => Err(format!("Unsupported sort order \"{}\", expected prefix or size", s)),
+        }
+    }
+}
+
+// (family, network address, prefix length) of a CIDR prefix
+fn prefix_key(prefix: &str) -> (u8, u128, u8) {
+    let (ip_s, len_s) = prefix.split_once('/').unwrap_or((prefix, ""));
+    match IpAddr::from_str(ip_s) {
+        Ok(IpAddr::V4(ip)) => (4, u32::from(ip) as u128, len_s.parse().unwrap_or(32)),
+        Ok(IpAddr::V6(ip)) => (6, u128::from(ip), len_s.parse().unwrap_or(128)),
+        Err(_) => (u8::MAX, 0, 0),
+    }
+}
+
+pub fn sort_subnets(subnets: &mut [String], order: SubnetOrder) {
+    match order {
+        SubnetOrder::Prefix => subnets.sort_by_cached_key(|s| prefix_key(s)),
+        SubnetOrder::Size => subnets.sort_by_cached_key(|s| {
+            let (family, ip, len) = prefix_key(s);
+            (family, len, ip)
+        }),
+    }
+}
+
+// Number of prefixes of the minimal CIDR set of an inclusive range, as
+// range_to_cidrs would return, without building them
+pub fn prefix_count(first: IpAddr, last: IpAddr) -> usize {
+    let (mut start, end, bits) = match (first, last) {
+        (IpAddr::V4(f), IpAddr::V4(l)) => (u32::from(f) as u128, u32::from(l) as u128, 32),
+        (IpAddr::V6(f), IpAddr::V6(l)) => (u128::from(f), u128::from(l), 128),
+        _ => return 0,
+    };
+    if start > end {
+        return 0;
+    }
+    // Last offset of a block of 2^k addresses
+    let span = |k: u32| if k == 128 { u128::MAX } else { (1u128 << k) - 1 };
+    let mut count = 0;
+    loop {
+        let mut k = start.trailing_zeros().min(bits);
+        while span(k) > end - start {
+            k -= 1;
+        }
+        count += 1;
+        match (start + span(k)).checked_add(1) {
+            Some(next) if next <= end => start = next,
+            _ => return count,
+        }
+    }
+}
+
+// Deaggregate an arbitrary inclusive range into minimal CIDR set
+pub fn range_to_cidrs(first_s: &str, last_s: &str) -> Vec<String> {
+    let first = IpAddr::from_str(first_s).ok();
+    let last = IpAddr::from_str(last_s).ok();
+
+    match (first, last) {
+        (Some(IpAddr::V4(f)), Some(IpAddr::V4(l))) => {
+            let mut start = u32::from_be_bytes(f.octets());
+            let end = u32::from_be_bytes(l.octets());
+            if start > end {
+                return vec![];
+            }
+            if start == 0 && end == u32::MAX {
+                return vec!["0.0.0.0/0".to_string()];
+            }
+            let mut res = Vec::new();
+            while start <= end {
+                let mut block: u32 = if start == 0 {
+                    1u32 << 31
+                } else {
+                    1u32 << start.trailing_zeros().min(31)
+                };
+
+                let remaining = end - start + 1;
+                while block > remaining {
+                    block >>= 1;
+                }
+
+                let prefix_len = 32 - block.trailing_zeros() as u8;
+                let net_ip = Ipv4Addr::from(start.to_be_bytes());
+                res.push(format!("{}/{}", net_ip, prefix_len));
+
+                start = match start.checked_add(block) {
+                    Some(next) if block != 0 => next,
+                    _ => break,
+                };
+            }
+            res
+        }
+        (Some(IpAddr::V6(f)), Some(IpAddr::V6(l))) => {
+            let mut start = u128::from_be_bytes(f.octets());
+            let end = u128::from_be_bytes(l.octets());
+            if start > end {
+                return vec![];
+            }
+            if start == 0 && end == u128::MAX {
+                return vec!["::/0".to_string()];
+            }
+            let mut res = Vec::new();
+            while start <= end {
+                let mut block: u128 = if start == 0 {
+                    1u128 << 127
+                } else {
+                    1u128 << start.trailing_zeros().min(127)
+                };
+
+                let remaining = end - start + 1;
+                while block > remaining {
+                    block >>= 1;
+                }
+
+                let prefix_len = 128 - block.trailing_zeros() as u8;
+                let net_ip = Ipv6Addr::from(start.to_be_bytes());
+                res.push(format!("{}/{}", net_ip, prefix_len));
+
+                start = match start.checked_add(block) {
+                    Some(next) if block != 0 => next,
+                    _ => break,
+                };
+            }
+            res
+        }
+        _ => vec![],
+    }
+}
+
+// Prefix of the minimal CIDR set of an inclusive range holding `ip`, ranges
+// not always being a single prefix
+pub fn containing_prefix(first: IpAddr, last: IpAddr, ip: IpAddr) -> Option<String> {
+    range_to_cidrs(&first.to_string(), &last.to_string())
+        .into_iter()
+        .find(|prefix| prefix_bounds(prefix).is_ok_and(|(first, last, _)| (first..=last).contains(&ip)))
+}
@@ -0,0 +1,121 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Configuration file of the iptoasn CLI, in TOML. Settings at the top level
+// apply to every invocation, and those of the profile selected with
+// --profile override them:
+//
+//   server = "http://127.0.0.1:53661"
+//   output = "plain"
+//   as_markers = "<>"
+//
+//   [profiles.work]
+//   server = "https://iptoasn.corp.example.com"
+//   api_key = "4c1f9e..."
+//   color = false
+#[derive(Default)]
+pub struct CliConfig {
+    pub defaults: CliSettings,
+    pub profiles: HashMap<String, CliSettings>,
+}
+
+// Defaults of the command line options. Options given on the command line
+// or in the environment take precedence.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CliSettings {
+    // Base URL of the webservice (--server)
+    pub server: Option<String>,
+    // API key sent to the webservice (--api-key)
+    pub api_key: Option<String>,
+    // Output of the subcommands (--output)
+    pub output: Option<CliOutput>,
+    // Opening and closing markers of annotations (--as-markers)
+    pub as_markers: Option<String>,
+    // Delimiter between the fields of annotations (--as-sep)
+    pub as_sep: Option<String>,
+    // ANSI colors in tables, false as with NO_COLOR
+    pub color: Option<bool>,
+    // Flag emoji in tables (--flag)
+    pub flag: Option<bool>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CliOutput {
+    // Tables on a terminal, plain text otherwise
+    Auto,
+    Json,
+    Plain,
+}
+
+impl CliSettings {
+    // Settings of `other` where set, and these otherwise
+    fn merge(self, other: CliSettings) -> CliSettings {
+        CliSettings {
+            server: other.server.or(self.server),
+            api_key: other.api_key.or(self.api_key),
+            output: other.output.or(self.output),
+            as_markers: other.as_markers.or(self.as_markers),
+            as_sep: other.as_sep.or(self.as_sep),
+            color: other.color.or(self.color),
+            flag: other.flag.or(self.flag),
+        }
+    }
+}
+
+impl CliConfig {
+    // ~/.config/iptoasn/config.toml on Linux, the platform configuration
+    // directory elsewhere
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("iptoasn").join("config.toml"))
+    }
+
+    // The configuration at `path`, or an empty one if the file doesn't exist
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(CliConfig::default()),
+            Err(e) => return Err(format!("Unable to read {}: {}", path.display(), e)),
+        };
+        let invalid = |e: toml::de::Error| format!("Invalid config {}: {}", path.display(), e);
+        // The top-level settings sit next to the profiles table, and unknown
+        // keys must still be rejected in both
+        let mut table: toml::Table = toml::from_str(&content).map_err(invalid)?;
+        let profiles = match table.remove("profiles") {
+            Some(profiles) => profiles.try_into().map_err(invalid)?,
+            None => HashMap::new(),
+        };
+        Ok(CliConfig {
+            defaults: table.try_into().map_err(invalid)?,
+            profiles,
+        })
+    }
+
+    // Settings of a profile, on top of the top-level ones
+    pub fn settings(self, profile: Option<&str>) -> Result<CliSettings, String> {
+        let Some(name) = profile else {
+            return Ok(self.defaults);
+        };
+        let mut profiles = self.profiles;
+        match profiles.remove(name) {
+            Some(settings) => Ok(self.defaults.merge(settings)),
+            None => {
+                let mut names: Vec<String> = profiles.into_keys().collect();
+                names.sort();
+                Err(format!(
+                    "Unknown profile \"{}\"{}",
+                    name,
+                    if names.is_empty() {
+                        String::new()
+                    } else {
+                        format!(", expected one of: {}", names.join(", "))
+                    }
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Server configuration file (--config), in TOML:
+//
+//   [[hosts]]
+//   host = "api.example.com"
+//   default_format = "json"
+//
+//   [[hosts]]
+//   host = "*.lookup.example.com"
+//   default_format = "html"
+//
+//   [country_codes]
+//   EU = "DE"
+//
+//   [[tenants]]
+//   name = "marketing"
+//   keys = ["2f6c1e..."]
+//   monthly_quota = 1000000
+//
+//   [[dnsbl]]
+//   name = "hosting"
+//   code = 2
+//   asns = [16509, 14061]
+//
+//   [normalization]
+//   uppercase_countries = true
+//   strip_country_suffix = true
+//   max_description_length = 64
+//
+//   [load_shedding]
+//   max_in_flight = 512
+//   routes = { "/v1/as/ips" = 16 }
+//
+//   strict_negotiation = true
+//   strict_not_found = true
+//   plain_ascii_descriptions = true
+//   api_keys = ["4c1f9e..."]
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub hosts: Vec<HostConfig>,
+    // Country code corrections applied when loading the database
+    #[serde(default)]
+    pub country_codes: HashMap<String, String>,
+    // Normalization of the country codes and descriptions of the database
+    #[serde(default)]
+    pub normalization: NormalizationConfig,
+    // Teams using the service with API keys, for usage accounting
+    #[serde(default)]
+    pub tenants: Vec<TenantConfig>,
+    // Reject unsupported Accept and Content-Type headers with 406 and 415
+    // instead of falling back to the default formats
+    #[serde(default)]
+    pub strict_negotiation: bool,
+    // Answer AS numbers missing from the database with 404 instead of 200
+    #[serde(default)]
+    pub strict_not_found: bool,
+    // Transliterate AS descriptions to ASCII in plain text output
+    #[serde(default)]
+    pub plain_ascii_descriptions: bool,
+    // Categories of the DNSBL-style answers of /v1/dnsbl
+    #[serde(default)]
+    pub dnsbl: Vec<DnsblConfig>,
+    // Keys required by all routes but the public ones, on top of --api-key
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    // Concurrency thresholds above which requests get a 503
+    #[serde(default)]
+    pub load_shedding: LoadSheddingConfig,
+    // Routes and formats being phased out, announced with Deprecation and
+    // Sunset headers
+    #[serde(default)]
+    pub deprecations: Vec<DeprecationConfig>,
+    // Answer the deprecated routes and formats with 410 Gone
+    #[serde(default)]
+    pub disable_deprecated: bool,
+}
+
+// Behavior for requests with a given Host header
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    // Host name, or *.domain to match all its subdomains
+    pub host: String,
+    // Output format used when the Accept header doesn't ask for one
+    pub default_format: Format,
+}
+
+// A team using the service, identified by its API keys
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantConfig {
+    // Name reported by /admin/usage and in the Prometheus labels
+    pub name: String,
+    pub keys: Vec<String>,
+    // Maximum number of IP addresses looked up per calendar month (UTC)
+    pub monthly_quota: Option<u64>,
+}
+
+// A DNSBL category: addresses announced by one of `asns`, or in a range
+// registered in one of `countries`, get `code` set in their answer
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsblConfig {
+    pub name: String,
+    // Bit of the last octet of the 127.0.0.x answer: 2, 4, 8, ... or 128
+    pub code: u8,
+    #[serde(default)]
+    pub asns: Vec<u32>,
+    #[serde(default)]
+    pub countries: Vec<String>,
+}
+
+// A route, or a response format of all or one route, being phased out
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeprecationConfig {
+    // Path of the route as in /openapi.json, e.g. /v1/as/n/{asn}
+    pub path: Option<String>,
+    // Media type of the deprecated responses, e.g. text/html
+    pub format: Option<String>,
+    // Date of the deprecation, YYYY-MM-DD
+    pub since: String,
+    // Date after which it may be removed, YYYY-MM-DD
+    pub sunset: Option<String>,
+    // Migration guide, sent in a Link header
+    pub link: Option<String>,
+}
+
+// Changes to the fields of the database when it is loaded, e.g. for
+// storage with field-length limits
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NormalizationConfig {
+    // Country codes in uppercase, before corrections
+    #[serde(default)]
+    pub uppercase_countries: bool,
+    // Remove a trailing country code from descriptions, as in "Example, US"
+    #[serde(default)]
+    pub strip_country_suffix: bool,
+    // Maximum number of characters of descriptions
+    pub max_description_length: Option<usize>,
+}
+
+// Requests are shed with 503 and a Retry-After of `retry_after` seconds
+// while more than `max_in_flight` are being served, more than
+// `max_in_flight_per_connection` on the same connection, or more than the
+// threshold of their route pattern in `routes`. Connections above
+// `max_connections` are answered with 503 and closed. Both first wait up to
+// `queue_timeout` milliseconds for a slot.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoadSheddingConfig {
+    pub max_in_flight: Option<u64>,
+    pub max_in_flight_per_connection: Option<u64>,
+    pub max_connections: Option<u64>,
+    pub queue_timeout: u64,
+    pub retry_after: u64,
+    pub routes: HashMap<String, u64>,
+}
+
+impl Default for LoadSheddingConfig {
+    fn default() -> Self {
+        LoadSheddingConfig {
+            max_in_flight: None,
+            max_in_flight_per_connection: None,
+            max_connections: None,
+            queue_timeout: 0,
+            retry_after: 1,
+            routes: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Html,
+    Plain,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        toml::from_str(&content).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+}
+
+impl HostConfig {
+    // `host` is the Host header value, possibly with a port
+    pub fn matches(&self, host: &str) -> bool {
+        let host = match host.rsplit_once(':') {
+            Some((name, port))
+                if port.bytes().all(|b| b.is_ascii_digit())
+                    && (!name.contains(':') || name.ends_with(']')) =>
+            {
+                name
+            }
+            _ => host,
+        };
+        match self.host.strip_prefix("*.") {
+            Some(domain) => host
+                .len()
+                .checked_sub(domain.len() + 1)
+                .is_some_and(|i| {
+                    host.as_bytes()[i] == b'.' && host[i + 1..].eq_ignore_ascii_case(domain)
+                }),
+            None => host.eq_ignore_ascii_case(&self.host),
+        }
+    }
+}
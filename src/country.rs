@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// Corrections applied to the country codes of the database at parse time,
+// on top of DEFAULT_CORRECTIONS (set_corrections)
+static CORRECTIONS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+// Codes found in the upstream data that aren't ISO 3166-1 alpha-2
+const DEFAULT_CORRECTIONS: [(&str, &str); 1] = [("UK", "GB")];
+
+// Code used by the upstream data for ranges without a country
+pub const NO_COUNTRY: &str = "None";
+
+// ISO 3166-1 alpha-2 officially assigned codes
+const ISO_3166_ALPHA2: [&str; 249] = [
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
+
+// Set the country code corrections, e.g. from the [country_codes] table of
+// the config file. They extend and override the default ones. Must be
+// called before the database is loaded.
+pub fn set_corrections(corrections: HashMap<String, String>) {
+    let mut all: HashMap<String, String> = DEFAULT_CORRECTIONS
+        .iter()
+        .map(|(from, to)| (from.to_string(), to.to_string()))
+        .collect();
+    all.extend(corrections);
+    let _ = CORRECTIONS.set(all);
+}
+
+// Check that corrections map to assigned codes, so that a typo in the
+// config file doesn't rewrite the ranges of a country to an unknown one
+pub fn check_corrections(corrections: &HashMap<String, String>) -> Result<(), String> {
+    let mut unknown: Vec<String> = corrections
+        .iter()
+        .filter(|(_, to)| !is_known(to))
+        .map(|(from, to)| format!("{} = {:?}", from, to))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    unknown.sort();
+    Err(format!("Unknown country codes in [country_codes]: {}", unknown.join(", ")))
+}
+
+// Corrected country code
+pub fn correct(code: &str) -> &str {
+    match CORRECTIONS.get() {
+        Some(corrections) => corrections.get(code).map_or(code, String::as_str),
+        None => DEFAULT_CORRECTIONS
+            .iter()
+            .find(|(from, _)| *from == code)
+            .map_or(code, |(_, to)| to),
+    }
+}
+
+// Whether the code is an assigned ISO 3166-1 alpha-2 code (or the upstream
+// marker for ranges without a country)
+pub fn is_known(code: &str) -> bool {
+    code == NO_COUNTRY || ISO_3166_ALPHA2.binary_search(&code).is_ok()
+}
+
+// Flag emoji of an assigned ISO 3166-1 alpha-2 code, as its pair of
+// regional indicator symbols
+pub fn flag(code: &str) -> Option<String> {
+    ISO_3166_ALPHA2.binary_search(&code).ok()?;
+    code.bytes()
+        .map(|b| char::from_u32(0x1F1E6 + u32::from(b - b'A')))
+        .collect()
+}
@@ -0,0 +1,158 @@
+use crate::config::DeprecationConfig;
+use crate::openapi;
+use crate::router::{self, Route};
+use http::{HeaderMap, HeaderValue};
+use log::warn;
+use std::collections::HashSet;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, SystemTime};
+
+// Routes and formats being phased out, from the [[deprecations]] tables of
+// the config file (set_deprecations)
+static DEPRECATIONS: OnceLock<Deprecations> = OnceLock::new();
+
+// Clients logged per deprecation, so that a crowd of them can't grow the
+// set without bounds
+const MAX_LOGGED_CLIENTS: usize = 10_000;
+
+struct Deprecations {
+    list: Vec<Deprecation>,
+    // Answer the deprecated routes and formats with 410 Gone
+    disabled: bool,
+}
+
+pub(crate) struct Deprecation {
+    // Route, as an OpenAPI path; all routes if None
+    path: Option<String>,
+    // Media type of the responses; all formats if None
+    format: Option<String>,
+    since: SystemTime,
+    sunset: Option<SystemTime>,
+    link: Option<String>,
+    // Clients whose use was already logged
+    logged: Mutex<HashSet<String>>,
+}
+
+// Set the deprecations, and whether they are disabled. Paths must be those
+// of /openapi.json. Must be called before the server is started.
+pub fn set_deprecations(configs: Vec<DeprecationConfig>, disabled: bool) -> Result<(), String> {
+    let paths: HashSet<String> = router::routes()
+        .iter()
+        .map(|entry| openapi::path_template(entry.pattern))
+        .collect();
+    let mut list = Vec::with_capacity(configs.len());
+    for config in configs {
+        if let Some(path) = config.path.as_ref().filter(|path| !paths.contains(*path)) {
+            return Err(format!("Unknown deprecated path {}, use one of /openapi.json", path));
+        }
+        let since = parse_date(&config.since)
+            .ok_or_else(|| format!("Invalid deprecation date {}, expected YYYY-MM-DD", config.since))?;
+        let sunset = match &config.sunset {
+            Some(sunset) => {
+                Some(parse_date(sunset).ok_or_else(|| format!("Invalid sunset date {}, expected YYYY-MM-DD", sunset))?)
+            }
+            None => None,
+        };
+        if sunset.is_some_and(|sunset| sunset < since) {
+            return Err(format!("The sunset of {} predates its deprecation", config.since));
+        }
+        list.push(Deprecation {
+            path: config.path,
+            format: config.format.map(|format| format.trim().to_ascii_lowercase()),
+            since,
+            sunset,
+            link: config.link,
+            logged: Mutex::new(HashSet::new()),
+        });
+    }
+    let _ = DEPRECATIONS.set(Deprecations { list, disabled });
+    Ok(())
+}
+
+pub(crate) fn is_disabled() -> bool {
+    DEPRECATIONS.get().is_some_and(|deprecations| deprecations.disabled)
+}
+
+// Deprecation of a route, or of one of its formats given the media type of a
+// response. Without a media type, only deprecations of whole routes match.
+pub(crate) fn find(route: Route, media_type: Option<&str>) -> Option<&'static Deprecation> {
+    let path = openapi::path_template(route.pattern());
+    DEPRECATIONS.get()?.list.iter().find(|deprecation| {
+        deprecation.path.as_ref().is_none_or(|deprecated| *deprecated == path)
+            && match (&deprecation.format, media_type) {
+                (None, _) => true,
+                (Some(format), Some(media_type)) => format.eq_ignore_ascii_case(media_type),
+                (Some(_), None) => false,
+            }
+    })
+}
+
+impl Deprecation {
+    // Deprecation (RFC 9745), Sunset (RFC 8594) and Link headers
+    pub(crate) fn add_headers(&self, headers: &mut HeaderMap) {
+        let unix = self.since.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        if let Ok(value) = HeaderValue::from_str(&format!("@{}", unix)) {
+            headers.insert("deprecation", value);
+        }
+        if let Some(sunset) = self.sunset {
+            if let Ok(value) = HeaderValue::from_str(&httpdate::fmt_http_date(sunset)) {
+                headers.insert("sunset", value);
+            }
+        }
+        if let Some(link) = &self.link {
+            if let Ok(value) = HeaderValue::from_str(&format!("<{}>; rel=\"deprecation\"", link)) {
+                headers.append(http::header::LINK, value);
+            }
+        }
+    }
+
+    // Log the first use by a client, named by its tenant or its address
+    pub(crate) fn log_use(&self, route: Route, media_type: Option<&str>, client: &str) {
+        let mut logged = self.logged.lock().unwrap_or_else(PoisonError::into_inner);
+        if logged.len() >= MAX_LOGGED_CLIENTS || !logged.insert(client.to_string()) {
+            return;
+        }
+        let path = openapi::path_template(route.pattern());
+        match &self.format {
+            Some(_) => warn!(
+                "Client {} uses deprecated format {} of {}",
+                client,
+                media_type.unwrap_or_default(),
+                path
+            ),
+            None => warn!("Client {} uses deprecated route {}", client, path),
+        }
+    }
+
+    // Message of the 410 response of a disabled deprecation
+    pub(crate) fn gone_message(&self) -> String {
+        let what = match &self.format {
+            Some(format) => format!("The {} format of this route", format),
+            None => "This route".to_string(),
+        };
+        match &self.link {
+            Some(link) => format!("{} is no longer available, see {}", what, link),
+            None => format!("{} is no longer available", what),
+        }
+    }
+}
+
+// Midnight UTC of a YYYY-MM-DD date
+fn parse_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return None;
+    }
+    // Days since the epoch of a proleptic Gregorian date, with years
+    // starting in March so that leap days come last
+    let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(days as u64 * 86400))
+}
@@ -0,0 +1,258 @@
+use crate::asns::Asns;
+use crate::cidr;
+use crate::dnsbl;
+use crate::load::{self, Connection};
+use log::{debug, error, info};
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+const TYPE_TXT: u16 = 16;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+const RCODE_FORMERR: u8 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+// Largest UDP response without EDNS; longer ones are truncated so that the
+// client retries over TCP
+const MAX_UDP_RESPONSE: usize = 512;
+
+// Lifetime of answers, negative ones included
+const TTL: u32 = 3600;
+
+// How long a TCP connection may stay idle between queries
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Team Cymru style DNS interface (https://team-cymru.com/community-services/ip-asn-mapping/)
+// answering TXT queries under a zone, e.g. with asn.local:
+//   4.3.2.1.origin.asn.local      "<asn> | <prefix> | <cc> | <registry> | <date>" for 1.2.3.4
+//   <32 nibbles>.origin6.asn.local the same for an IPv6 address
+//   AS15169.asn.local             "<asn> | <cc> | <registry> | <date> | <description>"
+// The registry and allocation date fields are left empty, the database
+// doesn't have them, but are kept for tools splitting the answer on '|'.
+// DNS can't carry API keys, so the listener is refused when they are
+// configured.
+pub struct DnsServer {
+    socket: UdpSocket,
+    listener: TcpListener,
+}
+
+impl DnsServer {
+    // Bound before the server is started, so that a listen address that
+    // can't be used fails the startup
+    pub async fn bind(listen_addr: SocketAddr) -> io::Result<Self> {
+        Ok(DnsServer {
+            socket: UdpSocket::bind(listen_addr).await?,
+            listener: TcpListener::bind(listen_addr).await?,
+        })
+    }
+
+    pub async fn serve(self, asns_arc: Arc<RwLock<Arc<Asns>>>, zone: String) {
+        let zone: Arc<str> = Arc::from(zone.trim_end_matches('.').to_ascii_lowercase());
+        if let Ok(addr) = self.socket.local_addr() {
+            info!("DNS server ready on {} (UDP and TCP) for zone {}", addr, zone);
+        }
+
+        let (asns_t, zone_t, listener) = (asns_arc.clone(), zone.clone(), self.listener);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        // Connections count against max_connections, as on HTTP
+                        let Some(connection) = load::open().await else {
+                            continue;
+                        };
+                        tokio::spawn(Self::handle_connection(stream, connection, asns_t.clone(), zone_t.clone()));
+                    }
+                    Err(e) => error!("Failed to accept DNS connection: {}", e),
+                }
+            }
+        });
+
+        let mut buf = vec![0u8; 65535];
+        loop {
+            let (len, peer) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Failed to receive DNS query: {}", e);
+                    continue;
+                }
+            };
+            let asns = asns_arc.read().unwrap_or_else(PoisonError::into_inner).clone();
+            if let Some(response) = Self::respond(&buf[..len], &asns, &zone, Some(MAX_UDP_RESPONSE)) {
+                let _ = self.socket.send_to(&response, peer).await;
+            }
+        }
+    }
+
+    // Queries of a TCP connection, each prefixed with its length
+    async fn handle_connection(
+        mut stream: TcpStream,
+        _connection: Arc<Connection>,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        zone: Arc<str>,
+    ) {
+        loop {
+            let mut len = [0u8; 2];
+            match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut len)).await {
+                Ok(Ok(_)) => {}
+                _ => return,
+            }
+            let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+            match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_exact(&mut query)).await {
+                Ok(Ok(_)) => {}
+                _ => return,
+            }
+            let asns = asns_arc.read().unwrap_or_else(PoisonError::into_inner).clone();
+            let Some(response) = Self::respond(&query, &asns, &zone, None) else {
+                return;
+            };
+            let mut out = Vec::with_capacity(response.len() + 2);
+            out.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            out.extend_from_slice(&response);
+            if stream.write_all(&out).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Response to a query message, None for messages too broken to answer
+    fn respond(query: &[u8], asns: &Asns, zone: &str, max_len: Option<usize>) -> Option<Vec<u8>> {
+        if query.len() < 12 || query[2] & 0x80 != 0 {
+            return None;
+        }
+        let opcode = (query[2] >> 3) & 0x0f;
+        let qdcount = u16::from_be_bytes([query[4], query[5]]);
+        if opcode != 0 {
+            return Some(Self::message(query, None, RCODE_NOTIMP, &[]));
+        }
+        if qdcount != 1 {
+            return Some(Self::message(query, None, RCODE_FORMERR, &[]));
+        }
+        let Some((name, question_end)) = Self::parse_name(query, 12) else {
+            return Some(Self::message(query, None, RCODE_FORMERR, &[]));
+        };
+        let Some(fixed) = query.get(question_end..question_end + 4) else {
+            return Some(Self::message(query, None, RCODE_FORMERR, &[]));
+        };
+        let question = &query[12..question_end + 4];
+        let qtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let qclass = u16::from_be_bytes([fixed[2], fixed[3]]);
+
+        let (rcode, text) = match Self::answer(asns, &name, zone) {
+            Ok(text) => (0, text),
+            Err(rcode) => (rcode, None),
+        };
+        // Names without a TXT record exist without data for other types
+        let text =
+            text.filter(|_| (qtype == TYPE_TXT || qtype == TYPE_ANY) && (qclass == CLASS_IN || qclass == CLASS_ANY));
+        let mut response =
+            Self::message(query, Some(question), rcode, text.as_deref().map_or(&[][..], |text| text.as_bytes()));
+        // Not authoritative for names outside of the zone
+        if rcode == RCODE_REFUSED {
+            response[2] &= !0x04;
+        }
+        match max_len {
+            Some(max_len) if response.len() > max_len => {
+                let mut truncated = Self::message(query, Some(question), rcode, &[]);
+                truncated[2] |= 0x02;
+                Some(truncated)
+            }
+            _ => Some(response),
+        }
+    }
+
+    // TXT text of a lowercased query name, or the rcode of a failure. Names
+    // of the zone without an answer exist, but have no record.
+    fn answer(asns: &Asns, name: &str, zone: &str) -> Result<Option<String>, u8> {
+        if name == zone {
+            return Ok(None);
+        }
+        let Some(rest) = name.strip_suffix(zone).and_then(|rest| rest.strip_suffix('.')) else {
+            return Err(RCODE_REFUSED);
+        };
+        if rest == "origin" || rest == "origin6" {
+            return Ok(None);
+        }
+        if let Some(number) = rest.strip_prefix("as").and_then(|number| u32::from_str(number).ok()) {
+            let (country, description) = asns.lookup_meta_by_asn(number).ok_or(RCODE_NXDOMAIN)?;
+            return Ok(Some(format!("{} | {} |  |  | {}", number, country, description)));
+        }
+        let ip = match (rest.strip_suffix(".origin"), rest.strip_suffix(".origin6")) {
+            (Some(reversed), _) if reversed.split('.').count() == 4 => {
+                dnsbl::parse_query(reversed).filter(|ip| ip.is_ipv4())
+            }
+            (_, Some(reversed)) if reversed.split('.').count() == 32 => {
+                dnsbl::parse_query(reversed).filter(|ip| ip.is_ipv6())
+            }
+            _ => None,
+        };
+        let ip = ip.ok_or(RCODE_NXDOMAIN)?;
+        let found = asns.lookup_by_ip(ip).ok_or(RCODE_NXDOMAIN)?;
+        let prefix = cidr::containing_prefix(found.first_ip, found.last_ip, ip).unwrap_or_default();
+        Ok(Some(format!("{} | {} | {} |  | ", found.number, prefix, found.country)))
+    }
+
+    // Lowercased dotted name at `offset`, and the offset following it.
+    // Questions don't use compression.
+    fn parse_name(message: &[u8], mut offset: usize) -> Option<(String, usize)> {
+        let mut labels: Vec<String> = Vec::new();
+        loop {
+            let len = *message.get(offset)? as usize;
+            offset += 1;
+            if len == 0 {
+                break;
+            }
+            if len > 63 {
+                return None;
+            }
+            let label = message.get(offset..offset + len)?;
+            labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+            offset += len;
+        }
+        Some((labels.join("."), offset))
+    }
+
+    // Response to `query`, with its question and a TXT answer for a
+    // non-empty `text`
+    fn message(query: &[u8], question: Option<&[u8]>, rcode: u8, text: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(512);
+        out.extend_from_slice(&query[..2]);
+        // QR and AA, with the opcode and RD bit of the query
+        out.push(0x80 | (query[2] & 0x79) | 0x04);
+        out.push(rcode);
+        let answers = u16::from(question.is_some() && !text.is_empty());
+        out.extend_from_slice(&u16::from(question.is_some()).to_be_bytes());
+        out.extend_from_slice(&answers.to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 0]);
+        let Some(question) = question else {
+            return out;
+        };
+        out.extend_from_slice(question);
+        if answers == 0 {
+            return out;
+        }
+        // Name of the question, by a pointer to it
+        out.extend_from_slice(&[0xc0, 12]);
+        out.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&TTL.to_be_bytes());
+        // Character strings of at most 255 bytes each
+        let strings: Vec<&[u8]> = text.chunks(255).collect();
+        out.extend_from_slice(&((text.len() + strings.len()) as u16).to_be_bytes());
+        for string in strings {
+            out.push(string.len() as u8);
+            out.extend_from_slice(string);
+        }
+        out
+    }
+}
+
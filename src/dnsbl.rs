@@ -0,0 +1,118 @@
+use crate::asns::Asns;
+use crate::config::DnsblConfig;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::OnceLock;
+
+// Categories of the DNSBL encoding, from the [[dnsbl]] tables of the config
+// file (set_categories)
+static CATEGORIES: OnceLock<Vec<Category>> = OnceLock::new();
+
+struct Category {
+    name: String,
+    code: u8,
+    asns: HashSet<u32>,
+    countries: HashSet<String>,
+}
+
+// Categories an address matched, and their codes ORed together
+pub struct Verdict {
+    pub code: u8,
+    pub categories: Vec<&'static str>,
+}
+
+// Set the categories. Each needs its own bit of the last octet, 127.0.0.1
+// excluded as it is the usual test entry of DNSBL zones. Must be called
+// before the server is started.
+pub fn set_categories(categories: Vec<DnsblConfig>) -> Result<(), String> {
+    let mut used = 0u8;
+    let mut list = Vec::with_capacity(categories.len());
+    for category in categories {
+        if category.code < 2 || !category.code.is_power_of_two() {
+            return Err(format!(
+                "Invalid code {} of DNSBL category \"{}\", expected one of 2, 4, 8, 16, 32, 64 or 128",
+                category.code, category.name
+            ));
+        }
+        if used & category.code != 0 {
+            return Err(format!(
+                "Code {} of DNSBL category \"{}\" is already used by another category",
+                category.code, category.name
+            ));
+        }
+        used |= category.code;
+        list.push(Category {
+            name: category.name,
+            code: category.code,
+            asns: category.asns.into_iter().collect(),
+            countries: category
+                .countries
+                .into_iter()
+                .map(|cc| cc.trim().to_ascii_uppercase())
+                .collect(),
+        });
+    }
+    let _ = CATEGORIES.set(list);
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    CATEGORIES.get().is_some_and(|categories| !categories.is_empty())
+}
+
+// Address of a DNSBL query name: the reversed octets of an IPv4 address, or
+// the reversed nibbles of an IPv6 address, optionally followed by the zone
+// (4.3.2.1.asn.example.com for 1.2.3.4). Nibbles are tried first, as the
+// first four of them can also read as IPv4 octets.
+pub fn parse_query(query: &str) -> Option<IpAddr> {
+    let labels: Vec<&str> = query.trim_end_matches('.').split('.').collect();
+    let nibbles: Option<Vec<u32>> = labels
+        .iter()
+        .take(32)
+        .map(|label| {
+            let mut chars = label.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c.to_digit(16),
+                _ => None,
+            }
+        })
+        .collect();
+    if let Some(nibbles) = nibbles.filter(|nibbles| nibbles.len() == 32) {
+        let address = nibbles.iter().rev().fold(0u128, |address, &nibble| address << 4 | nibble as u128);
+        return Some(IpAddr::V6(Ipv6Addr::from(address)));
+    }
+    let octets: Option<Vec<u8>> = labels
+        .iter()
+        .take(4)
+        .map(|label| {
+            let digits = !label.is_empty() && label.len() <= 3 && label.bytes().all(|b| b.is_ascii_digit());
+            digits.then(|| label.parse().ok()).flatten()
+        })
+        .collect();
+    let octets = octets.filter(|octets| octets.len() == 4)?;
+    Some(IpAddr::V4(Ipv4Addr::new(octets[3], octets[2], octets[1], octets[0])))
+}
+
+// Categories matching the announced range of an address. Unannounced
+// addresses match none.
+pub fn check(asns: &Asns, ip: IpAddr) -> Verdict {
+    let mut verdict = Verdict {
+        code: 0,
+        categories: Vec::new(),
+    };
+    let (Some(categories), Some(found)) = (CATEGORIES.get(), asns.lookup_by_ip(ip)) else {
+        return verdict;
+    };
+    for category in categories {
+        if category.asns.contains(&found.number) || category.countries.contains(found.country.as_ref()) {
+            verdict.code |= category.code;
+            verdict.categories.push(&category.name);
+        }
+    }
+    verdict
+}
+
+// A record answering a query, None (NXDOMAIN) when nothing matched
+pub fn answer(code: u8) -> Option<Ipv4Addr> {
+    (code != 0).then_some(Ipv4Addr::new(127, 0, 0, code))
+}
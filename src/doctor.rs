@@ -0,0 +1,198 @@
+use crate::config::Config;
+use crate::net::Listen;
+use crate::{country, deprecation, dnsbl, load};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+// Startup self-test used by `iptoasn-webservice --check-config` and `iptoasn doctor`,
+// making deployment failures diagnosable before the service goes live.
+#[derive(Default)]
+pub struct Doctor {
+    results: Vec<(bool, String, String)>,
+}
+
+impl Doctor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn report(&mut self, ok: bool, name: impl Into<String>, detail: impl Into<String>) {
+        self.results.push((ok, name.into(), detail.into()));
+    }
+
+    pub fn check_listen(&mut self, listen: &Result<Listen, String>) {
+        match listen {
+            Ok(listen) => self.report(true, "listen address", listen.to_string()),
+            Err(e) => self.report(false, "listen address", e.clone()),
+        }
+    }
+
+    // Check that the config file of the server parses, and that its tables
+    // pass the validation done at startup, including the [country_codes]
+    // overrides. Most of it is done by the setters of the tables, which is
+    // fine as the process exits after the report.
+    pub fn check_config(&mut self, path: &Path, config: Result<Config, String>) {
+        let name = format!("config file {}", path.display());
+        let config = match config {
+            Ok(config) => config,
+            Err(e) => return self.report(false, name, e.trim_end()),
+        };
+        let detail = format!(
+            "{} hosts, {} tenants, {} country code overrides",
+            config.hosts.len(),
+            config.tenants.len(),
+            config.country_codes.len()
+        );
+        let errors: Vec<String> = [
+            country::check_corrections(&config.country_codes),
+            load::set_limits(config.load_shedding),
+            dnsbl::set_categories(config.dnsbl),
+            deprecation::set_deprecations(config.deprecations, config.disable_deprecated),
+        ]
+        .into_iter()
+        .filter_map(Result::err)
+        .collect();
+        if errors.is_empty() {
+            self.report(true, name, detail);
+        } else {
+            self.report(false, name, errors.join("; "));
+        }
+    }
+
+    // Report on the config file of the CLI and its selected profile, which
+    // override the default options
+    pub fn check_cli_config(&mut self, path: &Path, loaded: Result<(), String>) {
+        let name = format!("config file {}", path.display());
+        match loaded {
+            Ok(()) if path.exists() => self.report(true, name, "parsed"),
+            Ok(()) => self.report(true, name, "not found, using the defaults"),
+            Err(e) => self.report(false, name, e.trim_end()),
+        }
+    }
+
+    // Check that a database (or server) URL is usable: file:// URLs must point
+    // to a readable file, HTTP(S) hostnames must resolve, and with `head` the
+    // URL must answer a HEAD request successfully.
+    pub async fn check_url(
+        &mut self,
+        name: &str,
+        url: &str,
+        http_client: Option<&reqwest::Client>,
+        head: bool,
+    ) {
+        let name = format!("{} {}", name, url);
+
+        if let Some(path) = url.strip_prefix("file://") {
+            match fs::metadata(path) {
+                Ok(meta) if meta.is_file() => {
+                    self.report(true, name, format!("readable file, {} bytes", meta.len()))
+                }
+                Ok(_) => self.report(false, name, "not a regular file"),
+                Err(e) => self.report(false, name, e.to_string()),
+            }
+            return;
+        }
+
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            self.report(false, name, "unsupported URL scheme");
+            return;
+        }
+        let parsed = match reqwest::Url::parse(url) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.report(false, name, format!("invalid URL: {}", e));
+                return;
+            }
+        };
+        let host = parsed.host_str().unwrap_or("");
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        match tokio::net::lookup_host((host, port)).await {
+            Ok(addrs) => {
+                let addrs: Vec<String> = addrs.map(|a| a.ip().to_string()).collect();
+                if addrs.is_empty() {
+                    self.report(false, name, format!("{} did not resolve", host));
+                    return;
+                }
+                if !head {
+                    self.report(true, name, format!("{} resolves to {}", host, addrs.join(", ")));
+                    return;
+                }
+            }
+            Err(e) => {
+                self.report(false, name, format!("unable to resolve {}: {}", host, e));
+                return;
+            }
+        }
+
+        let client;
+        let client_ref = if let Some(provided_client) = http_client {
+            provided_client
+        } else {
+            client = reqwest::Client::new();
+            &client
+        };
+        match client_ref
+            .head(url)
+            .header(
+                "User-Agent",
+                concat!("iptoasn-webservice/", env!("CARGO_PKG_VERSION")),
+            )
+            .send()
+            .await
+        {
+            Ok(res) if res.status().is_success() => {
+                let size = res
+                    .content_length()
+                    .map(|l| format!(", {} bytes", l))
+                    .unwrap_or_default();
+                self.report(true, name, format!("HEAD {}{}", res.status(), size))
+            }
+            Ok(res) => self.report(false, name, format!("HEAD {}", res.status())),
+            Err(e) => self.report(false, name, format!("HEAD failed: {}", e)),
+        }
+    }
+
+    // Check that the cache file can be written: its directory must exist (or
+    // be creatable) and be writable.
+    pub fn check_cache_file(&mut self, cache_file: &Path) {
+        let name = format!("cache file {}", cache_file.display());
+        let dir = match cache_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if let Err(e) = fs::create_dir_all(dir) {
+            self.report(false, name, format!("unable to create {}: {}", dir.display(), e));
+            return;
+        }
+        let probe = dir.join(".iptoasn-check");
+        let writable = fs::File::create(&probe).and_then(|mut f| f.write_all(b"ok"));
+        let _ = fs::remove_file(&probe);
+        if let Err(e) = writable {
+            self.report(false, name, format!("{} is not writable: {}", dir.display(), e));
+            return;
+        }
+        match fs::metadata(cache_file) {
+            Ok(meta) => self.report(
+                true,
+                name,
+                format!("directory writable, existing cache of {} bytes", meta.len()),
+            ),
+            Err(_) => self.report(true, name, "directory writable, no cache yet"),
+        }
+    }
+
+    // Print the report and return whether all checks passed.
+    pub fn print_report(&self) -> bool {
+        for (ok, name, detail) in &self.results {
+            println!("[{}] {}: {}", if *ok { " OK " } else { "FAIL" }, name, detail);
+        }
+        let failed = self.results.iter().filter(|(ok, _, _)| !ok).count();
+        if failed == 0 {
+            println!("All {} checks passed", self.results.len());
+        } else {
+            println!("{} of {} checks failed", failed, self.results.len());
+        }
+        failed == 0
+    }
+}
@@ -0,0 +1,101 @@
+use dnsclient::r#async::DNSClient;
+use dnsclient::UpstreamServer;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+// How hostnames are resolved: by the system resolver, or by querying the
+// given DNS servers directly
+pub enum Resolver {
+    System,
+    Servers(DNSClient),
+}
+
+impl Resolver {
+    // DNS servers given as ip or ip:port, port 53 by default
+    pub fn servers(addrs: &[String]) -> Result<Self, String> {
+        let servers = addrs
+            .iter()
+            .map(|addr| {
+                addr.parse::<SocketAddr>()
+                    .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                    .map(UpstreamServer::new)
+                    .map_err(|_| format!("Invalid resolver address \"{}\", expected ip or ip:port", addr))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Resolver::Servers(DNSClient::new(servers)))
+    }
+
+    // IPv4 and IPv6 addresses of a hostname, sorted
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        let mut ips: Vec<IpAddr> = match self {
+            Resolver::System => tokio::net::lookup_host((host, 0))
+                .await
+                .map_err(|e| e.to_string())?
+                .map(|addr| addr.ip())
+                .collect(),
+            Resolver::Servers(client) => client.query_addrs(host).await.map_err(|e| e.to_string())?,
+        };
+        ips.sort();
+        ips.dedup();
+        if ips.is_empty() {
+            return Err("no address".to_string());
+        }
+        Ok(ips)
+    }
+}
+
+// Hostname of a line of a domains list. Blank lines and # comments are
+// skipped, URLs are reduced to their host, and defanged names such as
+// hxxps://example[.]com are restored.
+pub fn parse_host(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let line = line.replace("[.]", ".").replace("(.)", ".").replace("hxxp", "http");
+    let rest = line.split_once("://").map_or(line.as_str(), |(_, rest)| rest);
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host_port = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    let host = match host_port.strip_prefix('[') {
+        // [IPv6 address]:port
+        Some(bracketed) => bracketed.split(']').next().unwrap_or(""),
+        None if host_port.matches(':').count() == 1 => host_port.split(':').next().unwrap_or(""),
+        None => host_port,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    (!host.is_empty()).then_some(host)
+}
+
+// Resolve hostnames, at most `concurrency` at a time, in input order
+pub async fn resolve_all(
+    hosts: Vec<String>,
+    resolver: Arc<Resolver>,
+    concurrency: usize,
+) -> Vec<(String, Result<Vec<IpAddr>, String>)> {
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, host) in hosts.iter().cloned().enumerate() {
+        let semaphore = semaphore.clone();
+        let resolver = resolver.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            (index, resolver.resolve(&host).await)
+        });
+    }
+    let mut results = vec![None; hosts.len()];
+    while let Some(joined) = tasks.join_next().await {
+        if let Ok((index, result)) = joined {
+            results[index] = Some(result);
+        }
+    }
+    hosts
+        .into_iter()
+        .zip(results)
+        .map(|(host, result)| (host, result.unwrap_or_else(|| Err("resolution failed".to_string()))))
+        .collect()
+}
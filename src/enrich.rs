@@ -0,0 +1,56 @@
+use crate::webservice::IpLookupResponse;
+use serde_json::{Map, Value};
+use std::sync::OnceLock;
+
+// Fields of IP lookup results and annotated log lines, which enrichers can't
+// replace
+const RESERVED_FIELDS: &[&str] = &[
+    "ip",
+    "announced",
+    "covered",
+    "first_ip",
+    "last_ip",
+    "as_number",
+    "as_country_code",
+    "as_description",
+    "warnings",
+    "id",
+    "index",
+    "line",
+    "format",
+];
+
+// Hooks appending organization-specific fields to IP lookup results
+// (set_enrichers)
+static ENRICHERS: OnceLock<Vec<Box<dyn Enricher>>> = OnceLock::new();
+
+// Source of extra fields for IP lookup results, e.g. internal tags or threat
+// scores. The fields are added to the JSON, NDJSON and MessagePack responses
+// and to the HTML page of every looked up address.
+pub trait Enricher: Send + Sync {
+    // Fields to add to a lookup result. Lookups are served synchronously, so
+    // this must not block on I/O.
+    fn enrich(&self, response: &IpLookupResponse) -> Map<String, Value>;
+}
+
+// Register the enrichers, applied in order: a field set by an enricher is
+// kept over the same field of the following ones. Must be called before the
+// server is started.
+pub fn set_enrichers(enrichers: Vec<Box<dyn Enricher>>) {
+    let _ = ENRICHERS.set(enrichers);
+}
+
+// Extra fields of a lookup result, from all the enrichers
+pub(crate) fn extra_fields(response: &IpLookupResponse) -> Map<String, Value> {
+    let mut extra = Map::new();
+    for enricher in ENRICHERS.get().map_or(&[][..], Vec::as_slice) {
+        for (name, value) in enricher.enrich(response) {
+            if RESERVED_FIELDS.contains(&name.as_str()) {
+                log::debug!("Ignoring enrichment field {:?}, which is a lookup field", name);
+                continue;
+            }
+            extra.entry(name).or_insert(value);
+        }
+    }
+    extra
+}
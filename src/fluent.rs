@@ -0,0 +1,393 @@
+use crate::asns::Asns;
+use flate2::read::GzDecoder;
+use log::{debug, error, info, warn};
+use rmpv::Value;
+use std::io::{Cursor, Read};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::Mutex;
+
+type Upstream = Mutex<Option<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+// Fluent forward protocol relay: Fluent Bit/Fluentd forward events to this
+// listener, the records get enriched with AS information and are forwarded
+// to another forward input (e.g. back to Fluent Bit) in Forward mode.
+//
+// Addresses are either host:port or, on Unix, unix:/path/to/socket.
+pub struct Fluent;
+
+impl Fluent {
+    const READ_BUFFER_SIZE: usize = 64 * 1024;
+    // Largest message accepted, well above the default chunk sizes of Fluent
+    // Bit (2 MiB) and Fluentd (8 MiB)
+    const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+    // Largest decompressed CompressedPackedForward payload
+    const MAX_DECODED_SIZE: usize = 128 * 1024 * 1024;
+
+    pub async fn start(
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        listen_addr: String,
+        forward_addr: String,
+        ip_key: String,
+    ) {
+        let upstream: Arc<Upstream> = Arc::new(Mutex::new(None));
+        let forward_addr: Arc<str> = Arc::from(forward_addr);
+        let ip_key: Arc<str> = Arc::from(ip_key);
+
+        #[cfg(not(unix))]
+        if listen_addr.starts_with("unix:") {
+            error!("Unix sockets are not supported on this platform: {}", listen_addr);
+            return;
+        }
+        #[cfg(unix)]
+        if let Some(path) = listen_addr.strip_prefix("unix:") {
+            let _ = std::fs::remove_file(path);
+            let listener = match UnixListener::bind(path) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("Failed to bind fluent listener to {}: {}", listen_addr, e);
+                    return;
+                }
+            };
+            info!("fluent forward listener ready on {}", listen_addr);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(Self::handle_connection(
+                            stream,
+                            asns_arc.clone(),
+                            upstream.clone(),
+                            forward_addr.clone(),
+                            ip_key.clone(),
+                        ));
+                    }
+                    Err(e) => error!("Failed to accept fluent connection: {}", e),
+                }
+            }
+        }
+        let listener = match TcpListener::bind(&listen_addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind fluent listener to {}: {}", listen_addr, e);
+                return;
+            }
+        };
+        info!("fluent forward listener ready on {}", listen_addr);
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(Self::handle_connection(
+                        stream,
+                        asns_arc.clone(),
+                        upstream.clone(),
+                        forward_addr.clone(),
+                        ip_key.clone(),
+                    ));
+                }
+                Err(e) => error!("Failed to accept fluent connection: {}", e),
+            }
+        }
+    }
+
+    async fn handle_connection<S>(
+        mut stream: S,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        upstream: Arc<Upstream>,
+        forward_addr: Arc<str>,
+        ip_key: Arc<str>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut chunk = vec![0u8; Self::READ_BUFFER_SIZE];
+        // Start of the message being framed, and how far it was scanned
+        let mut start = 0;
+        let mut framer = Framer::new();
+
+        loop {
+            // Decode as many complete messages as are buffered. Each byte is
+            // only scanned once, however many reads a message spans.
+            loop {
+                let len = match framer.advance(&buf[start..], Self::MAX_MESSAGE_SIZE) {
+                    Ok(Some(len)) => len,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Invalid fluent message, closing connection: {}", e);
+                        return;
+                    }
+                };
+                let mut cursor = Cursor::new(&buf[start..start + len]);
+                let message = match rmpv::decode::read_value(&mut cursor) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Invalid fluent message, closing connection: {}", e);
+                        return;
+                    }
+                };
+                start += len;
+                framer = Framer::new();
+
+                let asns = asns_arc
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .clone();
+                let (tag, entries, chunk_id) = match Self::parse_message(message) {
+                    Some(parsed) => parsed,
+                    None => {
+                        warn!("Unsupported fluent message, closing connection");
+                        return;
+                    }
+                };
+                let entries: Vec<Value> = entries
+                    .into_iter()
+                    .map(|entry| Self::enrich_entry(entry, &asns, &ip_key))
+                    .collect();
+
+                let forwarded = Self::forward(&upstream, &forward_addr, tag, entries).await;
+
+                // Only acknowledge chunks that could be forwarded, so that the
+                // sender retries the others.
+                if let (true, Some(chunk_id)) = (forwarded, chunk_id) {
+                    let ack = Value::Map(vec![(Value::from("ack"), chunk_id)]);
+                    let mut out = Vec::new();
+                    rmpv::encode::write_value(&mut out, &ack).unwrap();
+                    if stream.write_all(&out).await.is_err() {
+                        return;
+                    }
+                }
+            }
+
+            // Only the incomplete message is kept, moved once
+            if start > 0 {
+                buf.drain(..start);
+                start = 0;
+            }
+            match stream.read(&mut chunk).await {
+                Ok(0) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(e) => {
+                    debug!("Failed to read from fluent connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Returns the tag, the [time, record] entries and the chunk id to acknowledge
+    // of a Message, Forward, PackedForward or CompressedPackedForward mode message.
+    fn parse_message(message: Value) -> Option<(Value, Vec<Value>, Option<Value>)> {
+        let mut items = match message {
+            Value::Array(items) if items.len() >= 2 => items.into_iter(),
+            _ => return None,
+        };
+        let tag = items.next()?;
+        let second = items.next()?;
+
+        let (entries, option) = match second {
+            // Message mode: [tag, time, record, option]
+            Value::Integer(_) | Value::Ext(..) => {
+                let record = items.next()?;
+                (vec![Value::Array(vec![second, record])], items.next())
+            }
+            // Forward mode: [tag, [[time, record], ...], option]
+            Value::Array(entries) => (entries, items.next()),
+            // PackedForward mode: [tag, msgpack stream of entries, option]
+            // (older Fluentd versions send the stream as str instead of bin)
+            Value::Binary(packed) => {
+                let option = items.next();
+                (Self::unpack_entries(packed, option.as_ref())?, option)
+            }
+            Value::String(packed) => {
+                let option = items.next();
+                (Self::unpack_entries(packed.into_bytes(), option.as_ref())?, option)
+            }
+            _ => return None,
+        };
+
+        let chunk_id = Self::option_value(option.as_ref(), "chunk").cloned();
+        Some((tag, entries, chunk_id))
+    }
+
+    // Decode the entries of a (Compressed)PackedForward mode message
+    fn unpack_entries(packed: Vec<u8>, option: Option<&Value>) -> Option<Vec<Value>> {
+        let packed = if Self::option_value(option, "compressed").and_then(Value::as_str) == Some("gzip") {
+            let mut data = Vec::new();
+            GzDecoder::new(packed.as_slice())
+                .take(Self::MAX_DECODED_SIZE as u64 + 1)
+                .read_to_end(&mut data)
+                .ok()?;
+            if data.len() > Self::MAX_DECODED_SIZE {
+                warn!("Compressed fluent chunk larger than {} bytes once decoded", Self::MAX_DECODED_SIZE);
+                return None;
+            }
+            data
+        } else {
+            packed
+        };
+        let mut cursor = Cursor::new(packed.as_slice());
+        let mut entries = Vec::new();
+        while (cursor.position() as usize) < packed.len() {
+            entries.push(rmpv::decode::read_value(&mut cursor).ok()?);
+        }
+        Some(entries)
+    }
+
+    fn option_value<'a>(option: Option<&'a Value>, key: &str) -> Option<&'a Value> {
+        option?
+            .as_map()?
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(key))
+            .map(|(_, v)| v)
+    }
+
+    // Add as_number, as_country_code and as_description to a [time, record] entry
+    // if the record contains a valid IP address under `ip_key`.
+    fn enrich_entry(mut entry: Value, asns: &Asns, ip_key: &str) -> Value {
+        let record = match &mut entry {
+            Value::Array(e) => match e.get_mut(1) {
+                Some(Value::Map(record)) => record,
+                _ => return entry,
+            },
+            _ => return entry,
+        };
+        let ip = record
+            .iter()
+            .find(|(k, _)| k.as_str() == Some(ip_key))
+            .and_then(|(_, v)| v.as_str())
+            .and_then(|s| IpAddr::from_str(s.trim()).ok());
+        let Some(ip) = ip else {
+            return entry;
+        };
+
+        let (number, country, description) = match asns.lookup_by_ip(ip) {
+            Some(found) => (
+                found.number,
+                found.country.to_string(),
+                found.description.to_string(),
+            ),
+            None => (0, "None".to_string(), "Not announced".to_string()),
+        };
+        record.push((Value::from("as_number"), Value::from(number)));
+        record.push((Value::from("as_country_code"), Value::from(country)));
+        record.push((Value::from("as_description"), Value::from(description)));
+        entry
+    }
+
+    async fn connect(forward_addr: &str) -> std::io::Result<Box<dyn AsyncWrite + Send + Unpin>> {
+        match forward_addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+            None => Ok(Box::new(TcpStream::connect(forward_addr).await?)),
+        }
+    }
+
+    // Send entries to the forward destination in Forward mode, reconnecting
+    // once if the existing connection went away.
+    async fn forward(upstream: &Upstream, forward_addr: &str, tag: Value, entries: Vec<Value>) -> bool {
+        let message = Value::Array(vec![tag, Value::Array(entries)]);
+        let mut out = Vec::new();
+        rmpv::encode::write_value(&mut out, &message).unwrap();
+
+        let mut upstream = upstream.lock().await;
+        for _ in 0..2 {
+            if upstream.is_none() {
+                match Self::connect(forward_addr).await {
+                    Ok(conn) => *upstream = Some(conn),
+                    Err(e) => {
+                        warn!("Failed to connect to fluent destination {}: {}", forward_addr, e);
+                        return false;
+                    }
+                }
+            }
+            let conn = upstream.as_mut().unwrap();
+            match conn.write_all(&out).await {
+                Ok(()) => return true,
+                Err(e) => {
+                    warn!("Failed to forward events to {}: {}", forward_addr, e);
+                    *upstream = None;
+                }
+            }
+        }
+        false
+    }
+}
+
+// Incremental scanner finding the end of a MessagePack value, resuming where
+// the previous call stopped as more of the value arrives
+struct Framer {
+    // Offset of the next header to scan, possibly past the buffered bytes
+    // while a string or binary payload is incomplete
+    pos: usize,
+    // Values still to scan, including the elements of open arrays and maps
+    remaining: u64,
+}
+
+impl Framer {
+    fn new() -> Self {
+        Framer { pos: 0, remaining: 1 }
+    }
+
+    // Length of the value at the start of `buf` once complete, None while
+    // more bytes are needed
+    fn advance(&mut self, buf: &[u8], max_len: usize) -> Result<Option<usize>, String> {
+        while self.remaining > 0 {
+            let Some(&marker) = buf.get(self.pos) else {
+                return Ok(None);
+            };
+            // Size of the length or count field, and of the fixed payload
+            let (field, fixed): (usize, usize) = match marker {
+                // Fixed ints, maps, arrays and strings, nil and booleans
+                0x00..=0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (0, 0),
+                0xc4 | 0xd9 => (1, 0),
+                0xc5 | 0xda | 0xdc | 0xde => (2, 0),
+                0xc6 | 0xdb | 0xdd | 0xdf => (4, 0),
+                0xc7 => (1, 1),
+                0xc8 => (2, 1),
+                0xc9 => (4, 1),
+                0xca => (0, 4),
+                0xcb => (0, 8),
+                0xcc | 0xd0 => (0, 1),
+                0xcd | 0xd1 => (0, 2),
+                0xce | 0xd2 => (0, 4),
+                0xcf | 0xd3 => (0, 8),
+                0xd4 => (0, 2),
+                0xd5 => (0, 3),
+                0xd6 => (0, 5),
+                0xd7 => (0, 9),
+                0xd8 => (0, 17),
+                0xc1 => return Err("reserved marker 0xc1".to_string()),
+            };
+            let Some(bytes) = buf.get(self.pos + 1..self.pos + 1 + field) else {
+                return Ok(None);
+            };
+            let n = bytes.iter().fold(0u64, |n, &b| (n << 8) | u64::from(b));
+            let (payload, children) = match marker {
+                0x80..=0x8f => (0, u64::from(marker & 0x0f) * 2),
+                0x90..=0x9f => (0, u64::from(marker & 0x0f)),
+                0xa0..=0xbf => (u64::from(marker & 0x1f), 0),
+                0xdc | 0xdd => (0, n),
+                0xde | 0xdf => (0, n * 2),
+                _ => (n, 0),
+            };
+            let end = (self.pos as u64 + 1 + field as u64 + fixed as u64).saturating_add(payload);
+            // Every value takes at least a byte, so the count of values
+            // bounds the length too
+            if end.saturating_add(self.remaining - 1 + children) > max_len as u64 {
+                return Err(format!("message larger than {} bytes", max_len));
+            }
+            self.pos = end as usize;
+            self.remaining = self.remaining - 1 + children;
+        }
+        Ok((self.pos <= buf.len()).then_some(self.pos))
+    }
+}
@@ -0,0 +1,268 @@
+use crate::asn_registry;
+use crate::asns::Asns;
+use crate::auth;
+use crate::cidr::{self, SubnetOrder};
+use log::{error, info};
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use tonic::codegen::tokio_stream::{Stream, StreamExt};
+use tonic::codegen::{http, Body, BoxFuture, Service, StdError};
+use tonic::server::{Grpc, NamedService, StreamingService, UnaryService};
+use tonic::{Request, Response, Status, Streaming};
+use tonic_prost::ProstCodec;
+
+// Messages of proto/iptoasn.proto, kept in sync by hand so that building
+// doesn't require protoc
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct IpRequest {
+    #[prost(string, tag = "1")]
+    pub ip: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct IpResponse {
+    #[prost(string, tag = "1")]
+    pub ip: String,
+    #[prost(bool, tag = "2")]
+    pub announced: bool,
+    #[prost(string, tag = "3")]
+    pub first_ip: String,
+    #[prost(string, tag = "4")]
+    pub last_ip: String,
+    #[prost(uint32, tag = "5")]
+    pub as_number: u32,
+    #[prost(string, tag = "6")]
+    pub as_country_code: String,
+    #[prost(string, tag = "7")]
+    pub as_description: String,
+    // Set instead of the other fields for an invalid address of a batch
+    #[prost(string, tag = "8")]
+    pub error: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AsnRequest {
+    #[prost(uint32, tag = "1")]
+    pub as_number: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AsnResponse {
+    #[prost(uint32, tag = "1")]
+    pub as_number: u32,
+    // Whether the AS number is in the database, as opposed to a
+    // special-purpose or unknown one
+    #[prost(bool, tag = "2")]
+    pub found: bool,
+    #[prost(string, tag = "3")]
+    pub as_country_code: String,
+    #[prost(string, tag = "4")]
+    pub as_description: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubnetsResponse {
+    #[prost(uint32, tag = "1")]
+    pub as_number: u32,
+    #[prost(string, repeated, tag = "2")]
+    pub subnets: Vec<String>,
+}
+
+type IpResponseStream = Pin<Box<dyn Stream<Item = Result<IpResponse, Status>> + Send>>;
+
+// gRPC counterpart of the lookup routes, service iptoasn.v1.Lookup, for
+// internal services that prefer protobuf over JSON. It shares the database
+// of the web service, and the API keys of its non-public routes.
+#[derive(Clone)]
+pub struct GrpcService {
+    asns_arc: Arc<RwLock<Arc<Asns>>>,
+}
+
+impl GrpcService {
+    pub fn new(asns_arc: Arc<RwLock<Arc<Asns>>>) -> Self {
+        GrpcService { asns_arc }
+    }
+
+    pub async fn start(asns_arc: Arc<RwLock<Arc<Asns>>>, listen_addr: SocketAddr) {
+        info!("gRPC server ready on {}", listen_addr);
+        if let Err(e) = tonic::transport::Server::builder()
+            .add_service(GrpcService::new(asns_arc))
+            .serve(listen_addr)
+            .await
+        {
+            error!("gRPC server on {} failed: {}", listen_addr, e);
+        }
+    }
+
+    fn current_asns(&self) -> Arc<Asns> {
+        self.asns_arc
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn lookup_ip(asns: &Asns, ip_s: &str) -> Result<IpResponse, Status> {
+        let ip = IpAddr::from_str(ip_s.trim())
+            .map_err(|_| Status::invalid_argument(format!("Invalid IP address [{}]", ip_s)))?;
+        Ok(match asns.lookup_by_ip(ip) {
+            Some(found) => IpResponse {
+                ip: ip.to_string(),
+                announced: true,
+                first_ip: found.first_ip.to_string(),
+                last_ip: found.last_ip.to_string(),
+                as_number: found.number,
+                as_country_code: found.country.to_string(),
+                as_description: found.description.to_string(),
+                error: String::new(),
+            },
+            None => IpResponse {
+                ip: ip.to_string(),
+                ..Default::default()
+            },
+        })
+    }
+
+    fn lookup_asn(asns: &Asns, number: u32) -> AsnResponse {
+        match asns.lookup_meta_by_asn(number) {
+            Some((country, description)) => AsnResponse {
+                as_number: number,
+                found: true,
+                as_country_code: country.to_string(),
+                as_description: description.to_string(),
+            },
+            None => AsnResponse {
+                as_number: number,
+                found: false,
+                as_country_code: String::new(),
+                as_description: asn_registry::lookup(number)
+                    .map(|special| special.label.to_string())
+                    .unwrap_or_default(),
+            },
+        }
+    }
+
+    // Minimal CIDR prefixes of the ranges of an AS number, like
+    // /v1/as/n/<asn>/subnets. AS0 stands for all not routed space, which
+    // isn't enumerated.
+    fn list_subnets(asns: &Asns, number: u32) -> SubnetsResponse {
+        let mut subnets = Vec::new();
+        if number != 0 {
+            for (first, last) in asns.collect_ranges_by_asn(number) {
+                subnets.append(&mut cidr::range_to_cidrs(&first.to_string(), &last.to_string()));
+            }
+            cidr::sort_subnets(&mut subnets, SubnetOrder::default());
+        }
+        SubnetsResponse {
+            as_number: number,
+            subnets,
+        }
+    }
+}
+
+struct LookupIp(GrpcService);
+
+impl UnaryService<IpRequest> for LookupIp {
+    type Response = IpResponse;
+    type Future = Ready<Result<Response<IpResponse>, Status>>;
+
+    fn call(&mut self, request: Request<IpRequest>) -> Self::Future {
+        let asns = self.0.current_asns();
+        ready(GrpcService::lookup_ip(&asns, &request.into_inner().ip).map(Response::new))
+    }
+}
+
+// One response per request, in order, as they come. Invalid addresses get a
+// response with `error` set instead of ending the stream.
+struct LookupIpBatch(GrpcService);
+
+impl StreamingService<IpRequest> for LookupIpBatch {
+    type Response = IpResponse;
+    type ResponseStream = IpResponseStream;
+    type Future = Ready<Result<Response<IpResponseStream>, Status>>;
+
+    fn call(&mut self, request: Request<Streaming<IpRequest>>) -> Self::Future {
+        let service = self.0.clone();
+        let responses = request.into_inner().map(move |request| {
+            // The database may be refreshed during a long stream
+            let asns = service.current_asns();
+            let ip = request?.ip;
+            Ok(GrpcService::lookup_ip(&asns, &ip).unwrap_or_else(|status| IpResponse {
+                ip,
+                error: status.message().to_string(),
+                ..Default::default()
+            }))
+        });
+        ready(Ok(Response::new(Box::pin(responses) as IpResponseStream)))
+    }
+}
+
+struct LookupAsn(GrpcService);
+
+impl UnaryService<AsnRequest> for LookupAsn {
+    type Response = AsnResponse;
+    type Future = Ready<Result<Response<AsnResponse>, Status>>;
+
+    fn call(&mut self, request: Request<AsnRequest>) -> Self::Future {
+        let asns = self.0.current_asns();
+        ready(Ok(Response::new(GrpcService::lookup_asn(&asns, request.into_inner().as_number))))
+    }
+}
+
+struct ListSubnets(GrpcService);
+
+impl UnaryService<AsnRequest> for ListSubnets {
+    type Response = SubnetsResponse;
+    type Future = Ready<Result<Response<SubnetsResponse>, Status>>;
+
+    fn call(&mut self, request: Request<AsnRequest>) -> Self::Future {
+        let asns = self.0.current_asns();
+        ready(Ok(Response::new(GrpcService::list_subnets(&asns, request.into_inner().as_number))))
+    }
+}
+
+impl<B> Service<http::Request<B>> for GrpcService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if !auth::authorized(req.headers()) {
+            return Box::pin(ready(Ok(
+                Status::unauthenticated("Missing or invalid API key").into_http()
+            )));
+        }
+        let service = self.clone();
+        Box::pin(async move {
+            let response = match req.uri().path() {
+                "/iptoasn.v1.Lookup/LookupIp" => Grpc::new(ProstCodec::default()).unary(LookupIp(service), req).await,
+                "/iptoasn.v1.Lookup/LookupIpBatch" => {
+                    Grpc::new(ProstCodec::default()).streaming(LookupIpBatch(service), req).await
+                }
+                "/iptoasn.v1.Lookup/LookupAsn" => Grpc::new(ProstCodec::default()).unary(LookupAsn(service), req).await,
+                "/iptoasn.v1.Lookup/ListSubnets" => {
+                    Grpc::new(ProstCodec::default()).unary(ListSubnets(service), req).await
+                }
+                _ => Status::unimplemented("Unknown method").into_http(),
+            };
+            Ok(response)
+        })
+    }
+}
+
+impl NamedService for GrpcService {
+    const NAME: &'static str = "iptoasn.v1.Lookup";
+}
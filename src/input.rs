@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// What a token that is not a valid IP address looks like, so that users
+// pasting MACs, URLs or hostnames get a targeted error message.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InputKind {
+    Mac,
+    // URL, with its host (brackets removed for IPv6 literals)
+    Url(String),
+    // Address followed by a port, e.g. 192.0.2.1:443 or [2001:db8::1]:443
+    HostPort(String),
+    // CIDR prefix, with its address
+    Cidr(String),
+    Hostname,
+    Unknown,
+}
+
+impl InputKind {
+    pub fn classify(input: &str) -> InputKind {
+        let input = input.trim();
+        if Self::is_mac(input) {
+            return InputKind::Mac;
+        }
+        if let Some((scheme, rest)) = input.split_once("://") {
+            if !scheme.is_empty() && scheme.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+') {
+                let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+                let host_port = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+                return InputKind::Url(Self::strip_port(host_port).to_string());
+            }
+        }
+        if let Some((addr, len)) = input.split_once('/') {
+            if IpAddr::from_str(addr).is_ok() && len.parse::<u8>().is_ok() {
+                return InputKind::Cidr(addr.to_string());
+            }
+        }
+        let host = Self::strip_port(input);
+        if host != input && IpAddr::from_str(host).is_ok() {
+            return InputKind::HostPort(host.to_string());
+        }
+        if Self::is_hostname(host) {
+            return InputKind::Hostname;
+        }
+        InputKind::Unknown
+    }
+
+    // IP address contained in the input, if it can be extracted
+    pub fn extracted_ip(&self) -> Option<IpAddr> {
+        match self {
+            InputKind::Url(host) | InputKind::HostPort(host) | InputKind::Cidr(host) => {
+                IpAddr::from_str(host).ok()
+            }
+            _ => None,
+        }
+    }
+
+    // Error message for the input, or None if it doesn't look like anything known
+    pub fn hint(&self) -> Option<String> {
+        let hint = match self {
+            InputKind::Mac => "Looks like a MAC address; MAC addresses (and their OUI) identify hardware vendors, not networks, and can't be mapped to an AS".to_string(),
+            InputKind::Url(host) if IpAddr::from_str(host).is_ok() => {
+                format!("Looks like a URL; did you mean its host {}?", host)
+            }
+            InputKind::Url(host) => format!(
+                "Looks like a URL; did you mean its host {}? Hostnames must be resolved to an IP address first",
+                host
+            ),
+            InputKind::HostPort(host) => {
+                format!("Looks like an address with a port; did you mean {}?", host)
+            }
+            InputKind::Cidr(host) => {
+                format!("Looks like a CIDR prefix; did you mean its address {}?", host)
+            }
+            InputKind::Hostname => {
+                "Looks like a hostname; resolve it to an IP address first".to_string()
+            }
+            InputKind::Unknown => return None,
+        };
+        Some(hint)
+    }
+
+    // 00:1a:2b:3c:4d:5e, 00-1A-2B-3C-4D-5E or 001a.2b3c.4d5e
+    fn is_mac(s: &str) -> bool {
+        let hex = |part: &str, len: usize| part.len() == len && part.bytes().all(|b| b.is_ascii_hexdigit());
+        for sep in [':', '-'] {
+            let parts: Vec<&str> = s.split(sep).collect();
+            if parts.len() == 6 && parts.iter().all(|p| hex(p, 2)) {
+                return true;
+            }
+        }
+        let parts: Vec<&str> = s.split('.').collect();
+        parts.len() == 3 && parts.iter().all(|p| hex(p, 4))
+    }
+
+    fn strip_port(s: &str) -> &str {
+        if let Some(rest) = s.strip_prefix('[') {
+            return rest.split_once(']').map_or(s, |(addr, _)| addr);
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.contains(':') && port.bytes().all(|b| b.is_ascii_digit()) => host,
+            _ => s,
+        }
+    }
+
+    fn is_hostname(s: &str) -> bool {
+        let s = s.strip_suffix('.').unwrap_or(s);
+        let labels: Vec<&str> = s.split('.').collect();
+        labels.len() >= 2
+            && labels.iter().all(|l| {
+                !l.is_empty()
+                    && l.len() <= 63
+                    && !l.starts_with('-')
+                    && !l.ends_with('-')
+                    && l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            })
+            && labels
+                .last()
+                .is_some_and(|tld| tld.bytes().any(|b| b.is_ascii_alphabetic()))
+    }
+}
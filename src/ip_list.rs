@@ -0,0 +1,114 @@
+use hyper::StatusCode;
+
+// Longest line kept, well above an IPv6 address with a zone index
+pub(crate) const MAX_LINE_BYTES: usize = 256;
+
+// Streaming tokenizer of plain text bulk bodies: one IP address per line,
+// optionally wrapped by 'begin'/'end' lines. Only the current line is
+// buffered, and a body is rejected at its first overlong line or excess
+// line, so a hostile body costs no more than reading it up to that point.
+pub(crate) struct PlainIpList {
+    max_ips: usize,
+    // Non-blank lines allowed, addresses and begin/end markers alike
+    max_lines: usize,
+    // Current line, from its first non-blank byte
+    line: Vec<u8>,
+    line_number: usize,
+    lines: usize,
+    in_block: bool,
+    saw_begin: bool,
+    // Addresses accepted, including those already taken
+    accepted: usize,
+    ips: Vec<String>,
+}
+
+impl PlainIpList {
+    pub(crate) fn new(max_ips: usize) -> Self {
+        PlainIpList {
+            max_ips,
+            // Room for a begin/end pair around every address
+            max_lines: max_ips.saturating_mul(3),
+            line: Vec::with_capacity(MAX_LINE_BYTES),
+            line_number: 1,
+            lines: 0,
+            in_block: false,
+            saw_begin: false,
+            accepted: 0,
+            ips: Vec::new(),
+        }
+    }
+
+    // Tokenize a whole body at once
+    pub(crate) fn parse(body: &[u8], max_ips: usize) -> Result<Vec<String>, (StatusCode, String)> {
+        let mut list = PlainIpList::new(max_ips);
+        list.feed(body)?;
+        list.finish()
+    }
+
+    pub(crate) fn feed(&mut self, chunk: &[u8]) -> Result<(), (StatusCode, String)> {
+        for &byte in chunk {
+            if byte == b'\n' {
+                self.end_line()?;
+            } else if !self.line.is_empty() || !byte.is_ascii_whitespace() {
+                if self.line.len() == MAX_LINE_BYTES {
+                    return Err((
+                        StatusCode::BAD_REQUEST,
+                        format!(
+                            "Line {} is longer than {} bytes. Expected one IP address per line",
+                            self.line_number, MAX_LINE_BYTES
+                        ),
+                    ));
+                }
+                self.line.push(byte);
+            }
+        }
+        Ok(())
+    }
+
+    // Addresses of the lines completed so far, for streams consuming them as
+    // they come
+    pub(crate) fn take_ips(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.ips)
+    }
+
+    // Addresses of the body, once all of it was fed
+    pub(crate) fn finish(mut self) -> Result<Vec<String>, (StatusCode, String)> {
+        self.end_line()?;
+        Ok(self.ips)
+    }
+
+    fn end_line(&mut self) -> Result<(), (StatusCode, String)> {
+        self.line_number += 1;
+        let len = self.line.trim_ascii_end().len();
+        if len == 0 {
+            self.line.clear();
+            return Ok(());
+        }
+        self.line.truncate(len);
+
+        self.lines += 1;
+        if self.lines > self.max_lines {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("More than {} lines, at most {} IP addresses per request", self.max_lines, self.max_ips),
+            ));
+        }
+        if self.line.eq_ignore_ascii_case(b"begin") {
+            self.in_block = true;
+            self.saw_begin = true;
+        } else if self.line.eq_ignore_ascii_case(b"end") {
+            self.in_block = false;
+        } else if !self.saw_begin || self.in_block {
+            if self.accepted == self.max_ips {
+                return Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Too many IP addresses, at most {} per request", self.max_ips),
+                ));
+            }
+            self.accepted += 1;
+            self.ips.push(String::from_utf8_lossy(&self.line).into_owned());
+        }
+        self.line.clear();
+        Ok(())
+    }
+}
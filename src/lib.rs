@@ -1,8 +1,77 @@
+#[cfg(feature = "async")]
 #[macro_use]
 extern crate horrorshow;
 
+#[cfg(feature = "async")]
+pub mod admin;
+pub mod annotate;
+pub mod ascii;
+pub mod asn_registry;
 pub mod asns;
+#[cfg(feature = "async")]
+pub mod auth;
+mod buckets;
+pub mod build_info;
+pub mod cidr;
+pub mod cli_config;
+pub mod config;
+pub mod country;
+#[cfg(feature = "async")]
+pub mod deprecation;
+#[cfg(feature = "async")]
+pub mod dns;
+pub mod dnsbl;
+#[cfg(feature = "async")]
+pub mod doctor;
+#[cfg(feature = "async")]
+pub mod domains;
+#[cfg(feature = "async")]
+pub mod enrich;
+#[cfg(feature = "async")]
+pub mod fluent;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod input;
+#[cfg(feature = "async")]
+mod ip_list;
+#[cfg(feature = "async")]
+pub mod load;
+mod mmap;
+#[cfg(feature = "async")]
+pub mod negotiation;
+pub mod normalize;
+#[cfg(feature = "async")]
+pub mod net;
+#[cfg(feature = "async")]
+mod openapi;
+pub mod prefix_list;
+#[cfg(feature = "async")]
+pub mod proxy;
+#[cfg(feature = "async")]
+pub mod refresh;
+#[cfg(feature = "async")]
+pub mod replication;
+#[cfg(feature = "async")]
+mod render;
+#[cfg(feature = "async")]
+mod router;
+#[cfg(feature = "async")]
+pub mod selftest;
+#[cfg(feature = "async")]
+mod sse;
+#[cfg(feature = "async")]
+pub mod tls;
+#[cfg(feature = "async")]
+pub mod update;
+#[cfg(feature = "async")]
+pub mod usage;
+pub mod weblog;
+#[cfg(feature = "async")]
 pub mod webservice;
+#[cfg(feature = "async")]
+pub mod whois;
+#[cfg(all(windows, feature = "async"))]
+pub mod winservice;
 
 // Compile-time default URL for the IP-to-ASN database.
 // You can override this at build time by setting the environment variable
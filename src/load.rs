@@ -0,0 +1,337 @@
+use crate::config::LoadSheddingConfig;
+use crate::router::{self, Route};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+
+// Requests in flight and shed for each route pattern, and the concurrency
+// thresholds above which requests are shed with 503 (set_limits)
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+// Requests in flight on all routes
+static IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+
+static CONNECTIONS_OPEN: AtomicU64 = AtomicU64::new(0);
+
+// Connections closed above max_connections, with a 503 on HTTP
+static CONNECTIONS_REJECTED: AtomicU64 = AtomicU64::new(0);
+
+// Requests and connections that waited for a slot, admitted or not
+static REQUESTS_QUEUED: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_QUEUED: AtomicU64 = AtomicU64::new(0);
+
+// Woken when a request or connection ends, if anything can be queued
+static RELEASED: Notify = Notify::const_new();
+
+// Highest number of concurrent requests seen on a single connection
+static CONNECTION_PEAK: AtomicU64 = AtomicU64::new(0);
+
+// Why a request was shed, as the `reason` label of the metrics
+#[derive(Clone, Copy)]
+enum Reason {
+    Route,
+    Global,
+    Connection,
+}
+
+const REASONS: [(Reason, &str); 3] = [
+    (Reason::Route, "route"),
+    (Reason::Global, "global"),
+    (Reason::Connection, "connection"),
+];
+
+struct RouteLoad {
+    pattern: &'static str,
+    limit: Option<u64>,
+    in_flight: AtomicU64,
+    // Indexed by Reason
+    shed: [AtomicU64; 3],
+}
+
+struct Limits {
+    routes: Vec<RouteLoad>,
+    max_in_flight: Option<u64>,
+    max_in_flight_per_connection: Option<u64>,
+    max_connections: Option<u64>,
+    queue_timeout: Duration,
+    retry_after: Duration,
+}
+
+impl Limits {
+    fn new(config: LoadSheddingConfig) -> Result<Self, String> {
+        let mut routes: Vec<RouteLoad> = Vec::new();
+        for pattern in router::routes().iter().map(|entry| entry.pattern) {
+            if routes.iter().all(|load| load.pattern != pattern) {
+                routes.push(RouteLoad {
+                    pattern,
+                    limit: config.routes.get(pattern).copied().or_else(|| default_limit(pattern)),
+                    in_flight: AtomicU64::new(0),
+                    shed: Default::default(),
+                });
+            }
+        }
+        if let Some(unknown) = config
+            .routes
+            .keys()
+            .find(|pattern| routes.iter().all(|load| load.pattern != pattern.as_str()))
+        {
+            return Err(format!("Unknown route {:?} in [load_shedding.routes]", unknown));
+        }
+        Ok(Limits {
+            routes,
+            max_in_flight: config.max_in_flight,
+            max_in_flight_per_connection: config.max_in_flight_per_connection,
+            max_connections: config.max_connections,
+            queue_timeout: Duration::from_millis(config.queue_timeout),
+            retry_after: Duration::from_secs(config.retry_after),
+        })
+    }
+
+    fn route(&self, pattern: &str) -> &RouteLoad {
+        // Every route pattern has an entry
+        self.routes.iter().find(|load| load.pattern == pattern).unwrap()
+    }
+
+    fn released(&self) {
+        if !self.queue_timeout.is_zero() {
+            RELEASED.notify_waiters();
+        }
+    }
+}
+
+// Threshold of the routes decompressing retained snapshots, unless the
+// config sets one
+const SNAPSHOT_ROUTES_LIMIT: u64 = 2;
+
+fn default_limit(pattern: &str) -> Option<u64> {
+    [Route::IpHistory, Route::StatsTimeseries]
+        .iter()
+        .any(|route| route.pattern() == pattern)
+        .then_some(SNAPSHOT_ROUTES_LIMIT)
+}
+
+fn limits() -> &'static Limits {
+    LIMITS.get_or_init(|| Limits::new(LoadSheddingConfig::default()).unwrap())
+}
+
+// Set the concurrency thresholds, e.g. from the [load_shedding] table of the
+// config file. Without thresholds, requests are only counted. Must be called
+// before the server is started.
+pub fn set_limits(config: LoadSheddingConfig) -> Result<(), String> {
+    let _ = LIMITS.set(Limits::new(config)?);
+    Ok(())
+}
+
+// Threshold of open connections, if any
+pub fn max_connections() -> Option<u64> {
+    limits().max_connections
+}
+
+// Threshold of requests in flight on the same connection, if any
+pub fn max_in_flight_per_connection() -> Option<u64> {
+    limits().max_in_flight_per_connection
+}
+
+// A client connection, counting its requests in flight
+pub(crate) struct Connection {
+    in_flight: AtomicU64,
+}
+
+impl Connection {
+    fn try_open() -> Option<Arc<Self>> {
+        // Counted first, so that concurrent accepts can't all pass the check
+        let open = CONNECTIONS_OPEN.fetch_add(1, Ordering::Relaxed) + 1;
+        if limits().max_connections.is_some_and(|limit| open > limit) {
+            CONNECTIONS_OPEN.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Arc::new(Connection {
+            in_flight: AtomicU64::new(0),
+        }))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        CONNECTIONS_OPEN.fetch_sub(1, Ordering::Relaxed);
+        limits().released();
+    }
+}
+
+// Retry `attempt` each time a request or connection ends, until it succeeds
+// or the queue timeout elapses
+async fn queued<T>(queued: &AtomicU64, mut attempt: impl FnMut() -> Option<T>) -> Option<T> {
+    if let Some(admitted) = attempt() {
+        return Some(admitted);
+    }
+    let timeout = limits().queue_timeout;
+    if timeout.is_zero() {
+        return None;
+    }
+    queued.fetch_add(1, Ordering::Relaxed);
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        // Registered before the attempt, so that no release is missed
+        let released = RELEASED.notified();
+        tokio::pin!(released);
+        released.as_mut().enable();
+        if let Some(admitted) = attempt() {
+            return Some(admitted);
+        }
+        if tokio::time::timeout_at(deadline, released).await.is_err() {
+            return None;
+        }
+    }
+}
+
+// Open a connection accepted from a client. While `max_connections` are open,
+// the accept loop waits up to the queue timeout for one to close, leaving
+// further clients in the listen backlog. Shared by the HTTP, WHOIS and DNS
+// listeners.
+pub(crate) async fn open() -> Option<Arc<Connection>> {
+    let connection = queued(&CONNECTIONS_QUEUED, Connection::try_open).await;
+    if connection.is_none() {
+        CONNECTIONS_REJECTED.fetch_add(1, Ordering::Relaxed);
+    }
+    connection
+}
+
+// Open an HTTP connection. Above the cap, the client gets a 503 with
+// Retry-After, unless it's speaking TLS, and the connection is closed.
+pub(crate) async fn accept(tcp: &TcpStream, tls: bool) -> Option<Arc<Connection>> {
+    if let Some(connection) = open().await {
+        return Some(connection);
+    }
+    if !tls {
+        let response = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: 21\r\nConnection: close\r\n\r\nToo many connections\n",
+            limits().retry_after.as_secs()
+        );
+        // The socket buffer of a new connection fits the response, and a
+        // client that isn't reading doesn't get to hold up the accept loop
+        let _ = tcp.try_write(response.as_bytes());
+    }
+    None
+}
+
+// A request being served, counted until it is dropped
+pub(crate) struct InFlight {
+    route: &'static RouteLoad,
+    connection: Arc<Connection>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.route.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.connection.in_flight.fetch_sub(1, Ordering::Relaxed);
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+        limits().released();
+    }
+}
+
+fn try_admit(route: Route, connection: &Arc<Connection>) -> Result<InFlight, Reason> {
+    let limits = limits();
+    let load = limits.route(route.pattern());
+    // Counted first, so that concurrent requests can't all pass the checks
+    let on_route = load.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+    let on_connection = connection.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+    let global = IN_FLIGHT.fetch_add(1, Ordering::Relaxed) + 1;
+    CONNECTION_PEAK.fetch_max(on_connection, Ordering::Relaxed);
+
+    let exceeds = |limit: Option<u64>, count| limit.is_some_and(|limit| count > limit);
+    let shed = if exceeds(load.limit, on_route) {
+        Some(Reason::Route)
+    } else if route == Route::Metrics {
+        None
+    } else if exceeds(limits.max_in_flight, global) {
+        Some(Reason::Global)
+    } else if exceeds(limits.max_in_flight_per_connection, on_connection) {
+        Some(Reason::Connection)
+    } else {
+        None
+    };
+    match shed {
+        // Uncounted without waking up the queue, as no slot was freed
+        Some(reason) => {
+            load.in_flight.fetch_sub(1, Ordering::Relaxed);
+            connection.in_flight.fetch_sub(1, Ordering::Relaxed);
+            IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+            Err(reason)
+        }
+        None => Ok(InFlight {
+            route: load,
+            connection: connection.clone(),
+        }),
+    }
+}
+
+// Count a request of a route, or shed it when a threshold is still exceeded
+// after the queue timeout, returning how long the client should wait before
+// retrying. /metrics is only subject to its own route threshold, so that an
+// overload can still be observed.
+pub(crate) async fn admit(route: Route, connection: &Arc<Connection>) -> Result<InFlight, Duration> {
+    let mut reason = Reason::Route;
+    let attempt = || try_admit(route, connection).map_err(|shed| reason = shed).ok();
+    if let Some(in_flight) = queued(&REQUESTS_QUEUED, attempt).await {
+        return Ok(in_flight);
+    }
+    let limits = limits();
+    limits.route(route.pattern()).shed[reason as usize].fetch_add(1, Ordering::Relaxed);
+    Err(limits.retry_after)
+}
+
+pub fn write_metrics(out: &mut String) {
+    let limits = limits();
+    out.push_str("# HELP iptoasn_connections_open Number of open client connections.\n");
+    out.push_str("# TYPE iptoasn_connections_open gauge\n");
+    out.push_str(&format!(
+        "iptoasn_connections_open {}\n",
+        CONNECTIONS_OPEN.load(Ordering::Relaxed)
+    ));
+    out.push_str("# HELP iptoasn_connections_rejected_total Number of connections closed above max_connections.\n");
+    out.push_str("# TYPE iptoasn_connections_rejected_total counter\n");
+    out.push_str(&format!(
+        "iptoasn_connections_rejected_total {}\n",
+        CONNECTIONS_REJECTED.load(Ordering::Relaxed)
+    ));
+    out.push_str("# HELP iptoasn_queued_total Number of requests and connections that waited for a slot, by kind.\n");
+    out.push_str("# TYPE iptoasn_queued_total counter\n");
+    out.push_str(&format!(
+        "iptoasn_queued_total{{kind=\"request\"}} {}\n",
+        REQUESTS_QUEUED.load(Ordering::Relaxed)
+    ));
+    out.push_str(&format!(
+        "iptoasn_queued_total{{kind=\"connection\"}} {}\n",
+        CONNECTIONS_QUEUED.load(Ordering::Relaxed)
+    ));
+    out.push_str("# HELP iptoasn_connection_requests_in_flight_peak Highest number of concurrent requests on a single connection.\n");
+    out.push_str("# TYPE iptoasn_connection_requests_in_flight_peak gauge\n");
+    out.push_str(&format!(
+        "iptoasn_connection_requests_in_flight_peak {}\n",
+        CONNECTION_PEAK.load(Ordering::Relaxed)
+    ));
+    out.push_str("# HELP iptoasn_requests_in_flight Number of requests being served per route.\n");
+    out.push_str("# TYPE iptoasn_requests_in_flight gauge\n");
+    for load in &limits.routes {
+        out.push_str(&format!(
+            "iptoasn_requests_in_flight{{route=\"{}\"}} {}\n",
+            load.pattern,
+            load.in_flight.load(Ordering::Relaxed)
+        ));
+    }
+    out.push_str("# HELP iptoasn_requests_shed_total Number of requests rejected with 503 per route, by exceeded threshold.\n");
+    out.push_str("# TYPE iptoasn_requests_shed_total counter\n");
+    for load in &limits.routes {
+        for (reason, label) in REASONS {
+            out.push_str(&format!(
+                "iptoasn_requests_shed_total{{route=\"{}\",reason=\"{}\"}} {}\n",
+                load.pattern,
+                label,
+                load.shed[reason as usize].load(Ordering::Relaxed)
+            ));
+        }
+    }
+}
@@ -3,20 +3,73 @@ use mimalloc::MiMalloc;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-use iptoasn_webservice::asns::Asns;
+use iptoasn_webservice::admin;
+use iptoasn_webservice::asns::{Asns, LoadProgress};
+use iptoasn_webservice::auth;
+use iptoasn_webservice::build_info;
+use iptoasn_webservice::cidr::IpRangeSet;
+use iptoasn_webservice::config::Config;
+use iptoasn_webservice::country;
+use iptoasn_webservice::deprecation;
+use iptoasn_webservice::dns::DnsServer;
+use iptoasn_webservice::dnsbl;
+use iptoasn_webservice::doctor::Doctor;
+use iptoasn_webservice::fluent::Fluent;
+#[cfg(feature = "grpc")]
+use iptoasn_webservice::grpc::GrpcService;
+use iptoasn_webservice::load;
+use iptoasn_webservice::net;
+use iptoasn_webservice::normalize;
+use iptoasn_webservice::proxy::Proxy;
+use iptoasn_webservice::refresh::{self, Refresher};
+use iptoasn_webservice::replication;
+use iptoasn_webservice::usage;
 use iptoasn_webservice::webservice::WebService;
+use iptoasn_webservice::whois::WhoisServer;
+#[cfg(windows)]
+use iptoasn_webservice::winservice;
 use iptoasn_webservice::DEFAULT_DB_URL;
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use log::{error, info, warn};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use std::path::PathBuf;
+use std::{env, fs};
 
-#[tokio::main]
-async fn main() {
+// Exit codes of fatal startup errors, for container runtimes and init
+// systems. Command-line usage errors exit with 2, as reported by clap.
+const EXIT_CONFIG: i32 = 1;
+const EXIT_DATABASE: i32 = 3;
+const EXIT_LISTEN: i32 = 4;
+
+// Timeout of --healthcheck probes
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() {
     env_logger::init();
+    WebService::record_start();
 
-    let matches = Command::new("iptoasn-webservice")
+    // Started by the service control manager
+    #[cfg(windows)]
+    if env::args().any(|arg| arg == "--service") {
+        if let Err(e) = winservice::run(|| Box::pin(run())) {
+            error!("Unable to start the Windows service: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    tokio::runtime::Runtime::new()
+        .expect("Unable to start the runtime")
+        .block_on(run());
+}
+
+async fn run() {
+    let command = Command::new("iptoasn-webservice")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Frank Denis <github@pureftpd.org>")
         .about("IP to ASN webservice")
@@ -25,8 +78,147 @@ async fn main() {
                 .short('l')
                 .long("listen")
                 .value_name("listen_addr")
-                .help("Address:port to listen to")
-                .default_value("127.0.0.1:53661"),
+                .help("Address:port to listen to, e.g. [::]:53661 for all IPv6 and, where supported, IPv4 addresses [default: 127.0.0.1:53661 and [::1]:53661]"),
+        )
+        .arg(
+            Arg::new("listen_any")
+                .long("listen-any")
+                .value_name("port")
+                .help("Listen on all IPv4 and IPv6 addresses, with a dual-stack socket or one socket per address family [default port: 53661]")
+                .num_args(0..=1)
+                .require_equals(true)
+                .value_parser(clap::value_parser!(u16))
+                .default_missing_value("53661")
+                .conflicts_with("listen_addr"),
+        )
+        .arg(
+            Arg::new("ipv6_only")
+                .long("ipv6-only")
+                .help("Don't accept IPv4 clients on an IPv6 wildcard --listen address such as [::]:53661")
+                .action(ArgAction::SetTrue)
+                .requires("listen_addr"),
+        )
+        .arg(
+            Arg::new("bind_wait")
+                .long("bind-wait")
+                .value_name("seconds")
+                .help("Keep retrying for that long to bind a listen address that is in use or not assigned yet")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("healthcheck")
+                .long("healthcheck")
+                .help("Probe the server listening on the --listen or --listen-any port of the local host, then exit with 0 if it answers and 1 otherwise")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("backlog")
+                .long("backlog")
+                .value_name("n")
+                .help("Maximum number of pending connections in the listen queue")
+                .default_value("1024")
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("path")
+                .help("Path to a TOML configuration file"),
+        )
+        .arg(
+            Arg::new("api_key")
+                .long("api-key")
+                .value_name("key")
+                .help("API key required by all routes but /, /bulk, /openapi.json and /version; repeat or separate with commas")
+                .env("IPTOASN_API_KEYS")
+                .hide_env_values(true)
+                .action(ArgAction::Append)
+                .value_delimiter(','),
+        )
+        .arg(
+            Arg::new("tls_cert")
+                .long("tls-cert")
+                .value_name("path")
+                .help("PEM certificate chain to serve HTTPS with, reloaded when it changes")
+                .requires("tls_key")
+                .conflicts_with("upstream"),
+        )
+        .arg(
+            Arg::new("tls_key")
+                .long("tls-key")
+                .value_name("path")
+                .help("PEM private key of the --tls-cert certificate")
+                .requires("tls_cert"),
+        )
+        .arg(
+            Arg::new("max_request_timeout")
+                .long("max-request-timeout")
+                .value_name("seconds")
+                .help("Upper bound for the X-Request-Timeout header of bulk requests")
+                .default_value("30")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("max_body")
+                .long("max-body")
+                .value_name("MiB")
+                .help("Maximum size of request bodies as sent")
+                .default_value("64")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("max_decoded_body")
+                .long("max-decoded-body")
+                .value_name("MiB")
+                .help("Maximum size of gzip or zstd compressed request bodies once decompressed")
+                .default_value("64")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("max_bulk_ips")
+                .long("max-bulk-ips")
+                .value_name("count")
+                .help("Maximum number of IP addresses of a bulk lookup")
+                .default_value("100000")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("max_page_size")
+                .long("max-page-size")
+                .value_name("count")
+                .help("Maximum ?limit= of the list routes")
+                .default_value("10000")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("not_found_ttl")
+                .long("not-found-ttl")
+                .value_name("seconds")
+                .help("Cache lifetime of lookups of unannounced IP addresses")
+                .default_value("3600")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("no_expires")
+                .long("no-expires")
+                .help("Only send Cache-Control, without an Expires header")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("base_path")
+                .long("base-path")
+                .value_name("path")
+                .help("URL prefix to serve all routes under, e.g. /iptoasn"),
+        )
+        .arg(
+            Arg::new("trusted_proxies")
+                .long("trusted-proxies")
+                .value_name("CIDRs")
+                .help("Addresses or prefixes of the reverse proxies whose X-Real-IP and X-Forwarded-For headers are honored; repeat or separate with commas, empty to trust none")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .default_values(["127.0.0.0/8", "::1"]),
         )
         .arg(
             Arg::new("cache_file")
@@ -36,13 +228,39 @@ async fn main() {
                 .help("Path to cache file")
                 .default_value("cache/ip2asn-combined.tsv.gz"),
         )
+        .arg(
+            Arg::new("retain_snapshots")
+                .long("retain-snapshots")
+                .value_name("days")
+                .help("Keep replaced cache snapshots for that many days, for /v1/as/ip/<ip>/history (0 to keep only the latest)")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("backend")
+                .long("backend")
+                .value_name("backend")
+                .help("Where to keep the ranges: memory, or mmap for a memory-mapped snapshot next to the cache file")
+                .value_parser(["memory", "mmap"])
+                .default_value("memory"),
+        )
+        .arg(
+            Arg::new("index_mode")
+                .long("index-mode")
+                .value_name("mode")
+                .help("How in-memory ranges are searched: binary search only, or a /24 and /48 bucket table first")
+                .value_parser(["binary", "buckets"])
+                .default_value("binary"),
+        )
         .arg(
             Arg::new("db_url")
                 .short('u')
                 .long("dburl")
                 .value_name("db_url")
-                .help("URL of the database")
+                .help("URL of the database; repeat or separate with commas for an ordered failover list")
                 .env("IPTOASN_DB_URL")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
                 .default_value(DEFAULT_DB_URL),
         )
         .arg(
@@ -54,87 +272,641 @@ async fn main() {
                 .default_value("60")
                 .value_parser(clap::value_parser!(u64)),
         )
-        .get_matches();
+        .arg(
+            Arg::new("refresh_jitter")
+                .long("refresh-jitter")
+                .value_name("minutes")
+                .help("Maximum per-instance refresh offset derived from the hostname (minutes, 0 to disable)")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("rate_limit")
+                .long("rate-limit")
+                .value_name("KiB/s")
+                .help("Bandwidth limit for database downloads (KiB/s, 0 for unlimited)")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("fluent_listen")
+                .long("fluent-listen")
+                .value_name("addr")
+                .help("Address:port or unix:/path to accept Fluent forward protocol events on")
+                .requires("fluent_forward"),
+        )
+        .arg(
+            Arg::new("fluent_forward")
+                .long("fluent-forward")
+                .value_name("addr")
+                .help("Address:port or unix:/path of the Fluent forward input to send enriched events to")
+                .requires("fluent_listen"),
+        )
+        .arg(
+            Arg::new("fluent_ip_key")
+                .long("fluent-ip-key")
+                .value_name("key")
+                .help("Record key holding the IP address to enrich")
+                .default_value("ip"),
+        )
+        .arg(
+            Arg::new("dns_listen")
+                .long("dns-listen")
+                .value_name("addr")
+                .help("Address:port to answer Team Cymru style origin TXT queries on, over UDP and TCP, e.g. 127.0.0.1:5353; not with API keys")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("dns_zone")
+                .long("dns-zone")
+                .value_name("zone")
+                .help("Zone of the --dns-listen names, e.g. 4.3.2.1.origin.asn.local for 1.2.3.4")
+                .default_value("asn.local"),
+        )
+        .arg(
+            Arg::new("whois_listen")
+                .long("whois-listen")
+                .value_name("addr")
+                .help("Address:port to answer Team Cymru style WHOIS queries and bulk sessions on, e.g. 127.0.0.1:4343")
+                .value_parser(clap::value_parser!(SocketAddr)),
+        )
+        .arg(
+            Arg::new("upstream")
+                .long("upstream")
+                .value_name("url")
+                .help("Forward lookups to another iptoasn-webservice instance and cache the responses, instead of loading the database")
+                .conflicts_with("fluent_listen"),
+        )
+        .arg(
+            Arg::new("upstream_cache_size")
+                .long("upstream-cache-size")
+                .value_name("entries")
+                .help("Maximum number of cached upstream responses (0 to disable caching)")
+                .default_value("10000")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("standby")
+                .long("standby")
+                .value_name("url")
+                .help("Base URL of a standby instance to push each new database to; repeat for several")
+                .action(ArgAction::Append)
+                .requires("replication_token")
+                .conflicts_with_all(["primary", "upstream"]),
+        )
+        .arg(
+            Arg::new("primary")
+                .long("primary")
+                .value_name("url")
+                .help("Run as a standby: load the database of the primary instance at this base URL, and wait for it to push new ones")
+                .requires("replication_token")
+                .conflicts_with("upstream"),
+        )
+        .arg(
+            Arg::new("replication_token")
+                .long("replication-token")
+                .value_name("token")
+                .help("Shared secret between a primary and its standbys")
+                .env("IPTOASN_REPLICATION_TOKEN")
+                .hide_env_values(true),
+        )
+        .arg(
+            Arg::new("max_replication_body")
+                .long("max-replication-body")
+                .value_name("MiB")
+                .help("Maximum size of a database pushed by the primary, as sent and once decompressed")
+                .default_value("256")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("admin_token")
+                .long("admin-token")
+                .value_name("token")
+                .help("Secret required on the admin routes, which are disabled without one")
+                .env("IPTOASN_ADMIN_TOKEN")
+                .hide_env_values(true),
+        )
+        .arg(
+            Arg::new("check_config")
+                .long("check-config")
+                .help("Validate the configuration, database URLs and cache directory, then exit")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("check_head")
+                .long("check-head")
+                .help("With --check-config, also send HEAD requests to the database URLs")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("version_json")
+                .long("version-json")
+                .help("Print the version, git commit, build date and features as JSON, then exit")
+                .action(ArgAction::SetTrue),
+        );
+    #[cfg(feature = "grpc")]
+    let command = command.arg(
+        Arg::new("grpc_listen")
+            .long("grpc-listen")
+            .value_name("addr")
+            .help("Address:port to serve the gRPC lookup API on, e.g. 127.0.0.1:53662")
+            .value_parser(clap::value_parser!(SocketAddr))
+            .conflicts_with("upstream"),
+    );
+    #[cfg(windows)]
+    let command = command.arg(
+        Arg::new("service")
+            .long("service")
+            .help("Run as a Windows service, registered as iptoasn-webservice")
+            .action(ArgAction::SetTrue),
+    );
+    let matches = command.get_matches();
+    if matches.get_flag("version_json") {
+        println!("{}", serde_json::to_string_pretty(&build_info::build_info()).unwrap());
+        return;
+    }
+
+    let db_urls: Vec<String> = matches
+        .get_many::<String>("db_url")
+        .unwrap()
+        .cloned()
+        .collect();
+    let listen = match (
+        matches.get_one::<u16>("listen_any"),
+        matches.get_one::<String>("listen_addr"),
+    ) {
+        (Some(&port), _) => Ok(net::Listen::Any { port }),
+        (None, Some(addr)) => match SocketAddr::from_str(addr) {
+            Ok(addr) => Ok(net::Listen::Addr {
+                addr,
+                v6only: matches.get_flag("ipv6_only"),
+            }),
+            Err(e) => Err(format!("{}: {}", addr, e)),
+        },
+        (None, None) => Ok(net::Listen::Loopback {
+            port: net::DEFAULT_PORT,
+        }),
+    };
+    if matches.get_flag("healthcheck") {
+        let healthy = match &listen {
+            Ok(listen) => {
+                healthcheck(
+                    *listen,
+                    matches.get_one::<String>("base_path").map_or("", String::as_str),
+                    matches.contains_id("tls_cert"),
+                )
+                .await
+            }
+            Err(e) => {
+                error!("Invalid listen address {}", e);
+                false
+            }
+        };
+        std::process::exit(if healthy { 0 } else { 1 });
+    }
+    #[cfg(unix)]
+    exit_on_termination();
+    net::set_bind_wait(Duration::from_secs(*matches.get_one::<u64>("bind_wait").unwrap()));
 
-    let db_url = matches.get_one::<String>("db_url").unwrap();
-    let listen_addr = matches.get_one::<String>("listen_addr").unwrap();
     let refresh_delay = *matches.get_one::<u64>("refresh_delay").unwrap();
+    let refresh_jitter = *matches.get_one::<u64>("refresh_jitter").unwrap();
+    let rate_limit = match matches.get_one::<u64>("rate_limit").unwrap().checked_mul(1024) {
+        Some(rate_limit) => rate_limit,
+        None => {
+            error!("--rate-limit is too large");
+            std::process::exit(EXIT_CONFIG);
+        }
+    };
     let cache_file: PathBuf = PathBuf::from(matches.get_one::<String>("cache_file").unwrap());
 
-    // Create HTTP client once if URL is HTTP/HTTPS
-    let http_client = if db_url.starts_with("http://") || db_url.starts_with("https://") {
+    // Create HTTP client once if any URL is HTTP/HTTPS
+    let http_client = if db_urls
+        .iter()
+        .any(|u| u.starts_with("http://") || u.starts_with("https://"))
+    {
         Some(reqwest::Client::new())
     } else {
         None
     };
 
-    let asns = match get_asns(db_url, http_client.as_ref(), Some(cache_file.clone())).await {
+    let upstream = matches.get_one::<String>("upstream");
+    let config_path = matches.get_one::<String>("config").map(Path::new);
+    let config = config_path.map_or_else(|| Ok(Config::default()), Config::load);
+    let backlog = *matches.get_one::<u32>("backlog").unwrap();
+
+    if matches.get_flag("check_config") {
+        let mut doctor = Doctor::new();
+        doctor.check_listen(&listen);
+        if let Some(path) = config_path {
+            doctor.check_config(path, config);
+        }
+        if let Some(upstream) = upstream {
+            doctor
+                .check_url("upstream URL", upstream, None, matches.get_flag("check_head"))
+                .await;
+            if !doctor.print_report() {
+                std::process::exit(EXIT_CONFIG);
+            }
+            return;
+        }
+        for db_url in &db_urls {
+            doctor
+                .check_url(
+                    "database URL",
+                    db_url,
+                    http_client.as_ref(),
+                    matches.get_flag("check_head"),
+                )
+                .await;
+        }
+        doctor.check_cache_file(&cache_file);
+        if !doctor.print_report() {
+            std::process::exit(EXIT_CONFIG);
+        }
+        return;
+    }
+
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(EXIT_CONFIG);
+        }
+    };
+    let listen = match listen {
+        Ok(listen) => listen,
+        Err(e) => {
+            error!("Invalid listen address {}", e);
+            std::process::exit(EXIT_CONFIG);
+        }
+    };
+    let mut trusted_proxies = IpRangeSet::new();
+    for proxy in matches
+        .get_many::<String>("trusted_proxies")
+        .unwrap()
+        .map(|proxy| proxy.trim())
+        .filter(|proxy| !proxy.is_empty())
+    {
+        if let Err(e) = trusted_proxies.insert_str(proxy) {
+            error!("Invalid --trusted-proxies entry: {}", e);
+            std::process::exit(EXIT_CONFIG);
+        }
+    }
+    WebService::set_trusted_proxies(trusted_proxies);
+    if let Some(base_path) = matches.get_one::<String>("base_path") {
+        WebService::set_base_path(base_path);
+    }
+    if let (Some(cert), Some(key)) = (
+        matches.get_one::<String>("tls_cert"),
+        matches.get_one::<String>("tls_key"),
+    ) {
+        if let Err(e) = WebService::set_tls(Path::new(cert), Path::new(key)) {
+            error!("{}", e);
+            std::process::exit(EXIT_CONFIG);
+        }
+    }
+    WebService::set_hosts(config.hosts);
+    WebService::set_strict_negotiation(config.strict_negotiation);
+    WebService::set_strict_not_found(config.strict_not_found);
+    WebService::set_plain_ascii_descriptions(config.plain_ascii_descriptions);
+    if let Err(e) = country::check_corrections(&config.country_codes) {
+        error!("{}", e);
+        std::process::exit(EXIT_CONFIG);
+    }
+    country::set_corrections(config.country_codes);
+    normalize::set_options(config.normalization);
+    usage::set_tenants(config.tenants);
+    let mut usage_file = cache_file.clone().into_os_string();
+    usage_file.push(".usage.json");
+    usage::persist_to(PathBuf::from(usage_file));
+    auth::set_keys(
+        matches
+            .get_many::<String>("api_key")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .chain(config.api_keys),
+    );
+    // DNS queries can't send an API key
+    if auth::is_enabled() && matches.contains_id("dns_listen") {
+        error!("--dns-listen can't be used with API keys, DNS queries can't send one");
+        std::process::exit(EXIT_CONFIG);
+    }
+    if let Err(e) = load::set_limits(config.load_shedding) {
+        error!("{}", e);
+        std::process::exit(EXIT_CONFIG);
+    }
+    net::check_fd_limit(load::max_connections());
+    if let Err(e) = dnsbl::set_categories(config.dnsbl) {
+        error!("{}", e);
+        std::process::exit(EXIT_CONFIG);
+    }
+    if let Err(e) = deprecation::set_deprecations(config.deprecations, config.disable_deprecated) {
+        error!("{}", e);
+        std::process::exit(EXIT_CONFIG);
+    }
+    WebService::set_max_request_timeout(Duration::from_secs(
+        *matches.get_one::<u64>("max_request_timeout").unwrap(),
+    ));
+    match matches.get_one::<u64>("max_body").unwrap().checked_mul(1 << 20) {
+        Some(max_body) => WebService::set_max_body(max_body),
+        None => {
+            error!("--max-body is too large");
+            std::process::exit(EXIT_CONFIG);
+        }
+    }
+    match matches.get_one::<u64>("max_decoded_body").unwrap().checked_mul(1 << 20) {
+        Some(max_decoded_body) => WebService::set_max_decoded_body(max_decoded_body),
+        None => {
+            error!("--max-decoded-body is too large");
+            std::process::exit(EXIT_CONFIG);
+        }
+    }
+    match matches.get_one::<u64>("max_replication_body").unwrap().checked_mul(1 << 20) {
+        Some(max_replication_body) => WebService::set_max_replication_body(max_replication_body),
+        None => {
+            error!("--max-replication-body is too large");
+            std::process::exit(EXIT_CONFIG);
+        }
+    }
+    WebService::set_max_bulk_ips(*matches.get_one::<usize>("max_bulk_ips").unwrap());
+    WebService::set_max_page_size(*matches.get_one::<usize>("max_page_size").unwrap());
+    WebService::set_not_found_ttl(Duration::from_secs(
+        *matches.get_one::<u64>("not_found_ttl").unwrap(),
+    ));
+    if matches.get_flag("no_expires") {
+        WebService::disable_expires();
+    }
+    Asns::retain_snapshots(Duration::from_secs(
+        matches.get_one::<u64>("retain_snapshots").unwrap().saturating_mul(86_400),
+    ));
+    WebService::set_cache_file(cache_file.clone());
+    if matches.get_one::<String>("backend").unwrap() == "mmap" {
+        let mut ranges_file = cache_file.clone().into_os_string();
+        ranges_file.push(".ranges");
+        Asns::use_mmap_backend(PathBuf::from(ranges_file));
+    }
+    if matches.get_one::<String>("index_mode").unwrap() == "buckets" {
+        if matches.get_one::<String>("backend").unwrap() == "mmap" {
+            warn!("--index-mode buckets is ignored with --backend mmap");
+        }
+        Asns::use_bucket_index();
+    }
+
+    if let Some(token) = matches.get_one::<String>("admin_token") {
+        admin::set_token(token);
+    }
+    if let Some(token) = matches.get_one::<String>("replication_token") {
+        replication::set_token(token);
+    }
+    if let Some(standbys) = matches.get_many::<String>("standby") {
+        replication::set_standbys(standbys.cloned().collect());
+    }
+    if let Some(primary) = matches.get_one::<String>("primary") {
+        replication::set_primary(primary);
+    }
+
+    if let Some(upstream) = upstream {
+        let cache_size = *matches.get_one::<usize>("upstream_cache_size").unwrap();
+        if let Err(e) = Proxy::start(upstream.clone(), listen, backlog, cache_size).await {
+            error!("{}", e);
+            std::process::exit(EXIT_LISTEN);
+        }
+        return;
+    }
+
+    // A standby starts with the database of its primary, and only falls
+    // back to the database URLs when the primary can't be reached
+    let replicated = match replication::primary() {
+        Some(primary) => {
+            info!("Retrieving the database of the primary {}", primary);
+            replication::pull().await.ok()
+        }
+        None => None,
+    };
+    let asns = match replicated {
+        Some(asns) => Ok(asns),
+        None => get_asns(&db_urls, http_client.as_ref(), Some(cache_file.clone()), rate_limit).await,
+    };
+    let asns = match asns {
         Ok(asns) => asns,
         Err(e) => {
             error!("Failed to load initial database: {e}");
             error!("Application cannot start without initial data");
-            return;
+            std::process::exit(EXIT_DATABASE);
         }
     };
     let asns_arc = Arc::new(RwLock::new(Arc::new(asns)));
+    let refresher = Arc::new(Refresher::new(
+        asns_arc.clone(),
+        db_urls,
+        http_client,
+        Some(cache_file),
+        rate_limit,
+    ));
 
-    // Only start the refresh task if refresh_delay > 0
-    if refresh_delay > 0 {
-        let asns_arc_t = asns_arc.clone();
-        let db_url_t = db_url.clone();
-        let http_client_t = http_client.clone();
-        let cache_file_t = cache_file.clone();
+    // Only start the refresh task if refresh_delay > 0. Standbys get new
+    // databases from their primary instead.
+    if replication::primary().is_some() {
+        info!("Automatic database refresh disabled, databases are pushed by the primary");
+    } else if refresh_delay > 0 {
+        let refresher_t = refresher.clone();
+        let offset = refresh_offset(refresh_jitter * 60);
         tokio::spawn(async move {
+            // Shift the refresh schedule of this instance once, so that many
+            // instances don't all hit the database URL at the same time.
+            tokio::time::sleep(Duration::from_secs(offset)).await;
             loop {
                 tokio::time::sleep(Duration::from_secs(refresh_delay * 60)).await;
-                update_asns(
-                    &asns_arc_t,
-                    &db_url_t,
-                    http_client_t.as_ref(),
-                    Some(cache_file_t.clone()),
-                )
-                .await;
+                refresher_t.trigger();
             }
         });
         info!(
-            "Automatic database refresh enabled (every {} minutes)",
-            refresh_delay
+            "Automatic database refresh enabled (every {} minutes, offset {} seconds)",
+            refresh_delay, offset
         );
     } else {
         info!("Automatic database refresh disabled");
     }
 
-    WebService::start(asns_arc, listen_addr).await;
+    #[cfg(unix)]
+    reload_on_sighup(refresher.clone());
+
+    if let (Some(fluent_listen), Some(fluent_forward)) = (
+        matches.get_one::<String>("fluent_listen"),
+        matches.get_one::<String>("fluent_forward"),
+    ) {
+        let fluent_ip_key = matches.get_one::<String>("fluent_ip_key").unwrap();
+        tokio::spawn(Fluent::start(
+            asns_arc.clone(),
+            fluent_listen.clone(),
+            fluent_forward.clone(),
+            fluent_ip_key.clone(),
+        ));
+    }
+
+    if let Some(&dns_listen) = matches.get_one::<SocketAddr>("dns_listen") {
+        let dns = match DnsServer::bind(dns_listen).await {
+            Ok(dns) => dns,
+            Err(e) => {
+                error!("Failed to bind DNS listener to {}: {}", dns_listen, e);
+                std::process::exit(EXIT_LISTEN);
+            }
+        };
+        let dns_zone = matches.get_one::<String>("dns_zone").unwrap();
+        tokio::spawn(dns.serve(asns_arc.clone(), dns_zone.clone()));
+    }
+
+    if let Some(&whois_listen) = matches.get_one::<SocketAddr>("whois_listen") {
+        let whois = match WhoisServer::bind(whois_listen).await {
+            Ok(whois) => whois,
+            Err(e) => {
+                error!("Failed to bind WHOIS listener to {}: {}", whois_listen, e);
+                std::process::exit(EXIT_LISTEN);
+            }
+        };
+        let max_ips = *matches.get_one::<usize>("max_bulk_ips").unwrap();
+        tokio::spawn(whois.serve(asns_arc.clone(), max_ips));
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(&grpc_listen) = matches.get_one::<SocketAddr>("grpc_listen") {
+        tokio::spawn(GrpcService::start(asns_arc.clone(), grpc_listen));
+    }
+
+    if let Err(e) = WebService::start(asns_arc, refresher, listen, backlog).await {
+        error!("{}", e);
+        std::process::exit(EXIT_LISTEN);
+    }
 }
 
-async fn get_asns(
-    db_url: &str,
-    http_client: Option<&reqwest::Client>,
-    cache_file: Option<PathBuf>,
-) -> Result<Asns, &'static str> {
-    info!("Retrieving ASNs");
-    let asns = Asns::new(db_url, http_client, cache_file).await?;
-    info!("ASNs loaded");
-    Ok(asns)
+// Probe /version on the local address of a Listen, as a container
+// HEALTHCHECK without curl. The server only listens once its database is
+// loaded, so an answer means it is ready.
+async fn healthcheck(listen: net::Listen, base_path: &str, tls: bool) -> bool {
+    let addr = match listen {
+        net::Listen::Addr { addr, .. } if addr.ip().is_unspecified() => match addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::LOCALHOST, addr.port())),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::LOCALHOST, addr.port())),
+        },
+        net::Listen::Addr { addr, .. } => addr,
+        net::Listen::Loopback { port } | net::Listen::Any { port } => {
+            SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+        }
+    };
+    let url = format!(
+        "{}://{}{}/version",
+        if tls { "https" } else { "http" },
+        addr,
+        base_path.trim_end_matches('/')
+    );
+    // The certificate is for the public name, not the local address
+    let client = reqwest::Client::builder()
+        .timeout(HEALTHCHECK_TIMEOUT)
+        .danger_accept_invalid_certs(true)
+        .build();
+    let response = match client {
+        Ok(client) => client.get(&url).send().await,
+        Err(e) => {
+            error!("Unable to create the HTTP client: {}", e);
+            return false;
+        }
+    };
+    match response {
+        Ok(response) if response.status().is_success() => true,
+        Ok(response) => {
+            error!("{} answered {}", url, response.status());
+            false
+        }
+        Err(e) => {
+            error!("{} is unreachable: {}", url, e);
+            false
+        }
+    }
 }
 
-async fn update_asns(
-    asns_arc: &Arc<RwLock<Arc<Asns>>>,
-    db_url: &str,
-    http_client: Option<&reqwest::Client>,
-    cache_file: Option<PathBuf>,
-) {
-    info!("Attempting to update ASN database");
-    let asns = match get_asns(db_url, http_client, cache_file).await {
-        Ok(asns) => asns,
+// Exit as soon as SIGTERM or SIGINT is received, even while the database is
+// being loaded. The server runs as PID 1 in containers, where signals
+// without a handler are ignored, and stopping would otherwise wait for the
+// runtime's kill timeout.
+#[cfg(unix)]
+fn exit_on_termination() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (mut terminate, mut interrupt) = match (signal(SignalKind::terminate()), signal(SignalKind::interrupt())) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Unable to handle termination signals: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        };
+        info!("Received {}, exiting", name);
+        Asns::cancel_loads();
+        usage::save();
+        std::process::exit(0);
+    });
+}
+
+// Refresh the database as soon as SIGHUP is received, through the same
+// path as periodic refreshes. A refresh already in flight isn't restarted.
+#[cfg(unix)]
+fn reload_on_sighup(refresher: Arc<Refresher>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
         Err(e) => {
-            warn!("Failed to update ASN database: {e}");
-            warn!("Continuing with existing data");
+            warn!("Unable to handle SIGHUP: {e}");
             return;
         }
     };
-    let asns_arc_new = Arc::new(asns);
-    let mut asns_arc_w = asns_arc.write().unwrap();
-    *asns_arc_w = asns_arc_new;
-    info!("ASN database successfully updated");
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            // Standbys get new databases from their primary only
+            if replication::primary().is_some() {
+                info!("Received SIGHUP, ignored as databases are pushed by the primary");
+                continue;
+            }
+            let status = refresher.trigger();
+            info!("Received SIGHUP, refreshing the database (operation {})", status.id);
+        }
+    });
+}
+
+// Stable pseudo-random offset in [0, max_secs) derived from the hostname.
+fn refresh_offset(max_secs: u64) -> u64 {
+    if max_secs == 0 {
+        return 0;
+    }
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok())
+        .or_else(|| env::var("COMPUTERNAME").ok())
+        .unwrap_or_default();
+    let mut hasher = DefaultHasher::new();
+    hostname.trim().hash(&mut hasher);
+    hasher.finish() % max_secs
+}
+
+async fn get_asns(
+    db_urls: &[String],
+    http_client: Option<&reqwest::Client>,
+    cache_file: Option<PathBuf>,
+    rate_limit: u64,
+) -> Result<Asns, &'static str> {
+    info!("Retrieving ASNs");
+    let (logged_step, parse_logged_step) = (AtomicU64::new(0), AtomicU64::new(0));
+    let progress = LoadProgress {
+        download: &|downloaded, total| refresh::log_progress(&logged_step, downloaded, total),
+        parse: &|progress| refresh::log_parse_progress(&parse_logged_step, progress),
+    };
+    let asns = Asns::from_urls_with_load_progress(db_urls, http_client, cache_file, rate_limit, progress).await?;
+    info!("ASNs loaded from {}", asns.source());
+    Ok(asns)
 }
@@ -0,0 +1,440 @@
+use crate::asns::Asn;
+use memmap2::{Mmap, MmapMut};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// Binary snapshot of the ranges, searched in place through a memory map
+// (--backend mmap), so that the ranges never live on the heap:
+//
+//   header:  MAGIC, number of records (u64 LE)
+//   records: RECORD_SIZE bytes each, sorted by first address
+//     [0]      address family (4 or 6)
+//     [1..17]  first address (IPv4 in the first 4 bytes, zero padded)
+//     [17..33] last address
+//     [33..37] AS number (u32 LE)
+//     [37..41] offset of the country code in the string table (u32 LE)
+//     [41..45] offset of the description in the string table (u32 LE)
+//   strings: length (u16 LE) followed by UTF-8 bytes, deduplicated
+const MAGIC: &[u8; 8] = b"IP2ASNR1";
+// Layout version, the last digit of MAGIC; bump both together
+pub(crate) const FORMAT_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 16;
+const RECORD_SIZE: usize = 48;
+const KEY_SIZE: usize = 17;
+
+type Record = [u8; RECORD_SIZE];
+
+fn encode_ip(ip: IpAddr) -> [u8; KEY_SIZE] {
+    let mut key = [0u8; KEY_SIZE];
+    match ip {
+        IpAddr::V4(v4) => {
+            key[0] = 4;
+            key[1..5].copy_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            key[0] = 6;
+            key[1..].copy_from_slice(&v6.octets());
+        }
+    }
+    key
+}
+
+fn decode_ip(key: &[u8]) -> IpAddr {
+    if key[0] == 4 {
+        IpAddr::V4(Ipv4Addr::new(key[1], key[2], key[3], key[4]))
+    } else {
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&key[1..17]);
+        IpAddr::V6(Ipv6Addr::from(octets))
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// Where the builder puts the records
+enum Records {
+    // For a snapshot sent to other instances
+    Memory(Vec<Record>),
+    // Streamed to the temporary file of the snapshot as the database is
+    // parsed, after a header with a count filled in at the end
+    File { file: io::BufWriter<File>, tmp: PathBuf },
+}
+
+// Collects the ranges while the database is parsed, then writes the snapshot
+pub(crate) struct MmapBuilder {
+    records: Records,
+    count: usize,
+    // Whether the records came strictly ordered by first address, as in the
+    // upstream database, so that they don't need to be sorted
+    ordered: bool,
+    last_key: Option<[u8; KEY_SIZE]>,
+    strings: Vec<u8>,
+    offsets: HashMap<Arc<str>, u32>,
+}
+
+impl Default for MmapBuilder {
+    fn default() -> Self {
+        MmapBuilder {
+            records: Records::Memory(Vec::new()),
+            count: 0,
+            ordered: true,
+            last_key: None,
+            strings: Vec::new(),
+            offsets: HashMap::new(),
+        }
+    }
+}
+
+impl MmapBuilder {
+    // Builder of the snapshot at `path`, keeping the records on disk until
+    // it is written
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("ranges");
+        let tmp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+        // Readable too, to be sorted through a map
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(&tmp)?;
+        let mut file = io::BufWriter::new(file);
+        file.write_all(MAGIC)?;
+        file.write_all(&0u64.to_le_bytes())?;
+        let mut builder = MmapBuilder::default();
+        builder.records = Records::File { file, tmp };
+        Ok(builder)
+    }
+
+    pub(crate) fn push(&mut self, asn: &Asn) -> io::Result<()> {
+        let mut record = [0u8; RECORD_SIZE];
+        record[..KEY_SIZE].copy_from_slice(&encode_ip(asn.first_ip));
+        record[KEY_SIZE..33].copy_from_slice(&encode_ip(asn.last_ip)[1..]);
+        record[33..37].copy_from_slice(&asn.number.to_le_bytes());
+        record[37..41].copy_from_slice(&self.string_offset(&asn.country).to_le_bytes());
+        record[41..45].copy_from_slice(&self.string_offset(&asn.description).to_le_bytes());
+        let key: [u8; KEY_SIZE] = record[..KEY_SIZE].try_into().unwrap();
+        self.ordered &= self.last_key.is_none_or(|last| last < key);
+        self.last_key = Some(key);
+        self.count += 1;
+        match &mut self.records {
+            Records::Memory(records) => records.push(record),
+            Records::File { file, .. } => file.write_all(&record)?,
+        }
+        Ok(())
+    }
+
+    fn string_offset(&mut self, s: &Arc<str>) -> u32 {
+        if let Some(&offset) = self.offsets.get(s) {
+            return offset;
+        }
+        let offset = self.strings.len() as u32;
+        let mut bytes = s.as_bytes();
+        if bytes.len() > u16::MAX as usize {
+            let mut end = u16::MAX as usize;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            bytes = &bytes[..end];
+        }
+        self.strings.extend_from_slice(&(bytes.len() as u16).to_le_bytes());
+        self.strings.extend_from_slice(bytes);
+        self.offsets.insert(s.clone(), offset);
+        offset
+    }
+
+    // The snapshot in memory, e.g. to replicate it to standby instances
+    pub(crate) fn into_bytes(mut self) -> Vec<u8> {
+        let Records::Memory(records) = &mut self.records else {
+            unreachable!("into_bytes on a builder of a snapshot file");
+        };
+        if !self.ordered {
+            self.count = sort(records);
+        }
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + self.count * RECORD_SIZE + self.strings.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(self.count as u64).to_le_bytes());
+        for record in records.iter() {
+            bytes.extend_from_slice(record);
+        }
+        bytes.extend_from_slice(&self.strings);
+        bytes
+    }
+
+    // Complete the temporary file and rename it into place, so that a
+    // snapshot still mapped by the previous database is never modified.
+    pub(crate) fn write(mut self, path: &Path) -> io::Result<MmapRanges> {
+        let Records::File { file, tmp } = std::mem::replace(&mut self.records, Records::Memory(Vec::new())) else {
+            unreachable!("write on a builder of an in-memory snapshot");
+        };
+        let result = (|| {
+            let mut file = file.into_inner().map_err(|e| e.into_error())?;
+            if !self.ordered {
+                self.count = sort_file(&file, self.count)?;
+                file.set_len((HEADER_SIZE + self.count * RECORD_SIZE) as u64)?;
+            }
+            file.seek(SeekFrom::Start(8))?;
+            file.write_all(&(self.count as u64).to_le_bytes())?;
+            file.seek(SeekFrom::End(0))?;
+            file.write_all(&self.strings)?;
+            file.sync_all()?;
+            fs::rename(&tmp, path)
+        })();
+        if let Err(e) = result {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        MmapRanges::open(path)
+    }
+}
+
+impl Drop for MmapBuilder {
+    // A parse that failed or was cancelled leaves no temporary file
+    fn drop(&mut self) {
+        if let Records::File { tmp, .. } = &self.records {
+            let _ = fs::remove_file(tmp);
+        }
+    }
+}
+
+// Same semantics as the BTreeSet of the memory backend: ordered by first
+// address, and the first of several ranges with the same first address
+// wins. Returns the number of records left.
+fn sort(records: &mut Vec<Record>) -> usize {
+    records.sort_by(|a, b| a[..KEY_SIZE].cmp(&b[..KEY_SIZE]));
+    records.dedup_by(|b, a| a[..KEY_SIZE] == b[..KEY_SIZE]);
+    records.len()
+}
+
+// Sort the records of a snapshot file in place, through a writable map
+// rather than on the heap
+fn sort_file(file: &File, count: usize) -> io::Result<usize> {
+    // SAFETY: the temporary file is private to this process
+    let mut map = unsafe { MmapMut::map_mut(file)? };
+    let bytes = &mut map[HEADER_SIZE..HEADER_SIZE + count * RECORD_SIZE];
+    // SAFETY: records are byte arrays, without alignment requirements
+    let records = unsafe { std::slice::from_raw_parts_mut(bytes.as_mut_ptr() as *mut Record, count) };
+    records.sort_by(|a, b| a[..KEY_SIZE].cmp(&b[..KEY_SIZE]));
+    let mut kept = 0;
+    for i in 0..count {
+        if kept == 0 || records[kept - 1][..KEY_SIZE] != records[i][..KEY_SIZE] {
+            records[kept] = records[i];
+            kept += 1;
+        }
+    }
+    map.flush()?;
+    Ok(kept)
+}
+
+// Start of the string table, after the records of the header's count. None
+// for a snapshot truncated within its records.
+fn strings_start(bytes: &[u8]) -> Option<usize> {
+    let count = u64::from_le_bytes(bytes.get(8..HEADER_SIZE)?.try_into().ok()?);
+    usize::try_from(count)
+        .ok()
+        .and_then(|count| count.checked_mul(RECORD_SIZE))
+        .and_then(|size| size.checked_add(HEADER_SIZE))
+        .filter(|&end| end <= bytes.len())
+}
+
+// Ranges of a snapshot received from another instance. Unlike a mapped
+// snapshot, it comes from the network, so every offset is checked, and the
+// records must be in the order the builder writes them.
+pub(crate) fn decode(bytes: &[u8]) -> io::Result<Vec<Asn>> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid ranges snapshot: {}", what));
+    if bytes.len() < HEADER_SIZE || &bytes[..8] != MAGIC {
+        return Err(invalid("unknown format version"));
+    }
+    let strings_start = strings_start(bytes).ok_or_else(|| invalid("truncated records"))?;
+    let strings = &bytes[strings_start..];
+    // Strings are shared by many records, so each is decoded once
+    let mut decoded: HashMap<u32, Arc<str>> = HashMap::new();
+    let mut string = |offset: u32| -> io::Result<Arc<str>> {
+        if let Some(s) = decoded.get(&offset) {
+            return Ok(s.clone());
+        }
+        let start = offset as usize;
+        let len = strings
+            .get(start..start + 2)
+            .map(|len| u16::from_le_bytes([len[0], len[1]]) as usize)
+            .ok_or_else(|| invalid("string offset out of bounds"))?;
+        let s = strings
+            .get(start + 2..start + 2 + len)
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+            .ok_or_else(|| invalid("invalid string"))?;
+        let s: Arc<str> = Arc::from(s);
+        decoded.insert(offset, s.clone());
+        Ok(s)
+    };
+    let mut ranges = Vec::with_capacity(strings_start / RECORD_SIZE);
+    let mut previous: Option<&[u8]> = None;
+    for record in bytes[HEADER_SIZE..strings_start].chunks_exact(RECORD_SIZE) {
+        if !matches!(record[0], 4 | 6) {
+            return Err(invalid("unknown address family"));
+        }
+        if previous.is_some_and(|previous| previous >= &record[..KEY_SIZE]) {
+            return Err(invalid("records out of order"));
+        }
+        previous = Some(&record[..KEY_SIZE]);
+        let mut last = [0u8; KEY_SIZE];
+        last[0] = record[0];
+        last[1..].copy_from_slice(&record[KEY_SIZE..33]);
+        ranges.push(Asn {
+            first_ip: decode_ip(&record[..KEY_SIZE]),
+            last_ip: decode_ip(&last),
+            number: read_u32(&record[33..37]),
+            country: string(read_u32(&record[37..41]))?,
+            description: string(read_u32(&record[41..45]))?,
+        });
+    }
+    Ok(ranges)
+}
+
+pub(crate) struct MmapRanges {
+    map: Mmap,
+    count: usize,
+    // The strings of the snapshot by offset, decoded once, so that lookups
+    // don't allocate
+    strings: HashMap<u32, Arc<str>>,
+    empty: Arc<str>,
+}
+
+impl MmapRanges {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: snapshots are only ever replaced by renaming a new file
+        // over them, never modified in place, so the mapping stays valid.
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid ranges snapshot {}: {}", path.display(), what),
+            )
+        };
+        if map.len() < HEADER_SIZE || &map[..8] != MAGIC {
+            return Err(invalid("unknown format version"));
+        }
+        let start = strings_start(&map).ok_or_else(|| invalid("truncated records"))?;
+        let count = (start - HEADER_SIZE) / RECORD_SIZE;
+        let mut strings = HashMap::new();
+        let mut offset = 0;
+        let table = &map[start..];
+        while offset < table.len() {
+            let s = table
+                .get(offset..offset + 2)
+                .map(|len| 2 + u16::from_le_bytes([len[0], len[1]]) as usize)
+                .and_then(|end| table.get(offset + 2..offset + end))
+                .ok_or_else(|| invalid("truncated strings"))?;
+            strings.insert(offset as u32, Arc::from(String::from_utf8_lossy(s).as_ref()));
+            offset += 2 + s.len();
+        }
+        Ok(MmapRanges {
+            map,
+            count,
+            strings,
+            empty: Arc::from(""),
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+
+    fn record(&self, i: usize) -> &[u8] {
+        let start = HEADER_SIZE + i * RECORD_SIZE;
+        &self.map[start..start + RECORD_SIZE]
+    }
+
+    fn string(&self, offset: u32) -> Arc<str> {
+        self.strings.get(&offset).unwrap_or(&self.empty).clone()
+    }
+
+    pub(crate) fn get(&self, i: usize) -> Asn {
+        let record = self.record(i);
+        let mut last = [0u8; KEY_SIZE];
+        last[0] = record[0];
+        last[1..].copy_from_slice(&record[KEY_SIZE..33]);
+        Asn {
+            first_ip: decode_ip(&record[..KEY_SIZE]),
+            last_ip: decode_ip(&last),
+            number: read_u32(&record[33..37]),
+            country: self.string(read_u32(&record[37..41])),
+            description: self.string(read_u32(&record[41..45])),
+        }
+    }
+
+    // Number of ranges with a first address <= ip
+    fn count_up_to(&self, ip: IpAddr) -> usize {
+        let key = encode_ip(ip);
+        let (mut lo, mut hi) = (0, self.count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.record(mid)[..KEY_SIZE] <= key[..] {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    // Range with the greatest first address <= ip
+    pub(crate) fn find(&self, ip: IpAddr) -> Option<Asn> {
+        self.count_up_to(ip).checked_sub(1).map(|i| self.get(i))
+    }
+
+    // Ranges that may overlap [first, last]: from the one containing or
+    // preceding `first` to the last one starting at or before `last`
+    pub(crate) fn between(&self, first: IpAddr, last: IpAddr) -> impl Iterator<Item = Asn> + '_ {
+        let start = self.count_up_to(first).saturating_sub(1);
+        let end = self.count_up_to(last);
+        (start..end.max(start)).map(|i| self.get(i))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = Asn> + '_ {
+        (0..self.count).map(|i| self.get(i))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asn(first: [u8; 4], last: [u8; 4], number: u32, description: &str) -> Asn {
+        Asn {
+            first_ip: IpAddr::from(first),
+            last_ip: IpAddr::from(last),
+            number,
+            country: Arc::from("US"),
+            description: Arc::from(description),
+        }
+    }
+
+    #[test]
+    fn snapshot_file_sorts_records_on_disk() {
+        let path = std::env::temp_dir().join(format!("iptoasn-mmap-{}.ranges", std::process::id()));
+        let mut builder = MmapBuilder::create(&path).unwrap();
+        builder.push(&asn([2, 0, 0, 0], [2, 0, 0, 255], 64502, "TWO")).unwrap();
+        builder.push(&asn([1, 0, 0, 0], [1, 0, 0, 255], 64501, "ONE")).unwrap();
+        // The first of several ranges with the same first address wins
+        builder.push(&asn([1, 0, 0, 0], [1, 0, 0, 127], 64503, "ONE AGAIN")).unwrap();
+        let ranges = builder.write(&path).unwrap();
+        assert_eq!(ranges.len(), 2);
+        let found = ranges.find("1.0.0.10".parse().unwrap()).unwrap();
+        assert_eq!((found.number, found.description.as_ref()), (64501, "ONE"));
+        let found = ranges.find("2.0.0.10".parse().unwrap()).unwrap();
+        assert_eq!((found.number, found.description.as_ref()), (64502, "TWO"));
+
+        // A snapshot cut within its records or its strings is refused
+        let bytes = fs::read(&path).unwrap();
+        for len in [HEADER_SIZE + RECORD_SIZE, bytes.len() - 1] {
+            fs::write(&path, &bytes[..len]).unwrap();
+            assert!(MmapRanges::open(&path).is_err());
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::router::{self, Resolution};
+use serde::Serialize;
+
+// Methods and media types of a route, for strict content negotiation
+// (strict_negotiation in the config file). All come from the router table.
+#[derive(Serialize)]
+pub struct RouteMedia {
+    // Methods of all the routes at the path
+    pub methods: Vec<&'static str>,
+    // Media types of the responses
+    pub produces: &'static [&'static str],
+    // Media types of request bodies, empty for routes without a body
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    pub consumes: &'static [&'static str],
+}
+
+// Route of a request (path without the base path), if any
+pub fn route(method: &str, path: &str) -> Option<RouteMedia> {
+    let Resolution::Found(route, _) = router::resolve(method, path) else {
+        return None;
+    };
+    let entry = route.entry();
+    Some(RouteMedia {
+        methods: router::methods(path),
+        produces: entry.produces,
+        consumes: entry.consumes,
+    })
+}
+
+// Media type of a header value, without parameters, in lowercase
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+impl RouteMedia {
+    // Whether one of the media ranges of an Accept header matches a media
+    // type the route produces. Ranges with q=0 are refused.
+    pub fn acceptable(&self, accept: &str) -> bool {
+        accept.split(',').any(|range| {
+            let refused = range.split(';').skip(1).any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            let range = media_type(range);
+            !refused
+                && self
+                    .produces
+                    .iter()
+                    .any(|produced| match range.strip_suffix("/*") {
+                        Some("*") => true,
+                        Some(main) => produced.split('/').next() == Some(main),
+                        None => range == *produced,
+                    })
+        })
+    }
+
+    // Whether the route accepts a request body of that Content-Type
+    pub fn consumable(&self, content_type: &str) -> bool {
+        self.consumes.contains(&media_type(content_type).as_str())
+    }
+}
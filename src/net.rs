@@ -0,0 +1,239 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::future::poll_fn;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+
+// File descriptors kept aside for the database, cache file, logging and
+// outgoing connections, on top of one per accepted connection.
+const RESERVED_FDS: u64 = 64;
+
+pub const DEFAULT_PORT: u16 = 53661;
+
+// How long binding is retried while the address is unavailable (--bind-wait)
+static BIND_WAIT_MS: AtomicU64 = AtomicU64::new(0);
+
+const BIND_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+// Where the service accepts connections.
+#[derive(Clone, Copy, Debug)]
+pub enum Listen {
+    // A single address (--listen). An IPv6 wildcard address such as [::]
+    // also accepts IPv4 clients, unless v6only (--ipv6-only).
+    Addr { addr: SocketAddr, v6only: bool },
+    // The IPv4 and IPv6 loopback addresses, the default
+    Loopback { port: u16 },
+    // All IPv4 and IPv6 addresses (--listen-any)
+    Any { port: u16 },
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listen::Addr { addr, v6only: true } => write!(f, "{} (IPv6 only)", addr),
+            Listen::Addr { addr, .. } => write!(f, "{}", addr),
+            Listen::Loopback { port } => write!(f, "127.0.0.1:{} and [::1]:{}", port, port),
+            Listen::Any { port } => write!(f, "0.0.0.0:{} and [::]:{}", port, port),
+        }
+    }
+}
+
+// Bound listeners of a Listen, accepted from as one.
+pub struct Listeners {
+    listeners: Vec<TcpListener>,
+    // Listener polled first by the next accept, so that none is starved
+    next: AtomicUsize,
+}
+
+// Bound addresses, e.g. "127.0.0.1:53661, [::1]:53661"
+impl fmt::Display for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let addrs: Vec<String> = self
+            .listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .map(|addr| addr.to_string())
+            .collect();
+        f.write_str(&addrs.join(", "))
+    }
+}
+
+impl Listeners {
+    // Accept a connection on any of the listeners. IPv4 clients of a
+    // dual-stack socket are reported with their IPv4 address rather than an
+    // IPv4-mapped IPv6 one.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let (tcp, addr) = poll_fn(|cx| {
+            let count = self.listeners.len();
+            for i in 0..count {
+                if let Poll::Ready(result) = self.listeners[(start + i) % count].poll_accept(cx) {
+                    return Poll::Ready(result);
+                }
+            }
+            Poll::Pending
+        })
+        .await?;
+        Ok((tcp, SocketAddr::new(addr.ip().to_canonical(), addr.port())))
+    }
+}
+
+// Bind the listeners of a Listen with an explicit accept backlog.
+pub fn bind(listen: Listen, backlog: u32) -> io::Result<Listeners> {
+    let listeners = match listen {
+        Listen::Addr { addr, v6only } => {
+            let (listener, dual_stack) = bind_listener(addr, backlog, v6only)?;
+            if addr.ip().is_unspecified() && addr.is_ipv6() && !v6only && !dual_stack {
+                log::warn!(
+                    "Dual-stack sockets are not supported, only IPv6 clients can connect to {}; use --listen-any to accept both",
+                    addr
+                );
+            }
+            vec![listener]
+        }
+        Listen::Loopback { port } => {
+            let v4 = bind_listener((Ipv4Addr::LOCALHOST, port).into(), backlog, false)?.0;
+            // Hosts without IPv6 still serve IPv4 clients
+            match bind_listener((Ipv6Addr::LOCALHOST, port).into(), backlog, true) {
+                Ok((v6, _)) => vec![v4, v6],
+                Err(e) => {
+                    log::info!("Not listening on [::1]:{}: {}", port, e);
+                    vec![v4]
+                }
+            }
+        }
+        Listen::Any { port } => bind_any(port, backlog)?,
+    };
+    Ok(Listeners {
+        listeners,
+        next: AtomicUsize::new(0),
+    })
+}
+
+pub fn set_bind_wait(wait: Duration) {
+    BIND_WAIT_MS.store(wait.as_millis() as u64, Ordering::Relaxed);
+}
+
+// Bind the listeners of a Listen, retrying for up to --bind-wait while the
+// address is in use, e.g. by a previous instance still shutting down, or not
+// assigned to an interface yet.
+pub async fn bind_waiting(listen: Listen, backlog: u32) -> io::Result<Listeners> {
+    let deadline = Instant::now() + Duration::from_millis(BIND_WAIT_MS.load(Ordering::Relaxed));
+    let mut logged = false;
+    loop {
+        match bind(listen, backlog) {
+            Err(e)
+                if matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable)
+                    && Instant::now() < deadline =>
+            {
+                if !logged {
+                    log::info!("Unable to bind to {} yet ({}), retrying", listen, e);
+                    logged = true;
+                }
+                tokio::time::sleep(BIND_RETRY_INTERVAL).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+// A single dual-stack socket where supported, one socket per address family
+// where IPV6_V6ONLY can't be cleared (e.g. OpenBSD), and IPv4 only on hosts
+// without IPv6.
+fn bind_any(port: u16, backlog: u32) -> io::Result<Vec<TcpListener>> {
+    match bind_listener((Ipv6Addr::UNSPECIFIED, port).into(), backlog, false) {
+        Ok((listener, true)) => Ok(vec![listener]),
+        Ok((v6, false)) => {
+            let v4 = bind_listener((Ipv4Addr::UNSPECIFIED, port).into(), backlog, false)?.0;
+            Ok(vec![v4, v6])
+        }
+        Err(e) => {
+            log::info!("Not listening on [::]:{}: {}", port, e);
+            Ok(vec![bind_listener((Ipv4Addr::UNSPECIFIED, port).into(), backlog, false)?.0])
+        }
+    }
+}
+
+// Bind a TCP listener with an explicit accept backlog. IPv6 sockets get
+// IPV6_V6ONLY set explicitly, as its default differs across platforms
+// (off on Linux, on on Windows and the BSDs). Returns whether the socket
+// also accepts IPv4 clients.
+fn bind_listener(addr: SocketAddr, backlog: u32, v6only: bool) -> io::Result<(TcpListener, bool)> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    let dual_stack = match addr {
+        SocketAddr::V4(_) => false,
+        SocketAddr::V6(_) => socket.set_only_v6(v6only).is_ok() && !socket.only_v6().unwrap_or(true),
+    };
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog.min(i32::MAX as u32) as i32)?;
+    Ok((TcpListener::from_std(socket.into())?, dual_stack))
+}
+
+// Pause after an accept error. When file descriptors are exhausted, the
+// pending connection stays in the backlog and accept() fails again
+// immediately, so retrying right away would spin and flood the logs.
+pub async fn accept_error_backoff(e: &io::Error) {
+    let delay = match e.raw_os_error() {
+        #[cfg(unix)]
+        Some(libc::EMFILE) | Some(libc::ENFILE) => Duration::from_millis(100),
+        _ => Duration::from_millis(1),
+    };
+    tokio::time::sleep(delay).await;
+}
+
+// Raise the soft RLIMIT_NOFILE limit up to the hard limit, and warn if it is
+// still too low to hold `max_connections` simultaneous connections.
+#[cfg(unix)]
+pub fn check_fd_limit(max_connections: Option<u64>) {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim) } != 0 {
+        log::warn!("Unable to read RLIMIT_NOFILE: {}", io::Error::last_os_error());
+        return;
+    }
+    if rlim.rlim_cur < rlim.rlim_max {
+        let raised = libc::rlimit {
+            rlim_cur: rlim.rlim_max,
+            rlim_max: rlim.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            log::info!(
+                "Raised open file limit from {} to {}",
+                rlim.rlim_cur,
+                rlim.rlim_max
+            );
+            rlim = raised;
+        } else {
+            log::warn!(
+                "Unable to raise open file limit from {} to {}: {}",
+                rlim.rlim_cur,
+                rlim.rlim_max,
+                io::Error::last_os_error()
+            );
+        }
+    }
+    // Without a connection cap there's nothing to compare the limit to
+    let Some(connections) = max_connections else {
+        return;
+    };
+    let required = connections.saturating_add(RESERVED_FDS);
+    if rlim.rlim_cur < required {
+        log::warn!(
+            "Open file limit ({}) is too low for {} connections; at least {} is recommended (ulimit -n)",
+            rlim.rlim_cur,
+            connections,
+            required
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub fn check_fd_limit(_max_connections: Option<u64>) {}
@@ -0,0 +1,47 @@
+use crate::config::NormalizationConfig;
+use crate::country;
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+// Normalization of the fields of the database at parse time (set_options)
+static OPTIONS: OnceLock<NormalizationConfig> = OnceLock::new();
+
+// Set the normalization of the databases loaded from now on, e.g. from the
+// [normalization] table of the config file. Must be called before the
+// database is loaded.
+pub fn set_options(options: NormalizationConfig) {
+    let _ = OPTIONS.set(options);
+}
+
+// Country code in uppercase, if enabled. Corrections apply afterwards.
+pub fn country(code: &str) -> Cow<'_, str> {
+    match OPTIONS.get() {
+        Some(options) if options.uppercase_countries && code.bytes().any(|b| b.is_ascii_lowercase()) => {
+            Cow::Owned(code.to_ascii_uppercase())
+        }
+        _ => Cow::Borrowed(code),
+    }
+}
+
+// Description without a trailing country code (", US") and capped to
+// max_description_length characters, if enabled
+pub fn description(description: &str) -> Cow<'_, str> {
+    let Some(options) = OPTIONS.get() else {
+        return Cow::Borrowed(description);
+    };
+    let mut description = description;
+    if options.strip_country_suffix {
+        if let Some((name, code)) = description.rsplit_once(", ") {
+            if code.len() == 2 && country::is_known(&code.to_ascii_uppercase()) {
+                description = name.trim_end();
+            }
+        }
+    }
+    if let Some((end, _)) = options
+        .max_description_length
+        .and_then(|max| description.char_indices().nth(max))
+    {
+        description = description[..end].trim_end();
+    }
+    Cow::Borrowed(description)
+}
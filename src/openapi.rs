@@ -0,0 +1,533 @@
+use crate::auth;
+use crate::deprecation;
+use crate::render::MEDIA_TYPES;
+use crate::router::{self, Route};
+use crate::webservice::WebService;
+use serde_json::{json, Map, Value};
+
+// JSON response body of an operation, in components/schemas
+enum Schema {
+    One(&'static str),
+    List(&'static str),
+}
+
+// Documentation of the operation of a route
+struct Operation {
+    id: &'static str,
+    summary: &'static str,
+    // Query parameters, with their description
+    query: &'static [(&'static str, &'static str)],
+    schema: Option<Schema>,
+}
+
+const SORT_ASNS: (&str, &str) = ("sort", "asn (default) or -asn for descending AS numbers");
+const SORT_SUBNETS: (&str, &str) = ("sort", "prefix (default), or size for the largest blocks first");
+
+fn operation(route: Route) -> Operation {
+    let (id, summary, query, schema): (_, _, &[_], _) = match route {
+        Route::Index => (
+            "index",
+            "Service banner, or with Accept: application/json the capabilities of the service",
+            &[],
+            Some(Schema::One("Capabilities")),
+        ),
+        Route::BulkPage => ("bulkPage", "Bulk lookup form", &[], None),
+        Route::ClientIp => (
+            "lookupClientIp",
+            "Look up the IP address of the client",
+            &[],
+            Some(Schema::One("IpLookupResponse")),
+        ),
+        Route::Ip => (
+            "lookupIp",
+            "Look up an IP address",
+            &[("extract", "true to look up the address found in a URL or host:port")],
+            Some(Schema::One("IpLookupResponse")),
+        ),
+        Route::IpHistory => (
+            "ipHistory",
+            "Origin AS of an IP address in each retained database snapshot",
+            &[],
+            None,
+        ),
+        Route::Ips => (
+            "lookupIps",
+            "Look up a list of IP addresses",
+            &[("order", "input (default) or sorted")],
+            Some(Schema::List("IpLookupResponse")),
+        ),
+        Route::IpsStream => (
+            "streamIps",
+            "Look up IP addresses sent one per line, as server-sent events while the body is being sent",
+            &[],
+            None,
+        ),
+        Route::AnnotateLog => (
+            "annotateLog",
+            "Annotate the client IP address of each line of a log",
+            &[
+                ("format", "auto (default), apache, nginx or syslog"),
+                ("description", "true to include the AS description"),
+                ("output", "ndjson for one JSON record per line"),
+                ("country", "Keep only the lines of clients in that country"),
+                ("asn", "Keep only the lines of clients in that AS"),
+            ],
+            None,
+        ),
+        Route::MissingAsn => ("missingAsn", "Error: the AS number is missing", &[], None),
+        Route::Asns => (
+            "listAsns",
+            "List all known AS numbers",
+            &[SORT_ASNS],
+            Some(Schema::List("AsNameResponse")),
+        ),
+        Route::AsSearch => (
+            "searchAsns",
+            "Find AS numbers by description",
+            &[("q", "Text contained in the description, case-insensitively")],
+            Some(Schema::List("AsNameResponse")),
+        ),
+        Route::AsSubnets => (
+            "asSubnets",
+            "List the subnets of an AS number",
+            &[
+                ("format", "bird, frr, junos or ios for a router prefix-list"),
+                SORT_SUBNETS,
+            ],
+            Some(Schema::One("AsSubnetsResponse")),
+        ),
+        Route::AsCountries => (
+            "asCountries",
+            "Registry countries of the ranges of an AS number, with their prefix counts",
+            &[],
+            Some(Schema::One("AsCountriesResponse")),
+        ),
+        Route::As => (
+            "lookupAs",
+            "Look up an AS number",
+            &[],
+            Some(Schema::One("AsNameResponse")),
+        ),
+        Route::Prefix => (
+            "lookupPrefix",
+            "Look up the announced ranges and AS numbers of a prefix",
+            &[],
+            None,
+        ),
+        Route::Range => (
+            "lookupRange",
+            "Look up the announced ranges and AS numbers of an inclusive range, with its CIDR prefixes",
+            &[("first", "First IP address of the range"), ("last", "Last IP address of the range")],
+            None,
+        ),
+        Route::CountrySubnets => (
+            "countrySubnets",
+            "List the subnets of a country",
+            &[SORT_SUBNETS],
+            None,
+        ),
+        Route::Country => ("countryAsns", "List the AS numbers of a country", &[], None),
+        Route::ClientGeoIp => ("geoipClient", "Look up the client, ipinfo.io style", &[], None),
+        Route::GeoIp => ("geoip", "Look up an IP address, ipinfo.io style", &[], None),
+        Route::Dnsbl => (
+            "dnsbl",
+            "DNSBL-style answer for a reversed IP address, optionally followed by a zone",
+            &[],
+            Some(Schema::One("DnsblResponse")),
+        ),
+        Route::StatsTimeseries => (
+            "statsTimeseries",
+            "Prefix count or address space of an AS number or a country in each retained database snapshot",
+            &[
+                ("asn", "AS number, e.g. 13335 or AS13335"),
+                ("country", "Country code, instead of asn"),
+                ("metric", "prefix_count (default), ipv4_addresses, or ipv6_64s for IPv6 /64 networks"),
+            ],
+            None,
+        ),
+        Route::Metrics => ("metrics", "Prometheus metrics", &[], None),
+        Route::DbStatus => ("dbStatus", "Status of the loaded database", &[], None),
+        Route::Limits => (
+            "limits",
+            "Limits enforced on requests, and the quota of the caller's tenant",
+            &[],
+            Some(Schema::One("LimitsResponse")),
+        ),
+        Route::AdminUsage => ("adminUsage", "Usage of each tenant (admin token only)", &[], None),
+        Route::AdminStatus => (
+            "adminStatus",
+            "Database, refresh and process status (admin token only)",
+            &[],
+            Some(Schema::One("AdminStatus")),
+        ),
+        Route::AdminRefresh => (
+            "adminRefresh",
+            "Start a database refresh (admin token only)",
+            &[("wait", "true to return the operation once finished")],
+            None,
+        ),
+        Route::AdminRefreshStatus => (
+            "adminRefreshStatus",
+            "Status of a database refresh (admin token only)",
+            &[],
+            None,
+        ),
+        Route::ReplicationSnapshot => (
+            "replicationSnapshot",
+            "Binary snapshot of the database, for standby instances (replication token)",
+            &[],
+            None,
+        ),
+        Route::ReplicationStage => (
+            "replicationStage",
+            "Validate and stage a snapshot pushed by the primary (replication token)",
+            &[],
+            Some(Schema::One("ReplicationResponse")),
+        ),
+        Route::ReplicationActivate => (
+            "replicationActivate",
+            "Serve the staged snapshot of a generation (replication token)",
+            &[],
+            Some(Schema::One("ReplicationResponse")),
+        ),
+        Route::OpenApi => ("openapi", "This document", &[], None),
+        Route::Version => (
+            "version",
+            "Version, git commit, build date and features of the server",
+            &[],
+            Some(Schema::One("BuildInfo")),
+        ),
+    };
+    Operation {
+        id,
+        summary,
+        query,
+        schema,
+    }
+}
+
+fn schemas() -> Value {
+    json!({
+        "IpLookupResponse": {
+            "type": "object",
+            "required": ["ip", "announced", "covered"],
+            "properties": {
+                "ip": {"type": "string"},
+                "announced": {"type": "boolean"},
+                "covered": {
+                    "type": "boolean",
+                    "description": "Whether the address is in a range of the dataset, including ranges listed as not routed (AS0)",
+                },
+                "first_ip": {"type": "string"},
+                "last_ip": {"type": "string"},
+                "as_number": {"$ref": "#/components/schemas/AsNumber"},
+                "as_country_code": {"type": "string"},
+                "as_description": {"type": "string"},
+                "as_description_ascii": {
+                    "type": "string",
+                    "description": "ASCII transliteration of a description that isn't ASCII",
+                },
+                "warnings": {"type": "array", "items": {"$ref": "#/components/schemas/LookupWarning"}},
+                "id": {"description": "Identifier sent with the address in NDJSON bulk requests"},
+                "index": {"type": "integer", "description": "Position of the address in a bulk request"},
+            },
+            "additionalProperties": {"description": "Field added by an enricher of the deployment"},
+        },
+        "AsNumber": {
+            "description": "AS number, a string such as \"3.10\" for 4-byte numbers with asn_notation=asdot",
+            "oneOf": [
+                {"type": "integer", "format": "int64", "minimum": 0},
+                {"type": "string", "pattern": "^[0-9]+\\.[0-9]+$"},
+            ],
+        },
+        "LookupWarning": {
+            "type": "object",
+            "required": ["code", "message"],
+            "properties": {
+                "code": {"type": "string", "enum": ["large_range", "few_ranges"]},
+                "message": {"type": "string"},
+            },
+        },
+        "AsNameResponse": {
+            "type": "object",
+            "required": ["as_number", "found", "as_country_code", "as_description"],
+            "properties": {
+                "as_number": {"$ref": "#/components/schemas/AsNumber"},
+                "found": {"type": "boolean", "description": "Whether the AS number is in the database"},
+                "as_country_code": {"type": "string"},
+                "as_description": {"type": "string"},
+                "as_description_ascii": {
+                    "type": "string",
+                    "description": "ASCII transliteration of a description that isn't ASCII",
+                },
+                "special_purpose": {"type": "string", "description": "IANA special-purpose registry entry"},
+            },
+        },
+        "BuildInfo": {
+            "type": "object",
+            "required": ["version", "git_commit", "build_date", "target", "features", "data_format_version"],
+            "properties": {
+                "version": {"type": "string"},
+                "git_commit": {"type": "string"},
+                "build_date": {"type": "string", "format": "date-time"},
+                "target": {"type": "string"},
+                "features": {"type": "array", "items": {"type": "string"}},
+                "data_format_version": {"type": "integer", "description": "Layout version of --backend mmap snapshots"},
+            },
+        },
+        "Capabilities": {
+            "type": "object",
+            "required": ["service", "build", "database", "endpoints", "limits", "output_types"],
+            "properties": {
+                "service": {"type": "string"},
+                "build": {"$ref": "#/components/schemas/BuildInfo"},
+                "database": {"type": "object", "description": "Same as GET /v1/db/status"},
+                "endpoints": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["method", "path", "produces"],
+                        "properties": {
+                            "method": {"type": "string"},
+                            "path": {"type": "string", "description": "Path template, base path included"},
+                            "produces": {"type": "array", "items": {"type": "string"}},
+                            "consumes": {"type": "array", "items": {"type": "string"}},
+                        },
+                    },
+                },
+                "limits": {"$ref": "#/components/schemas/Limits"},
+                "output_types": {"type": "array", "items": {"type": "string"}},
+            },
+        },
+        "AdminStatus": {
+            "type": "object",
+            "required": ["entries", "asns", "countries", "source", "db_source", "generation", "updated", "started", "uptime_seconds"],
+            "properties": {
+                "entries": {"type": "integer", "description": "Ranges of the loaded database"},
+                "asns": {"type": "integer", "description": "Distinct ASNs announcing ranges"},
+                "countries": {"type": "integer", "description": "Distinct country codes of announced ranges"},
+                "source": {"type": "string"},
+                "db_source": {"type": "string", "enum": ["network", "cache", "file", "replica"]},
+                "generation": {"type": "string"},
+                "updated": {"type": "string"},
+                "last_refresh": {"type": "string", "format": "date-time", "description": "End of the last successful refresh"},
+                "last_refresh_error": {"type": "string"},
+                "last_refresh_error_at": {"type": "string", "format": "date-time"},
+                "started": {"type": "string", "format": "date-time"},
+                "uptime_seconds": {"type": "integer"},
+                "resident_memory_bytes": {"type": "integer", "description": "Resident set size of the process, on Linux"},
+            },
+        },
+        "Limits": {
+            "type": "object",
+            "required": [
+                "max_body_bytes",
+                "max_decoded_body_bytes",
+                "max_request_timeout_ms",
+                "max_bulk_ips",
+                "max_page_size",
+                "prefix_max_ranges",
+            ],
+            "properties": {
+                "max_body_bytes": {"type": "integer", "description": "Maximum size of a request body as sent"},
+                "max_decoded_body_bytes": {"type": "integer", "description": "Maximum size of a compressed request body once decompressed"},
+                "max_request_timeout_ms": {"type": "integer", "description": "Upper bound of the X-Request-Timeout header of bulk requests"},
+                "max_bulk_ips": {"type": "integer", "description": "Maximum number of addresses of a bulk lookup"},
+                "max_page_size": {"type": "integer", "description": "Maximum limit parameter of the list routes"},
+                "prefix_max_ranges": {"type": "integer", "description": "Maximum number of ranges listed by /v1/as/prefix"},
+            },
+        },
+        "LimitsResponse": {
+            "allOf": [
+                {"$ref": "#/components/schemas/Limits"},
+                {
+                    "type": "object",
+                    "properties": {
+                        "max_in_flight_per_connection": {"type": "integer", "description": "Requests in flight on a connection above which requests get a 503"},
+                        "tenant": {"type": "string", "description": "Tenant of the API key of the request"},
+                        "monthly_quota": {"type": "integer", "description": "IP lookups allowed to the tenant per calendar month (UTC)"},
+                        "remaining": {"type": "integer", "description": "IP lookups left to the tenant this month"},
+                        "quota_reset_seconds": {"type": "integer", "description": "Time until the monthly quota is reset"},
+                    },
+                },
+            ],
+        },
+        "AsCountriesResponse": {
+            "type": "object",
+            "required": ["as_number", "countries"],
+            "properties": {
+                "as_number": {"$ref": "#/components/schemas/AsNumber"},
+                "countries": {
+                    "type": "array",
+                    "description": "Countries with the most prefixes first",
+                    "items": {
+                        "type": "object",
+                        "required": ["country_code", "ranges", "prefixes"],
+                        "properties": {
+                            "country_code": {"type": "string"},
+                            "ranges": {"type": "integer", "description": "Ranges of the dataset registered in the country"},
+                            "prefixes": {"type": "integer", "description": "CIDR prefixes those ranges make up"},
+                        },
+                    },
+                },
+            },
+        },
+        "DnsblResponse": {
+            "type": "object",
+            "required": ["query", "ip", "listed", "categories"],
+            "properties": {
+                "query": {"type": "string"},
+                "ip": {"type": "string"},
+                "listed": {"type": "boolean"},
+                "answer": {"type": "string", "description": "127.0.0.x A record, the codes of the matching categories ORed together"},
+                "categories": {"type": "array", "items": {"type": "string"}},
+                "as_number": {"$ref": "#/components/schemas/AsNumber"},
+                "as_country_code": {"type": "string"},
+            },
+        },
+        "ReplicationResponse": {
+            "type": "object",
+            "required": ["generation"],
+            "properties": {
+                "generation": {"type": "string"},
+                "entries": {"type": "integer", "format": "int64", "minimum": 0},
+            },
+        },
+        "AsSubnetsResponse": {
+            "type": "object",
+            "required": ["as_number", "subnets"],
+            "properties": {
+                "as_number": {"$ref": "#/components/schemas/AsNumber"},
+                "subnets": {"type": "array", "items": {"type": "string"}},
+                "total": {"type": "integer", "description": "Number of subnets of all pages, with ?offset= or ?limit="},
+                "offset": {"type": "integer"},
+                "limit": {"type": "integer"},
+                "next_offset": {"type": "integer", "description": "Offset of the next page, if there is one"},
+            },
+        },
+    })
+}
+
+// Request body of the routes taking one
+fn request_body(route: Route, consumes: &[&str]) -> Option<Value> {
+    let content: Map<String, Value> = consumes
+        .iter()
+        .map(|&media_type| {
+            let schema = match (route, media_type) {
+                (Route::Ips, "application/json") => json!({"type": "array", "items": {"type": "string"}}),
+                (_, "application/octet-stream") => json!({"type": "string", "format": "binary"}),
+                _ => json!({"type": "string"}),
+            };
+            (media_type.to_string(), json!({ "schema": schema }))
+        })
+        .collect();
+    (!content.is_empty()).then(|| json!({ "required": true, "content": content }))
+}
+
+fn parameters(route: Route, pattern: &str, operation: &Operation, produces: &[&str]) -> Vec<Value> {
+    let mut parameters: Vec<Value> = pattern
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix(':').or_else(|| segment.strip_prefix('*')))
+        .map(|name| json!({"name": name, "in": "path", "required": true, "schema": {"type": "string"}}))
+        .collect();
+    let mut query = operation.query.to_vec();
+    if route.is_list() {
+        query.push(("offset", "Number of items to skip"));
+        query.push(("limit", "Maximum number of items to return, up to max_page_size of /v1/limits"));
+    }
+    if produces == MEDIA_TYPES {
+        query.push(("sep", "Field separator of text/plain responses"));
+        query.push(("asn_notation", "asdot for AS numbers in asdot notation"));
+    }
+    parameters.extend(query.into_iter().map(|(name, description)| {
+        json!({"name": name, "in": "query", "description": description, "schema": {"type": "string"}})
+    }));
+    parameters
+}
+
+fn responses(operation: &Operation, produces: &[&str]) -> Value {
+    let content: Map<String, Value> = produces
+        .iter()
+        .map(|&media_type| {
+            let schema = match (&operation.schema, media_type) {
+                (Some(Schema::One(name)), "application/json") => {
+                    json!({"$ref": format!("#/components/schemas/{}", name)})
+                }
+                (Some(Schema::List(name)), "application/json") => {
+                    json!({"type": "array", "items": {"$ref": format!("#/components/schemas/{}", name)}})
+                }
+                (_, "application/json") => json!({"type": "object"}),
+                (_, "application/msgpack" | "application/octet-stream") => {
+                    json!({"type": "string", "format": "binary"})
+                }
+                _ => json!({"type": "string"}),
+            };
+            (media_type.to_string(), json!({ "schema": schema }))
+        })
+        .collect();
+    json!({
+        "200": {"description": operation.summary, "content": content},
+        "4XX": {"description": "Invalid request, or route not found"},
+    })
+}
+
+// Path of a route pattern in OpenAPI notation: {name} for both :name and
+// *name parameters
+pub(crate) fn path_template(pattern: &str) -> String {
+    let path: Vec<String> = pattern
+        .split('/')
+        .map(|segment| match segment.strip_prefix(':').or_else(|| segment.strip_prefix('*')) {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect();
+    path.join("/")
+}
+
+// OpenAPI 3 document of the routes of the router
+pub(crate) fn document() -> Value {
+    let mut paths = Map::new();
+    for entry in router::routes() {
+        let (method, pattern, route) = (entry.method, entry.pattern, entry.route);
+        let produces = entry.produces;
+        let operation = operation(route);
+        let mut op = json!({
+            "operationId": operation.id,
+            "summary": operation.summary,
+            "parameters": parameters(route, pattern, &operation, produces),
+            "responses": responses(&operation, produces),
+        });
+        if let Some(body) = request_body(route, entry.consumes) {
+            op["requestBody"] = body;
+        }
+        if deprecation::find(route, None).is_some() {
+            op["deprecated"] = json!(true);
+        }
+        if auth::is_enabled() && !route.is_public() {
+            op["security"] = json!([{"bearerKey": []}, {"headerKey": []}]);
+        }
+        let item = paths
+            .entry(path_template(pattern))
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[method.to_ascii_lowercase()] = op;
+    }
+    let base_path = WebService::base_path();
+    let mut components = json!({"schemas": schemas()});
+    if auth::is_enabled() {
+        components["securitySchemes"] = json!({
+            "bearerKey": {"type": "http", "scheme": "bearer"},
+            "headerKey": {"type": "apiKey", "in": "header", "name": "X-Api-Key"},
+        });
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "iptoasn-webservice",
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{"url": if base_path.is_empty() { "/" } else { base_path }}],
+        "paths": paths,
+        "components": components,
+    })
+}
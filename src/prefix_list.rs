@@ -0,0 +1,87 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+// Router configuration snippets built from the subnets of an AS, so that
+// generated filters can be pasted into router configs directly.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PrefixListFormat {
+    Bird,
+    Frr,
+    Junos,
+    Ios,
+}
+
+impl FromStr for PrefixListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bird" => Ok(PrefixListFormat::Bird),
+            "frr" => Ok(PrefixListFormat::Frr),
+            "junos" => Ok(PrefixListFormat::Junos),
+            "ios" => Ok(PrefixListFormat::Ios),
+            _ => Err(format!(
+                "Unsupported format [{}]. Use bird, frr, junos or ios",
+                s
+            )),
+        }
+    }
+}
+
+impl PrefixListFormat {
+    // Render the prefixes as prefix-lists (or BIRD prefix sets) named `name`,
+    // with a `<name>-IN` route-map matching them for FRR and IOS.
+    pub fn render(&self, name: &str, prefixes: &[String]) -> String {
+        let (v6, v4): (Vec<&String>, Vec<&String>) =
+            prefixes.iter().partition(|p| p.contains(':'));
+        let mut out = String::new();
+        match self {
+            PrefixListFormat::Bird => {
+                for (suffix, list) in [("V4", &v4), ("V6", &v6)] {
+                    // BIRD doesn't accept empty sets
+                    if list.is_empty() {
+                        continue;
+                    }
+                    let _ = writeln!(out, "define {}_{} = [", name, suffix);
+                    for (i, prefix) in list.iter().enumerate() {
+                        let sep = if i + 1 < list.len() { "," } else { "" };
+                        let _ = writeln!(out, "    {}{}", prefix, sep);
+                    }
+                    out.push_str("];\n");
+                }
+            }
+            PrefixListFormat::Frr | PrefixListFormat::Ios => {
+                for (family, list) in [("ip", &v4), ("ipv6", &v6)] {
+                    for (i, prefix) in list.iter().enumerate() {
+                        let _ = writeln!(
+                            out,
+                            "{} prefix-list {} seq {} permit {}",
+                            family,
+                            name,
+                            (i + 1) * 5,
+                            prefix
+                        );
+                    }
+                }
+                let mut seq = 10;
+                for (family, list) in [("ip", &v4), ("ipv6", &v6)] {
+                    if list.is_empty() {
+                        continue;
+                    }
+                    let _ = writeln!(out, "route-map {}-IN permit {}", name, seq);
+                    let _ = writeln!(out, " match {} address prefix-list {}", family, name);
+                    seq += 10;
+                }
+            }
+            PrefixListFormat::Junos => {
+                out.push_str("policy-options {\n");
+                let _ = writeln!(out, "    prefix-list {} {{", name);
+                for prefix in v4.iter().chain(v6.iter()) {
+                    let _ = writeln!(out, "        {};", prefix);
+                }
+                out.push_str("    }\n}\n");
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,363 @@
+use crate::auth;
+use crate::net;
+use crate::load;
+use crate::router::{self, Resolution};
+use crate::webservice::{WebService, DB_GENERATION, DB_UPDATED, DB_WARNING, TOTAL_COUNT};
+use http::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, ETAG, EXPIRES, IF_NONE_MATCH, RETRY_AFTER, VARY};
+use http::{HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+// Response headers passed through from the upstream instance
+const FORWARDED_HEADERS: [HeaderName; 10] = [
+    CONTENT_TYPE,
+    CACHE_CONTROL,
+    EXPIRES,
+    VARY,
+    RETRY_AFTER,
+    DB_GENERATION,
+    DB_UPDATED,
+    DB_WARNING,
+    TOTAL_COUNT,
+    ETAG,
+];
+
+// Back-off applied when the upstream rate limits us without a Retry-After header
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+// Longest back-off taken from an upstream Retry-After header
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(86_400);
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+    expires: Instant,
+}
+
+// Caching proxy mode: instead of loading the database, forward lookups to
+// another iptoasn-webservice instance and cache the responses for as long as
+// their Cache-Control header allows. While the upstream is rate limiting us,
+// stale cached responses are served, so tiny edge boxes can keep answering.
+pub struct Proxy {
+    upstream: String,
+    client: reqwest::Client,
+    cache: Mutex<HashMap<(String, String), CachedResponse>>,
+    max_entries: usize,
+    backoff_until: Mutex<Option<Instant>>,
+}
+
+impl Proxy {
+    pub async fn start(
+        upstream: String,
+        listen: net::Listen,
+        backlog: u32,
+        max_entries: usize,
+    ) -> Result<(), String> {
+        let listener = net::bind_waiting(listen, backlog)
+            .await
+            .map_err(|e| format!("Failed to bind to {}: {}", listen, e))?;
+        let proxy = Arc::new(Proxy {
+            upstream: upstream.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            cache: Mutex::new(HashMap::new()),
+            max_entries,
+            backoff_until: Mutex::new(None),
+        });
+
+        log::info!("webservice ready on {}, proxying to {}", listener, proxy.upstream);
+
+        loop {
+            let (tcp, remote_addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::error!("Failed to accept connection: {}", e);
+                    net::accept_error_backoff(&e).await;
+                    continue;
+                }
+            };
+            let Some(connection) = load::accept(&tcp, false).await else {
+                log::debug!("Too many connections, closed the one from {}", remote_addr);
+                continue;
+            };
+            let io = TokioIo::new(tcp);
+            let proxy = proxy.clone();
+
+            tokio::task::spawn(async move {
+                // Open until the connection is served
+                let _connection = connection;
+                let service = service_fn(move |req| {
+                    let proxy = proxy.clone();
+                    async move { proxy.handle_request(req, remote_addr).await }
+                });
+
+                if let Err(err) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(io, service)
+                    .await
+                {
+                    log::error!("Error serving connection: {:?}", err);
+                }
+            });
+        }
+    }
+
+    async fn handle_request(
+        &self,
+        req: Request<hyper::body::Incoming>,
+        remote_addr: SocketAddr,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let accept = req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let Some(path) = WebService::strip_base_path(req.uri().path()) else {
+            let mut response = Response::new(Full::new(Bytes::from("Not Found")));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        };
+        // The API keys are checked here, as responses are served from the cache
+        let method = if req.method() == Method::HEAD { "GET" } else { req.method().as_str() };
+        if matches!(router::resolve(method, path), Resolution::Found(route, _) if !route.is_public())
+            && !auth::authorized(req.headers())
+        {
+            let response = WebService::unauthorized();
+            return Ok(if req.method() == Method::HEAD { WebService::strip_body(response) } else { response });
+        }
+        let query = req.uri().query().map(|q| format!("?{}", q)).unwrap_or_default();
+
+        // Lookups of the client's own address must name it explicitly, or the
+        // upstream would answer for the address of this proxy.
+        let path = match path {
+            "/v1/as/ip" | "/geoip" => {
+                let client_ip = WebService::extract_client_ip(req.headers(), remote_addr);
+                format!("{}/{}{}", path, client_ip, query)
+            }
+            _ => format!("{}{}", path, query),
+        };
+
+        // The ETags of the upstream are answered from the cache too
+        let if_none_match = req.headers().get(IF_NONE_MATCH).cloned();
+        match *req.method() {
+            Method::GET => Ok(WebService::not_modified(
+                self.get(path, accept).await,
+                if_none_match.as_ref(),
+            )),
+            Method::HEAD => {
+                let response = WebService::not_modified(self.get(path, accept).await, if_none_match.as_ref());
+                Ok(if response.status() == StatusCode::NOT_MODIFIED {
+                    response
+                } else {
+                    WebService::strip_body(response)
+                })
+            }
+            Method::PUT => {
+                let content_type = req.headers().get(CONTENT_TYPE).cloned();
+                // Decompressed, within the limits of a local bulk lookup
+                let body = match WebService::read_body(req, WebService::max_body()).await {
+                    Ok(body) => body,
+                    Err((status, message)) => return Ok(WebService::body_error(status, &message, false)),
+                };
+                let mut request = self
+                    .client
+                    .put(format!("{}{}", self.upstream, path))
+                    .header(ACCEPT, accept)
+                    .body(body);
+                if let Some(content_type) = content_type {
+                    request = request.header(CONTENT_TYPE, content_type);
+                }
+                Ok(self.send(request).await.map(|(r, _)| r).unwrap_or_else(|r| r))
+            }
+            _ => {
+                let mut response = Response::new(Full::new(Bytes::from("Not Found")));
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                Ok(response)
+            }
+        }
+    }
+
+    async fn get(&self, path: String, accept: String) -> Response<Full<Bytes>> {
+        let key = (path, accept);
+        let now = Instant::now();
+
+        if let Some(response) = self.cached(&key, |cached| cached.expires > now) {
+            return response;
+        }
+        if self.backing_off(now) {
+            return self
+                .cached(&key, |_| true)
+                .unwrap_or_else(|| Self::rate_limited(self.retry_after(now)));
+        }
+
+        let request = self
+            .client
+            .get(format!("{}{}", self.upstream, key.0))
+            .header(ACCEPT, &key.1);
+        match self.send(request).await {
+            // A max-age too large for an Instant isn't cached at all
+            Ok((response, Some(ttl))) => {
+                if let Some(expires) = now.checked_add(ttl) {
+                    self.store(key, &response, expires).await;
+                }
+                response
+            }
+            Ok((response, None)) => response,
+            // Serve stale data rather than an error if we have it
+            Err(response) => self.cached(&key, |_| true).unwrap_or(response),
+        }
+    }
+
+    // Forward a request; returns the response and how long it may be cached,
+    // or an error response if the upstream is unreachable or rate limiting.
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<(Response<Full<Bytes>>, Option<Duration>), Response<Full<Bytes>>> {
+        let res = match request.send().await {
+            Ok(res) => res,
+            Err(e) => {
+                log::warn!("Upstream request failed: {}", e);
+                return Err(Self::error(StatusCode::BAD_GATEWAY, "Bad Gateway\n"));
+            }
+        };
+        let status = res.status();
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_RETRY_AFTER)
+                .min(MAX_RETRY_AFTER);
+            log::warn!(
+                "Upstream returned {}, backing off for {} seconds",
+                status,
+                retry_after.as_secs()
+            );
+            *self.backoff_until.lock().unwrap_or_else(PoisonError::into_inner) =
+                Some(Instant::now() + retry_after);
+            return Err(Self::rate_limited(retry_after));
+        }
+
+        let ttl = res
+            .headers()
+            .get(CACHE_CONTROL)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                v.split(',')
+                    .find_map(|d| d.trim().strip_prefix("max-age="))
+                    .and_then(|s| s.parse::<u64>().ok())
+            })
+            .filter(|&secs| secs > 0 && status.is_success())
+            .map(Duration::from_secs);
+        let headers: Vec<(HeaderName, HeaderValue)> = FORWARDED_HEADERS
+            .iter()
+            .filter_map(|name| res.headers().get(name).map(|v| (name.clone(), v.clone())))
+            .collect();
+        let body = match res.bytes().await {
+            Ok(body) => body,
+            Err(e) => {
+                log::warn!("Failed to read upstream response: {}", e);
+                return Err(Self::error(StatusCode::BAD_GATEWAY, "Bad Gateway\n"));
+            }
+        };
+
+        let mut response = Response::new(Full::new(body));
+        *response.status_mut() = status;
+        for (name, value) in headers {
+            response.headers_mut().insert(name, value);
+        }
+        Ok((response, ttl))
+    }
+
+    fn cached(
+        &self,
+        key: &(String, String),
+        usable: impl Fn(&CachedResponse) -> bool,
+    ) -> Option<Response<Full<Bytes>>> {
+        let cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        let cached = cache.get(key).filter(|cached| usable(cached))?;
+        let mut response = Response::new(Full::new(cached.body.clone()));
+        *response.status_mut() = cached.status;
+        for (name, value) in &cached.headers {
+            response.headers_mut().insert(name.clone(), value.clone());
+        }
+        Some(response)
+    }
+
+    async fn store(&self, key: (String, String), response: &Response<Full<Bytes>>, expires: Instant) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let body = match response.body().clone().collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(e) => match e {},
+        };
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        let mut cache = self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if cache.len() >= self.max_entries {
+            let now = Instant::now();
+            cache.retain(|_, cached| cached.expires > now);
+            if cache.len() >= self.max_entries {
+                cache.clear();
+            }
+        }
+        cache.insert(
+            key,
+            CachedResponse {
+                status: response.status(),
+                headers,
+                body,
+                expires,
+            },
+        );
+    }
+
+    fn backing_off(&self, now: Instant) -> bool {
+        matches!(
+            *self.backoff_until.lock().unwrap_or_else(PoisonError::into_inner),
+            Some(until) if until > now
+        )
+    }
+
+    fn retry_after(&self, now: Instant) -> Duration {
+        self.backoff_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .map(|until| until.saturating_duration_since(now))
+            .unwrap_or_default()
+    }
+
+    fn rate_limited(retry_after: Duration) -> Response<Full<Bytes>> {
+        let mut response = Self::error(StatusCode::SERVICE_UNAVAILABLE, "Service Unavailable\n");
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs().max(1)));
+        response
+    }
+
+    fn error(status: StatusCode, message: &'static str) -> Response<Full<Bytes>> {
+        let mut response = Response::new(Full::new(Bytes::from(message)));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        *response.status_mut() = status;
+        response
+    }
+}
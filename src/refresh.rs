@@ -0,0 +1,337 @@
+use crate::asns::{Asns, LoadProgress, ParseProgress};
+use crate::replication;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Instant;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::sync::watch;
+
+// Number of finished refresh operations kept for status polling
+const HISTORY_SIZE: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RefreshState {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Serialize)]
+pub struct RefreshStatus {
+    pub id: u64,
+    pub state: RefreshState,
+    pub started: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Entries of the database loaded by a successful refresh
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+}
+
+// Progress of the database download of a running refresh
+#[derive(Clone, Copy, Serialize)]
+pub struct DownloadProgress {
+    pub downloaded_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u64>,
+}
+
+impl DownloadProgress {
+    pub fn new(downloaded_bytes: u64, total_bytes: Option<u64>) -> Self {
+        DownloadProgress {
+            downloaded_bytes,
+            total_bytes,
+            percent: total_bytes
+                .filter(|&total| total > 0)
+                .map(|total| downloaded_bytes.min(total) * 100 / total),
+        }
+    }
+}
+
+// Download size steps logged when the server didn't send a Content-Length
+const UNKNOWN_SIZE_LOG_STEP: u64 = 10 * 1024 * 1024;
+
+// Log download progress in 10% steps. `logged` holds the last logged step,
+// and must be reset to 0 before each download.
+pub fn log_progress(logged: &AtomicU64, downloaded: u64, total: Option<u64>) {
+    let progress = DownloadProgress::new(downloaded, total);
+    let step = match progress.percent {
+        Some(percent) => percent / 10,
+        None => downloaded / UNKNOWN_SIZE_LOG_STEP,
+    };
+    if step > logged.load(Ordering::Relaxed) {
+        logged.store(step, Ordering::Relaxed);
+        match (progress.percent, total) {
+            (Some(percent), Some(total)) => log::info!(
+                "Downloaded {}% of the database ({} of {} bytes)",
+                percent,
+                downloaded,
+                total
+            ),
+            _ => log::info!("Downloaded {} bytes of the database", downloaded),
+        }
+    }
+}
+
+// Log parse progress in 10% steps, of the compressed database. `logged`
+// holds the last logged step, and must be reset to 0 before each parse.
+pub fn log_parse_progress(logged: &AtomicU64, progress: ParseProgress) {
+    let step = progress.percent() / 10;
+    if step > logged.load(Ordering::Relaxed) {
+        logged.store(step, Ordering::Relaxed);
+        log::info!(
+            "Parsed {}% of the database ({} lines)",
+            progress.percent(),
+            progress.lines
+        );
+    }
+}
+
+#[derive(Default)]
+struct Operations {
+    last_id: u64,
+    history: VecDeque<RefreshStatus>,
+    // Kept after they leave the history
+    last_succeeded: Option<RefreshStatus>,
+    last_failed: Option<RefreshStatus>,
+}
+
+// Database refreshes, either periodic or requested via /admin/refresh.
+// Only one download runs at a time: requesting a refresh while one is in
+// flight returns the ID of the running operation instead of starting another.
+pub struct Refresher {
+    asns_arc: Arc<RwLock<Arc<Asns>>>,
+    db_urls: Vec<String>,
+    http_client: Option<reqwest::Client>,
+    cache_file: Option<PathBuf>,
+    rate_limit: u64,
+    operations: Mutex<Operations>,
+    progress: Mutex<Option<DownloadProgress>>,
+    logged_step: AtomicU64,
+    parse_logged_step: AtomicU64,
+    // Database replicated by the primary, until it is activated
+    staged: Mutex<Option<Arc<Asns>>>,
+    // ID of the last finished operation
+    finished: watch::Sender<u64>,
+}
+
+impl Refresher {
+    pub fn new(
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        db_urls: Vec<String>,
+        http_client: Option<reqwest::Client>,
+        cache_file: Option<PathBuf>,
+        rate_limit: u64,
+    ) -> Self {
+        Refresher {
+            asns_arc,
+            db_urls,
+            http_client,
+            cache_file,
+            rate_limit,
+            operations: Mutex::default(),
+            progress: Mutex::default(),
+            logged_step: AtomicU64::new(0),
+            parse_logged_step: AtomicU64::new(0),
+            staged: Mutex::default(),
+            finished: watch::Sender::new(0),
+        }
+    }
+
+    // Start a refresh in the background, unless one is already running.
+    // Returns the status of the started or already running operation.
+    pub fn trigger(self: &Arc<Self>) -> RefreshStatus {
+        let mut operations = self.operations.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(running) = operations
+            .history
+            .iter()
+            .find(|op| op.state == RefreshState::Running)
+        {
+            return running.clone();
+        }
+        operations.last_id += 1;
+        let status = RefreshStatus {
+            id: operations.last_id,
+            state: RefreshState::Running,
+            started: Self::now(),
+            finished: None,
+            error: None,
+            entries: None,
+            duration_ms: None,
+        };
+        if operations.history.len() >= HISTORY_SIZE {
+            operations.history.pop_front();
+        }
+        operations.history.push_back(status.clone());
+        drop(operations);
+
+        let refresher = self.clone();
+        let id = status.id;
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let result = refresher.update_asns().await;
+            let mut operations = refresher
+                .operations
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(op) = operations.history.iter_mut().find(|op| op.id == id) {
+                op.finished = Some(Self::now());
+                op.duration_ms = Some(start.elapsed().as_millis() as u64);
+                match result {
+                    Ok(entries) => {
+                        op.state = RefreshState::Succeeded;
+                        op.entries = Some(entries);
+                    }
+                    Err(e) => {
+                        op.state = RefreshState::Failed;
+                        op.error = Some(e.to_string());
+                    }
+                }
+                let op = op.clone();
+                match op.state {
+                    RefreshState::Succeeded => operations.last_succeeded = Some(op),
+                    _ => operations.last_failed = Some(op),
+                }
+            }
+            drop(operations);
+            refresher.finished.send_replace(id);
+        });
+        status
+    }
+
+    // Status of an operation once it has finished, None if it is unknown
+    pub async fn wait(&self, id: u64) -> Option<RefreshStatus> {
+        let mut finished = self.finished.subscribe();
+        loop {
+            let status = self.status(id)?;
+            if status.state != RefreshState::Running {
+                return Some(status);
+            }
+            // The sender lives as long as self
+            let _ = finished.changed().await;
+        }
+    }
+
+    pub fn status(&self, id: u64) -> Option<RefreshStatus> {
+        self.operations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .history
+            .iter()
+            .find(|op| op.id == id)
+            .cloned()
+    }
+
+    // Last successful and last failed operations, if any
+    pub fn last_outcomes(&self) -> (Option<RefreshStatus>, Option<RefreshStatus>) {
+        let operations = self.operations.lock().unwrap_or_else(PoisonError::into_inner);
+        (operations.last_succeeded.clone(), operations.last_failed.clone())
+    }
+
+    // Download progress of the running refresh, if any
+    pub fn progress(&self) -> Option<DownloadProgress> {
+        *self.progress.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record_progress(&self, downloaded: u64, total: Option<u64>) {
+        log_progress(&self.logged_step, downloaded, total);
+        *self.progress.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(DownloadProgress::new(downloaded, total));
+    }
+
+    // Load the database and serve it, returning its number of entries
+    async fn update_asns(&self) -> Result<usize, &'static str> {
+        log::info!("Attempting to update ASN database");
+        self.logged_step.store(0, Ordering::Relaxed);
+        self.parse_logged_step.store(0, Ordering::Relaxed);
+        let progress = LoadProgress {
+            download: &|downloaded, total| self.record_progress(downloaded, total),
+            parse: &|progress| log_parse_progress(&self.parse_logged_step, progress),
+        };
+        let result = Asns::from_urls_with_load_progress(
+            &self.db_urls,
+            self.http_client.as_ref(),
+            self.cache_file.clone(),
+            self.rate_limit,
+            progress,
+        )
+        .await;
+        *self.progress.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        let asns = match result {
+            Ok(asns) => asns,
+            Err(e) => {
+                log::warn!("Failed to update ASN database: {e}");
+                log::warn!("Continuing with existing data");
+                return Err(e);
+            }
+        };
+        log::info!("ASNs loaded from {}", asns.source());
+        let asns = Arc::new(asns);
+        // Standbys stage the new database first, so that all instances swap
+        // at about the same time
+        let staged = if replication::standbys().is_empty() {
+            Vec::new()
+        } else {
+            let snapshot = {
+                let asns = asns.clone();
+                tokio::task::spawn_blocking(move || asns.to_snapshot())
+                    .await
+                    .map_err(|_| "Unable to serialize the database")?
+            };
+            replication::push(&asns, snapshot).await
+        };
+        let generation = asns.generation().to_string();
+        let entries = asns.len();
+        self.swap(asns);
+        log::info!("ASN database successfully updated");
+        if !staged.is_empty() {
+            replication::activate(staged, &generation).await;
+        }
+        Ok(entries)
+    }
+
+    fn swap(&self, asns: Arc<Asns>) {
+        *self.asns_arc.write().unwrap_or_else(PoisonError::into_inner) = asns;
+    }
+
+    // Generation of the database being served
+    pub fn generation(&self) -> String {
+        let asns = self.asns_arc.read().unwrap_or_else(PoisonError::into_inner);
+        asns.generation().to_string()
+    }
+
+    // Keep a database replicated by the primary until it is activated,
+    // replacing the one staged before
+    pub fn stage(&self, asns: Asns) {
+        *self.staged.lock().unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(asns));
+    }
+
+    // Serve the staged database, if it is of that generation. True when it
+    // is, or when that generation is already served.
+    pub fn activate(&self, generation: &str) -> bool {
+        let mut staged = self.staged.lock().unwrap_or_else(PoisonError::into_inner);
+        if staged.as_ref().is_some_and(|asns| asns.generation() == generation) {
+            self.swap(staged.take().unwrap());
+            log::info!("Replicated database {} activated", generation);
+            return true;
+        }
+        self.generation() == generation
+    }
+
+    fn now() -> String {
+        OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default()
+    }
+}
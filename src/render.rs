@@ -0,0 +1,313 @@
+use http::header::CONTENT_TYPE;
+use http::{HeaderValue, Response, StatusCode};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use serde::{Serialize, Serializer};
+use std::cell::Cell;
+
+// Output format of a response, negotiated from the Accept header
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputType {
+    Json,
+    Html,
+    Plain,
+    #[cfg(feature = "csv")]
+    Csv,
+    #[cfg(feature = "msgpack")]
+    Msgpack,
+}
+
+// Media types of the routes rendering a `Render` value, for negotiation
+pub(crate) const MEDIA_TYPES: &[&str] = &[
+    "application/json",
+    "text/html",
+    "text/plain",
+    #[cfg(feature = "csv")]
+    "text/csv",
+    #[cfg(feature = "msgpack")]
+    "application/msgpack",
+];
+
+impl OutputType {
+    // First output type named in an Accept header, in order of preference
+    pub(crate) fn from_accept(accept: &str) -> Option<Self> {
+        if accept.contains("application/json") {
+            return Some(OutputType::Json);
+        }
+        #[cfg(feature = "msgpack")]
+        if accept.contains("application/msgpack") || accept.contains("application/x-msgpack") {
+            return Some(OutputType::Msgpack);
+        }
+        #[cfg(feature = "csv")]
+        if accept.contains("text/csv") {
+            return Some(OutputType::Csv);
+        }
+        if accept.contains("text/plain") {
+            return Some(OutputType::Plain);
+        }
+        if accept.contains("text/html") {
+            return Some(OutputType::Html);
+        }
+        None
+    }
+}
+
+// How AS numbers are written (?asn_notation=)
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum AsnNotation {
+    #[default]
+    Asplain,
+    // Numbers above 65535 as <high>.<low>, e.g. 3.10 for 196618
+    Asdot,
+}
+
+// Options of a response, from its query parameters
+pub(crate) struct Style<'a> {
+    // Field separator of plain text (?sep=)
+    pub sep: &'a str,
+    pub asn_notation: AsnNotation,
+}
+
+impl Style<'_> {
+    // An AS number in the notation of the response
+    pub(crate) fn asn(&self, number: u32) -> String {
+        asn(number, self.asn_notation)
+    }
+}
+
+fn asn(number: u32, notation: AsnNotation) -> String {
+    match notation {
+        AsnNotation::Asdot if number > 0xffff => format!("{}.{}", number >> 16, number & 0xffff),
+        _ => number.to_string(),
+    }
+}
+
+thread_local! {
+    // Notation of the AS numbers of the value being serialized by a format
+    static SERIALIZED_NOTATION: Cell<AsnNotation> = const { Cell::new(AsnNotation::Asplain) };
+}
+
+// Run `serialize` with AS numbers in `notation`, restoring the default
+// afterwards, even on panic
+pub(crate) fn with_notation<T>(notation: AsnNotation, serialize: impl FnOnce() -> T) -> T {
+    struct Restore;
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            SERIALIZED_NOTATION.set(AsnNotation::Asplain);
+        }
+    }
+    SERIALIZED_NOTATION.set(notation);
+    let _restore = Restore;
+    serialize()
+}
+
+// `serialize_with` of AS number fields: a number, or a string in asdot
+// notation for numbers above 65535
+pub(crate) fn serialize_asn<S: Serializer>(number: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    match SERIALIZED_NOTATION.get() {
+        AsnNotation::Asdot if *number > 0xffff => serializer.serialize_str(&asn(*number, AsnNotation::Asdot)),
+        _ => serializer.serialize_u32(*number),
+    }
+}
+
+pub(crate) fn serialize_asn_option<S: Serializer>(number: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    match number {
+        Some(number) => serialize_asn(number, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub(crate) fn serialize_asns<S: Serializer>(numbers: &[u32], serializer: S) -> Result<S::Ok, S::Error> {
+    struct Asn(u32);
+    impl Serialize for Asn {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize_asn(&self.0, serializer)
+        }
+    }
+    serializer.collect_seq(numbers.iter().map(|&number| Asn(number)))
+}
+
+// Header and rows of a response, for tabular formats
+#[cfg(feature = "csv")]
+pub(crate) struct Table {
+    pub header: &'static [&'static str],
+    pub rows: Vec<Vec<String>>,
+}
+
+// A response body. Structured formats use its Serialize implementation, the
+// others one of the views below.
+pub(crate) trait Render: Serialize {
+    // Lines of text, with `style.sep` between fields
+    fn plain(&self, style: &Style) -> String;
+    // Complete HTML page
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error>;
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table;
+}
+
+// An output format. AS numbers are written in the notation of the style,
+// by the views of the value or, for structured formats, by its
+// `serialize_asn` fields.
+pub(crate) trait Renderer {
+    const CONTENT_TYPE: &'static str;
+    fn render<R: Render + ?Sized>(value: &R, style: &Style) -> Result<Vec<u8>, String>;
+}
+
+pub(crate) struct Json;
+pub(crate) struct Plain;
+pub(crate) struct Html;
+
+impl Renderer for Json {
+    const CONTENT_TYPE: &'static str = "application/json; charset=utf-8";
+
+    fn render<R: Render + ?Sized>(value: &R, style: &Style) -> Result<Vec<u8>, String> {
+        with_notation(style.asn_notation, || serde_json::to_vec(value)).map_err(|e| e.to_string())
+    }
+}
+
+impl Renderer for Plain {
+    const CONTENT_TYPE: &'static str = "text/plain; charset=utf-8";
+
+    fn render<R: Render + ?Sized>(value: &R, style: &Style) -> Result<Vec<u8>, String> {
+        Ok(value.plain(style).into_bytes())
+    }
+}
+
+impl Renderer for Html {
+    const CONTENT_TYPE: &'static str = "text/html; charset=utf-8";
+
+    fn render<R: Render + ?Sized>(value: &R, style: &Style) -> Result<Vec<u8>, String> {
+        value.html(style).map(String::into_bytes).map_err(|e| e.to_string())
+    }
+}
+
+// RFC 4180, with a header line and no formulas. The field separator
+// doesn't apply.
+#[cfg(feature = "csv")]
+pub(crate) struct Csv;
+
+#[cfg(feature = "csv")]
+impl Renderer for Csv {
+    const CONTENT_TYPE: &'static str = "text/csv; charset=utf-8; header=present";
+
+    fn render<R: Render + ?Sized>(value: &R, style: &Style) -> Result<Vec<u8>, String> {
+        let field = |s: &str| {
+            // Fields that a spreadsheet would take for a formula, e.g. an AS
+            // description of "=HYPERLINK(...)", are prefixed with a quote
+            let s = if s.starts_with(['=', '+', '-', '@']) {
+                format!("'{}", s)
+            } else {
+                s.to_string()
+            };
+            if s.contains(['"', ',', '\r', '\n']) {
+                format!("\"{}\"", s.replace('"', "\"\""))
+            } else {
+                s
+            }
+        };
+        let table = value.table(style);
+        let mut out = table.header.join(",");
+        out.push_str("\r\n");
+        for row in &table.rows {
+            let row: Vec<String> = row.iter().map(|s| field(s)).collect();
+            out.push_str(&row.join(","));
+            out.push_str("\r\n");
+        }
+        Ok(out.into_bytes())
+    }
+}
+
+// MessagePack encoding of the JSON structure
+#[cfg(feature = "msgpack")]
+pub(crate) struct Msgpack;
+
+#[cfg(feature = "msgpack")]
+impl Renderer for Msgpack {
+    const CONTENT_TYPE: &'static str = "application/msgpack";
+
+    fn render<R: Render + ?Sized>(value: &R, style: &Style) -> Result<Vec<u8>, String> {
+        fn convert(value: serde_json::Value) -> rmpv::Value {
+            match value {
+                serde_json::Value::Null => rmpv::Value::Nil,
+                serde_json::Value::Bool(b) => rmpv::Value::Boolean(b),
+                serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                    (Some(u), _) => rmpv::Value::from(u),
+                    (_, Some(i)) => rmpv::Value::from(i),
+                    _ => rmpv::Value::from(n.as_f64().unwrap_or_default()),
+                },
+                serde_json::Value::String(s) => rmpv::Value::from(s),
+                serde_json::Value::Array(items) => {
+                    rmpv::Value::Array(items.into_iter().map(convert).collect())
+                }
+                serde_json::Value::Object(map) => rmpv::Value::Map(
+                    map.into_iter()
+                        .map(|(k, v)| (rmpv::Value::from(k), convert(v)))
+                        .collect(),
+                ),
+            }
+        }
+        let value = with_notation(style.asn_notation, || serde_json::to_value(value)).map_err(|e| e.to_string())?;
+        let mut out = Vec::new();
+        rmpv::encode::write_value(&mut out, &convert(value)).map_err(|e| e.to_string())?;
+        Ok(out)
+    }
+}
+
+// Body of an output type
+pub(crate) fn render<R: Render + ?Sized>(
+    output_type: OutputType,
+    value: &R,
+    style: &Style,
+) -> Result<(Vec<u8>, &'static str), String> {
+    Ok(match output_type {
+        OutputType::Json => (Json::render(value, style)?, Json::CONTENT_TYPE),
+        OutputType::Html => (Html::render(value, style)?, Html::CONTENT_TYPE),
+        OutputType::Plain => (Plain::render(value, style)?, Plain::CONTENT_TYPE),
+        #[cfg(feature = "csv")]
+        OutputType::Csv => (Csv::render(value, style)?, Csv::CONTENT_TYPE),
+        #[cfg(feature = "msgpack")]
+        OutputType::Msgpack => (Msgpack::render(value, style)?, Msgpack::CONTENT_TYPE),
+    })
+}
+
+// 200 response with the rendered body, or a 500 if it couldn't be rendered
+pub(crate) fn response<R: Render + ?Sized>(output_type: OutputType, value: &R, style: &Style) -> Response<Full<Bytes>> {
+    match render(output_type, value, style) {
+        Ok((body, content_type)) => {
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            *response.status_mut() = StatusCode::OK;
+            response
+        }
+        Err(e) => internal_error(&e),
+    }
+}
+
+// 200 JSON response, or a 500 if the value couldn't be serialized
+pub(crate) fn json_response<T: Serialize + ?Sized>(value: &T) -> Response<Full<Bytes>> {
+    match serde_json::to_string(value) {
+        Ok(json) => {
+            let mut response = Response::new(Full::new(Bytes::from(json)));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/json; charset=utf-8"),
+            );
+            response
+        }
+        Err(e) => internal_error(&e.to_string()),
+    }
+}
+
+// 500 response to a body that couldn't be serialized
+pub(crate) fn internal_error(e: &str) -> Response<Full<Bytes>> {
+    log::error!("Unable to serialize response: {}", e);
+    let mut response = Response::new(Full::new(Bytes::from("Internal Server Error\n")));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    response
+}
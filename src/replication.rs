@@ -0,0 +1,165 @@
+use crate::asns::Asns;
+use crate::auth;
+use http::HeaderMap;
+use hyper::body::Bytes;
+use log::{info, warn};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinSet;
+
+// Headers describing a replicated snapshot
+pub const GENERATION_HEADER: &str = "x-db-generation";
+pub const UPDATED_HEADER: &str = "x-db-updated";
+pub const SOURCE_HEADER: &str = "x-db-source";
+
+pub const SNAPSHOT_PATH: &str = "/admin/replication/snapshot";
+pub const ACTIVATE_PATH: &str = "/admin/replication/activate";
+
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+// Base URLs of the standby instances a primary pushes its snapshots to
+// (--standby)
+static STANDBYS: OnceLock<Vec<String>> = OnceLock::new();
+
+// Base URL of the primary instance a standby pulls its first snapshot from
+// (--primary)
+static PRIMARY: OnceLock<String> = OnceLock::new();
+
+// Shared secret of the replication routes (--replication-token)
+static TOKEN: OnceLock<String> = OnceLock::new();
+
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+// Push the databases loaded from now on to the standby instances at these
+// base URLs. Must be called before the server is started.
+pub fn set_standbys(urls: Vec<String>) {
+    let urls = urls
+        .into_iter()
+        .map(|url| url.trim_end_matches('/').to_string())
+        .collect();
+    let _ = STANDBYS.set(urls);
+}
+
+// Run as the standby of the primary instance at this base URL
+pub fn set_primary(url: &str) {
+    let _ = PRIMARY.set(url.trim_end_matches('/').to_string());
+}
+
+pub fn set_token(token: &str) {
+    let _ = TOKEN.set(token.trim().to_string());
+}
+
+pub fn standbys() -> &'static [String] {
+    STANDBYS.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn primary() -> Option<&'static str> {
+    PRIMARY.get().map(String::as_str)
+}
+
+// Whether a request sends the replication token. Without a token, the
+// replication routes are disabled.
+pub fn authorized(headers: &HeaderMap) -> bool {
+    TOKEN
+        .get()
+        .filter(|token| !token.is_empty())
+        .is_some_and(|token| auth::sends_token(headers, token))
+}
+
+fn client() -> &'static reqwest::Client {
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+fn bearer() -> String {
+    format!("Bearer {}", TOKEN.get().map_or("", String::as_str))
+}
+
+// Send a snapshot to every standby, which validates and stages it. Returns
+// the standbys that staged it, or already serve that generation.
+pub async fn push(asns: &Asns, snapshot: Vec<u8>) -> Vec<String> {
+    let snapshot = Bytes::from(snapshot);
+    let updated = httpdate::fmt_http_date(asns.updated());
+    let mut pushes = JoinSet::new();
+    for standby in standbys() {
+        let request = client()
+            .put(format!("{}{}", standby, SNAPSHOT_PATH))
+            .header(reqwest::header::AUTHORIZATION, bearer())
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header(GENERATION_HEADER, asns.generation())
+            .header(UPDATED_HEADER, &updated)
+            .header(SOURCE_HEADER, asns.source())
+            .body(snapshot.clone());
+        let standby = standby.clone();
+        pushes.spawn(async move {
+            let result = request.send().await.and_then(|response| response.error_for_status());
+            (standby, result)
+        });
+    }
+    let mut staged = Vec::new();
+    while let Some(Ok((standby, result))) = pushes.join_next().await {
+        match result {
+            Ok(_) => staged.push(standby),
+            Err(e) => warn!("Unable to replicate the database to {}: {}", standby, e),
+        }
+    }
+    staged
+}
+
+// Have the standbys that staged a generation serve it
+pub async fn activate(standbys: Vec<String>, generation: &str) {
+    let mut activations = JoinSet::new();
+    for standby in standbys {
+        let request = client()
+            .post(format!("{}{}/{}", standby, ACTIVATE_PATH, generation))
+            .header(reqwest::header::AUTHORIZATION, bearer());
+        activations.spawn(async move {
+            let result = request.send().await.and_then(|response| response.error_for_status());
+            (standby, result)
+        });
+    }
+    while let Some(Ok((standby, result))) = activations.join_next().await {
+        match result {
+            Ok(_) => info!("Database {} activated on {}", generation, standby),
+            Err(e) => warn!("Unable to activate the database on {}: {}", standby, e),
+        }
+    }
+}
+
+// Database currently served by the primary
+pub async fn pull() -> Result<Asns, &'static str> {
+    let primary = primary().ok_or("No primary instance")?;
+    let response = client()
+        .get(format!("{}{}", primary, SNAPSHOT_PATH))
+        .header(reqwest::header::AUTHORIZATION, bearer())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| {
+            warn!("Unable to fetch the database from the primary {}: {}", primary, e);
+            "Unable to fetch the database from the primary"
+        })?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let generation = header(GENERATION_HEADER).ok_or("Missing database generation")?;
+    let updated = header(UPDATED_HEADER)
+        .and_then(|updated| httpdate::parse_http_date(&updated).ok())
+        .unwrap_or_else(SystemTime::now);
+    let source = header(SOURCE_HEADER).unwrap_or_else(|| primary.to_string());
+    let snapshot = response.bytes().await.map_err(|e| {
+        warn!("Unable to fetch the database from the primary {}: {}", primary, e);
+        "Unable to fetch the database from the primary"
+    })?;
+    tokio::task::spawn_blocking(move || Asns::from_snapshot(&snapshot, source, updated, generation))
+        .await
+        .map_err(|_| "Unable to read the replicated snapshot")?
+}
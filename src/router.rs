@@ -0,0 +1,352 @@
+use crate::webservice::WebService;
+
+// Handlers of the web service
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Route {
+    Index,
+    BulkPage,
+    ClientIp,
+    Ip,
+    IpHistory,
+    StatsTimeseries,
+    Ips,
+    IpsStream,
+    AnnotateLog,
+    MissingAsn,
+    Asns,
+    AsSearch,
+    AsSubnets,
+    AsCountries,
+    As,
+    Prefix,
+    Range,
+    CountrySubnets,
+    Country,
+    ClientGeoIp,
+    GeoIp,
+    Dnsbl,
+    Metrics,
+    DbStatus,
+    Limits,
+    AdminUsage,
+    AdminStatus,
+    AdminRefresh,
+    AdminRefreshStatus,
+    ReplicationSnapshot,
+    ReplicationStage,
+    ReplicationActivate,
+    OpenApi,
+    Version,
+}
+
+impl Route {
+    // Routes counted against the monthly quota of tenants
+    pub(crate) fn is_lookup(self) -> bool {
+        !matches!(
+            self,
+            Route::Index
+                | Route::BulkPage
+                | Route::Metrics
+                | Route::Limits
+                | Route::AdminUsage
+                | Route::AdminStatus
+                | Route::AdminRefresh
+                | Route::AdminRefreshStatus
+                | Route::ReplicationSnapshot
+                | Route::ReplicationStage
+                | Route::ReplicationActivate
+                | Route::OpenApi
+                | Route::Version
+        )
+    }
+
+    // Routes checking the admin token, disabled without one
+    pub(crate) fn is_admin(self) -> bool {
+        matches!(
+            self,
+            Route::AdminUsage | Route::AdminStatus | Route::AdminRefresh | Route::AdminRefreshStatus
+        )
+    }
+
+    // Routes served without an API key when authentication is enabled. The
+    // replication routes check the replication token instead.
+    pub(crate) fn is_public(self) -> bool {
+        matches!(
+            self,
+            Route::Index
+                | Route::BulkPage
+                | Route::ReplicationSnapshot
+                | Route::ReplicationStage
+                | Route::ReplicationActivate
+                | Route::OpenApi
+                | Route::Version
+        )
+    }
+
+    // Entry of the route in ROUTES
+    pub(crate) fn entry(self) -> &'static RouteEntry {
+        // Every route has one
+        ROUTES.iter().find(|entry| entry.route == self).unwrap()
+    }
+
+    // Path pattern of the route, as in ROUTES
+    pub(crate) fn pattern(self) -> &'static str {
+        self.entry().pattern
+    }
+
+    // Routes returning lists, which take ?offset= and ?limit=
+    pub(crate) fn is_list(self) -> bool {
+        matches!(
+            self,
+            Route::Asns | Route::AsSearch | Route::AsSubnets | Route::CountrySubnets | Route::Country
+        )
+    }
+
+    // Routes whose responses only depend on the database and the request
+    // URL, tagged with an ETag. Not the lookups of the client's own address.
+    pub(crate) fn is_cacheable(self) -> bool {
+        matches!(
+            self,
+            Route::Ip
+                | Route::As
+                | Route::Asns
+                | Route::AsSearch
+                | Route::AsSubnets
+                | Route::AsCountries
+                | Route::Prefix
+                | Route::Range
+                | Route::CountrySubnets
+                | Route::Country
+                | Route::GeoIp
+                | Route::Dnsbl
+        )
+    }
+
+    // Routes looking up a single IP address; bulk routes count their addresses
+    pub(crate) fn is_single_lookup(self) -> bool {
+        matches!(
+            self,
+            Route::ClientIp
+                | Route::Ip
+                | Route::IpHistory
+                | Route::Prefix
+                | Route::Range
+                | Route::ClientGeoIp
+                | Route::GeoIp
+                | Route::Dnsbl
+        )
+    }
+}
+
+// A route: method, path pattern and handler, with the media types of its
+// responses and of its request bodies (for content negotiation, the
+// capabilities of / and /openapi.json)
+pub(crate) struct RouteEntry {
+    pub method: &'static str,
+    pub pattern: &'static str,
+    pub route: Route,
+    pub produces: &'static [&'static str],
+    // Empty for routes without a body
+    pub consumes: &'static [&'static str],
+}
+
+const HTML: &[&str] = &["text/html"];
+const JSON: &[&str] = &["application/json"];
+const PLAIN: &[&str] = &["text/plain"];
+const STATUS: &[&str] = &["application/json", "text/html", "text/plain"];
+// Routes rendering their responses in every output format
+const LOOKUP: &[&str] = crate::render::MEDIA_TYPES;
+
+// GET route, without a body
+const fn get(pattern: &'static str, route: Route, produces: &'static [&'static str]) -> RouteEntry {
+    with_body("GET", pattern, route, produces, &[])
+}
+
+const fn with_body(
+    method: &'static str,
+    pattern: &'static str,
+    route: Route,
+    produces: &'static [&'static str],
+    consumes: &'static [&'static str],
+) -> RouteEntry {
+    RouteEntry {
+        method,
+        pattern,
+        route,
+        produces,
+        consumes,
+    }
+}
+
+// `:name` matches one path segment, `*name` the rest of the path, slashes
+// included. The first matching pattern wins, so more specific patterns come
+// first.
+const ROUTES: &[RouteEntry] = &[
+    get("/", Route::Index, &["text/plain", "application/json"]),
+    get("/bulk", Route::BulkPage, HTML),
+    get("/v1/as/ip", Route::ClientIp, LOOKUP),
+    get("/v1/as/ip/:ip/history", Route::IpHistory, LOOKUP),
+    get("/v1/as/ip/*ip", Route::Ip, LOOKUP),
+    with_body(
+        "PUT",
+        "/v1/as/ips",
+        Route::Ips,
+        LOOKUP,
+        &["application/json", "application/x-ndjson", "text/plain"],
+    ),
+    with_body("POST", "/v1/as/ips/stream", Route::IpsStream, &["text/event-stream"], PLAIN),
+    with_body(
+        "PUT",
+        "/v1/annotate/log",
+        Route::AnnotateLog,
+        &["text/plain", "application/x-ndjson"],
+        &["text/plain", "application/octet-stream"],
+    ),
+    get("/v1/as/n", Route::MissingAsn, &["application/json", "text/plain"]),
+    get("/v1/as/ns", Route::Asns, LOOKUP),
+    get("/v1/as/search", Route::AsSearch, LOOKUP),
+    get("/v1/as/n/:asn/subnets", Route::AsSubnets, LOOKUP),
+    get("/v1/as/n/:asn/countries", Route::AsCountries, LOOKUP),
+    get("/v1/as/n/:asn", Route::As, LOOKUP),
+    get("/v1/as/prefix/*prefix", Route::Prefix, LOOKUP),
+    get("/v1/as/range", Route::Range, LOOKUP),
+    get("/v1/as/country/:cc/subnets", Route::CountrySubnets, LOOKUP),
+    get("/v1/as/country/:cc", Route::Country, LOOKUP),
+    get("/geoip", Route::ClientGeoIp, JSON),
+    get("/geoip/:ip", Route::GeoIp, JSON),
+    get("/v1/dnsbl/:query", Route::Dnsbl, LOOKUP),
+    get("/v1/stats/timeseries", Route::StatsTimeseries, LOOKUP),
+    get("/metrics", Route::Metrics, PLAIN),
+    get("/v1/db/status", Route::DbStatus, STATUS),
+    get("/v1/limits", Route::Limits, JSON),
+    get("/admin/usage", Route::AdminUsage, JSON),
+    get("/admin/status", Route::AdminStatus, JSON),
+    with_body("POST", "/admin/refresh", Route::AdminRefresh, JSON, &[]),
+    get("/admin/refresh/:id", Route::AdminRefreshStatus, JSON),
+    get(
+        "/admin/replication/snapshot",
+        Route::ReplicationSnapshot,
+        &["application/octet-stream", "application/json"],
+    ),
+    with_body(
+        "PUT",
+        "/admin/replication/snapshot",
+        Route::ReplicationStage,
+        JSON,
+        &["application/octet-stream"],
+    ),
+    with_body("POST", "/admin/replication/activate/:generation", Route::ReplicationActivate, JSON, &[]),
+    get("/openapi.json", Route::OpenApi, JSON),
+    get("/version", Route::Version, JSON),
+];
+
+// All the routes
+pub(crate) fn routes() -> &'static [RouteEntry] {
+    ROUTES
+}
+
+// Percent-decoded path parameters of a matched route
+#[derive(Default)]
+pub(crate) struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    // Value of a parameter of the route pattern, empty if there is none
+    pub(crate) fn get(&self, name: &str) -> &str {
+        self.0
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map_or("", |(_, value)| value.as_str())
+    }
+}
+
+pub(crate) enum Resolution {
+    Found(Route, Params),
+    // The path matches routes of other methods only
+    MethodNotAllowed(Vec<&'static str>),
+    NotFound,
+}
+
+// Route of a request. A trailing slash is ignored, and HEAD requests match
+// GET routes.
+pub(crate) fn resolve(method: &str, path: &str) -> Resolution {
+    let path = normalize(path);
+    let method = if method == "HEAD" { "GET" } else { method };
+    for entry in ROUTES {
+        if entry.method == method {
+            if let Some(params) = matches(entry.pattern, path) {
+                return Resolution::Found(entry.route, params);
+            }
+        }
+    }
+    match methods(path) {
+        allowed if allowed.is_empty() => Resolution::NotFound,
+        allowed => Resolution::MethodNotAllowed(allowed),
+    }
+}
+
+// Methods of the routes matching a path, HEAD included with GET
+pub(crate) fn methods(path: &str) -> Vec<&'static str> {
+    let path = normalize(path);
+    let mut allowed = Vec::new();
+    for entry in ROUTES {
+        if !allowed.contains(&entry.method) && matches(entry.pattern, path).is_some() {
+            allowed.push(entry.method);
+        }
+    }
+    if allowed.contains(&"GET") {
+        allowed.push("HEAD");
+    }
+    allowed
+}
+
+// Path without its trailing slash, but for the root
+fn normalize(path: &str) -> &str {
+    match path.strip_suffix('/') {
+        Some(stripped) if !stripped.is_empty() => stripped,
+        _ => path,
+    }
+}
+
+// Parameters of a path matching a pattern
+fn matches(pattern: &'static str, path: &str) -> Option<Params> {
+    let mut params = Params::default();
+    let mut rest = path.strip_prefix('/')?;
+    for segment in pattern[1..].split('/').filter(|s| !s.is_empty()) {
+        if let Some(name) = segment.strip_prefix('*') {
+            if rest.is_empty() {
+                return None;
+            }
+            params.0.push((name, WebService::percent_decode(rest)));
+            return Some(params);
+        }
+        let (value, tail) = rest.split_once('/').unwrap_or((rest, ""));
+        match segment.strip_prefix(':') {
+            Some(_) if value.is_empty() => return None,
+            Some(name) => params.0.push((name, WebService::percent_decode(value))),
+            None if value == segment => {}
+            None => return None,
+        }
+        if tail.is_empty() && rest.len() > value.len() {
+            // Empty segment after a slash
+            return None;
+        }
+        rest = tail;
+    }
+    rest.is_empty().then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_types_come_from_the_matched_route() {
+        let media = crate::negotiation::route("GET", "/v1/as/ip/192.0.2.1/history").unwrap();
+        assert_eq!(media.produces, Route::IpHistory.entry().produces);
+        let media = crate::negotiation::route("PUT", "/admin/replication/snapshot").unwrap();
+        assert_eq!(media.produces, ["application/json"]);
+        assert_eq!(media.consumes, ["application/octet-stream"]);
+        assert_eq!(media.methods, ["GET", "PUT", "HEAD"]);
+        assert!(crate::negotiation::route("POST", "/v1/as/ip/192.0.2.1").is_none());
+    }
+}
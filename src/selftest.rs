@@ -0,0 +1,462 @@
+use reqwest::header::{HeaderMap, ACCEPT, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use reqwest::{Method, StatusCode};
+use serde::Serialize;
+use serde_json::Value;
+
+// Conformance checks of a running instance, used by `iptoasn selftest` as a
+// deployment smoke test and as the integration test driver of the crate.
+// Lookups are checked against an AS, prefix and address taken from the
+// instance itself, so that any database can be served.
+pub struct SelfTest {
+    client: reqwest::Client,
+    server: String,
+    results: Vec<Check>,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+// A response, read in full
+struct Reply {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    fn content_type(&self) -> &str {
+        self.header(CONTENT_TYPE.as_str()).unwrap_or("")
+    }
+
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    fn json(&self) -> Result<Value, String> {
+        serde_json::from_slice(&self.body).map_err(|e| format!("invalid JSON: {}", e))
+    }
+
+    // The response, if it has the expected status
+    fn expect(self, status: StatusCode) -> Result<Self, String> {
+        match self.status {
+            s if s == status => Ok(self),
+            StatusCode::UNAUTHORIZED => Err("HTTP 401, the server requires an API key (--api-key)".to_string()),
+            s => Err(format!("HTTP {}, expected {}", s.as_u16(), status.as_u16())),
+        }
+    }
+}
+
+// AS, prefix and address of the database the lookups are checked against
+struct Sample {
+    asn: u64,
+    country: String,
+    prefix: String,
+    ip: String,
+}
+
+impl SelfTest {
+    pub fn new(client: reqwest::Client, server: &str) -> Self {
+        SelfTest {
+            client,
+            server: server.trim_end_matches('/').to_string(),
+            results: Vec::new(),
+        }
+    }
+
+    fn report(&mut self, name: &str, result: Result<String, String>) {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        self.results.push(Check {
+            name: name.to_string(),
+            ok,
+            detail,
+        });
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: Option<&str>,
+    ) -> Result<Reply, String> {
+        let mut request = self.client.request(method, format!("{}{}", self.server, path));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        if let Some(body) = body {
+            request = request.body(body.to_string());
+        }
+        let response = request.send().await.map_err(|e| format!("request failed: {}", e))?;
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| format!("unable to read the response: {}", e))?;
+        Ok(Reply {
+            status,
+            headers,
+            body: body.to_vec(),
+        })
+    }
+
+    async fn get_json(&self, path: &str) -> Result<Value, String> {
+        self.send(Method::GET, path, &[(ACCEPT.as_str(), "application/json")], None)
+            .await?
+            .expect(StatusCode::OK)?
+            .json()
+    }
+
+    // Run all the checks. Lookups are skipped if the instance can't provide
+    // a sample of its database.
+    pub async fn run(&mut self) {
+        let features = self.check_service().await;
+        let Some(sample) = self.check_sample().await else {
+            return;
+        };
+        self.check_lookups(&sample).await;
+        self.check_formats(&sample, &features).await;
+        self.check_bulk(&sample).await;
+        self.check_errors().await;
+        self.check_headers(&sample).await;
+    }
+
+    // Build information, API description, database status and metrics.
+    // Returns the optional features of the server.
+    async fn check_service(&mut self) -> Vec<String> {
+        let version = self.get_json("/version").await;
+        let features = match &version {
+            Ok(version) => version["features"]
+                .as_array()
+                .map(|features| features.iter().filter_map(|f| f.as_str().map(str::to_string)).collect())
+                .unwrap_or_default(),
+            Err(_) => Vec::new(),
+        };
+        let result = version.and_then(|version| match version["version"].as_str() {
+            Some(v) => Ok(format!(
+                "version {}, commit {}",
+                v,
+                version["git_commit"].as_str().unwrap_or("unknown")
+            )),
+            None => Err("no version field".to_string()),
+        });
+        self.report("GET /version", result);
+
+        let result = self.get_json("/openapi.json").await.and_then(|spec| {
+            match (spec["openapi"].as_str(), spec["paths"].as_object()) {
+                (Some(v), Some(paths)) => Ok(format!("OpenAPI {}, {} paths", v, paths.len())),
+                _ => Err("not an OpenAPI document".to_string()),
+            }
+        });
+        self.report("GET /openapi.json", result);
+
+        let result = self.get_json("/v1/db/status").await.and_then(|status| {
+            match status["entries"].as_u64() {
+                Some(0) => Err("the database is empty".to_string()),
+                Some(entries) => Ok(format!(
+                    "{} entries, generation {}",
+                    entries,
+                    status["generation"].as_str().unwrap_or("unknown")
+                )),
+                None => Err("no entries field".to_string()),
+            }
+        });
+        self.report("GET /v1/db/status", result);
+
+        let result = match self.send(Method::GET, "/metrics", &[], None).await {
+            Ok(reply) => reply.expect(StatusCode::OK).and_then(|reply| {
+                if reply.text().starts_with("# HELP") {
+                    Ok(format!("{} metric lines", reply.text().lines().count()))
+                } else {
+                    Err("not in the Prometheus text format".to_string())
+                }
+            }),
+            Err(e) => Err(e),
+        };
+        self.report("GET /metrics", result);
+        features
+    }
+
+    // The first AS of the database, with one of its prefixes
+    async fn check_sample(&mut self) -> Option<Sample> {
+        let result = self.get_json("/v1/as/ns?limit=1").await.and_then(|asns| {
+            let first = asns.as_array().and_then(|asns| asns.first()).ok_or("no AS listed")?;
+            match first["as_number"].as_u64() {
+                Some(asn) => Ok((asn, first["as_country_code"].as_str().unwrap_or("").to_string())),
+                None => Err("no as_number field".to_string()),
+            }
+        });
+        let (asn, country) = match result {
+            Ok(found) => {
+                self.report("GET /v1/as/ns", Ok(format!("first AS is AS{}", found.0)));
+                found
+            }
+            Err(e) => {
+                self.report("GET /v1/as/ns", Err(e));
+                return None;
+            }
+        };
+
+        let result = self
+            .get_json(&format!("/v1/as/n/{}/subnets", asn))
+            .await
+            .and_then(|subnets| {
+                subnets["subnets"]
+                    .as_array()
+                    .and_then(|subnets| subnets.first())
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .ok_or_else(|| format!("no subnet for AS{}", asn))
+            });
+        self.report(
+            "GET /v1/as/n/<asn>/subnets",
+            result.as_ref().map(|prefix| format!("AS{} announces {}", asn, prefix)).map_err(Clone::clone),
+        );
+        let prefix = result.ok()?;
+        let ip = prefix.split('/').next().unwrap_or(&prefix).to_string();
+        Some(Sample {
+            asn,
+            country,
+            prefix,
+            ip,
+        })
+    }
+
+    async fn check_lookups(&mut self, sample: &Sample) {
+        let result = self.get_json(&format!("/v1/as/ip/{}", sample.ip)).await.and_then(|found| {
+            match (found["announced"].as_bool(), found["as_number"].as_u64()) {
+                (Some(true), Some(asn)) if asn == sample.asn => Ok(format!("{} is announced by AS{}", sample.ip, asn)),
+                (Some(true), Some(asn)) => Err(format!("{} is announced by AS{}, expected AS{}", sample.ip, asn, sample.asn)),
+                _ => Err(format!("{} is not announced", sample.ip)),
+            }
+        });
+        self.report("GET /v1/as/ip/<ip>", result);
+
+        let result = self.get_json(&format!("/v1/as/n/{}", sample.asn)).await.and_then(|meta| {
+            match meta["as_number"].as_u64() {
+                Some(asn) if asn == sample.asn => Ok(format!(
+                    "AS{} is {}",
+                    asn,
+                    meta["as_description"].as_str().unwrap_or("")
+                )),
+                _ => Err("wrong or missing as_number".to_string()),
+            }
+        });
+        self.report("GET /v1/as/n/<asn>", result);
+
+        let result = self
+            .get_json(&format!("/v1/as/prefix/{}", sample.prefix))
+            .await
+            .and_then(|found| match found["as_numbers"].as_array() {
+                Some(asns) if asns.iter().any(|asn| asn.as_u64() == Some(sample.asn)) => {
+                    Ok(format!("{} coverage: {}", sample.prefix, found["coverage"].as_str().unwrap_or("unknown")))
+                }
+                _ => Err(format!("AS{} is missing from {}", sample.asn, sample.prefix)),
+            });
+        self.report("GET /v1/as/prefix/<prefix>", result);
+
+        if !sample.country.is_empty() && sample.country != "None" {
+            let result = self
+                .get_json(&format!("/v1/as/country/{}", sample.country))
+                .await
+                .and_then(|found| match found["as_numbers"].as_array() {
+                    Some(asns) if asns.iter().any(|asn| asn.as_u64() == Some(sample.asn)) => {
+                        Ok(format!("{} has {} ASes", sample.country, asns.len()))
+                    }
+                    _ => Err(format!("AS{} is missing from {}", sample.asn, sample.country)),
+                });
+            self.report("GET /v1/as/country/<cc>", result);
+        }
+
+        let result = self.get_json(&format!("/geoip/{}", sample.ip)).await.and_then(|found| {
+            match found["org"].as_str() {
+                Some(org) if org.starts_with(&format!("AS{} ", sample.asn)) => Ok(org.to_string()),
+                _ => Err(format!("org doesn't name AS{}", sample.asn)),
+            }
+        });
+        self.report("GET /geoip/<ip>", result);
+
+        let result = self.get_json("/v1/as/ip").await.and_then(|found| match found["ip"].as_str() {
+            Some(ip) => Ok(format!("client address {}", ip)),
+            None => Err("no ip field".to_string()),
+        });
+        self.report("GET /v1/as/ip", result);
+    }
+
+    // Output types of an IP lookup
+    async fn check_formats(&mut self, sample: &Sample, features: &[String]) {
+        let path = format!("/v1/as/ip/{}", sample.ip);
+        let mut formats = vec![("text/plain", "plain"), ("text/html", "HTML")];
+        if features.iter().any(|f| f == "csv") {
+            formats.push(("text/csv", "CSV"));
+        }
+        if features.iter().any(|f| f == "msgpack") {
+            formats.push(("application/msgpack", "MessagePack"));
+        }
+        for (accept, name) in formats {
+            let result = match self.send(Method::GET, &path, &[(ACCEPT.as_str(), accept)], None).await {
+                Ok(reply) => reply.expect(StatusCode::OK).and_then(|reply| {
+                    let content_type = reply.content_type().to_string();
+                    let asn = sample.asn.to_string();
+                    let valid = match accept {
+                        "text/plain" => reply.text().starts_with(&asn),
+                        "text/html" => reply.text().contains("<html"),
+                        "text/csv" => reply.text().starts_with("ip,") && reply.text().contains(&asn),
+                        _ => matches!(rmpv::decode::read_value(&mut reply.body.as_slice()), Ok(rmpv::Value::Map(_))),
+                    };
+                    match (content_type.starts_with(accept), valid) {
+                        (true, true) => Ok(content_type),
+                        (false, _) => Err(format!("Content-Type {}", content_type)),
+                        (true, false) => Err(format!("unexpected {} body", name)),
+                    }
+                }),
+                Err(e) => Err(e),
+            };
+            self.report(&format!("{} output", name), result);
+        }
+    }
+
+    async fn check_bulk(&mut self, sample: &Sample) {
+        let body = serde_json::to_string(&[sample.ip.as_str(), "not-an-ip"]).unwrap();
+        let headers = [
+            (ACCEPT.as_str(), "application/json"),
+            (CONTENT_TYPE.as_str(), "application/json"),
+        ];
+        let result = match self.send(Method::PUT, "/v1/as/ips", &headers, Some(&body)).await {
+            Ok(reply) => reply.expect(StatusCode::OK).and_then(|reply| reply.json()).and_then(|found| {
+                match found.as_array().map(Vec::as_slice) {
+                    Some([first, second])
+                        if first["as_number"].as_u64() == Some(sample.asn) && second["announced"] == false =>
+                    {
+                        Ok("2 results in input order".to_string())
+                    }
+                    _ => Err("unexpected results".to_string()),
+                }
+            }),
+            Err(e) => Err(e),
+        };
+        self.report("PUT /v1/as/ips", result);
+
+        let line = format!("{} - - [01/Jan/2025:00:00:00 +0000] \"GET / HTTP/1.1\" 200 1\n", sample.ip);
+        let headers = [(CONTENT_TYPE.as_str(), "text/plain")];
+        let result = match self.send(Method::PUT, "/v1/annotate/log", &headers, Some(&line)).await {
+            Ok(reply) => reply.expect(StatusCode::OK).and_then(|reply| {
+                if reply.text().contains(&format!("AS{}", sample.asn)) {
+                    Ok("client address annotated".to_string())
+                } else {
+                    Err("the line isn't annotated".to_string())
+                }
+            }),
+            Err(e) => Err(e),
+        };
+        self.report("PUT /v1/annotate/log", result);
+    }
+
+    async fn check_errors(&mut self) {
+        let json = [(ACCEPT.as_str(), "application/json")];
+        let cases: [(&str, Method, &str, StatusCode); 4] = [
+            ("invalid AS number", Method::GET, "/v1/as/n/not-an-asn", StatusCode::BAD_REQUEST),
+            ("missing AS number", Method::GET, "/v1/as/n", StatusCode::BAD_REQUEST),
+            ("unknown route", Method::GET, "/v1/selftest-no-such-route", StatusCode::NOT_FOUND),
+            ("wrong method", Method::POST, "/version", StatusCode::METHOD_NOT_ALLOWED),
+        ];
+        for (name, method, path, status) in cases {
+            let result = match self.send(method, path, &json, None).await {
+                Ok(reply) => reply.expect(status).and_then(|reply| {
+                    if status == StatusCode::METHOD_NOT_ALLOWED && reply.header("allow").is_none() {
+                        return Err("no Allow header".to_string());
+                    }
+                    Ok(format!("HTTP {}", status.as_u16()))
+                }),
+                Err(e) => Err(e),
+            };
+            self.report(name, result);
+        }
+
+        let result = self.get_json("/v1/as/ip/not-an-ip").await.and_then(|found| {
+            if found["announced"] == false {
+                Ok("reported as not announced".to_string())
+            } else {
+                Err("reported as announced".to_string())
+            }
+        });
+        self.report("invalid IP address", result);
+    }
+
+    // Caching and conditional request headers
+    async fn check_headers(&mut self, sample: &Sample) {
+        let path = format!("/v1/as/ip/{}", sample.ip);
+        let json = (ACCEPT.as_str(), "application/json");
+        let reply = match self.send(Method::GET, &path, &[json], None).await {
+            Ok(reply) => reply,
+            Err(e) => {
+                self.report("caching headers", Err(e));
+                return;
+            }
+        };
+        let missing: Vec<&str> = ["cache-control", "etag", "vary", "x-db-generation"]
+            .into_iter()
+            .filter(|name| reply.header(name).is_none())
+            .collect();
+        let result = if missing.is_empty() {
+            Ok(reply.header("cache-control").unwrap_or("").to_string())
+        } else {
+            Err(format!("missing {}", missing.join(", ")))
+        };
+        self.report("caching headers", result);
+
+        if let Some(etag) = reply.header(ETAG.as_str()) {
+            let headers = [json, (IF_NONE_MATCH.as_str(), etag)];
+            let result = match self.send(Method::GET, &path, &headers, None).await {
+                Ok(reply) => reply.expect(StatusCode::NOT_MODIFIED).map(|_| "HTTP 304".to_string()),
+                Err(e) => Err(e),
+            };
+            self.report("If-None-Match", result);
+        }
+
+        let result = match self.send(Method::HEAD, &path, &[json], None).await {
+            Ok(reply) => reply.expect(StatusCode::OK).and_then(|reply| {
+                if reply.body.is_empty() {
+                    Ok("HTTP 200 without a body".to_string())
+                } else {
+                    Err("the response has a body".to_string())
+                }
+            }),
+            Err(e) => Err(e),
+        };
+        self.report("HEAD", result);
+    }
+
+    pub fn checks(&self) -> &[Check] {
+        &self.results
+    }
+
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|check| check.ok)
+    }
+
+    // Print the report and return whether all checks passed.
+    pub fn print_report(&self) -> bool {
+        for check in &self.results {
+            println!("[{}] {}: {}", if check.ok { " OK " } else { "FAIL" }, check.name, check.detail);
+        }
+        let failed = self.results.iter().filter(|check| !check.ok).count();
+        if failed == 0 {
+            println!("All {} checks passed", self.results.len());
+        } else {
+            println!("{} of {} checks failed", failed, self.results.len());
+        }
+        failed == 0
+    }
+}
@@ -0,0 +1,70 @@
+use http_body_util::{Either, Full};
+use hyper::body::{Body, Bytes, Frame};
+use hyper::Response;
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+// Events buffered for a slow client before the producer waits
+const CHANNEL_CAPACITY: usize = 16;
+
+// Comment line sent while no event is, so that proxies don't time out an
+// idle stream
+pub(crate) const KEEPALIVE: &[u8] = b": keepalive\n\n";
+
+// Events of a streaming response, attached to the response of the handler as
+// an extension and turned into its body by `into_body`. Extensions must be
+// Clone, hence the shared slot.
+#[derive(Clone)]
+pub(crate) struct EventStream(Arc<Mutex<Option<mpsc::Receiver<Bytes>>>>);
+
+impl EventStream {
+    // The stream, and the sender of its events. The stream ends when the
+    // sender is dropped, and sending fails once the client is gone.
+    pub(crate) fn channel() -> (Self, mpsc::Sender<Bytes>) {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        (EventStream(Arc::new(Mutex::new(Some(receiver)))), sender)
+    }
+}
+
+// Body sending the events of a channel as they come
+pub(crate) struct ChannelBody(mpsc::Receiver<Bytes>);
+
+impl Body for ChannelBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        self.0.poll_recv(cx).map(|event| event.map(|event| Ok(Frame::data(event))))
+    }
+}
+
+pub(crate) type ResponseBody = Either<Full<Bytes>, ChannelBody>;
+
+// The response with the events of its EventStream as the body, if it has one
+pub(crate) fn into_body(response: Response<Full<Bytes>>) -> Response<ResponseBody> {
+    let (mut parts, body) = response.into_parts();
+    let receiver = parts
+        .extensions
+        .remove::<EventStream>()
+        .and_then(|stream| stream.0.lock().unwrap_or_else(|e| e.into_inner()).take());
+    match receiver {
+        Some(receiver) => Response::from_parts(parts, Either::Right(ChannelBody(receiver))),
+        None => Response::from_parts(parts, Either::Left(body)),
+    }
+}
+
+// An event in the text/event-stream format, with `data` on a single line
+pub(crate) fn event(name: Option<&str>, id: Option<usize>, data: &str, out: &mut Vec<u8>) {
+    if let Some(id) = id {
+        out.extend_from_slice(format!("id: {}\n", id).as_bytes());
+    }
+    if let Some(name) = name {
+        out.extend_from_slice(format!("event: {}\n", name).as_bytes());
+    }
+    out.extend_from_slice(b"data: ");
+    out.extend_from_slice(data.as_bytes());
+    out.extend_from_slice(b"\n\n");
+}
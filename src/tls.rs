@@ -0,0 +1,125 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+// How often the certificate and key files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// Certificate of the listener (--tls-cert, --tls-key), replaced when its
+// files change, e.g. after a renewal. Handshakes in progress keep the
+// certificate they started with.
+pub struct Certificate {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    // Modification times of the files the current certificate was read from
+    modified: RwLock<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl std::fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Certificate")
+            .field("cert_path", &self.cert_path)
+            .field("key_path", &self.key_path)
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap_or_else(PoisonError::into_inner).clone())
+    }
+}
+
+impl Certificate {
+    // Read a PEM certificate chain and its private key (PKCS#8, PKCS#1 or
+    // SEC1)
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Arc<Self>, String> {
+        let modified = Self::modified(cert_path, key_path);
+        let certified = Self::read(cert_path, key_path)?;
+        Ok(Arc::new(Certificate {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(certified)),
+            modified: RwLock::new(modified),
+        }))
+    }
+
+    fn read(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, String> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("Unable to read the certificate {}: {}", cert_path.display(), e))?;
+        if certs.is_empty() {
+            return Err(format!("No certificate in {}", cert_path.display()));
+        }
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|e| format!("Unable to read the private key {}: {}", key_path.display(), e))?;
+        let key = ring::sign::any_supported_type(&key)
+            .map_err(|e| format!("Unsupported private key {}: {}", key_path.display(), e))?;
+        let certified = CertifiedKey::new(certs, key);
+        // Files being replaced one after the other may not match yet
+        certified.keys_match().map_err(|e| {
+            format!(
+                "The private key {} doesn't match the certificate {}: {}",
+                key_path.display(),
+                cert_path.display(),
+                e
+            )
+        })?;
+        Ok(certified)
+    }
+
+    fn modified(cert_path: &Path, key_path: &Path) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(cert_path), modified(key_path))
+    }
+
+    // Reload the certificate if one of its files changed since it was read.
+    // A certificate that can't be read is retried on the next check, and the
+    // previous one is kept in the meantime.
+    fn reload_if_changed(&self) {
+        let modified = Self::modified(&self.cert_path, &self.key_path);
+        if modified == *self.modified.read().unwrap_or_else(PoisonError::into_inner) {
+            return;
+        }
+        match Self::read(&self.cert_path, &self.key_path) {
+            Ok(certified) => {
+                *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(certified);
+                *self.modified.write().unwrap_or_else(PoisonError::into_inner) = modified;
+                log::info!("TLS certificate {} reloaded", self.cert_path.display());
+            }
+            Err(e) => log::warn!("Keeping the previous TLS certificate: {}", e),
+        }
+    }
+
+    // Check the files for changes until the process exits
+    pub fn watch(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                self.reload_if_changed();
+            }
+        });
+    }
+
+    // Acceptor of TLS connections, negotiating HTTP/2 or HTTP/1.1 with ALPN
+    pub fn acceptor(self: &Arc<Self>) -> Result<TlsAcceptor, String> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
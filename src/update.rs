@@ -0,0 +1,123 @@
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+// GitHub repository whose releases are checked, as owner/name.
+// Can be overridden at build time with IPTOASN_UPDATE_REPO.
+pub const UPDATE_REPO: &str = match option_env!("IPTOASN_UPDATE_REPO") {
+    Some(repo) => repo,
+    None => "rda0/iptoasn-webservice",
+};
+
+// Minimum delay between two automatic checks
+const CHECK_INTERVAL: Duration = Duration::from_secs(7 * 24 * 3600);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+const STATE_FILE_NAME: &str = "update-check";
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+}
+
+// A release newer than the running version
+pub struct Update {
+    pub version: String,
+    pub url: String,
+}
+
+// Compare the running version against the latest GitHub release.
+// Returns None if the running version is up to date.
+pub async fn check() -> Result<Option<Update>, String> {
+    let client = reqwest::Client::builder()
+        .timeout(CHECK_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let url = format!("https://api.github.com/repos/{}/releases/latest", UPDATE_REPO);
+    let res = client
+        .get(&url)
+        .header("Accept", "application/vnd.github+json")
+        .header("User-Agent", concat!("iptoasn/", env!("CARGO_PKG_VERSION")))
+        .send()
+        .await
+        .map_err(|e| format!("Unable to reach {}: {}", url, e))?;
+    if !res.status().is_success() {
+        return Err(format!("{} returned {}", url, res.status()));
+    }
+    let body = res
+        .text()
+        .await
+        .map_err(|e| format!("Unable to read response from {}: {}", url, e))?;
+    let release: Release = serde_json::from_str(&body)
+        .map_err(|e| format!("Invalid response from {}: {}", url, e))?;
+
+    let latest = release.tag_name.trim_start_matches('v');
+    if parse_version(latest) > parse_version(env!("CARGO_PKG_VERSION")) {
+        Ok(Some(Update {
+            version: latest.to_string(),
+            url: release.html_url,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+// Automatic check, done at most once per CHECK_INTERVAL. It runs in the
+// background so that it never delays the command, and its notice is only
+// printed if it completes before the command does. Failures (e.g. when
+// offline) are silent, and the next attempt waits for the interval too.
+pub fn notify_if_due() {
+    if std::env::var_os("IPTOASN_NO_UPDATE_CHECK").is_some() {
+        return;
+    }
+    tokio::spawn(async {
+        if !tokio::task::spawn_blocking(claim_check).await.unwrap_or(false) {
+            return;
+        }
+        if let Ok(Ok(Some(update))) = tokio::time::timeout(CHECK_TIMEOUT, check()).await {
+            eprintln!(
+                "A new version of iptoasn is available: {} (running {}): {}",
+                update.version,
+                env!("CARGO_PKG_VERSION"),
+                update.url
+            );
+            eprintln!("Set IPTOASN_NO_UPDATE_CHECK=1 to disable this notice.");
+        }
+    });
+}
+
+// Whether a check is due, recording it in the state file if so
+fn claim_check() -> bool {
+    let Some(state_file) = state_file_path() else {
+        return false;
+    };
+    let due = fs::metadata(&state_file)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_none_or(|elapsed| elapsed >= CHECK_INTERVAL);
+    if due {
+        if let Some(dir) = state_file.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let _ = fs::write(&state_file, env!("CARGO_PKG_VERSION"));
+    }
+    due
+}
+
+fn state_file_path() -> Option<PathBuf> {
+    let cache_file = crate::asns::Asns::default_cache_file_path()?;
+    Some(cache_file.parent()?.join(STATE_FILE_NAME))
+}
+
+// "0.2.10" -> [0, 2, 10]; pre-release suffixes are ignored
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or("")
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
@@ -0,0 +1,341 @@
+use crate::config::TenantConfig;
+use http::header::AUTHORIZATION;
+use http::HeaderMap;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::Duration;
+use time::{Date, Month, OffsetDateTime};
+
+// Tenants of the service, and the tenant of each API key (set_tenants)
+static TENANTS: OnceLock<Tenants> = OnceLock::new();
+
+struct Tenants {
+    list: Vec<Tenant>,
+    by_key: HashMap<String, usize>,
+}
+
+// File keeping the monthly counters across restarts (persist_to), and
+// whether they changed since it was last written
+static STATE_FILE: OnceLock<PathBuf> = OnceLock::new();
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+// How often changed counters are written
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Contents of the state file
+#[derive(Serialize, Deserialize)]
+struct SavedUsage {
+    // YYYY-MM, as in /admin/usage
+    month: String,
+    tenants: HashMap<String, SavedCounters>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedCounters {
+    requests: u64,
+    ips: u64,
+}
+
+// Usage of the current calendar month (UTC)
+#[derive(Default)]
+struct MonthlyUsage {
+    month: u32,
+    requests: u64,
+    ips: u64,
+}
+
+// A team using the service with one or more API keys
+pub struct Tenant {
+    name: String,
+    monthly_quota: Option<u64>,
+    monthly: Mutex<MonthlyUsage>,
+    // Since startup, for Prometheus counters
+    total_requests: AtomicU64,
+    total_ips: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct TenantUsage {
+    pub tenant: String,
+    pub requests: u64,
+    pub ips: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub monthly_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct UsageReport {
+    pub month: String,
+    pub tenants: Vec<TenantUsage>,
+}
+
+// Months since year 0, so that a change of month is a change of value
+fn current_month() -> u32 {
+    let now = OffsetDateTime::now_utc();
+    now.year() as u32 * 12 + u8::from(now.month()) as u32 - 1
+}
+
+// Time until the monthly quotas are reset
+pub fn until_next_month() -> Duration {
+    let now = OffsetDateTime::now_utc();
+    let (year, month) = match now.month() {
+        Month::December => (now.year() + 1, Month::January),
+        month => (now.year(), month.next()),
+    };
+    Date::from_calendar_date(year, month, 1)
+        .map(|date| (date.midnight().assume_utc() - now).unsigned_abs())
+        .unwrap_or_default()
+}
+
+// Set the tenants from the [[tenants]] tables of the config file. Must be
+// called before the server is started; without tenants, nothing is counted.
+pub fn set_tenants(tenants: Vec<TenantConfig>) {
+    let mut by_key = HashMap::new();
+    let list = tenants
+        .into_iter()
+        .enumerate()
+        .map(|(i, tenant)| {
+            for key in tenant.keys {
+                by_key.insert(key, i);
+            }
+            Tenant {
+                name: tenant.name,
+                monthly_quota: tenant.monthly_quota,
+                monthly: Mutex::default(),
+                total_requests: AtomicU64::new(0),
+                total_ips: AtomicU64::new(0),
+            }
+        })
+        .collect();
+    let _ = TENANTS.set(Tenants { list, by_key });
+}
+
+// Keep the monthly counters in this file, restoring those of the current
+// month it holds. Must be called after set_tenants, before the server is
+// started.
+pub fn persist_to(path: PathBuf) {
+    let Some(tenants) = TENANTS.get().filter(|tenants| !tenants.list.is_empty()) else {
+        return;
+    };
+    match fs::read(&path) {
+        Ok(bytes) => match serde_json::from_slice::<SavedUsage>(&bytes) {
+            Ok(saved) if saved.month == month_name() => {
+                for tenant in &tenants.list {
+                    if let Some(counters) = saved.tenants.get(&tenant.name) {
+                        let mut monthly = tenant.monthly();
+                        monthly.requests = counters.requests;
+                        monthly.ips = counters.ips;
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Ignoring the usage counters of {}: {}", path.display(), e),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Unable to read the usage counters of {}: {}", path.display(), e),
+    }
+    let _ = STATE_FILE.set(path);
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let _ = tokio::task::spawn_blocking(save).await;
+        }
+    });
+}
+
+// Write the monthly counters to the state file, if they changed. Also called
+// before exiting.
+pub fn save() {
+    let (Some(path), Some(tenants)) = (STATE_FILE.get(), TENANTS.get()) else {
+        return;
+    };
+    if !CHANGED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let saved = SavedUsage {
+        month: month_name(),
+        tenants: tenants
+            .list
+            .iter()
+            .map(|tenant| {
+                let monthly = tenant.monthly();
+                let counters = SavedCounters {
+                    requests: monthly.requests,
+                    ips: monthly.ips,
+                };
+                (tenant.name.clone(), counters)
+            })
+            .collect(),
+    };
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let written = serde_json::to_vec(&saved)
+        .map_err(std::io::Error::other)
+        .and_then(|bytes| fs::write(&tmp, bytes))
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = written {
+        warn!("Unable to save the usage counters to {}: {}", path.display(), e);
+        CHANGED.store(true, Ordering::Relaxed);
+    }
+}
+
+// API key sent with an `X-Api-Key` or an `Authorization: Bearer` header
+pub fn api_key(headers: &HeaderMap) -> Option<&str> {
+    if let Some(key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return Some(key.trim());
+    }
+    let authorization = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, key) = authorization.trim().split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| key.trim())
+}
+
+// Tenant owning the API key of a request, if any
+pub fn tenant(headers: &HeaderMap) -> Option<&'static Tenant> {
+    tenant_by_key(api_key(headers)?)
+}
+
+pub fn tenant_by_key(key: &str) -> Option<&'static Tenant> {
+    let tenants = TENANTS.get()?;
+    let i = *tenants.by_key.get(key)?;
+    tenants.list.get(i)
+}
+
+// Current month, as YYYY-MM
+fn month_name() -> String {
+    let now = OffsetDateTime::now_utc();
+    format!("{:04}-{:02}", now.year(), u8::from(now.month()))
+}
+
+pub fn report() -> UsageReport {
+    UsageReport {
+        month: month_name(),
+        tenants: TENANTS
+            .get()
+            .map(|tenants| tenants.list.iter().map(Tenant::usage).collect())
+            .unwrap_or_default(),
+    }
+}
+
+// Per-tenant counters in the Prometheus text format
+pub fn write_metrics(out: &mut String) {
+    let Some(tenants) = TENANTS.get().filter(|tenants| !tenants.list.is_empty()) else {
+        return;
+    };
+    out.push_str("# HELP iptoasn_tenant_requests_total Number of requests per tenant.\n");
+    out.push_str("# TYPE iptoasn_tenant_requests_total counter\n");
+    for tenant in &tenants.list {
+        out.push_str(&format!(
+            "iptoasn_tenant_requests_total{{tenant=\"{}\"}} {}\n",
+            tenant.label(),
+            tenant.total_requests.load(Ordering::Relaxed)
+        ));
+    }
+    out.push_str("# HELP iptoasn_tenant_ips_total Number of IP addresses looked up per tenant.\n");
+    out.push_str("# TYPE iptoasn_tenant_ips_total counter\n");
+    for tenant in &tenants.list {
+        out.push_str(&format!(
+            "iptoasn_tenant_ips_total{{tenant=\"{}\"}} {}\n",
+            tenant.label(),
+            tenant.total_ips.load(Ordering::Relaxed)
+        ));
+    }
+}
+
+impl Tenant {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Usage of the current month, reset when a new month starts
+    fn monthly(&self) -> MutexGuard<'_, MonthlyUsage> {
+        let mut monthly = self.monthly.lock().unwrap_or_else(PoisonError::into_inner);
+        let month = current_month();
+        if monthly.month != month {
+            *monthly = MonthlyUsage {
+                month,
+                ..Default::default()
+            };
+        }
+        monthly
+    }
+
+    pub fn monthly_quota(&self) -> Option<u64> {
+        self.monthly_quota
+    }
+
+    // IP lookups left this month, if the tenant has a quota
+    pub fn remaining(&self) -> Option<u64> {
+        self.monthly_quota
+            .map(|quota| quota.saturating_sub(self.monthly().ips))
+    }
+
+    // Whether the monthly quota of IP lookups is used up
+    pub fn over_quota(&self) -> bool {
+        self.monthly_quota
+            .is_some_and(|quota| self.monthly().ips >= quota)
+    }
+
+    // Error message once the quota is used up, or too little of it is left
+    // for the `requested` addresses of a bulk request
+    pub fn quota_message(&self, requested: Option<u64>) -> String {
+        match (requested, self.remaining()) {
+            (Some(requested), Some(remaining)) if remaining > 0 => format!(
+                "Monthly quota of tenant {} exceeded: {} IP addresses requested, {} left",
+                self.name, requested, remaining
+            ),
+            _ => format!("Monthly quota of tenant {} exceeded", self.name),
+        }
+    }
+
+    pub fn record_request(&self) {
+        self.monthly().requests += 1;
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+        CHANGED.store(true, Ordering::Relaxed);
+    }
+
+    // Count IP lookups before they are made. If fewer are left this month,
+    // nothing is counted and false is returned.
+    pub fn charge_ips(&self, count: u64) -> bool {
+        let mut monthly = self.monthly();
+        if self
+            .monthly_quota
+            .is_some_and(|quota| monthly.ips.saturating_add(count) > quota)
+        {
+            return false;
+        }
+        monthly.ips += count;
+        self.total_ips.fetch_add(count, Ordering::Relaxed);
+        CHANGED.store(true, Ordering::Relaxed);
+        true
+    }
+
+    fn usage(&self) -> TenantUsage {
+        let monthly = self.monthly();
+        TenantUsage {
+            tenant: self.name.clone(),
+            requests: monthly.requests,
+            ips: monthly.ips,
+            monthly_quota: self.monthly_quota,
+            remaining: self
+                .monthly_quota
+                .map(|quota| quota.saturating_sub(monthly.ips)),
+        }
+    }
+
+    // Name escaped for a Prometheus label value
+    fn label(&self) -> String {
+        self.name
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    }
+}
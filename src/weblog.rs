@@ -0,0 +1,252 @@
+use std::net::IpAddr;
+use std::ops::Range;
+use std::str::FromStr;
+
+// Log line formats the client IP address can be extracted from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    // Detect the format of each line
+    Auto,
+    // Common, combined and vhost_combined access logs
+    Apache,
+    // Access logs (same layout as Apache's combined) and error logs
+    Nginx,
+    // RFC 3164 and RFC 5424 syslog messages
+    Syslog,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(LogFormat::Auto),
+            "apache" => Ok(LogFormat::Apache),
+            "nginx" => Ok(LogFormat::Nginx),
+            "syslog" => Ok(LogFormat::Syslog),
+            _ => Err(format!(
+                "Unsupported log format \"{}\", expected auto, apache, nginx or syslog",
+                s
+            )),
+        }
+    }
+}
+
+impl LogFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogFormat::Auto => "auto",
+            LogFormat::Apache => "apache",
+            LogFormat::Nginx => "nginx",
+            LogFormat::Syslog => "syslog",
+        }
+    }
+
+    // Guess the format of a single line; None if it doesn't look like any
+    fn detect(line: &str) -> Option<LogFormat> {
+        if Self::syslog_message(line).is_some() {
+            return Some(LogFormat::Syslog);
+        }
+        if Self::is_nginx_error(line) {
+            return Some(LogFormat::Nginx);
+        }
+        if Self::access_client(line).is_some() {
+            return Some(LogFormat::Apache);
+        }
+        None
+    }
+
+    // Client IP address of a log line, with its position in the line, and
+    // the format the line was parsed as (the detected one for Auto).
+    pub fn client_ip(&self, line: &str) -> Option<(LogFormat, IpAddr, Range<usize>)> {
+        let format = match self {
+            LogFormat::Auto => Self::detect(line)?,
+            format => *format,
+        };
+        let (ip, range) = match format {
+            LogFormat::Auto => return None,
+            LogFormat::Apache => Self::access_client(line)?,
+            LogFormat::Nginx if Self::is_nginx_error(line) => {
+                let start = line.find("client: ")? + "client: ".len();
+                ip_at(line, start)?
+            }
+            LogFormat::Nginx => Self::access_client(line)?,
+            LogFormat::Syslog => {
+                let offset = Self::syslog_message(line)?;
+                let (ip, range) = syslog_client(&line[offset..])?;
+                (ip, range.start + offset..range.end + offset)
+            }
+        };
+        Some((format, ip, range))
+    }
+
+    // Same as client_ip, for lines that may not be valid UTF-8 (common in
+    // attack traffic). The range applies to the original bytes.
+    pub fn client_ip_bytes(&self, line: &[u8]) -> Option<(LogFormat, IpAddr, Range<usize>)> {
+        match std::str::from_utf8(line) {
+            Ok(line) => self.client_ip(line),
+            Err(_) => self.client_ip(&ascii_placeholders(line)),
+        }
+    }
+
+    // Access logs start with the client address, or with vhost:port
+    // followed by the client address (Apache's vhost_combined).
+    fn access_client(line: &str) -> Option<(IpAddr, Range<usize>)> {
+        let mut fields = line.split(' ');
+        let first = fields.next()?;
+        if let Some(found) = ip_at(line, 0).filter(|(_, range)| range.end == first.len()) {
+            return Some(found);
+        }
+        let (_, port) = first.rsplit_once(':')?;
+        if port.is_empty() || !port.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let second = fields.next()?;
+        let start = first.len() + 1;
+        ip_at(line, start).filter(|(_, range)| range.end == start + second.len())
+    }
+
+    // 2025/10/27 12:10:13 [error] 1234#0: *5 ..., client: 192.0.2.1, ...
+    fn is_nginx_error(line: &str) -> bool {
+        let b = line.as_bytes();
+        b.len() > 21
+            && b[4] == b'/'
+            && b[7] == b'/'
+            && b[10] == b' '
+            && b[13] == b':'
+            && b[20] == b'['
+            && line.contains("client: ")
+    }
+
+    // Offset of the message part of a syslog line, after the header
+    fn syslog_message(line: &str) -> Option<usize> {
+        // Optional <PRI>
+        let body_start = match line.strip_prefix('<') {
+            Some(rest) => {
+                let end = rest.find('>')?;
+                if end == 0 || end > 3 || !rest[..end].bytes().all(|b| b.is_ascii_digit()) {
+                    return None;
+                }
+                end + 2
+            }
+            None => 0,
+        };
+        let body = &line[body_start..];
+
+        // RFC 5424: VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID SD MSG
+        if let Some(rest) = body.strip_prefix("1 ") {
+            let mut offset = body_start + 2;
+            let mut rest = rest;
+            for _ in 0..5 {
+                let (field, tail) = rest.split_once(' ')?;
+                offset += field.len() + 1;
+                rest = tail;
+            }
+            // Structured data is either "-" or a sequence of [...] elements
+            if let Some(tail) = rest.strip_prefix("- ") {
+                return Some(offset + rest.len() - tail.len());
+            }
+            if rest.starts_with('[') {
+                let end = rest.find("] ").map_or(rest.len(), |i| i + 2);
+                return Some(offset + end);
+            }
+            return None;
+        }
+
+        // RFC 3164: Mmm dd hh:mm:ss HOSTNAME TAG: MSG
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+        let b = body.as_bytes();
+        if b.len() < 16
+            || !MONTHS.iter().any(|m| body.starts_with(m))
+            || b[3] != b' '
+            || b[9] != b':'
+            || b[12] != b':'
+            || b[15] != b' '
+        {
+            return None;
+        }
+        let rest = &body[16..];
+        let (_host, after_host) = rest.split_once(' ')?;
+        let tag_end = after_host.find(": ")?;
+        Some(line.len() - after_host.len() + tag_end + 2)
+    }
+}
+
+// Well-known ways of naming the client in syslog messages (sshd, PAM,
+// iptables, postfix, ...), tried before falling back to the first address.
+const SYSLOG_CLIENT_MARKERS: [&str; 6] = ["from ", "rhost=", "SRC=", "client=", "client: ", "[client "];
+
+fn syslog_client(message: &str) -> Option<(IpAddr, Range<usize>)> {
+    for marker in SYSLOG_CLIENT_MARKERS {
+        for (i, _) in message.match_indices(marker) {
+            let mut start = i + marker.len();
+            // postfix: client=host[192.0.2.1]
+            if let Some(bracket) = message[start..].find('[') {
+                if message[start..start + bracket]
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+                {
+                    start += bracket + 1;
+                }
+            }
+            if let Some(found) = ip_at(message, start) {
+                return Some(found);
+            }
+        }
+    }
+    first_ip(message)
+}
+
+// Replace each byte of invalid UTF-8 sequences with '?', so that offsets
+// in the result are offsets in the original bytes
+fn ascii_placeholders(mut bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                out.push_str(valid);
+                return out;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                out.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                let invalid = e.error_len().unwrap_or(rest.len());
+                out.extend(std::iter::repeat_n('?', invalid));
+                bytes = &rest[invalid..];
+            }
+        }
+    }
+}
+
+fn is_ip_char(b: u8) -> bool {
+    b.is_ascii_hexdigit() || b == b'.' || b == b':'
+}
+
+// First IP address token of a string
+fn first_ip(s: &str) -> Option<(IpAddr, Range<usize>)> {
+    let b = s.as_bytes();
+    (0..b.len())
+        .filter(|&i| is_ip_char(b[i]) && (i == 0 || !is_ip_char(b[i - 1])))
+        .find_map(|i| ip_at(s, i))
+}
+
+// IP address starting at `start` (optionally in [brackets] or followed by
+// a :port), with the range of the address itself
+fn ip_at(s: &str, start: usize) -> Option<(IpAddr, Range<usize>)> {
+    let b = s.as_bytes();
+    let start = if b.get(start) == Some(&b'[') { start + 1 } else { start };
+    let len = b[start..].iter().take_while(|&&c| is_ip_char(c)).count();
+    let token = s[start..start + len].trim_end_matches(['.', ':']);
+    if let Ok(ip) = IpAddr::from_str(token) {
+        return Some((ip, start..start + token.len()));
+    }
+    // IPv4 address with a port
+    let (host, _) = token.rsplit_once(':')?;
+    if host.contains(':') {
+        return None;
+    }
+    let ip = IpAddr::from_str(host).ok()?;
+    Some((ip, start..start + host.len()))
+}
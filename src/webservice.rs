@@ -1,177 +1,2600 @@
-use crate::asns::Asns;
+use crate::admin;
+use crate::asn_registry;
+use crate::ascii;
+use crate::asns::{Asn, Asns, DbSource, OriginWindow, SnapshotCounts, StatsSubject};
+use crate::auth;
+use crate::build_info::{self, BuildInfo};
+use crate::negotiation;
+use crate::net;
+use crate::openapi;
+use crate::cidr::{self, IpRangeSet, SubnetOrder};
+use crate::deprecation::{self, Deprecation};
+use crate::dnsbl;
+use crate::enrich;
+use crate::config::{Format, HostConfig};
+use crate::input::InputKind;
+use crate::ip_list::PlainIpList;
+use crate::load;
+use crate::prefix_list::PrefixListFormat;
+use crate::refresh::{DownloadProgress, Refresher};
+use crate::replication;
+#[cfg(feature = "csv")]
+use crate::render::Table;
+use crate::render::{self, AsnNotation, OutputType, Render, Style};
+use crate::router::{self, Resolution, Route};
+use crate::sse::{self, EventStream};
+use crate::tls;
+use crate::usage::{self, Tenant};
+use crate::weblog::LogFormat;
+use flate2::read::MultiGzDecoder;
 use horrorshow::prelude::*;
-use http::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE, EXPIRES, VARY};
-use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode};
-use http_body_util::{BodyExt, Full};
-use hyper::body::Bytes;
+use http::header::{
+    ACCEPT, ACCEPT_ENCODING, ALLOW, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, EXPIRES,
+    HOST, IF_NONE_MATCH, LOCATION, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+};
+use http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Bytes};
 use hyper::service::service_fn;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyper_util::server::conn::auto;
+use ruzstd::decoding::StreamingDecoder;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::convert::Infallible;
+use std::io::Read;
+use std::borrow::Cow;
 use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
-use time::macros::format_description;
-use time::OffsetDateTime;
-use tokio::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
 
 const TTL: u32 = 86_400;
 
-#[derive(PartialEq, Eq)]
-enum OutputType {
-    Json,
-    Html,
-    Plain,
-}
+// Lookup results get a warning when the database is older than this, or
+// when their ASN appears in fewer ranges than FEW_RANGES
+const STALE_DATABASE_AGE: Duration = Duration::from_secs(48 * 3600);
+const FEW_RANGES: usize = 5;
+
+const BULK_PAGE: &str = include_str!("bulk.html");
+
+// Separator between the fields of plain outputs, unless set with ?sep=
+const DEFAULT_FIELD_SEPARATOR: &str = " | ";
+
+// Idle time after which a lookup stream sends a keepalive comment
+const STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+// Response headers identifying the database that served the response
+pub(crate) const DB_GENERATION: HeaderName = HeaderName::from_static("x-db-generation");
+pub(crate) const DB_UPDATED: HeaderName = HeaderName::from_static("x-db-updated");
+// Set to stale_database when the database is older than STALE_DATABASE_AGE
+pub(crate) const DB_WARNING: HeaderName = HeaderName::from_static("x-db-warning");
+
+// Number of items of a paginated list, all pages included
+pub(crate) const TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+// Number of request handlers that panicked, exposed on /metrics
+static HANDLER_PANICS: AtomicU64 = AtomicU64::new(0);
+
+// Upper bound for the X-Request-Timeout header of bulk requests
+static MAX_REQUEST_TIMEOUT_MS: AtomicU64 = AtomicU64::new(30_000);
+
+// Maximum size of a request body as sent (--max-body)
+static MAX_BODY: AtomicU64 = AtomicU64::new(64 << 20);
+
+// Maximum size of a compressed request body once decompressed (--max-decoded-body)
+static MAX_DECODED_BODY: AtomicU64 = AtomicU64::new(64 << 20);
+
+// Maximum size of a snapshot pushed by the primary, as sent and once
+// decompressed (--max-replication-body)
+static MAX_REPLICATION_BODY: AtomicU64 = AtomicU64::new(256 << 20);
+
+// Maximum number of addresses of a bulk lookup (--max-bulk-ips)
+static MAX_BULK_IPS: AtomicUsize = AtomicUsize::new(100_000);
+
+// Maximum ?limit= of the list routes (--max-page-size)
+static MAX_PAGE_SIZE: AtomicUsize = AtomicUsize::new(10_000);
+
+// Cache lifetime of lookups of unannounced addresses, in seconds
+static NOT_FOUND_TTL: AtomicU64 = AtomicU64::new(3_600);
+
+// Whether cacheable responses include an Expires header
+static EXPIRES_ENABLED: AtomicBool = AtomicBool::new(true);
+
+// Whether unsupported Accept and Content-Type headers are rejected
+// (strict_negotiation in the config file)
+static STRICT_NEGOTIATION: AtomicBool = AtomicBool::new(false);
+
+// Whether AS numbers missing from the database get 404 instead of 200
+// (strict_not_found in the config file)
+static STRICT_NOT_FOUND: AtomicBool = AtomicBool::new(false);
+
+// Transliterate AS descriptions to ASCII in plain text output
+static PLAIN_ASCII_DESCRIPTIONS: AtomicBool = AtomicBool::new(false);
+
+// When the server was started, for /admin/status
+static STARTED: OnceLock<SystemTime> = OnceLock::new();
+
+// URL prefix all routes are served under (--base-path), without trailing slash
+static BASE_PATH: OnceLock<String> = OnceLock::new();
+
+// Maximum number of ranges listed by /v1/as/prefix, for short prefixes
+const PREFIX_MAX_RANGES: usize = 1000;
+
+// Cache file whose retained snapshots /v1/as/ip/<ip>/history reads
+static CACHE_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+// Per-Host behavior from the config file
+static HOSTS: OnceLock<Vec<HostConfig>> = OnceLock::new();
+
+// Peers whose X-Real-IP and X-Forwarded-For headers are honored
+// (--trusted-proxies)
+static TRUSTED_PROXIES: OnceLock<IpRangeSet> = OnceLock::new();
+
+// Certificate of the HTTPS listener (--tls-cert, --tls-key)
+static TLS_CERTIFICATE: OnceLock<Arc<tls::Certificate>> = OnceLock::new();
+
+// Time allowed for the TLS handshake of a new connection
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 enum BodyInputType {
     Json,
+    // One IP string or {"ip": "...", "id": ...} object per line
+    Ndjson,
     Plain,
 }
 
 #[derive(Default, Serialize, Deserialize)]
-struct IpLookupResponse {
-    ip: String,
-    announced: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    first_ip: Option<String>,
+pub struct IpLookupResponse {
+    pub ip: String,
+    pub announced: bool,
+    // Whether the address is in a row of the dataset, AS0 rows of space
+    // listed as not routed included
+    #[serde(default)]
+    pub covered: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
-    last_ip: Option<String>,
+    pub first_ip: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    as_number: Option<u32>,
+    pub last_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "render::serialize_asn_option")]
+    pub as_number: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    as_country_code: Option<String>,
+    pub as_country_code: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    as_description: Option<String>,
+    pub as_description: Option<String>,
+    // ASCII transliteration of a description that isn't ASCII
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub as_description_ascii: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<LookupWarning>,
+    // Identifier sent by the client with the address (NDJSON bulk input)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    // Position of the address in a bulk request, from 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    // Fields added by the enrichers (enrich::set_enrichers)
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl IpLookupResponse {
+    // Fields in the order of IP_COLUMNS
+    #[cfg(feature = "csv")]
+    fn row(&self, style: &Style) -> Vec<String> {
+        vec![
+            self.ip.clone(),
+            self.announced.to_string(),
+            self.first_ip.clone().unwrap_or_default(),
+            self.last_ip.clone().unwrap_or_default(),
+            style.asn(self.as_number.unwrap_or(0)),
+            self.as_country_code.clone().unwrap_or_default(),
+            self.as_description.clone().unwrap_or_default(),
+            self.covered.to_string(),
+        ]
+    }
+
     fn not_found(ip: String) -> Self {
         Self {
             ip,
             ..Default::default()
         }
     }
-}
 
-#[derive(Serialize)]
-struct AsMetaResponse {
-    as_number: u32,
-    as_country_code: String,
-    as_description: String,
-}
+    fn found(ip: String, found: &Asn) -> Self {
+        Self {
+            ip,
+            announced: true,
+            covered: true,
+            first_ip: Some(found.first_ip.to_string()),
+            last_ip: Some(found.last_ip.to_string()),
+            as_number: Some(found.number),
+            as_country_code: Some(found.country.to_string()),
+            as_description: Some(found.description.to_string()),
+            as_description_ascii: ascii::fold_non_ascii(&found.description),
+            ..Default::default()
+        }
+    }
+
+    // Address in an AS0 row: listed by the dataset, but not routed
+    fn not_routed(ip: String, row: &Asn) -> Self {
+        Self {
+            ip,
+            covered: true,
+            first_ip: Some(row.first_ip.to_string()),
+            last_ip: Some(row.last_ip.to_string()),
+            ..Default::default()
+        }
+    }
+
+    // Look up an address, flagging results that shouldn't be trusted blindly
+    fn lookup(ip: IpAddr, asns: &Asns) -> Self {
+        let found = asns.lookup_row_by_ip(ip);
+        let mut response = match &found {
+            Some(found) if found.number > 0 => Self::found(ip.to_string(), found),
+            Some(row) => Self::not_routed(ip.to_string(), row),
+            None => Self::not_found(ip.to_string()),
+        };
+        let announced = found.as_deref().filter(|found| found.number > 0);
+        response.warnings = LookupWarning::check(asns, announced);
+        response.extra = enrich::extra_fields(&response);
+        response
+    }
+}
+
+// Condition that lowers the confidence in a lookup result
+#[derive(Serialize, Deserialize)]
+pub struct LookupWarning {
+    pub code: String,
+    pub message: String,
+}
+
+impl LookupWarning {
+    fn new(code: &str, message: String) -> Self {
+        LookupWarning {
+            code: code.to_string(),
+            message,
+        }
+    }
+
+    fn check(asns: &Asns, found: Option<&Asn>) -> Vec<LookupWarning> {
+        // The age of the database is flagged once per response, by
+        // generation_headers
+        let mut warnings = Vec::new();
+        let Some(found) = found else {
+            return warnings;
+        };
+        let larger_than_slash8 = match (found.first_ip, found.last_ip) {
+            (IpAddr::V4(first), IpAddr::V4(last)) => u32::from(last) - u32::from(first) >= 1 << 24,
+            (IpAddr::V6(first), IpAddr::V6(last)) => u128::from(last) - u128::from(first) >= 1 << 120,
+            _ => false,
+        };
+        if larger_than_slash8 {
+            warnings.push(Self::new(
+                "large_range",
+                "Range is larger than a /8, likely a placeholder".to_string(),
+            ));
+        }
+        let ranges = asns.range_count(found.number);
+        if found.number != 0 && ranges < FEW_RANGES {
+            warnings.push(Self::new(
+                "few_ranges",
+                format!("AS{} appears in only {} range(s)", found.number, ranges),
+            ));
+        }
+        warnings
+    }
+}
+
+// NDJSON record of PUT /v1/annotate/log
+#[derive(Serialize)]
+struct AnnotatedLogLine<'a> {
+    line: &'a str,
+    // Format the line was parsed as, if a client address was found
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<&'static str>,
+    #[serde(flatten)]
+    lookup: Option<IpLookupResponse>,
+}
+
+#[derive(Serialize)]
+struct AsMetaResponse {
+    #[serde(serialize_with = "render::serialize_asn")]
+    as_number: u32,
+    // Whether the AS number is in the database
+    found: bool,
+    as_country_code: String,
+    as_description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_description_ascii: Option<String>,
+    // Code of the IANA special-purpose registry entry, e.g. private_use
+    #[serde(skip_serializing_if = "Option::is_none")]
+    special_purpose: Option<&'static str>,
+}
+
+#[derive(Serialize)]
+struct OriginHistoryResponse {
+    ip: String,
+    // Number of retained snapshots scanned
+    snapshots: usize,
+    // Origin AS windows, oldest first
+    history: Vec<OriginHistoryEntry>,
+}
+
+#[derive(Serialize)]
+struct OriginHistoryEntry {
+    // Unix timestamps of the oldest and newest snapshot of the window
+    first_seen: u64,
+    last_seen: u64,
+    // 0 when the address wasn't announced
+    #[serde(serialize_with = "render::serialize_asn")]
+    as_number: u32,
+    as_country_code: String,
+    as_description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_description_ascii: Option<String>,
+    snapshots: usize,
+}
+
+impl From<OriginWindow> for OriginHistoryEntry {
+    fn from(window: OriginWindow) -> Self {
+        let unix = |t: SystemTime| t.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        OriginHistoryEntry {
+            first_seen: unix(window.first_seen),
+            last_seen: unix(window.last_seen),
+            as_number: window.number,
+            as_country_code: window.country,
+            as_description_ascii: ascii::fold_non_ascii(&window.description),
+            as_description: window.description,
+            snapshots: window.snapshots,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TimeseriesResponse {
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "render::serialize_asn_option")]
+    as_number: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country_code: Option<String>,
+    metric: &'static str,
+    // One point per retained snapshot, oldest first
+    points: Vec<TimeseriesPoint>,
+}
+
+#[derive(Serialize)]
+struct TimeseriesPoint {
+    // Unix timestamps of when the snapshot was first and last seen
+    first_seen: u64,
+    last_seen: u64,
+    value: u64,
+}
+
+// Value of a point of /v1/stats/timeseries
+type TimeseriesMetric = fn(&SnapshotCounts) -> u64;
+
+// Metrics of /v1/stats/timeseries, the first one being the default
+const TIMESERIES_METRICS: &[(&str, TimeseriesMetric)] = &[
+    ("prefix_count", |counts| counts.prefixes),
+    ("ipv4_addresses", |counts| counts.ipv4_addresses),
+    ("ipv6_64s", |counts| counts.ipv6_64s),
+];
+
+#[derive(Serialize)]
+struct PrefixLookupResponse {
+    prefix: String,
+    #[serde(flatten)]
+    announcements: Announcements,
+}
+
+#[derive(Serialize)]
+struct RangeLookupResponse {
+    first_ip: String,
+    last_ip: String,
+    // Minimal CIDR decomposition of the range
+    cidrs: Vec<String>,
+    #[serde(flatten)]
+    announcements: Announcements,
+}
+
+// Announcements within the addresses of a prefix or range
+#[derive(Serialize)]
+struct Announcements {
+    // full, partial or none: how much of the addresses is announced
+    coverage: &'static str,
+    // Distinct AS numbers announcing parts of the addresses
+    #[serde(serialize_with = "render::serialize_asns")]
+    as_numbers: Vec<u32>,
+    // Share of the addresses announced by each AS number, largest first
+    ownership: Vec<PrefixShare>,
+    unannounced_addresses: String,
+    unannounced_percent: f64,
+    // Announced ranges overlapping the addresses
+    ranges: Vec<PrefixRange>,
+    // Whether only the first PREFIX_MAX_RANGES ranges are listed
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+#[derive(Serialize)]
+struct PrefixShare {
+    #[serde(serialize_with = "render::serialize_asn")]
+    as_number: u32,
+    // Decimal string, as IPv6 counts exceed the integers of JSON parsers
+    addresses: String,
+    percent: f64,
+}
+
+#[derive(Serialize)]
+struct PrefixRange {
+    first_ip: String,
+    last_ip: String,
+    #[serde(serialize_with = "render::serialize_asn")]
+    as_number: u32,
+    as_country_code: String,
+    as_description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_description_ascii: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AsSubnetsResponse {
+    #[serde(serialize_with = "render::serialize_asn")]
+    as_number: u32,
+    subnets: Vec<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    page: Option<PageInfo>,
+}
+
+#[derive(Serialize)]
+struct AsCountriesResponse {
+    #[serde(serialize_with = "render::serialize_asn")]
+    as_number: u32,
+    countries: Vec<AsCountry>,
+}
+
+#[derive(Serialize)]
+struct AsCountry {
+    country_code: String,
+    ranges: usize,
+    prefixes: usize,
+}
+
+// GET /v1/dnsbl/<query>, the A record a DNSBL zone would answer with
+#[derive(Serialize)]
+struct DnsblResponse {
+    query: String,
+    ip: String,
+    listed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    answer: Option<String>,
+    categories: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "render::serialize_asn_option")]
+    as_number: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_country_code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CountryAsnsResponse {
+    country_code: String,
+    #[serde(serialize_with = "render::serialize_asns")]
+    as_numbers: Vec<u32>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    page: Option<PageInfo>,
+}
+
+#[derive(Serialize)]
+struct CountrySubnetsResponse {
+    country_code: String,
+    subnets: Vec<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    page: Option<PageInfo>,
+}
+
+// ?offset=&limit= of the list routes
+#[derive(Clone, Copy)]
+struct Page {
+    offset: usize,
+    limit: Option<usize>,
+}
+
+// Position of a page in the whole list, added to JSON objects
+#[derive(Serialize)]
+struct PageInfo {
+    total: usize,
+    offset: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit: Option<usize>,
+    // Offset of the next page, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_offset: Option<usize>,
+}
+
+impl Page {
+    // None when the whole list is requested
+    fn from_query(query: Option<&str>) -> Result<Option<Page>, String> {
+        let param = |name| {
+            WebService::query_param(query, name)
+                .map(|value| {
+                    usize::from_str(value).map_err(|_| {
+                        format!("Invalid {} \"{}\", expected a non-negative integer", name, value)
+                    })
+                })
+                .transpose()
+        };
+        let (offset, limit) = (param("offset")?, param("limit")?);
+        let max = MAX_PAGE_SIZE.load(Ordering::Relaxed);
+        if let Some(limit) = limit.filter(|&limit| limit > max) {
+            return Err(format!("Invalid limit {}, at most {}", limit, max));
+        }
+        if offset.is_none() && limit.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Page {
+            offset: offset.unwrap_or(0),
+            limit,
+        }))
+    }
+
+    // Items of the page, with its position when paginated
+    fn apply<T>(page: Option<Page>, mut items: Vec<T>) -> (Vec<T>, Option<PageInfo>) {
+        let Some(page) = page else {
+            return (items, None);
+        };
+        let total = items.len();
+        let start = page.offset.min(total);
+        let end = page.limit.map_or(total, |limit| start.saturating_add(limit).min(total));
+        items.truncate(end);
+        items.drain(..start);
+        let info = PageInfo {
+            total,
+            offset: page.offset,
+            limit: page.limit,
+            next_offset: (end < total).then_some(end),
+        };
+        (items, Some(info))
+    }
+
+    // X-Total-Count of the responses of paginated requests
+    fn total_count_header(response: &mut Response<Full<Bytes>>, total: Option<usize>) {
+        if let Some(total) = total {
+            response.headers_mut().insert(TOTAL_COUNT, HeaderValue::from(total));
+        }
+    }
+}
+
+// Response shape of popular "what is my ASN" APIs (ipinfo.io style), so
+// existing clients only need to change the base URL.
+#[derive(Serialize)]
+struct GeoIpResponse {
+    ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    org: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bogon: Option<bool>,
+}
+
+// Snapshot staged or activated by a standby
+#[derive(Serialize)]
+struct ReplicationResponse {
+    generation: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct DbStatusResponse {
+    source: String,
+    // network, cache, file or replica
+    db_source: &'static str,
+    entries: usize,
+    // Content hash of the loaded database, and when it was downloaded
+    generation: String,
+    updated: String,
+    // Download progress while a refresh is running
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download: Option<DownloadProgress>,
+    // Country codes not corrected to an ISO 3166-1 code, with their number of ranges
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    unmapped_country_codes: BTreeMap<String, usize>,
+}
+
+// GET /admin/status: what the database holds, how its refreshes went, and
+// the state of the process
+#[derive(Serialize)]
+struct AdminStatusResponse {
+    entries: usize,
+    asns: usize,
+    countries: usize,
+    source: String,
+    db_source: &'static str,
+    generation: String,
+    updated: String,
+    // End of the last successful refresh; the initial load is `updated`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_refresh: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_refresh_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_refresh_error_at: Option<String>,
+    started: String,
+    uptime_seconds: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resident_memory_bytes: Option<u64>,
+}
+
+// GET / with Accept: application/json, for clients to configure
+// themselves from the root URL
+#[derive(Serialize)]
+struct CapabilitiesResponse {
+    service: &'static str,
+    build: BuildInfo,
+    database: DbStatusResponse,
+    endpoints: Vec<Endpoint>,
+    limits: Limits,
+    // Media types of the lookup routes
+    output_types: &'static [&'static str],
+}
+
+#[derive(Serialize)]
+struct Endpoint {
+    method: &'static str,
+    // Path template with {name} parameters, base path included
+    path: String,
+    produces: &'static [&'static str],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    consumes: &'static [&'static str],
+}
+
+#[derive(Serialize)]
+struct Limits {
+    max_body_bytes: u64,
+    max_decoded_body_bytes: u64,
+    max_request_timeout_ms: u64,
+    max_bulk_ips: usize,
+    max_page_size: usize,
+    prefix_max_ranges: usize,
+}
+
+impl Limits {
+    fn current() -> Self {
+        Limits {
+            max_body_bytes: MAX_BODY.load(Ordering::Relaxed),
+            max_decoded_body_bytes: MAX_DECODED_BODY.load(Ordering::Relaxed),
+            max_request_timeout_ms: MAX_REQUEST_TIMEOUT_MS.load(Ordering::Relaxed),
+            max_bulk_ips: MAX_BULK_IPS.load(Ordering::Relaxed),
+            max_page_size: MAX_PAGE_SIZE.load(Ordering::Relaxed),
+            prefix_max_ranges: PREFIX_MAX_RANGES,
+        }
+    }
+}
+
+// Response of /v1/limits: the limits of all requests, and those of the
+// caller
+#[derive(Serialize)]
+struct LimitsResponse {
+    #[serde(flatten)]
+    limits: Limits,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_in_flight_per_connection: Option<u64>,
+    // Tenant of the API key of the request, and its monthly quota of IP
+    // lookups
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    monthly_quota: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_reset_seconds: Option<u64>,
+}
+
+#[cfg(feature = "csv")]
+const IP_COLUMNS: &[&str] = &[
+    "ip",
+    "announced",
+    "first_ip",
+    "last_ip",
+    "as_number",
+    "as_country_code",
+    "as_description",
+    "covered",
+];
+
+#[cfg(feature = "csv")]
+const AS_COLUMNS: &[&str] = &["as_number", "as_country_code", "as_description"];
+
+// Description for plain text output, transliterated to ASCII if configured
+fn plain_description(description: &str) -> Cow<'_, str> {
+    if PLAIN_ASCII_DESCRIPTIONS.load(Ordering::Relaxed) {
+        ascii::fold(description)
+    } else {
+        Cow::Borrowed(description)
+    }
+}
+
+impl Render for IpLookupResponse {
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        if self.announced {
+            format!(
+                "{}{sep}{}-{}{sep}{}{sep}{}\n",
+                style.asn(self.as_number.unwrap()),
+                self.first_ip.as_deref().unwrap(),
+                self.last_ip.as_deref().unwrap(),
+                self.as_country_code.as_deref().unwrap(),
+                plain_description(self.as_description.as_deref().unwrap())
+            )
+        } else {
+            format!("0{sep}{}{sep}None{sep}Not announced\n", self.ip)
+        }
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let html = html! {
+            head {
+                title : "iptoasn lookup";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("Information for IP address: {}", self.ip);
+                }
+                table {
+                    tr {
+                        th : "Announced";
+                        td {
+                            @ if self.announced {
+                                : "Yes";
+                            } else {
+                                : "No";
+                            }
+                        }
+                    }
+                    @ if !self.announced {
+                        tr {
+                            th : "Covered by the dataset";
+                            td {
+                                @ if let (Some(first_ip), Some(last_ip)) = (&self.first_ip, &self.last_ip) {
+                                    : format_args!("Yes, listed as not routed ({} - {})", first_ip, last_ip);
+                                } else {
+                                    : "No";
+                                }
+                            }
+                        }
+                    }
+                    @ if self.announced {
+                        tr {
+                            th : "AS Number";
+                            td : format_args!("AS{}", style.asn(self.as_number.unwrap()));
+                        }
+                        tr {
+                            th : "AS Range";
+                            td : format_args!("{} - {}", self.first_ip.as_ref().unwrap(), self.last_ip.as_ref().unwrap());
+                        }
+                        tr {
+                            th : "AS Country Code";
+                            td : self.as_country_code.as_ref().unwrap();
+                        }
+                        tr {
+                            th : "AS Description";
+                            td : self.as_description.as_ref().unwrap();
+                        }
+                    }
+                    @ for warning in &self.warnings {
+                        tr {
+                            th : "Warning";
+                            td : &warning.message;
+                        }
+                    }
+                    @ for (name, value) in &self.extra {
+                        tr {
+                            th : name;
+                            td {
+                                @ if let serde_json::Value::String(value) = value {
+                                    : value;
+                                } else {
+                                    : value.to_string();
+                                }
+                            }
+                        }
+                    }
+                }
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: IP_COLUMNS,
+            rows: vec![self.row(style)],
+        }
+    }
+}
+
+impl Render for [IpLookupResponse] {
+    // Columns are aligned with the default separator only, since custom
+    // separators are meant for machine parsing
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        let aligned = sep == DEFAULT_FIELD_SEPARATOR;
+        let max_ip_len = self.iter().map(|r| r.ip.len()).max().unwrap_or(0).max(20);
+        let mut out = String::new();
+
+        for r in self {
+            let asn_str = if r.announced {
+                style.asn(r.as_number.unwrap())
+            } else {
+                "0".to_string()
+            };
+            let desc_cc = if r.announced {
+                format!(
+                    "{}, {}",
+                    plain_description(r.as_description.as_ref().unwrap()),
+                    r.as_country_code.as_ref().unwrap()
+                )
+            } else {
+                "Not announced".to_string()
+            };
+            if aligned {
+                out.push_str(&format!("{:<8} | {:<width$} | {}", asn_str, r.ip, desc_cc, width = max_ip_len));
+            } else {
+                out.push_str(&format!("{}{sep}{}{sep}{}", asn_str, r.ip, desc_cc));
+            }
+            // Identifier of the NDJSON entry, as the last field. Strings with
+            // control characters are written as JSON, so that their line
+            // breaks and tabs are escaped rather than splitting the line.
+            match &r.id {
+                Some(serde_json::Value::String(id)) if !id.contains(char::is_control) => {
+                    out.push_str(&format!("{sep}{}", id))
+                }
+                Some(id) => out.push_str(&format!("{sep}{}", id)),
+                None => {}
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        WebService::html_vec_text(self, style)
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: IP_COLUMNS,
+            rows: self.iter().map(|r| r.row(style)).collect(),
+        }
+    }
+}
+
+impl AsMetaResponse {
+    #[cfg(feature = "csv")]
+    fn row(&self, style: &Style) -> Vec<String> {
+        vec![
+            style.asn(self.as_number),
+            self.as_country_code.clone(),
+            self.as_description.clone(),
+        ]
+    }
+}
+
+impl Render for AsMetaResponse {
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        let mut out = format!(
+            "{}{sep}{}{sep}{}\n",
+            style.asn(self.as_number),
+            self.as_country_code,
+            plain_description(&self.as_description)
+        );
+        // A comment line, as a real AS could be named "Not found"
+        if !self.found {
+            out.push_str("# not found\n");
+        }
+        out
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let html = html! {
+            head {
+                title : "iptoasn lookup";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("Information for AS number: AS{}", style.asn(self.as_number));
+                }
+                table {
+                    tr {
+                        th : "AS Number";
+                        td : format_args!("AS{}", style.asn(self.as_number));
+                    }
+                    tr {
+                        th : "AS Country Code";
+                        td : &self.as_country_code;
+                    }
+                    tr {
+                        th : "AS Description";
+                        td : &self.as_description;
+                    }
+                    @ if let Some(special) = asn_registry::lookup(self.as_number) {
+                        tr {
+                            th : "Special Purpose";
+                            td : format_args!("{} ({})", special.label, special.reference);
+                        }
+                    }
+                }
+                @ if !self.found {
+                    p : "This AS number isn't in the database.";
+                }
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }
+        .into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: AS_COLUMNS,
+            rows: vec![self.row(style)],
+        }
+    }
+}
+
+// List of ASNs, serialized as a plain array, under a heading in HTML
+#[derive(Serialize)]
+#[serde(transparent)]
+struct AsMetaList<'a> {
+    #[serde(skip)]
+    heading: String,
+    items: &'a [AsMetaResponse],
+}
+
+impl Render for AsMetaList<'_> {
+    fn plain(&self, style: &Style) -> String {
+        let mut out = String::with_capacity(self.items.len() * 32);
+        for item in self.items {
+            out.push_str(&item.plain(style));
+        }
+        out
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let html = html! {
+            head {
+                title : "iptoasn AS list";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em } table { width: 100%; } th, td { padding: .25em .5em; }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : &self.heading;
+                }
+                table(class="table table-sm table-striped") {
+                    thead {
+                        tr {
+                            th : "AS Number";
+                            th : "AS Country Code";
+                            th : "AS Description";
+                        }
+                    }
+                    tbody {
+                        @ for item in self.items {
+                            tr {
+                                td : format_args!("AS{}", style.asn(item.as_number));
+                                td : &item.as_country_code;
+                                td : &item.as_description;
+                            }
+                        }
+                    }
+                }
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: AS_COLUMNS,
+            rows: self.items.iter().map(|item| item.row(style)).collect(),
+        }
+    }
+}
+
+impl Render for AsSubnetsResponse {
+    fn plain(&self, _style: &Style) -> String {
+        self.subnets.iter().map(|subnet| format!("{}\n", subnet)).collect()
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let body_text = self.subnets.join("\n");
+        let html = html! {
+            head {
+                title : "iptoasn subnets";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("Subnets for AS{}", style.asn(self.as_number));
+                }
+                pre : body_text;
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: &["as_number", "subnet"],
+            rows: self
+                .subnets
+                .iter()
+                .map(|subnet| vec![style.asn(self.as_number), subnet.clone()])
+                .collect(),
+        }
+    }
+}
+
+impl Render for AsCountriesResponse {
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        self.countries
+            .iter()
+            .map(|c| format!("{}{sep}{}{sep}{}\n", c.country_code, c.ranges, c.prefixes))
+            .collect()
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let html = html! {
+            head {
+                title : "iptoasn AS countries";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em } th, td { padding: .25em .5em; }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("Countries of AS{}", style.asn(self.as_number));
+                }
+                @ if self.countries.is_empty() {
+                    p : "No ranges found";
+                } else {
+                    table(class="table table-sm table-striped") {
+                        thead {
+                            tr {
+                                th : "Country Code";
+                                th : "Ranges";
+                                th : "Prefixes";
+                            }
+                        }
+                        tbody {
+                            @ for c in &self.countries {
+                                tr {
+                                    td {
+                                        a(href=format!("{}/v1/as/country/{}", WebService::base_path(), c.country_code)) : &c.country_code;
+                                    }
+                                    td : c.ranges;
+                                    td : c.prefixes;
+                                }
+                            }
+                        }
+                    }
+                }
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: &["as_number", "country_code", "ranges", "prefixes"],
+            rows: self
+                .countries
+                .iter()
+                .map(|c| {
+                    vec![
+                        style.asn(self.as_number),
+                        c.country_code.clone(),
+                        c.ranges.to_string(),
+                        c.prefixes.to_string(),
+                    ]
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Render for DnsblResponse {
+    // The answer, or NXDOMAIN like a DNS resolver would report
+    fn plain(&self, _style: &Style) -> String {
+        format!("{}\n", self.answer.as_deref().unwrap_or("NXDOMAIN"))
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let html = html! {
+            head {
+                title : "iptoasn DNSBL";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em } th { padding-right: 1em }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("DNSBL answer for {}", self.ip);
+                }
+                table {
+                    tr {
+                        th : "Query";
+                        td : &self.query;
+                    }
+                    tr {
+                        th : "Answer";
+                        td : self.answer.as_deref().unwrap_or("NXDOMAIN");
+                    }
+                    tr {
+                        th : "Categories";
+                        td : self.categories.join(", ");
+                    }
+                    @ if let Some(number) = self.as_number {
+                        tr {
+                            th : "AS Number";
+                            td : format_args!("AS{}", style.asn(number));
+                        }
+                    }
+                    @ if let Some(country) = &self.as_country_code {
+                        tr {
+                            th : "AS Country Code";
+                            td : country;
+                        }
+                    }
+                }
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }
+        .into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: &["query", "ip", "listed", "answer", "categories", "as_number", "as_country_code"],
+            rows: vec![vec![
+                self.query.clone(),
+                self.ip.clone(),
+                self.listed.to_string(),
+                self.answer.clone().unwrap_or_default(),
+                self.categories.join(" "),
+                self.as_number.map(|n| style.asn(n)).unwrap_or_default(),
+                self.as_country_code.clone().unwrap_or_default(),
+            ]],
+        }
+    }
+}
+
+impl Render for CountryAsnsResponse {
+    fn plain(&self, style: &Style) -> String {
+        self.as_numbers.iter().map(|&n| format!("{}\n", style.asn(n))).collect()
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let html = html! {
+            head {
+                title : "iptoasn country AS list";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("ASNs for {}", self.country_code);
+                }
+                @ if self.as_numbers.is_empty() {
+                    p : "No ASNs found";
+                } else {
+                    ul {
+                        @ for n in &self.as_numbers {
+                            li {
+                                a(href=format!("{}/v1/as/n/{}", WebService::base_path(), n)) : format_args!("AS{}", style.asn(*n));
+                            }
+                        }
+                    }
+                }
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }
+        .into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: &["country_code", "as_number"],
+            rows: self
+                .as_numbers
+                .iter()
+                .map(|&n| vec![self.country_code.clone(), style.asn(n)])
+                .collect(),
+        }
+    }
+}
+
+impl Render for CountrySubnetsResponse {
+    fn plain(&self, _style: &Style) -> String {
+        self.subnets.iter().map(|subnet| format!("{}\n", subnet)).collect()
+    }
+
+    fn html(&self, _style: &Style) -> Result<String, horrorshow::Error> {
+        let body_text = self.subnets.join("\n");
+        let html = html! {
+            head {
+                title : "iptoasn country subnets";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("Subnets for {}", self.country_code);
+                }
+                pre : body_text;
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }
+        .into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, _style: &Style) -> Table {
+        Table {
+            header: &["country_code", "subnet"],
+            rows: self
+                .subnets
+                .iter()
+                .map(|subnet| vec![self.country_code.clone(), subnet.clone()])
+                .collect(),
+        }
+    }
+}
+
+impl Announcements {
+    // Ownership and ranges lines following the first line of plain outputs
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        let mut shares: Vec<String> = self
+            .ownership
+            .iter()
+            .map(|share| format!("AS{} {}%", style.asn(share.as_number), share.percent))
+            .collect();
+        shares.push(format!("unannounced {}%", self.unannounced_percent));
+        let mut plain = format!("# ownership: {}\n", shares.join(", "));
+        for range in &self.ranges {
+            plain.push_str(&format!(
+                "{}{sep}{} - {}{sep}{}, {}\n",
+                style.asn(range.as_number),
+                range.first_ip,
+                range.last_ip,
+                plain_description(&range.as_description),
+                range.as_country_code
+            ));
+        }
+        if self.truncated {
+            plain.push_str(&format!("# truncated: only the first {} ranges are listed\n", PREFIX_MAX_RANGES));
+        }
+        plain
+    }
+
+    // Page of the announcements of `subject`, with the CIDR decomposition of
+    // a range
+    fn html(&self, subject: &str, cidrs: Option<&[String]>, style: &Style) -> Result<String, horrorshow::Error> {
+        let base = WebService::base_path();
+        let html = html! {
+            head {
+                title : "iptoasn prefix lookup";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em } th, td { padding: .25em .5em; }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("Information for {}", subject);
+                }
+                @ if let Some(cidrs) = cidrs {
+                    p {
+                        : "CIDR prefixes: ";
+                        @ for (i, cidr) in cidrs.iter().enumerate() {
+                            @ if i > 0 {
+                                : ", ";
+                            }
+                            a(href=format!("{}/v1/as/prefix/{}", base, cidr)) : cidr;
+                        }
+                    }
+                }
+                p {
+                    : "Coverage: ";
+                    strong : match self.coverage {
+                        "full" => "fully announced",
+                        "partial" => "partially announced",
+                        _ => "not announced",
+                    };
+                }
+                table(class="table table-sm") {
+                    thead {
+                        tr {
+                            th : "AS Number";
+                            th : "Addresses";
+                            th : "Share";
+                        }
+                    }
+                    tbody {
+                        @ for share in &self.ownership {
+                            tr {
+                                td {
+                                    a(href=format!("{}/v1/as/n/{}", base, share.as_number)) : format_args!("AS{}", style.asn(share.as_number));
+                                }
+                                td : &share.addresses;
+                                td : format_args!("{}%", share.percent);
+                            }
+                        }
+                        tr {
+                            td : "Unannounced";
+                            td : &self.unannounced_addresses;
+                            td : format_args!("{}%", self.unannounced_percent);
+                        }
+                    }
+                }
+                @ if !self.ranges.is_empty() {
+                    table(class="table table-sm table-striped") {
+                        thead {
+                            tr {
+                                th : "AS Number";
+                                th : "AS Range";
+                                th : "AS Country Code";
+                                th : "AS Description";
+                            }
+                        }
+                        tbody {
+                            @ for range in &self.ranges {
+                                tr {
+                                    td {
+                                        a(href=format!("{}/v1/as/n/{}", base, range.as_number)) : format_args!("AS{}", style.asn(range.as_number));
+                                    }
+                                    td : format_args!("{} - {}", range.first_ip, range.last_ip);
+                                    td : &range.as_country_code;
+                                    td : &range.as_description;
+                                }
+                            }
+                        }
+                    }
+                }
+                @ if self.truncated {
+                    p : format_args!("Only the first {} ranges are listed.", PREFIX_MAX_RANGES);
+                }
+                footer {
+                    p { small {
+                        : "Powered by ";
+                        a(href="https://iptoasn.com") : "iptoasn.com";
+                    } }
+                }
+            }
+        }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    // CSV rows of the ranges, after a first column naming the prefix or range
+    #[cfg(feature = "csv")]
+    fn table(&self, header: &'static [&'static str], subject: &str, style: &Style) -> Table {
+        Table {
+            header,
+            rows: self
+                .ranges
+                .iter()
+                .map(|range| {
+                    vec![
+                        subject.to_string(),
+                        range.first_ip.clone(),
+                        range.last_ip.clone(),
+                        style.asn(range.as_number),
+                        range.as_country_code.clone(),
+                        range.as_description.clone(),
+                    ]
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Render for PrefixLookupResponse {
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        format!("{}{sep}{}\n{}", self.prefix, self.announcements.coverage, self.announcements.plain(style))
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        self.announcements.html(&format!("prefix: {}", self.prefix), None, style)
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        self.announcements.table(
+            &["prefix", "first_ip", "last_ip", "as_number", "as_country_code", "as_description"],
+            &self.prefix,
+            style,
+        )
+    }
+}
+
+impl Render for RangeLookupResponse {
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        format!(
+            "{} - {}{sep}{}\n# cidrs: {}\n{}",
+            self.first_ip,
+            self.last_ip,
+            self.announcements.coverage,
+            self.cidrs.join(", "),
+            self.announcements.plain(style)
+        )
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        self.announcements
+            .html(&format!("range: {} - {}", self.first_ip, self.last_ip), Some(&self.cidrs), style)
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        self.announcements.table(
+            &["range", "first_ip", "last_ip", "as_number", "as_country_code", "as_description"],
+            &format!("{} - {}", self.first_ip, self.last_ip),
+            style,
+        )
+    }
+}
+
+fn http_date(secs: u64) -> String {
+    httpdate::fmt_http_date(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+impl Render for OriginHistoryResponse {
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        let mut plain = String::new();
+        for entry in &self.history {
+            plain.push_str(&format!(
+                "{}{sep}{}{sep}{}{sep}{}, {}\n",
+                http_date(entry.first_seen),
+                http_date(entry.last_seen),
+                style.asn(entry.as_number),
+                plain_description(&entry.as_description),
+                entry.as_country_code
+            ));
+        }
+        plain
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let date = http_date;
+            let html = html! {
+                head {
+                    title : "iptoasn origin history";
+                    meta(name="viewport", content="width=device-width, initial-scale=1");
+                    link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                    style : "body { margin: 1em 4em } th, td { padding: .25em .5em; }";
+                }
+                body(class="container-fluid") {
+                    header {
+                        h1 : format_args!("Origin history of {}", self.ip);
+                    }
+                    p : format_args!("{} retained snapshots", self.snapshots);
+                    table(class="table table-sm table-striped") {
+                        thead {
+                            tr {
+                                th : "First seen";
+                                th : "Last seen";
+                                th : "AS Number";
+                                th : "AS Country Code";
+                                th : "AS Description";
+                            }
+                        }
+                        tbody {
+                            @ for entry in &self.history {
+                                tr {
+                                    td : date(entry.first_seen);
+                                    td : date(entry.last_seen);
+                                    td : format_args!("AS{}", style.asn(entry.as_number));
+                                    td : &entry.as_country_code;
+                                    td : &entry.as_description;
+                                }
+                            }
+                        }
+                    }
+                }
+            }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, style: &Style) -> Table {
+        Table {
+            header: &["first_seen", "last_seen", "as_number", "as_country_code", "as_description", "snapshots"],
+            rows: self
+                .history
+                .iter()
+                .map(|entry| {
+                    vec![
+                        entry.first_seen.to_string(),
+                        entry.last_seen.to_string(),
+                        style.asn(entry.as_number),
+                        entry.as_country_code.clone(),
+                        entry.as_description.clone(),
+                        entry.snapshots.to_string(),
+                    ]
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Render for TimeseriesResponse {
+    fn plain(&self, style: &Style) -> String {
+        let sep = style.sep;
+        let mut plain = String::new();
+        for point in &self.points {
+            plain.push_str(&format!(
+                "{}{sep}{}{sep}{}\n",
+                http_date(point.first_seen),
+                http_date(point.last_seen),
+                point.value
+            ));
+        }
+        plain
+    }
+
+    fn html(&self, style: &Style) -> Result<String, horrorshow::Error> {
+        let date = http_date;
+        let subject = match (self.as_number, &self.country_code) {
+            (Some(number), _) => format!("AS{}", style.asn(number)),
+            (None, Some(country_code)) => country_code.clone(),
+            (None, None) => String::new(),
+        };
+        let html = html! {
+            head {
+                title : "iptoasn statistics";
+                meta(name="viewport", content="width=device-width, initial-scale=1");
+                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
+                style : "body { margin: 1em 4em } th, td { padding: .25em .5em; }";
+            }
+            body(class="container-fluid") {
+                header {
+                    h1 : format_args!("{} of {} over time", self.metric, subject);
+                }
+                table(class="table table-sm table-striped") {
+                    thead {
+                        tr {
+                            th : "First seen";
+                            th : "Last seen";
+                            th : self.metric;
+                        }
+                    }
+                    tbody {
+                        @ for point in &self.points {
+                            tr {
+                                td : date(point.first_seen);
+                                td : date(point.last_seen);
+                                td : point.value;
+                            }
+                        }
+                    }
+                }
+            }
+        }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
+
+    #[cfg(feature = "csv")]
+    fn table(&self, _style: &Style) -> Table {
+        Table {
+            header: &["first_seen", "last_seen", "value"],
+            rows: self
+                .points
+                .iter()
+                .map(|point| vec![point.first_seen.to_string(), point.last_seen.to_string(), point.value.to_string()])
+                .collect(),
+        }
+    }
+}
+
+pub struct WebService;
+
+impl WebService {
+    async fn handle_request(
+        req: Request<hyper::body::Incoming>,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        refresher: Arc<Refresher>,
+        remote_addr: SocketAddr,
+        connection: Arc<load::Connection>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        // HEAD requests run the GET handlers, and only the body is dropped
+        let head = req.method() == Method::HEAD;
+        let method = if head { &Method::GET } else { req.method() };
+        // Paths outside of the base path don't match any route
+        let uri = Self::strip_base_path(req.uri().path()).unwrap_or("");
+        let sep = Self::field_separator(req.uri().query());
+        if sep.contains(['\n', '\r']) {
+            return Ok(Self::plain_error(
+                StatusCode::BAD_REQUEST,
+                "The field separator can't contain line breaks",
+            ));
+        }
+        let asn_notation = match Self::query_param(req.uri().query(), "asn_notation") {
+            Some("asdot") => AsnNotation::Asdot,
+            _ => AsnNotation::Asplain,
+        };
+        let style = Style { sep: &sep, asn_notation };
+
+        if STRICT_NEGOTIATION.load(Ordering::Relaxed) {
+            if let Some(response) = Self::negotiation_error(method, uri, req.headers()) {
+                return Ok(response);
+            }
+        }
+
+        let resolution = router::resolve(method.as_str(), uri);
+        let route = match &resolution {
+            Resolution::Found(route, _) => Some(*route),
+            _ => None,
+        };
+
+        // Requests over a concurrency threshold are shed before any work
+        let mut in_flight = match route {
+            Some(route) => match load::admit(route, &connection).await {
+                Ok(in_flight) => Some(in_flight),
+                Err(retry_after) => {
+                    let response = Self::overloaded(retry_after);
+                    return Ok(if head { Self::strip_body(response) } else { response });
+                }
+            },
+            None => None,
+        };
+
+        // The admin token replaces the API key on the admin routes
+        let admin_token = route.is_some_and(Route::is_admin);
+        if matches!(resolution, Resolution::Found(route, _) if !route.is_public())
+            && !admin_token
+            && !auth::authorized(req.headers())
+        {
+            let response = Self::unauthorized();
+            return Ok(if head { Self::strip_body(response) } else { response });
+        }
+
+        // Usage accounting of the tenant owning the API key, if any
+        let tenant = usage::tenant(req.headers());
+        if let Some(tenant) = tenant {
+            tenant.record_request();
+            // Bulk lookups are charged once their addresses are known
+            if route.is_some_and(Route::is_lookup) && tenant.over_quota()
+                || route.is_some_and(Route::is_single_lookup) && !tenant.charge_ips(1)
+            {
+                return Ok(Self::quota_exceeded(tenant, None));
+            }
+        }
+
+        let (route, params) = match resolution {
+            Resolution::Found(route, params) => (route, params),
+            Resolution::MethodNotAllowed(allowed) => {
+                let mut response = Self::plain_error(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed");
+                if let Ok(allow) = HeaderValue::from_str(&allowed.join(", ")) {
+                    response.headers_mut().insert(ALLOW, allow);
+                }
+                return Ok(if head { Self::strip_body(response) } else { response });
+            }
+            Resolution::NotFound => {
+                let response = Self::plain_error(StatusCode::NOT_FOUND, "Not Found");
+                return Ok(if head { Self::strip_body(response) } else { response });
+            }
+        };
+        if deprecation::is_disabled() {
+            if let Some(deprecation) = deprecation::find(route, None) {
+                let response = Self::gone(deprecation);
+                return Ok(if head { Self::strip_body(response) } else { response });
+            }
+        }
+        let client = match tenant {
+            Some(tenant) => tenant.name().to_string(),
+            None => Self::extract_client_ip(req.headers(), remote_addr),
+        };
+        let etag = route
+            .is_cacheable()
+            .then(|| Self::etag(&Self::current_asns(&asns_arc), req.uri(), req.headers()));
+        let if_none_match = req.headers().get(IF_NONE_MATCH).cloned();
+        let query = req.uri().query();
+        let page = match Page::from_query(query) {
+            Ok(page) if route.is_list() => page,
+            Ok(_) => None,
+            Err(e) if route.is_list() => return Ok(Self::plain_error(StatusCode::BAD_REQUEST, &e)),
+            Err(_) => None,
+        };
+        let response = match route {
+            Route::Index => Ok(Self::index(req.headers(), asns_arc, &refresher)),
+            Route::BulkPage => Ok(Self::bulk_page()),
+            Route::ClientIp => {
+                let client_ip = Self::extract_client_ip(req.headers(), remote_addr);
+                Self::ip_lookup(&client_ip, false, req.headers(), asns_arc, &style)
+            }
+            Route::IpHistory => Self::origin_history(params.get("ip"), req.headers(), &style).await,
+            Route::StatsTimeseries => {
+                let (asn, country) = (Self::query_param(query, "asn"), Self::query_param(query, "country"));
+                let metric = Self::query_param(query, "metric");
+                Self::stats_timeseries(asn, country, metric, req.headers(), &style).await
+            }
+            Route::Ip => {
+                let extract = Self::query_param(query, "extract") == Some("true");
+                Self::ip_lookup(params.get("ip"), extract, req.headers(), asns_arc, &style)
+            }
+            Route::MissingAsn => {
+                let accept = Self::accept_type(req.headers());
+                let mut resp = match accept {
+                    OutputType::Plain => Response::new(Full::new(Bytes::from(
+                        "Missing AS number. Use /v1/as/n/<AS123> or /v1/as/n/<123>\n",
+                    ))),
+                    _ => Response::new(Full::new(Bytes::from(
+                        r#"{"error":"Missing AS number. Use /v1/as/n/<AS123> or /v1/as/n/<123>"}"#,
+                    ))),
+                };
+                *resp.status_mut() = StatusCode::BAD_REQUEST;
+                resp.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(match accept {
+                        OutputType::Plain => "text/plain; charset=utf-8",
+                        _ => "application/json; charset=utf-8",
+                    }),
+                );
+                Ok(resp)
+            }
+            Route::Asns => {
+                let sort = Self::query_param(query, "sort");
+                Self::as_meta_list(sort, page, req.headers(), asns_arc, &style)
+            }
+            Route::AsSearch => {
+                let q = Self::query_param(query, "q");
+                Self::as_search(q, page, req.headers(), asns_arc, &style)
+            }
+            Route::AsSubnets => {
+                let format = Self::query_param(query, "format");
+                let sort = Self::query_param(query, "sort");
+                Self::as_subnets_lookup(params.get("asn"), format, sort, page, req.headers(), asns_arc, &style)
+            }
+            Route::AsCountries => Self::as_countries_lookup(params.get("asn"), req.headers(), asns_arc, &style),
+            Route::As => Self::as_meta_lookup(params.get("asn"), req.headers(), asns_arc, &style),
+            Route::Prefix => Self::prefix_lookup(params.get("prefix"), req.headers(), asns_arc, &style),
+            Route::Range => {
+                let (first, last) = (Self::query_param(query, "first"), Self::query_param(query, "last"));
+                Self::range_lookup(first, last, req.headers(), asns_arc, &style)
+            }
+            Route::CountrySubnets => {
+                let sort = Self::query_param(query, "sort");
+                Self::country_subnets_lookup(params.get("cc"), sort, page, req.headers(), asns_arc, &style)
+            }
+            Route::Country => Self::country_asns_lookup(params.get("cc"), page, req.headers(), asns_arc, &style),
+            Route::Ips => Self::handle_put_ips(req, asns_arc, tenant, &style).await,
+            // The stream outlives the handler, and keeps its request in flight
+            Route::IpsStream => Ok(Self::ips_stream(req, asns_arc, tenant, in_flight.take())),
+            Route::AnnotateLog => Self::handle_annotate_log(req, asns_arc, tenant, &style).await,
+            Route::ClientGeoIp => {
+                let client_ip = Self::extract_client_ip(req.headers(), remote_addr);
+                Self::geoip_lookup(&client_ip, asns_arc)
+            }
+            Route::GeoIp => Self::geoip_lookup(params.get("ip"), asns_arc),
+            Route::Dnsbl => Self::dnsbl_lookup(params.get("query"), req.headers(), asns_arc, &style),
+            Route::Metrics => Self::metrics(asns_arc),
+            Route::DbStatus => Self::db_status(req.headers(), asns_arc, &refresher),
+            Route::Limits => Ok(Self::limits(tenant)),
+            Route::AdminUsage => Self::admin_usage(req.headers()),
+            Route::AdminStatus => Self::admin_status(req.headers(), asns_arc, &refresher),
+            Route::AdminRefresh => {
+                let wait = Self::query_param(req.uri().query(), "wait") == Some("true");
+                Self::admin_refresh(req.headers(), &refresher, None, wait).await
+            }
+            Route::AdminRefreshStatus => {
+                Self::admin_refresh(req.headers(), &refresher, Some(params.get("id")), false).await
+            }
+            Route::ReplicationSnapshot => Self::replication_snapshot(req.headers(), asns_arc).await,
+            Route::ReplicationStage => Self::replication_stage(req, &refresher).await,
+            Route::ReplicationActivate => {
+                Self::replication_activate(req.headers(), &refresher, params.get("generation"))
+            }
+            Route::OpenApi => Ok(Self::openapi()),
+            Route::Version => Ok(Self::version()),
+        };
+
+        let response = response.map(|response| Self::deprecation_headers(route, response, &client));
+        let response = response.map(|mut response| {
+            if let Some(etag) = etag.filter(|_| response.status() == StatusCode::OK) {
+                response.headers_mut().insert(ETAG, etag);
+            }
+            Self::not_modified(response, if_none_match.as_ref())
+        });
+        match response {
+            Ok(response) if head && response.status() != StatusCode::NOT_MODIFIED => Ok(Self::strip_body(response)),
+            response => response,
+        }
+    }
+
+    // Deprecation headers of a deprecated route or format, logged once per
+    // client, or a 410 once deprecated surfaces are disabled
+    fn deprecation_headers(route: Route, mut response: Response<Full<Bytes>>, client: &str) -> Response<Full<Bytes>> {
+        let media_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.split(';').next().unwrap_or_default().trim().to_string());
+        let Some(deprecation) = deprecation::find(route, media_type.as_deref()) else {
+            return response;
+        };
+        if deprecation::is_disabled() {
+            return Self::gone(deprecation);
+        }
+        deprecation.log_use(route, media_type.as_deref(), client);
+        deprecation.add_headers(response.headers_mut());
+        response
+    }
+
+    fn gone(deprecation: &Deprecation) -> Response<Full<Bytes>> {
+        let mut response = Self::plain_error(StatusCode::GONE, &deprecation.gone_message());
+        deprecation.add_headers(response.headers_mut());
+        response
+    }
+
+    // Weak ETag of a cacheable route: its responses only change with the
+    // database, so the tag is the database generation and a hash of what
+    // selects the representation. Weak, since a response may still differ
+    // in details such as the age of a stale database.
+    fn etag(asns: &Asns, uri: &http::Uri, headers: &HeaderMap) -> HeaderValue {
+        let mut hasher = Sha256::new();
+        hasher.update(uri.to_string());
+        for name in [ACCEPT, HOST] {
+            hasher.update([0]);
+            hasher.update(headers.get(name).map_or(&b""[..], |v| v.as_bytes()));
+        }
+        let hash = hasher.finalize();
+        let hash: String = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        HeaderValue::from_str(&format!("W/\"{}-{}\"", asns.generation(), hash))
+            .unwrap_or(HeaderValue::from_static("W/\"\""))
+    }
+
+    // 304 Not Modified, without a body, when the client already has the
+    // response: its ETag is one of the If-None-Match tags, compared weakly
+    pub(crate) fn not_modified(
+        response: Response<Full<Bytes>>,
+        if_none_match: Option<&HeaderValue>,
+    ) -> Response<Full<Bytes>> {
+        let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        let matches = match (response.headers().get(ETAG), if_none_match.and_then(|v| v.to_str().ok())) {
+            (Some(etag), Some(tags)) => {
+                let etag = opaque(etag.to_str().unwrap_or(""));
+                tags.trim() == "*" || tags.split(',').any(|tag| opaque(tag) == etag)
+            }
+            _ => false,
+        };
+        if !matches || response.status() != StatusCode::OK {
+            return response;
+        }
+        let (mut parts, _) = response.into_parts();
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(CONTENT_TYPE);
+        parts.headers.remove(CONTENT_LENGTH);
+        Response::from_parts(parts, Full::new(Bytes::new()))
+    }
+
+    // Response to a request without an accepted API key (auth::set_keys)
+    pub(crate) fn unauthorized() -> Response<Full<Bytes>> {
+        let mut response = Self::plain_error(StatusCode::UNAUTHORIZED, "Missing or invalid API key");
+        response.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static("Bearer realm=\"iptoasn-webservice\""),
+        );
+        response
+    }
+
+    fn overloaded(retry_after: Duration) -> Response<Full<Bytes>> {
+        let mut response = Self::plain_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Too many requests in flight, retry later",
+        );
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs()));
+        response
+    }
+
+    // 429 to a tenant without lookups left, or without enough left for the
+    // addresses of a bulk request
+    fn quota_exceeded(tenant: &Tenant, requested: Option<u64>) -> Response<Full<Bytes>> {
+        let mut response = Self::plain_error(StatusCode::TOO_MANY_REQUESTS, &tenant.quota_message(requested));
+        response.headers_mut().insert(
+            RETRY_AFTER,
+            HeaderValue::from(usage::until_next_month().as_secs()),
+        );
+        response
+    }
+
+    // Response to a HEAD request: same headers as the GET response, with
+    // the Content-Length of its body, but without the body itself.
+    pub(crate) fn strip_body(response: Response<Full<Bytes>>) -> Response<Full<Bytes>> {
+        let (mut parts, body) = response.into_parts();
+        if let Some(len) = body.size_hint().exact() {
+            parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+        Response::from_parts(parts, Full::new(Bytes::new()))
+    }
+
+    // A panic while holding the lock can't leave the database in an
+    // inconsistent state, since it is only ever replaced as a whole.
+    fn current_asns(asns_arc: &RwLock<Arc<Asns>>) -> Arc<Asns> {
+        asns_arc
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    // Run the handler in its own task, so that a panic is turned into a 500
+    // response instead of silently tearing down the connection.
+    async fn handle_request_guarded(
+        req: Request<hyper::body::Incoming>,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        refresher: Arc<Refresher>,
+        remote_addr: SocketAddr,
+        connection: Arc<load::Connection>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let method = req.method().clone();
+        let path = req.uri().path().to_string();
+        let asns = Self::current_asns(&asns_arc);
+        let handler = Self::handle_request(req, asns_arc, refresher, remote_addr, connection);
+        let response = match tokio::task::spawn(handler).await {
+            Ok(response) => response,
+            Err(e) => {
+                HANDLER_PANICS.fetch_add(1, Ordering::Relaxed);
+                log::error!("Request handler for {} {} failed: {}", method, path, e);
+                let mut response = Response::new(Full::new(Bytes::from("Internal Server Error\n")));
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; charset=utf-8"),
+                );
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                Ok(response)
+            }
+        };
+        response.map(|mut response| {
+            Self::generation_headers(response.headers_mut(), &asns);
+            log::info!(
+                target: "access",
+                "{} \"{} {}\" {} db={}",
+                remote_addr.ip(),
+                method,
+                path,
+                response.status().as_u16(),
+                asns.generation()
+            );
+            response
+        })
+    }
+
+    // Database that served the response, so that disputed results can be
+    // reproduced against the same snapshot. A refresh completing during the
+    // request may have served it from the next one.
+    fn generation_headers(headers: &mut HeaderMap, asns: &Asns) {
+        if let Ok(generation) = HeaderValue::from_str(asns.generation()) {
+            headers.insert(DB_GENERATION, generation);
+        }
+        if let Ok(updated) = HeaderValue::from_str(&httpdate::fmt_http_date(asns.updated())) {
+            headers.insert(DB_UPDATED, updated);
+        }
+        let age = SystemTime::now().duration_since(asns.updated()).unwrap_or_default();
+        if age > STALE_DATABASE_AGE {
+            headers.insert(DB_WARNING, HeaderValue::from_static("stale_database"));
+        }
+    }
+
+    // 406 when no media type of the Accept header can be produced, 415 when
+    // the body has a Content-Type the route doesn't take, listing what the
+    // route supports. Requests without these headers get the defaults.
+    fn negotiation_error(
+        method: &Method,
+        uri: &str,
+        headers: &HeaderMap,
+    ) -> Option<Response<Full<Bytes>>> {
+        let route = negotiation::route(method.as_str(), uri)?;
+        let header = |name| headers.get(name).and_then(|v: &HeaderValue| v.to_str().ok());
+        let (status, error) = if header(ACCEPT).is_some_and(|accept| !route.acceptable(accept)) {
+            (StatusCode::NOT_ACCEPTABLE, "None of the accepted media types can be produced")
+        } else if !route.consumes.is_empty()
+            && header(CONTENT_TYPE).is_some_and(|content_type| !route.consumable(content_type))
+        {
+            (StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported Content-Type")
+        } else {
+            return None;
+        };
+        let json = serde_json::json!({ "error": error, "supported": route });
+        let mut response = Response::new(Full::new(Bytes::from(json.to_string())));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        if let Ok(allow) = HeaderValue::from_str(&route.methods.join(", ")) {
+            response.headers_mut().insert(ALLOW, allow);
+        }
+        *response.status_mut() = status;
+        Some(response)
+    }
+
+    fn metrics(asns_arc: Arc<RwLock<Arc<Asns>>>) -> Result<Response<Full<Bytes>>, Infallible> {
+        let asns = Self::current_asns(&asns_arc);
+        let mut out = String::new();
+        out.push_str("# HELP iptoasn_handler_panics_total Number of request handlers that panicked.\n");
+        out.push_str("# TYPE iptoasn_handler_panics_total counter\n");
+        out.push_str(&format!(
+            "iptoasn_handler_panics_total {}\n",
+            HANDLER_PANICS.load(Ordering::Relaxed)
+        ));
+        out.push_str("# HELP iptoasn_db_entries Number of ranges in the loaded database.\n");
+        out.push_str("# TYPE iptoasn_db_entries gauge\n");
+        out.push_str(&format!("iptoasn_db_entries {}\n", asns.len()));
+        out.push_str("# HELP iptoasn_db_source Path that supplied the loaded database (1 for the current one).\n");
+        out.push_str("# TYPE iptoasn_db_source gauge\n");
+        for db_source in DbSource::ALL {
+            out.push_str(&format!(
+                "iptoasn_db_source{{db_source=\"{}\"}} {}\n",
+                db_source.as_str(),
+                u8::from(db_source == asns.db_source())
+            ));
+        }
+        usage::write_metrics(&mut out);
+        load::write_metrics(&mut out);
+
+        let mut response = Response::new(Full::new(Bytes::from(out)));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        );
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        *response.status_mut() = StatusCode::OK;
+        Ok(response)
+    }
+
+    // Response to a request not allowed on the admin routes, if it isn't.
+    // Without an admin token, they are refused to every client.
+    fn admin_denied(headers: &HeaderMap) -> Option<Response<Full<Bytes>>> {
+        if admin::authorized(headers) {
+            return None;
+        }
+        if admin::token_enabled() {
+            let mut response = Self::plain_error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Bearer realm=\"iptoasn-webservice\""),
+            );
+            return Some(response);
+        }
+        Some(Self::plain_error(
+            StatusCode::FORBIDDEN,
+            "The admin routes are disabled without an admin token",
+        ))
+    }
+
+    // GET /admin/usage returns the requests and IP lookups of each tenant
+    // for the current month. Requires the admin token.
+    fn admin_usage(headers: &HeaderMap) -> Result<Response<Full<Bytes>>, Infallible> {
+        if let Some(response) = Self::admin_denied(headers) {
+            return Ok(response);
+        }
+        let mut response = render::json_response(&usage::report());
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(response)
+    }
+
+    // GET /admin/status returns the contents of the database, the outcome
+    // of the last refreshes, the uptime and the memory use of the process.
+    // Requires the admin token.
+    fn admin_status(
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        refresher: &Refresher,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        if let Some(response) = Self::admin_denied(headers) {
+            return Ok(response);
+        }
+        let asns = Self::current_asns(&asns_arc);
+        let (succeeded, failed) = refresher.last_outcomes();
+        let started = *STARTED.get_or_init(SystemTime::now);
+        let (last_refresh_error, last_refresh_error_at) = match failed {
+            Some(failed) => (failed.error, failed.finished),
+            None => (None, None),
+        };
+        let status = AdminStatusResponse {
+            entries: asns.len(),
+            asns: asns.asn_count(),
+            countries: asns.country_count(),
+            source: asns.source().to_string(),
+            db_source: asns.db_source().as_str(),
+            generation: asns.generation().to_string(),
+            updated: httpdate::fmt_http_date(asns.updated()),
+            last_refresh: succeeded.and_then(|succeeded| succeeded.finished),
+            last_refresh_error,
+            last_refresh_error_at,
+            started: time::OffsetDateTime::from(started)
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default(),
+            uptime_seconds: started.elapsed().unwrap_or_default().as_secs(),
+            resident_memory_bytes: Self::resident_memory(),
+        };
+        let mut response = render::json_response(&status);
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(response)
+    }
+
+    // Resident set size of the process, from /proc on Linux
+    fn resident_memory() -> Option<u64> {
+        let status = std::fs::read_to_string("/proc/self/status").ok()?;
+        let kib = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))?
+            .trim()
+            .strip_suffix("kB")?
+            .trim();
+        u64::from_str(kib).ok().map(|kib| kib * 1024)
+    }
+
+    // POST /admin/refresh starts a database refresh (or returns the one in
+    // flight) with 202 Accepted, or with ?wait=true returns it once finished;
+    // GET /admin/refresh/<id> returns its status. Requires the admin token.
+    async fn admin_refresh(
+        headers: &HeaderMap,
+        refresher: &Arc<Refresher>,
+        id: Option<&str>,
+        wait: bool,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        if let Some(response) = Self::admin_denied(headers) {
+            return Ok(response);
+        }
+        let (status, code) = match id {
+            None if wait => {
+                let id = refresher.trigger().id;
+                (refresher.wait(id).await, StatusCode::OK)
+            }
+            None => (Some(refresher.trigger()), StatusCode::ACCEPTED),
+            Some(id) => (
+                id.parse::<u64>().ok().and_then(|id| refresher.status(id)),
+                StatusCode::OK,
+            ),
+        };
+        let Some(status) = status else {
+            let mut response = Response::new(Full::new(Bytes::from(
+                r#"{"error":"Unknown refresh operation"}"#,
+            )));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/json; charset=utf-8"),
+            );
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        };
+
+        let mut response = render::json_response(&status);
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        if code == StatusCode::ACCEPTED {
+            if let Ok(location) = HeaderValue::from_str(&format!(
+                "{}/admin/refresh/{}",
+                Self::base_path(),
+                status.id
+            )) {
+                response.headers_mut().insert(LOCATION, location);
+            }
+        }
+        if response.status() == StatusCode::OK {
+            *response.status_mut() = code;
+        }
+        Ok(response)
+    }
+
+    // GET /admin/replication/snapshot returns the database in the binary
+    // snapshot format, for standbys starting up. Requires the replication
+    // token.
+    async fn replication_snapshot(
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        if !replication::authorized(headers) {
+            return Ok(Self::unauthorized());
+        }
+        let asns = Self::current_asns(&asns_arc);
+        let snapshot = {
+            let asns = asns.clone();
+            tokio::task::spawn_blocking(move || asns.to_snapshot()).await
+        };
+        let Ok(snapshot) = snapshot else {
+            return Ok(Self::plain_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unable to serialize the database",
+            ));
+        };
+        let mut response = Response::new(Full::new(Bytes::from(snapshot)));
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        for (name, value) in [
+            (replication::GENERATION_HEADER, asns.generation().to_string()),
+            (replication::UPDATED_HEADER, httpdate::fmt_http_date(asns.updated())),
+            (replication::SOURCE_HEADER, asns.source().to_string()),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+        Ok(response)
+    }
+
+    // PUT /admin/replication/snapshot validates a snapshot pushed by the
+    // primary and stages it with 202 Accepted, until
+    // POST /admin/replication/activate/<generation>. A generation already
+    // served isn't read again. Requires the replication token.
+    async fn replication_stage(
+        req: Request<hyper::body::Incoming>,
+        refresher: &Refresher,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        if !replication::authorized(req.headers()) {
+            return Ok(Self::unauthorized());
+        }
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let Some(generation) = header(replication::GENERATION_HEADER) else {
+            return Ok(Self::body_error(
+                StatusCode::BAD_REQUEST,
+                "Missing X-Db-Generation header",
+                true,
+            ));
+        };
+        if refresher.generation() == generation {
+            return Ok(Self::replication_response(
+                StatusCode::OK,
+                ReplicationResponse { generation, entries: None },
+            ));
+        }
+        let updated = header(replication::UPDATED_HEADER)
+            .and_then(|updated| httpdate::parse_http_date(&updated).ok())
+            .unwrap_or_else(SystemTime::now);
+        let source = header(replication::SOURCE_HEADER).unwrap_or_default();
+        // Snapshots are larger than client bodies, and only sent by the primary
+        let max_body = MAX_REPLICATION_BODY.load(Ordering::Relaxed);
+        let body = match Self::read_body_within(req, max_body, max_body).await {
+            Ok(body) => body,
+            Err((status, message)) => return Ok(Self::body_error(status, &message, true)),
+        };
+        let asns = {
+            let generation = generation.clone();
+            tokio::task::spawn_blocking(move || Asns::from_snapshot(&body, source, updated, generation)).await
+        };
+        let asns = match asns {
+            Ok(Ok(asns)) => asns,
+            Ok(Err(e)) => return Ok(Self::body_error(StatusCode::UNPROCESSABLE_ENTITY, e, true)),
+            Err(_) => {
+                return Ok(Self::body_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unable to read the replicated snapshot",
+                    true,
+                ))
+            }
+        };
+        let entries = asns.len();
+        refresher.stage(asns);
+        Ok(Self::replication_response(
+            StatusCode::ACCEPTED,
+            ReplicationResponse { generation, entries: Some(entries) },
+        ))
+    }
+
+    // POST /admin/replication/activate/<generation> serves the staged
+    // snapshot of that generation, 409 Conflict if none is staged. Requires
+    // the replication token.
+    fn replication_activate(
+        headers: &HeaderMap,
+        refresher: &Refresher,
+        generation: &str,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        if !replication::authorized(headers) {
+            return Ok(Self::unauthorized());
+        }
+        if !refresher.activate(generation) {
+            return Ok(Self::body_error(
+                StatusCode::CONFLICT,
+                "No staged database of that generation",
+                true,
+            ));
+        }
+        Ok(Self::replication_response(
+            StatusCode::OK,
+            ReplicationResponse { generation: generation.to_string(), entries: None },
+        ))
+    }
+
+    fn replication_response(status: StatusCode, body: ReplicationResponse) -> Response<Full<Bytes>> {
+        let mut response = render::json_response(&body);
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        if response.status() == StatusCode::OK {
+            *response.status_mut() = status;
+        }
+        response
+    }
 
-#[derive(Serialize)]
-struct AsSubnetsResponse {
-    as_number: u32,
-    subnets: Vec<String>,
-}
+    // Field separator of plain outputs (?sep=, percent-encoded)
+    fn field_separator(query: Option<&str>) -> String {
+        match Self::query_param(query, "sep") {
+            Some(sep) if !sep.is_empty() => Self::percent_decode(sep),
+            _ => DEFAULT_FIELD_SEPARATOR.to_string(),
+        }
+    }
 
-#[derive(Serialize)]
-struct CountryAsnsResponse {
-    country_code: String,
-    as_numbers: Vec<u32>,
-}
+    pub(crate) fn percent_decode(s: &str) -> String {
+        let b = s.as_bytes();
+        let mut out = Vec::with_capacity(b.len());
+        let mut i = 0;
+        while i < b.len() {
+            let hex = b.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+            match (b[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+                (b'%', Some(byte)) => {
+                    out.push(byte);
+                    i += 3;
+                }
+                (byte, _) => {
+                    out.push(byte);
+                    i += 1;
+                }
+            }
+        }
+        String::from_utf8_lossy(&out).into_owned()
+    }
 
-#[derive(Serialize)]
-struct CountrySubnetsResponse {
-    country_code: String,
-    subnets: Vec<String>,
-}
+    fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+        query?
+            .split('&')
+            .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v)
+    }
 
-pub struct WebService;
+    // Plain-text banner, or the capabilities of the service when JSON is
+    // asked for explicitly. Not cached, as it includes the database status.
+    fn index(headers: &HeaderMap, asns_arc: Arc<RwLock<Arc<Asns>>>, refresher: &Refresher) -> Response<Full<Bytes>> {
+        let json = headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(OutputType::from_accept)
+            == Some(OutputType::Json);
+        if !json {
+            let mut response = Response::new(Full::new(Bytes::from("iptoasn-webservice\n")));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            );
+            *response.status_mut() = StatusCode::OK;
+            return response;
+        }
+        let asns = Self::current_asns(&asns_arc);
+        let capabilities = CapabilitiesResponse {
+            service: env!("CARGO_PKG_NAME"),
+            build: build_info::build_info(),
+            database: Self::db_status_of(&asns, refresher),
+            endpoints: router::routes()
+                .iter()
+                .map(|entry| Endpoint {
+                    method: entry.method,
+                    path: format!("{}{}", Self::base_path(), openapi::path_template(entry.pattern)),
+                    produces: entry.produces,
+                    consumes: entry.consumes,
+                })
+                .collect(),
+            limits: Limits::current(),
+            output_types: render::MEDIA_TYPES,
+        };
+        let mut response = render::json_response(&capabilities);
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        response
+    }
 
-impl WebService {
-    async fn handle_request(
-        req: Request<hyper::body::Incoming>,
-        asns_arc: Arc<RwLock<Arc<Asns>>>,
-        remote_addr: SocketAddr,
-    ) -> Result<Response<Full<Bytes>>, Infallible> {
-        let method = req.method();
-        let uri = req.uri().path();
+    // OpenAPI 3 document of all routes, e.g. to generate clients
+    fn openapi() -> Response<Full<Bytes>> {
+        let document = openapi::document();
+        let mut response = Response::new(Full::new(Bytes::from(document.to_string())));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/json; charset=utf-8"),
+        );
+        Self::cache_headers(response.headers_mut());
+        *response.status_mut() = StatusCode::OK;
+        response
+    }
 
-        match (method, uri) {
-            (&Method::GET, "/") => Ok(Self::index()),
-            (&Method::GET, "/v1/as/ip") => {
-                let client_ip = Self::extract_client_ip(req.headers(), remote_addr);
-                Self::ip_lookup(&client_ip, req.headers(), asns_arc)
-            }
-            (&Method::GET, path) if path.starts_with("/v1/as/ip/") => {
-                let ip_s = path.strip_prefix("/v1/as/ip/").unwrap_or("");
-                Self::ip_lookup(ip_s, req.headers(), asns_arc)
-            }
-            (&Method::GET, "/v1/as/n") => {
-                let accept = Self::accept_type(req.headers());
-                let mut resp = match accept {
-                    OutputType::Plain => Response::new(Full::new(Bytes::from(
-                        "Missing AS number. Use /v1/as/n/<AS123> or /v1/as/n/<123>\n",
-                    ))),
-                    _ => Response::new(Full::new(Bytes::from(
-                        r#"{"error":"Missing AS number. Use /v1/as/n/<AS123> or /v1/as/n/<123>"}"#,
-                    ))),
-                };
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                resp.headers_mut().insert(
-                    CONTENT_TYPE,
-                    HeaderValue::from_static(match accept {
-                        OutputType::Plain => "text/plain; charset=utf-8",
-                        _ => "application/json; charset=utf-8",
-                    }),
-                );
-                Ok(resp)
-            }
-            (&Method::GET, "/v1/as/ns") => Self::as_meta_list(req.headers(), asns_arc),
-            (&Method::GET, path) if path.starts_with("/v1/as/n/") && path.ends_with("/subnets") => {
-                let asn_s = path.strip_prefix("/v1/as/n/").unwrap_or("");
-                let asn_s = asn_s.strip_suffix("/subnets").unwrap_or(asn_s);
-                Self::as_subnets_lookup(asn_s, req.headers(), asns_arc)
-            }
-            (&Method::GET, path) if path.starts_with("/v1/as/n/") => {
-                let asn_s = path.strip_prefix("/v1/as/n/").unwrap_or("");
-                Self::as_meta_lookup(asn_s, req.headers(), asns_arc)
-            }
-            (&Method::GET, path) if path.starts_with("/v1/as/country/") && path.ends_with("/subnets") => {
-                let cc = path.strip_prefix("/v1/as/country/").unwrap_or("");
-                let cc = cc.strip_suffix("/subnets").unwrap_or(cc);
-                Self::country_subnets_lookup(cc, req.headers(), asns_arc)
-            }
-            (&Method::GET, path) if path.starts_with("/v1/as/country/") => {
-                let cc = path.strip_prefix("/v1/as/country/").unwrap_or("");
-                Self::country_asns_lookup(cc, req.headers(), asns_arc)
-            }
-            (&Method::PUT, "/v1/as/ips") => Self::handle_put_ips(req, asns_arc).await,
-            _ => {
-                let mut response = Response::new(Full::new(Bytes::from("Not Found")));
-                *response.status_mut() = StatusCode::NOT_FOUND;
-                Ok(response)
-            }
-        }
+    // GET /version, not cached, so that audits see upgrades right away
+    fn version() -> Response<Full<Bytes>> {
+        let mut response = render::json_response(&build_info::build_info());
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        response
     }
 
-    fn index() -> Response<Full<Bytes>> {
-        let mut response = Response::new(Full::new(Bytes::from("iptoasn-webservice\n")));
+    // Bulk lookup form, calling PUT /v1/as/ips from the browser. Its URLs are
+    // relative, so that it works under any base path.
+    fn bulk_page() -> Response<Full<Bytes>> {
+        let mut response = Response::new(Full::new(Bytes::from_static(BULK_PAGE.as_bytes())));
         response.headers_mut().insert(
             CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
+            HeaderValue::from_static("text/html; charset=utf-8"),
         );
+        Self::cache_headers(response.headers_mut());
         *response.status_mut() = StatusCode::OK;
         response
     }
 
-    fn extract_client_ip(headers: &HeaderMap, remote_addr: SocketAddr) -> String {
+    // Serve all routes under a URL prefix such as /iptoasn. Must be called
+    // before the server is started.
+    pub fn set_base_path(base_path: &str) {
+        let base_path = base_path.trim_matches('/');
+        let base_path = if base_path.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", base_path)
+        };
+        let _ = BASE_PATH.set(base_path);
+    }
+
+    // Host-based defaults from the config file. Must be called before the
+    // server is started.
+    pub fn set_hosts(hosts: Vec<HostConfig>) {
+        let _ = HOSTS.set(hosts);
+    }
+
+    // Cache file of the database, whose retained snapshots are the history
+    // of /v1/as/ip/<ip>/history. Must be called before the server is started.
+    pub fn set_cache_file(path: PathBuf) {
+        let _ = CACHE_FILE.set(path);
+    }
+
+    // Serve HTTPS instead of plain HTTP, with a PEM certificate chain and
+    // private key reloaded when the files change. Must be called before the
+    // server is started.
+    pub fn set_tls(cert_path: &Path, key_path: &Path) -> Result<(), String> {
+        let certificate = tls::Certificate::load(cert_path, key_path)?;
+        let _ = TLS_CERTIFICATE.set(certificate);
+        Ok(())
+    }
+
+    // Only honor the forwarded client address headers of peers in this set,
+    // e.g. the reverse proxies in front of the service. Must be called before
+    // the server is started.
+    pub fn set_trusted_proxies(proxies: IpRangeSet) {
+        let _ = TRUSTED_PROXIES.set(proxies);
+    }
+
+    pub(crate) fn base_path() -> &'static str {
+        BASE_PATH.get().map(String::as_str).unwrap_or("")
+    }
+
+    // Route path of a request path, or None if it is outside of the base path
+    pub(crate) fn strip_base_path(path: &str) -> Option<&str> {
+        match path.strip_prefix(Self::base_path())? {
+            "" => Some("/"),
+            rest if rest.starts_with('/') => Some(rest),
+            _ => None,
+        }
+    }
+
+    // Address of the client: the peer address, unless the peer is a trusted
+    // proxy forwarding the address of its own client
+    pub(crate) fn extract_client_ip(headers: &HeaderMap, remote_addr: SocketAddr) -> String {
+        let trusted = |ip: IpAddr| TRUSTED_PROXIES.get().is_some_and(|proxies| proxies.contains(ip));
+        if !trusted(remote_addr.ip()) {
+            return remote_addr.ip().to_string();
+        }
+
         if let Some(ip_str) = headers.get("x-real-ip").and_then(|v| v.to_str().ok()) {
             return ip_str.to_string();
         }
 
+        // Each proxy appends the address it received the request from, so the
+        // client is the rightmost address that isn't a trusted proxy; those on
+        // its left may have been made up by the client
         if let Some(forwarded) = headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
-            if let Some(first_ip) = forwarded
-                .split(',')
-                .next()
-                .map(str::trim)
-                .filter(|s| !s.is_empty())
-            {
-                return first_ip.to_string();
+            let mut hops = forwarded.rsplit(',').map(str::trim).filter(|s| !s.is_empty()).peekable();
+            while let Some(hop) = hops.next() {
+                match IpAddr::from_str(hop) {
+                    Ok(ip) if trusted(ip) && hops.peek().is_some() => continue,
+                    _ => return hop.to_string(),
+                }
             }
         }
 
@@ -179,20 +2602,32 @@ impl WebService {
     }
 
     fn accept_type(headers: &HeaderMap) -> OutputType {
-        if let Some(accept) = headers.get(ACCEPT) {
-            if let Ok(accept_str) = accept.to_str() {
-                if accept_str.contains("application/json") {
-                    return OutputType::Json;
-                }
-                if accept_str.contains("text/plain") {
-                    return OutputType::Plain;
-                }
-                if accept_str.contains("text/html") {
-                    return OutputType::Html;
-                }
-            }
-        }
-        OutputType::Html
+        headers
+            .get(ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(OutputType::from_accept)
+            .or_else(|| Self::host_default_type(headers))
+            .unwrap_or(OutputType::Html)
+    }
+
+    // Whether the Accept header asks for HTML, for routes that return JSON
+    // by default, as HTML is also the output type of requests without one
+    fn explicit_html(headers: &HeaderMap) -> bool {
+        headers
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+    }
+
+    // Output type configured for the Host of the request, if any
+    fn host_default_type(headers: &HeaderMap) -> Option<OutputType> {
+        let host = headers.get(HOST)?.to_str().ok()?;
+        let host_config = HOSTS.get()?.iter().find(|h| h.matches(host))?;
+        Some(match host_config.default_format {
+            Format::Json => OutputType::Json,
+            Format::Html => OutputType::Html,
+            Format::Plain => OutputType::Plain,
+        })
     }
 
     fn body_input_type(headers: &HeaderMap) -> Option<BodyInputType> {
@@ -201,6 +2636,7 @@ impl WebService {
                 let ct_main = ct_str.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
                 return match ct_main.as_str() {
                     "application/json" => Some(BodyInputType::Json),
+                    "application/x-ndjson" => Some(BodyInputType::Ndjson),
                     "text/plain" => Some(BodyInputType::Plain),
                     _ => None,
                 };
@@ -209,90 +2645,121 @@ impl WebService {
         None
     }
 
-    fn cache_headers(headers: &mut HeaderMap) {
-        let now = OffsetDateTime::now_utc();
-        let expires = now + time::Duration::seconds(TTL as i64);
+    // Send Cache-Control only, without Expires (--no-expires). Expires is
+    // redundant for HTTP/1.1 caches and misleading when the clock is skewed.
+    pub fn disable_expires() {
+        EXPIRES_ENABLED.store(false, Ordering::Relaxed);
+    }
 
-        let format = format_description!(
-            "[weekday repr:short], [day] [month repr:short] [year] [hour]:[minute]:[second] GMT"
-        );
-        let expires_str = expires.format(&format).unwrap();
+    pub fn set_strict_negotiation(strict: bool) {
+        STRICT_NEGOTIATION.store(strict, Ordering::Relaxed);
+    }
 
-        headers.insert(
-            CACHE_CONTROL,
-            HeaderValue::from_str(&format!("max-age={}", TTL)).unwrap(),
-        );
-        headers.insert(EXPIRES, HeaderValue::from_str(&expires_str).unwrap());
-        headers.insert(VARY, HeaderValue::from_static("Accept"));
+    pub fn set_strict_not_found(strict: bool) {
+        STRICT_NOT_FOUND.store(strict, Ordering::Relaxed);
     }
 
-    fn output_json(response: &IpLookupResponse) -> Response<Full<Bytes>> {
-        let json = serde_json::to_string(&response).unwrap();
-        let mut response = Response::new(Full::new(Bytes::from(json)));
+    // Write AS descriptions in plain text responses as their ASCII
+    // transliteration, for consumers that reject other bytes
+    pub fn set_plain_ascii_descriptions(ascii: bool) {
+        PLAIN_ASCII_DESCRIPTIONS.store(ascii, Ordering::Relaxed);
+    }
 
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("application/json; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
+    // Unannounced space gets announced over time, so "not announced"
+    // answers are cached for a shorter time (--not-found-ttl).
+    pub fn set_not_found_ttl(ttl: Duration) {
+        NOT_FOUND_TTL.store(ttl.as_secs(), Ordering::Relaxed);
+    }
 
-        response
+    fn cache_headers(headers: &mut HeaderMap) {
+        Self::cache_headers_with_ttl(headers, TTL as u64, false);
     }
 
-    fn output_json_vec(responses: &[IpLookupResponse]) -> Response<Full<Bytes>> {
-        let json = serde_json::to_string(responses).unwrap();
-        let mut response = Response::new(Full::new(Bytes::from(json)));
+    // Cache headers of IP lookup responses, depending on whether all the
+    // looked up addresses were announced. Not found results also get
+    // s-maxage and must-revalidate, so that shared caches such as CDNs don't
+    // apply a longer TTL of their own nor serve them once stale.
+    fn ip_cache_headers(headers: &mut HeaderMap, announced: bool) {
+        if announced {
+            Self::cache_headers(headers);
+        } else {
+            Self::cache_headers_with_ttl(headers, NOT_FOUND_TTL.load(Ordering::Relaxed), true);
+        }
+    }
 
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("application/json; charset=utf-8"),
+    // With authentication enabled, responses are private, or shared caches
+    // would serve them to clients without a key
+    fn cache_headers_with_ttl(headers: &mut HeaderMap, ttl: u64, not_found: bool) {
+        let cache_control = match (not_found, auth::is_enabled()) {
+            (true, true) => format!("private, max-age={ttl}, must-revalidate"),
+            (true, false) => format!("max-age={ttl}, s-maxage={ttl}, must-revalidate"),
+            (false, true) => format!("private, max-age={}", ttl),
+            (false, false) => format!("max-age={}", ttl),
+        };
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&cache_control).unwrap_or(HeaderValue::from_static("no-cache")),
         );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-
-        response
+        if EXPIRES_ENABLED.load(Ordering::Relaxed) {
+            // IMF-fixdate, always in GMT regardless of the local timezone
+            let expires = SystemTime::now() + Duration::from_secs(ttl);
+            match HeaderValue::from_str(&httpdate::fmt_http_date(expires)) {
+                Ok(value) => {
+                    headers.insert(EXPIRES, value);
+                }
+                Err(e) => log::warn!("Unable to format Expires header: {}", e),
+            }
+        }
+        headers.insert(VARY, HeaderValue::from_static("Accept"));
     }
 
-    fn output_html(response: &IpLookupResponse) -> Response<Full<Bytes>> {
+    // Table of bulk results, linking each address and AS to its own page
+    fn html_vec_text(
+        responses: &[IpLookupResponse],
+        style: &Style,
+    ) -> Result<String, horrorshow::Error> {
+        let base_path = Self::base_path();
         let html = html! {
             head {
-                title : "iptoasn lookup";
+                title : "iptoasn bulk lookup";
                 meta(name="viewport", content="width=device-width, initial-scale=1");
                 link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
-                style : "body { margin: 1em 4em }";
+                style : "body { margin: 1em 4em } td, th { padding-right: 1em }";
             }
             body(class="container-fluid") {
                 header {
-                    h1 : format_args!("Information for IP address: {}", response.ip);
+                    h1 : format_args!("Information for {} IP addresses", responses.len());
                 }
                 table {
                     tr {
-                        th : "Announced";
-                        td {
-                            @ if response.announced {
-                                : "Yes";
-                            } else {
-                                : "No";
-                            }
-                        }
+                        th : "IP address";
+                        th : "AS Number";
+                        th : "AS Range";
+                        th : "AS Country Code";
+                        th : "AS Description";
                     }
-                    @ if response.announced {
-                        tr {
-                            th : "AS Number";
-                            td : format_args!("AS{}", response.as_number.unwrap());
-                        }
-                        tr {
-                            th : "AS Range";
-                            td : format_args!("{} - {}", response.first_ip.as_ref().unwrap(), response.last_ip.as_ref().unwrap());
-                        }
+                    @ for r in responses {
                         tr {
-                            th : "AS Country Code";
-                            td : response.as_country_code.as_ref().unwrap();
-                        }
-                        tr {
-                            th : "AS Description";
-                            td : response.as_description.as_ref().unwrap();
+                            td {
+                                @ if IpAddr::from_str(&r.ip).is_ok() {
+                                    a(href=format_args!("{}/v1/as/ip/{}", base_path, r.ip)) : &r.ip;
+                                } else {
+                                    : &r.ip;
+                                }
+                            }
+                            @ if let (true, Some(number)) = (r.announced, r.as_number) {
+                                td {
+                                    a(href=format_args!("{}/v1/as/n/{}", base_path, number)) : format_args!("AS{}", style.asn(number));
+                                }
+                                td : format_args!("{} - {}", r.first_ip.as_deref().unwrap_or(""), r.last_ip.as_deref().unwrap_or(""));
+                                td : r.as_country_code.as_deref().unwrap_or("");
+                                td : r.as_description.as_deref().unwrap_or("");
+                            } else {
+                                td : "AS0";
+                                td;
+                                td : "None";
+                                td : "Not announced";
+                            }
                         }
                     }
                 }
@@ -303,191 +2770,462 @@ impl WebService {
                     } }
                 }
             }
-        }.into_string()
-            .unwrap();
-        let html = format!("<!DOCTYPE html>\n<html>{html}</html>");
+        }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
+    }
 
-        let mut response = Response::new(Full::new(Bytes::from(html)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/html; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
+    fn output(
+        output_type: &OutputType,
+        response: &IpLookupResponse,
+        style: &Style,
+    ) -> Response<Full<Bytes>> {
+        let mut r = render::response(*output_type, response, style);
+        if r.status() == StatusCode::OK {
+            Self::ip_cache_headers(r.headers_mut(), response.announced);
+        }
+        r
+    }
 
-        response
+    // Rendered response, cacheable for the default TTL
+    fn output_cached<R: Render + ?Sized>(output_type: OutputType, value: &R, style: &Style) -> Response<Full<Bytes>> {
+        let mut r = render::response(output_type, value, style);
+        if r.status() == StatusCode::OK {
+            Self::cache_headers(r.headers_mut());
+        }
+        r
     }
 
-    fn output_plain(response: &IpLookupResponse) -> Response<Full<Bytes>> {
-        let plain = if response.announced {
-            format!(
-                "{} | {}-{} | {} | {}",
-                response.as_number.unwrap(),
-                response.first_ip.as_deref().unwrap(),
-                response.last_ip.as_deref().unwrap(),
-                response.as_country_code.as_deref().unwrap(),
-                response.as_description.as_deref().unwrap()
-            )
-        } else {
-            format!("0 | {} | None | Not announced", response.ip)
+    fn ip_lookup(
+        ip_s: &str,
+        extract: bool,
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let ip = match std::net::IpAddr::from_str(ip_s) {
+            Err(_) => {
+                // Give a targeted error for MACs, URLs, hostnames and the like,
+                // or with ?extract=true look up the address they contain.
+                let kind = InputKind::classify(ip_s);
+                match (extract, kind.extracted_ip(), kind.hint()) {
+                    (true, Some(ip), _) => ip,
+                    (_, _, Some(hint)) => {
+                        return Ok(Self::output_input_error(
+                            &Self::accept_type(headers),
+                            ip_s,
+                            &hint,
+                        ));
+                    }
+                    _ => {
+                        let response = IpLookupResponse::not_found(ip_s.to_owned());
+                        return Ok(Self::output(&Self::accept_type(headers), &response, style));
+                    }
+                }
+            }
+            Ok(ip) => ip,
         };
 
-        let mut response = Response::new(Full::new(Bytes::from(plain)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
+        let asns = Self::current_asns(&asns_arc);
+        let response = IpLookupResponse::lookup(ip, &asns);
 
-        response
+        Ok(Self::output(&Self::accept_type(headers), &response, style))
     }
 
-    fn output_plain_vec(responses: &[IpLookupResponse]) -> Response<Full<Bytes>> {
-        let max_ip_len = responses.iter().map(|r| r.ip.len()).max().unwrap_or(0).max(20);
-        let mut out = String::new();
-
-        for r in responses {
-            let asn_str = if r.announced {
-                r.as_number.unwrap().to_string()
-            } else {
-                "0".to_string()
-            };
-            let desc_cc = if r.announced {
-                format!("{}, {}", r.as_description.as_ref().unwrap(), r.as_country_code.as_ref().unwrap())
-            } else {
-                "Not announced".to_string()
-            };
-            out.push_str(&format!("{:<8} | {:<width$} | {}\n", asn_str, r.ip, desc_cc, width = max_ip_len));
-        }
+    // GET /v1/as/ip/<ip>/history returns the origin AS of an address in each
+    // cache snapshot retained with --retain-snapshots, as time windows
+    async fn origin_history(
+        ip_s: &str,
+        headers: &HeaderMap,
+        style: &Style<'_>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let Ok(ip) = IpAddr::from_str(ip_s) else {
+            return Ok(Self::plain_error(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid IP address [{}]", ip_s),
+            ));
+        };
+        let Some(cache_file) = CACHE_FILE.get() else {
+            return Ok(Self::plain_error(StatusCode::NOT_FOUND, "No snapshot archive"));
+        };
+        // Snapshots are decompressed from disk, away from the async workers
+        let windows = match tokio::task::spawn_blocking(move || Asns::origin_history(cache_file, ip)).await {
+            Ok(Ok(windows)) => windows,
+            Ok(Err(e)) => {
+                log::error!("Failed to read snapshots of {}: {}", cache_file.display(), e);
+                return Ok(Self::plain_error(StatusCode::SERVICE_UNAVAILABLE, "Snapshots unavailable"));
+            }
+            Err(e) => {
+                log::error!("Snapshot scan failed: {}", e);
+                return Ok(Self::plain_error(StatusCode::INTERNAL_SERVER_ERROR, "Snapshot scan failed"));
+            }
+        };
+        let resp = OriginHistoryResponse {
+            ip: ip.to_string(),
+            snapshots: windows.iter().map(|window| window.snapshots).sum(),
+            history: windows.into_iter().map(OriginHistoryEntry::from).collect(),
+        };
+        Ok(render::response(Self::accept_type(headers), &resp, style))
+    }
 
-        let mut response = Response::new(Full::new(Bytes::from(out)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
+    // GET /v1/stats/timeseries?asn=<asn>|country=<cc>&metric=<metric> returns
+    // the prefix count or address space of an AS number or a country in each
+    // cache snapshot retained with --retain-snapshots, for growth dashboards
+    async fn stats_timeseries(
+        asn_s: Option<&str>,
+        country_s: Option<&str>,
+        metric_s: Option<&str>,
+        headers: &HeaderMap,
+        style: &Style<'_>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let subject = match (asn_s, country_s) {
+            (Some(asn_s), None) => match Self::parse_as_number(asn_s) {
+                Some(number) => StatsSubject::Asn(number),
+                None => return Ok(Self::plain_error(StatusCode::BAD_REQUEST, "Invalid AS number. Use AS123 or 123")),
+            },
+            (None, Some(country_s)) => match Self::normalize_country_code(country_s) {
+                Some(country_code) => StatsSubject::Country(country_code),
+                None => return Ok(Self::plain_error(StatusCode::BAD_REQUEST, "Invalid country code")),
+            },
+            _ => {
+                return Ok(Self::plain_error(
+                    StatusCode::BAD_REQUEST,
+                    "Use /v1/stats/timeseries?asn=<asn> or /v1/stats/timeseries?country=<country code>",
+                ))
+            }
+        };
+        let Some(&(metric, value)) = TIMESERIES_METRICS
+            .iter()
+            .find(|(name, _)| metric_s.is_none_or(|metric_s| metric_s == *name))
+        else {
+            let names: Vec<&str> = TIMESERIES_METRICS.iter().map(|(name, _)| *name).collect();
+            return Ok(Self::plain_error(
+                StatusCode::BAD_REQUEST,
+                &format!("Unknown metric. Use one of: {}", names.join(", ")),
+            ));
+        };
+        let Some(cache_file) = CACHE_FILE.get() else {
+            return Ok(Self::plain_error(StatusCode::NOT_FOUND, "No snapshot archive"));
+        };
+        // Snapshots are decompressed from disk, away from the async workers
+        let subject_t = subject.clone();
+        let stats = match tokio::task::spawn_blocking(move || Asns::snapshot_stats(cache_file, &subject_t)).await {
+            Ok(Ok(stats)) => stats,
+            Ok(Err(e)) => {
+                log::error!("Failed to read snapshots of {}: {}", cache_file.display(), e);
+                return Ok(Self::plain_error(StatusCode::SERVICE_UNAVAILABLE, "Snapshots unavailable"));
+            }
+            Err(e) => {
+                log::error!("Snapshot scan failed: {}", e);
+                return Ok(Self::plain_error(StatusCode::INTERNAL_SERVER_ERROR, "Snapshot scan failed"));
+            }
+        };
+        let unix = |t: SystemTime| t.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let (as_number, country_code) = match subject {
+            StatsSubject::Asn(number) => (Some(number), None),
+            StatsSubject::Country(country_code) => (None, Some(country_code)),
+        };
+        let resp = TimeseriesResponse {
+            as_number,
+            country_code,
+            metric,
+            points: stats
+                .iter()
+                .map(|stats| TimeseriesPoint {
+                    first_seen: unix(stats.first_seen),
+                    last_seen: unix(stats.last_seen),
+                    value: value(&stats.counts),
+                })
+                .collect(),
+        };
+        Ok(render::response(Self::accept_type(headers), &resp, style))
     }
 
-    fn output(output_type: &OutputType, response: &IpLookupResponse) -> Response<Full<Bytes>> {
-        match *output_type {
-            OutputType::Json => Self::output_json(response),
-            OutputType::Html => Self::output_html(response),
-            OutputType::Plain => Self::output_plain(response),
+    // GET /v1/dnsbl/<query> answers like a DNSBL zone listing the [[dnsbl]]
+    // categories of the config file, for policy checks without a resolver
+    fn dnsbl_lookup(
+        query: &str,
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        if !dnsbl::is_enabled() {
+            return Ok(Self::plain_error(StatusCode::NOT_FOUND, "No DNSBL categories configured"));
         }
+        let output_type = Self::accept_type(headers);
+        let Some(ip) = dnsbl::parse_query(query) else {
+            return Ok(Self::output_input_error(
+                &output_type,
+                query,
+                "Invalid DNSBL query. Use the reversed IPv4 octets or IPv6 nibbles, e.g. 4.3.2.1 for 1.2.3.4",
+            ));
+        };
+        let asns = Self::current_asns(&asns_arc);
+        let verdict = dnsbl::check(&asns, ip);
+        let found = asns.lookup_by_ip(ip);
+        let resp = DnsblResponse {
+            query: query.to_string(),
+            ip: ip.to_string(),
+            listed: verdict.code != 0,
+            answer: dnsbl::answer(verdict.code).map(|answer| answer.to_string()),
+            categories: verdict.categories,
+            as_number: found.as_ref().map(|found| found.number),
+            as_country_code: found.map(|found| found.country.to_string()),
+        };
+        Ok(Self::output_cached(output_type, &resp, style))
     }
 
-    fn ip_lookup(
+    fn output_input_error(output_type: &OutputType, input: &str, hint: &str) -> Response<Full<Bytes>> {
+        let (body, content_type) = match output_type {
+            OutputType::Json => (
+                serde_json::json!({ "ip": input, "error": hint }).to_string(),
+                "application/json; charset=utf-8",
+            ),
+            OutputType::Html => {
+                let html = html! {
+                    head {
+                        title : "iptoasn lookup";
+                    }
+                    body {
+                        p : hint;
+                    }
+                }
+                .into_string()
+                .unwrap_or_default();
+                (
+                    format!("<!DOCTYPE html>\n<html>{html}</html>"),
+                    "text/html; charset=utf-8",
+                )
+            }
+            _ => (format!("{}\n", hint), "text/plain; charset=utf-8"),
+        };
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        response
+    }
+
+    fn geoip_lookup(
         ip_s: &str,
-        headers: &HeaderMap,
         asns_arc: Arc<RwLock<Arc<Asns>>>,
     ) -> Result<Response<Full<Bytes>>, Infallible> {
         let ip = match std::net::IpAddr::from_str(ip_s) {
-            Err(_) => {
-                let response = IpLookupResponse::not_found(ip_s.to_owned());
-                return Ok(Self::output(&Self::accept_type(headers), &response));
-            }
             Ok(ip) => ip,
-        };
-
-        let asns = asns_arc.read().unwrap().clone();
-
-        let found = match asns.lookup_by_ip(ip) {
-            None => {
-                let response = IpLookupResponse::not_found(ip.to_string());
-                return Ok(Self::output(&Self::accept_type(headers), &response));
+            Err(_) => {
+                let mut resp = Response::new(Full::new(Bytes::from(
+                    r#"{"error":"Invalid IP address"}"#,
+                )));
+                *resp.status_mut() = StatusCode::BAD_REQUEST;
+                resp.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/json; charset=utf-8"),
+                );
+                return Ok(resp);
             }
-            Some(found) => found,
         };
 
-        let response = IpLookupResponse {
-            ip: ip.to_string(),
-            announced: true,
-            first_ip: Some(found.first_ip.to_string()),
-            last_ip: Some(found.last_ip.to_string()),
-            as_number: Some(found.number),
-            as_country_code: Some(found.country.to_string()),
-            as_description: Some(found.description.to_string()),
+        let asns = Self::current_asns(&asns_arc);
+        let geoip = match asns.lookup_by_ip(ip) {
+            Some(found) => GeoIpResponse {
+                ip: ip.to_string(),
+                org: Some(format!("AS{} {}", found.number, found.description)),
+                country: Some(found.country.to_string()),
+                bogon: None,
+            },
+            None => GeoIpResponse {
+                ip: ip.to_string(),
+                org: None,
+                country: None,
+                bogon: Some(true),
+            },
         };
 
-        Ok(Self::output(&Self::accept_type(headers), &response))
+        let announced = geoip.bogon.is_none();
+        let mut response = render::json_response(&geoip);
+        Self::ip_cache_headers(response.headers_mut(), announced);
+        Ok(response)
     }
 
-    fn parse_plain_ip_list(body: &str) -> Vec<String> {
-        let mut ips = Vec::new();
-        let mut in_block = false;
-        let mut saw_begin = false;
-
-        for raw_line in body.lines() {
-            let line = raw_line.trim();
-            if line.is_empty() {
-                continue;
-            }
-            match line.to_ascii_lowercase().as_str() {
-                "begin" => {
-                    in_block = true;
-                    saw_begin = true;
-                    continue;
+    // POST /v1/as/ips/stream looks up the addresses of a plain text body, one
+    // per line, and sends each result as a server-sent event as soon as its
+    // line has arrived, so that log shippers can keep a single request open
+    // for continuous enrichment. The stream ends with an `end` event once the
+    // body is complete, or with an `error` event.
+    fn ips_stream(
+        req: Request<hyper::body::Incoming>,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        tenant: Option<&'static Tenant>,
+        in_flight: Option<load::InFlight>,
+    ) -> Response<Full<Bytes>> {
+        let (stream, sender) = EventStream::channel();
+        let mut body = req.into_body();
+        tokio::task::spawn(async move {
+            let _in_flight = in_flight;
+            let mut list = PlainIpList::new(usize::MAX);
+            let mut index = 0;
+            loop {
+                let frame = match tokio::time::timeout(STREAM_KEEPALIVE, body.frame()).await {
+                    Ok(frame) => frame,
+                    Err(_) => {
+                        if sender.send(Bytes::from_static(sse::KEEPALIVE)).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                let mut events = Vec::new();
+                // Ok(true) once the body is complete
+                let (ips, outcome) = match frame {
+                    Some(Ok(frame)) => match frame.data_ref().map_or(Ok(()), |data| list.feed(data)) {
+                        Ok(()) => (list.take_ips(), Ok(false)),
+                        // The lines before the error are still answered
+                        Err((_, message)) => (list.take_ips(), Err(message)),
+                    },
+                    Some(Err(_)) => (Vec::new(), Err("Failed to read request body".to_string())),
+                    None => match std::mem::replace(&mut list, PlainIpList::new(usize::MAX)).finish() {
+                        Ok(ips) => (ips, Ok(true)),
+                        Err((_, message)) => (Vec::new(), Err(message)),
+                    },
+                };
+                if let Some(tenant) = tenant.filter(|tenant| !tenant.charge_ips(ips.len() as u64)) {
+                    let message = tenant.quota_message(Some(ips.len() as u64));
+                    let error = serde_json::json!({ "error": message }).to_string();
+                    sse::event(Some("error"), None, &error, &mut events);
+                    let _ = sender.send(Bytes::from(events)).await;
+                    return;
                 }
-                "end" => {
-                    in_block = false;
-                    continue;
+                // The database of each batch, so that a long stream follows refreshes
+                let asns = Self::current_asns(&asns_arc);
+                for ip_s in ips {
+                    let mut result = match IpAddr::from_str(&ip_s) {
+                        Ok(ip) => IpLookupResponse::lookup(ip, &asns),
+                        Err(_) => IpLookupResponse::not_found(ip_s.clone()),
+                    };
+                    if !result.announced {
+                        // Echo unannounced addresses as sent
+                        result.ip = ip_s;
+                    }
+                    result.index = Some(index);
+                    let data = serde_json::to_string(&result).unwrap_or_default();
+                    sse::event(None, Some(index), &data, &mut events);
+                    index += 1;
                 }
-                _ => {}
+                let done = match outcome {
+                    Ok(done) => {
+                        if done {
+                            let end = serde_json::json!({ "processed": index }).to_string();
+                            sse::event(Some("end"), None, &end, &mut events);
+                        }
+                        done
+                    }
+                    Err(message) => {
+                        let error = serde_json::json!({ "error": message }).to_string();
+                        sse::event(Some("error"), None, &error, &mut events);
+                        true
+                    }
+                };
+                // Sending fails once the client is gone
+                let sent = events.is_empty() || sender.send(Bytes::from(events)).await.is_ok();
+                if done || !sent {
+                    return;
+                }
+            }
+        });
+
+        let mut response = Response::new(Full::new(Bytes::new()));
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        // Events are sent as they come through nginx too
+        headers.insert("x-accel-buffering", HeaderValue::from_static("no"));
+        response.extensions_mut().insert(stream);
+        response
+    }
+
+    // Entries of an NDJSON body: each line is an IP string, or an object
+    // with an "ip" string and an optional "id" echoed back in the result
+    fn parse_ndjson_ip_list(body: &str) -> Result<Vec<(String, Option<serde_json::Value>)>, String> {
+        let mut entries = Vec::new();
+        for (i, line) in body.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
             }
-            if saw_begin {
-                if in_block {
-                    ips.push(line.to_string());
+            let entry = match serde_json::from_str::<serde_json::Value>(line) {
+                Ok(serde_json::Value::String(ip)) => Some((ip, None)),
+                Ok(serde_json::Value::Object(mut object)) => match object.remove("ip") {
+                    Some(serde_json::Value::String(ip)) => {
+                        Some((ip, object.remove("id").filter(|id| !id.is_null())))
+                    }
+                    _ => None,
+                },
+                _ => None,
+            };
+            match entry {
+                Some((ip, id)) => entries.push((ip.trim().to_string(), id)),
+                None => {
+                    return Err(format!(
+                        "Invalid NDJSON on line {}. Expected an IP string or an object with an \"ip\" string",
+                        i + 1
+                    ))
                 }
-            } else {
-                ips.push(line.to_string());
             }
         }
-
-        ips
+        Ok(entries)
     }
 
+    // Results are in the order of the input addresses, or sorted by address
+    // with ?order=sorted; either way, each one has the `index` of its
+    // address in the input.
     async fn handle_put_ips(
         req: Request<hyper::body::Incoming>,
         asns_arc: Arc<RwLock<Arc<Asns>>>,
+        tenant: Option<&Tenant>,
+        style: &Style<'_>,
     ) -> Result<Response<Full<Bytes>>, Infallible> {
         let headers = req.headers().clone();
+        let deadline = Self::request_deadline(&headers);
+        let sorted = match Self::query_param(req.uri().query(), "order") {
+            None | Some("input") => false,
+            Some("sorted") => true,
+            Some(order) => {
+                return Ok(Self::plain_error(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Unsupported order \"{}\", expected input or sorted", order),
+                ))
+            }
+        };
 
         let output_type = match Self::accept_type(&headers) {
-            OutputType::Plain => OutputType::Plain,
-            _ => OutputType::Json,
+            OutputType::Html if !Self::explicit_html(&headers) => OutputType::Json,
+            output_type => output_type,
         };
 
         let input_type = Self::body_input_type(&headers);
-
-        let collected = match req.into_body().collect().await {
-            Ok(c) => c,
-            Err(_) => {
-                let mut resp = match output_type {
-                    OutputType::Plain => Response::new(Full::new(Bytes::from(
-                        "Failed to read request body\n",
-                    ))),
-                    _ => Response::new(Full::new(Bytes::from(
-                        r#"{"error":"Failed to read request body"}"#,
-                    ))),
-                };
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                resp.headers_mut().insert(
-                    CONTENT_TYPE,
-                    HeaderValue::from_static(match output_type {
-                        OutputType::Plain => "text/plain; charset=utf-8",
-                        _ => "application/json; charset=utf-8",
-                    }),
-                );
-                return Ok(resp);
+        let max_bulk_ips = MAX_BULK_IPS.load(Ordering::Relaxed);
+
+        // Plain text bodies are tokenized as they arrive, unless compressed
+        let streamed = matches!(input_type, Some(BodyInputType::Plain) | None)
+            && headers.get(CONTENT_ENCODING).is_none_or(|v| v.as_bytes().eq_ignore_ascii_case(b"identity"));
+        let body = if streamed {
+            Self::read_plain_ip_list(req, MAX_BODY.load(Ordering::Relaxed), max_bulk_ips)
+                .await
+                .map(|ips| (Bytes::new(), Some(ips)))
+        } else {
+            Self::read_body(req, MAX_BODY.load(Ordering::Relaxed))
+                .await
+                .map(|body| (body, None))
+        };
+        let (body_bytes, streamed_ips) = match body {
+            Ok(body) => body,
+            Err((status, message)) => {
+                return Ok(Self::body_error(status, &message, output_type != OutputType::Plain))
             }
         };
-
-        let body_bytes = collected.to_bytes();
         let body_str = String::from_utf8_lossy(&body_bytes);
 
+        // Identifiers of the NDJSON entries, in the order of the addresses
+        let mut ids: Vec<Option<serde_json::Value>> = Vec::new();
         let ip_list: Vec<String> = match input_type {
             Some(BodyInputType::Json) => {
                 match serde_json::from_slice::<Vec<String>>(&body_bytes) {
@@ -495,7 +3233,12 @@ impl WebService {
                     Err(_) => {
                         let looks_plain = !body_str.trim_start().starts_with('[');
                         if output_type == OutputType::Plain || looks_plain {
-                            let ips = Self::parse_plain_ip_list(&body_str);
+                            let ips = match PlainIpList::parse(&body_bytes, max_bulk_ips) {
+                                Ok(ips) => ips,
+                                Err((status, message)) => {
+                                    return Ok(Self::body_error(status, &message, output_type != OutputType::Plain))
+                                }
+                            };
                             if ips.is_empty() {
                                 let mut resp = Response::new(Full::new(Bytes::from(
                                     "Invalid text body. Expected newline-separated IPs, optionally wrapped by 'begin'/'end'\n",
@@ -522,8 +3265,44 @@ impl WebService {
                     }
                 }
             }
+            Some(BodyInputType::Ndjson) => match Self::parse_ndjson_ip_list(&body_str) {
+                Ok(entries) if !entries.is_empty() => {
+                    let (ips, entry_ids) = entries.into_iter().unzip();
+                    ids = entry_ids;
+                    ips
+                }
+                result => {
+                    let message = result
+                        .err()
+                        .unwrap_or_else(|| "Empty body. Expected one IP address per line".to_string());
+                    let mut resp = match output_type {
+                        OutputType::Plain => Response::new(Full::new(Bytes::from(format!("{}\n", message)))),
+                        _ => Response::new(Full::new(Bytes::from(
+                            serde_json::json!({ "error": message }).to_string(),
+                        ))),
+                    };
+                    *resp.status_mut() = StatusCode::BAD_REQUEST;
+                    resp.headers_mut().insert(
+                        CONTENT_TYPE,
+                        HeaderValue::from_static(match output_type {
+                            OutputType::Plain => "text/plain; charset=utf-8",
+                            _ => "application/json; charset=utf-8",
+                        }),
+                    );
+                    return Ok(resp);
+                }
+            },
             Some(BodyInputType::Plain) | None => {
-                let ips = Self::parse_plain_ip_list(&body_str);
+                let parsed = match streamed_ips {
+                    Some(ips) => Ok(ips),
+                    None => PlainIpList::parse(&body_bytes, max_bulk_ips),
+                };
+                let ips = match parsed {
+                    Ok(ips) => ips,
+                    Err((status, message)) => {
+                        return Ok(Self::body_error(status, &message, output_type != OutputType::Plain))
+                    }
+                };
                 if ips.is_empty() {
                     let mut resp = match output_type {
                         OutputType::Plain => Response::new(Full::new(Bytes::from(
@@ -547,540 +3326,890 @@ impl WebService {
             }
         };
 
-        let asns = asns_arc.read().unwrap().clone();
-        let mut results: Vec<IpLookupResponse> = Vec::with_capacity(ip_list.len());
+        let total = ip_list.len();
+        if total > max_bulk_ips {
+            return Ok(Self::body_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                &format!("Too many IP addresses ({}), at most {} per request", total, max_bulk_ips),
+                output_type != OutputType::Plain,
+            ));
+        }
+        // Charged before the lookups, so that a batch can't overrun the quota
+        if let Some(tenant) = tenant.filter(|tenant| !tenant.charge_ips(total as u64)) {
+            return Ok(Self::quota_exceeded(tenant, Some(total as u64)));
+        }
+        let asns = Self::current_asns(&asns_arc);
+        let (results, truncated) = Self::lookup_ips(ip_list, ids, &asns, sorted, deadline);
+
+        let mut response = render::response(output_type, results.as_slice(), style);
+        if truncated {
+            // Same shape as a full response; partial results aren't cached
+            let headers = response.headers_mut();
+            headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+            headers.insert("x-truncated", HeaderValue::from_static("true"));
+            headers.insert(TOTAL_COUNT, HeaderValue::from(total));
+            return Ok(response);
+        }
+        Self::ip_cache_headers(response.headers_mut(), results.iter().all(|r| r.announced));
+        Ok(response)
+    }
 
-        for ip_s in ip_list {
+    // Results of the addresses of a bulk request, in input order or sorted,
+    // and whether the deadline cut them short
+    fn lookup_ips(
+        ip_list: Vec<String>,
+        ids: Vec<Option<serde_json::Value>>,
+        asns: &Asns,
+        sorted: bool,
+        deadline: Option<Instant>,
+    ) -> (Vec<IpLookupResponse>, bool) {
+        let total = ip_list.len();
+        let mut results: Vec<IpLookupResponse> = Vec::with_capacity(total);
+        let mut truncated = false;
+        let mut ids = ids.into_iter();
+
+        for (i, ip_s) in ip_list.into_iter().enumerate() {
+            // Stop once the client's deadline has passed rather than finishing
+            // a response nobody is waiting for anymore
+            if i % 256 == 0 && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                truncated = true;
+                break;
+            }
             match std::net::IpAddr::from_str(&ip_s) {
                 Ok(ip) => {
-                    if let Some(found) = asns.lookup_by_ip(ip) {
-                        results.push(IpLookupResponse {
-                            ip: ip.to_string(),
-                            announced: true,
-                            first_ip: Some(found.first_ip.to_string()),
-                            last_ip: Some(found.last_ip.to_string()),
-                            as_number: Some(found.number),
-                            as_country_code: Some(found.country.to_string()),
-                            as_description: Some(found.description.to_string()),
-                        });
-                    } else {
-                        results.push(IpLookupResponse::not_found(ip_s));
+                    let mut response = IpLookupResponse::lookup(ip, asns);
+                    if !response.announced {
+                        // Echo unannounced addresses as sent
+                        response.ip = ip_s;
                     }
+                    results.push(response);
                 }
                 Err(_) => {
                     results.push(IpLookupResponse::not_found(ip_s));
                 }
             }
+            if let Some(result) = results.last_mut() {
+                result.id = ids.next().flatten();
+                result.index = Some(i);
+            }
+        }
+        if sorted {
+            // By address, IPv4 first, then invalid entries; entries with the
+            // same address keep their input order
+            results.sort_by_key(|r| {
+                let ip = IpAddr::from_str(&r.ip).ok();
+                (ip.is_none(), ip)
+            });
+        }
+        (results, truncated)
+    }
+
+    // PUT /v1/annotate/log: annotate the client address of each log line,
+    // like the iptoasn CLI, with ?format=auto|apache|nginx|syslog. Returns
+    // the annotated lines, or one JSON object per line with ?output=ndjson.
+    // ?country=<cc> and ?asn=<asn> keep only the lines of clients in that
+    // country or AS.
+    async fn handle_annotate_log(
+        req: Request<hyper::body::Incoming>,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        tenant: Option<&Tenant>,
+        style: &Style<'_>,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let query = req.uri().query();
+        let format = match LogFormat::from_str(Self::query_param(query, "format").unwrap_or("auto")) {
+            Ok(format) => format,
+            Err(e) => return Ok(Self::plain_error(StatusCode::BAD_REQUEST, &e)),
+        };
+        let include_description = Self::query_param(query, "description") == Some("true");
+        let ndjson = Self::query_param(query, "output") == Some("ndjson")
+            || req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.contains("application/x-ndjson"));
+
+        let country = Self::query_param(query, "country").map(str::to_ascii_uppercase);
+        let asn = match Self::query_param(query, "asn") {
+            Some(asn) => match Self::parse_as_number(asn) {
+                Some(asn) => Some(asn),
+                None => return Ok(Self::plain_error(StatusCode::BAD_REQUEST, "Invalid AS number")),
+            },
+            None => None,
+        };
+
+        let body_bytes = match Self::read_body(req, MAX_BODY.load(Ordering::Relaxed)).await {
+            Ok(body) => body,
+            Err((status, message)) => return Ok(Self::body_error(status, &message, false)),
+        };
+        let asns = Self::current_asns(&asns_arc);
+        // Answered from the membership sets of the database, without a lookup
+        let kept = |ip: IpAddr| {
+            country.as_ref().is_none_or(|country| asns.ip_in_country(ip, country))
+                && asn.is_none_or(|asn| asns.ip_in_asn(ip, asn))
+        };
+        let filtered = country.is_some() || asn.is_some();
+
+        // Lines are handled as bytes, so that lines that aren't valid UTF-8
+        // are passed through unchanged, and so are the line endings
+        let mut lines = Vec::new();
+        for raw in body_bytes.split_inclusive(|&b| b == b'\n') {
+            let line = raw.strip_suffix(b"\n").unwrap_or(raw);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let client = format.client_ip_bytes(line);
+            if filtered && !client.as_ref().is_some_and(|(_, ip, _)| kept(ip.to_canonical())) {
+                continue;
+            }
+            lines.push((raw, line, client));
+        }
+        // Charged before the lookups, so that a log can't overrun the quota
+        let looked_up = lines.iter().filter(|(_, _, client)| client.is_some()).count() as u64;
+        if let Some(tenant) = tenant.filter(|tenant| !tenant.charge_ips(looked_up)) {
+            return Ok(Self::quota_exceeded(tenant, Some(looked_up)));
+        }
+
+        let mut out = Vec::with_capacity(body_bytes.len() * 5 / 4);
+        for (raw, line, client) in lines {
+            let ending = &raw[line.len()..];
+            let found = client
+                .as_ref()
+                .and_then(|(_, ip, _)| asns.lookup_by_ip(ip.to_canonical()));
+            if ndjson {
+                let record = AnnotatedLogLine {
+                    line: &String::from_utf8_lossy(line),
+                    format: client.as_ref().map(|(format, _, _)| format.name()),
+                    lookup: client.as_ref().map(|(_, ip, _)| {
+                        let mut response = match &found {
+                            Some(found) => IpLookupResponse::found(ip.to_string(), found),
+                            None => IpLookupResponse::not_found(ip.to_string()),
+                        };
+                        response.extra = enrich::extra_fields(&response);
+                        response
+                    }),
+                };
+                if let Err(e) = render::with_notation(style.asn_notation, || serde_json::to_writer(&mut out, &record)) {
+                    return Ok(render::internal_error(&e.to_string()));
+                }
+                out.push(b'\n');
+                continue;
+            }
+            let Some((_, _, range)) = client else {
+                out.extend_from_slice(raw);
+                continue;
+            };
+            let annotation = match found {
+                Some(found) if include_description => {
+                    format!("[AS{}, {}, {}]", style.asn(found.number), found.country, found.description)
+                }
+                Some(found) => format!("[AS{}, {}]", style.asn(found.number), found.country),
+                None if include_description => "[AS0, None, Not announced]".to_string(),
+                None => "[AS0, None]".to_string(),
+            };
+            // Keep bracketed addresses such as host[192.0.2.1] intact
+            let end = if line[range.end..].starts_with(b"]") { range.end + 1 } else { range.end };
+            out.extend_from_slice(&line[..end]);
+            out.push(b' ');
+            out.extend_from_slice(annotation.as_bytes());
+            out.extend_from_slice(&line[end..]);
+            out.extend_from_slice(ending);
         }
 
-        let mut response = match output_type {
-            OutputType::Plain => Self::output_plain_vec(&results),
-            _ => Self::output_json_vec(&results),
+        // Lines passed through with invalid UTF-8 leave the charset undeclared
+        let content_type = if ndjson {
+            "application/x-ndjson"
+        } else if std::str::from_utf8(&out).is_ok() {
+            "text/plain; charset=utf-8"
+        } else {
+            "text/plain"
         };
+        let mut response = Response::new(Full::new(Bytes::from(out)));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
         *response.status_mut() = StatusCode::OK;
         Ok(response)
     }
 
-    fn parse_as_number(input: &str) -> Option<u32> {
-        let s = input.trim();
-        let s = s
-            .strip_prefix("AS")
-            .or_else(|| s.strip_prefix("as"))
-            .unwrap_or(s);
-        u32::from_str(s).ok()
+    // Body of a request, decompressed according to its Content-Encoding
+    // (gzip or zstd): 415 for an unsupported encoding, 413 when the
+    // decompressed body is larger than --max-decoded-body, 400 for a corrupt
+    // body
+    pub(crate) async fn read_body(
+        req: Request<hyper::body::Incoming>,
+        max_body: u64,
+    ) -> Result<Bytes, (StatusCode, String)> {
+        Self::read_body_within(req, max_body, MAX_DECODED_BODY.load(Ordering::Relaxed)).await
     }
 
-    fn output_as_meta_json(resp: &AsMetaResponse) -> Response<Full<Bytes>> {
-        let json = serde_json::to_string(resp).unwrap();
-        let mut response = Response::new(Full::new(Bytes::from(json)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("application/json; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
+    // Same as read_body, with its own limit once decompressed
+    async fn read_body_within(
+        req: Request<hyper::body::Incoming>,
+        max_body: u64,
+        max_decoded: u64,
+    ) -> Result<Bytes, (StatusCode, String)> {
+        let encoding = req
+            .headers()
+            .get(CONTENT_ENCODING)
+            .map(|v| v.to_str().unwrap_or("").trim().to_ascii_lowercase());
+        let max = usize::try_from(max_body).unwrap_or(usize::MAX);
+        let body = match Limited::new(req.into_body(), max).collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(e) if e.is::<LengthLimitError>() => {
+                return Err((
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Request body larger than {} bytes", max_body),
+                ))
+            }
+            Err(_) => return Err((StatusCode::BAD_REQUEST, "Failed to read request body".to_string())),
+        };
+        let gzip = match encoding.as_deref() {
+            None | Some("identity") => return Ok(body),
+            Some("gzip" | "x-gzip") => true,
+            Some("zstd") => false,
+            Some(encoding) => {
+                return Err((
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    format!("Unsupported Content-Encoding \"{}\", expected gzip or zstd", encoding),
+                ))
+            }
+        };
+        // Decompressing up to the decoded limit takes long enough to hold up
+        // the other requests of an async worker
+        match tokio::task::spawn_blocking(move || Self::decode_body(&body, gzip, max_decoded)).await {
+            Ok(decoded) => decoded,
+            Err(_) => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to decompress request body".to_string(),
+            )),
+        }
+    }
+
+    // Decompressed gzip or zstd body, up to `limit` bytes
+    fn decode_body(body: &[u8], gzip: bool, limit: u64) -> Result<Bytes, (StatusCode, String)> {
+        let mut decoded = Vec::new();
+        // One byte more than the limit, to tell a body of exactly the limit
+        // from a larger one
+        let read = if gzip {
+            MultiGzDecoder::new(body).take(limit + 1).read_to_end(&mut decoded)
+        } else {
+            match StreamingDecoder::new(body) {
+                Ok(decoder) => decoder.take(limit + 1).read_to_end(&mut decoded),
+                Err(e) => Err(std::io::Error::other(e)),
+            }
+        };
+        if let Err(e) = read {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("Failed to decompress request body: {}", e),
+            ));
+        }
+        if decoded.len() as u64 > limit {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Decompressed request body larger than {} bytes", limit),
+            ));
+        }
+        Ok(Bytes::from(decoded))
+    }
+
+    // Addresses of a plain text body, tokenized frame by frame so that an
+    // overlong line or too many lines end the request without buffering
+    // the rest of the body
+    async fn read_plain_ip_list(
+        req: Request<hyper::body::Incoming>,
+        max_body: u64,
+        max_ips: usize,
+    ) -> Result<Vec<String>, (StatusCode, String)> {
+        let max = usize::try_from(max_body).unwrap_or(usize::MAX);
+        let mut body = Limited::new(req.into_body(), max);
+        let mut list = PlainIpList::new(max_ips);
+        while let Some(frame) = body.frame().await {
+            match frame {
+                Ok(frame) => {
+                    if let Some(data) = frame.data_ref() {
+                        list.feed(data)?;
+                    }
+                }
+                Err(e) if e.is::<LengthLimitError>() => {
+                    return Err((
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        format!("Request body larger than {} bytes", max_body),
+                    ))
+                }
+                Err(_) => return Err((StatusCode::BAD_REQUEST, "Failed to read request body".to_string())),
+            }
+        }
+        list.finish()
+    }
+
+    // Response to a request body that couldn't be read, in JSON or plain text.
+    // 415 responses list the supported encodings (RFC 7694).
+    pub(crate) fn body_error(status: StatusCode, message: &str, json: bool) -> Response<Full<Bytes>> {
+        let mut response = if json {
+            let mut response = Response::new(Full::new(Bytes::from(
+                serde_json::json!({ "error": message }).to_string(),
+            )));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/json; charset=utf-8"),
+            );
+            *response.status_mut() = status;
+            response
+        } else {
+            Self::plain_error(status, message)
+        };
+        if status == StatusCode::UNSUPPORTED_MEDIA_TYPE {
+            response
+                .headers_mut()
+                .insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, zstd"));
+        }
         response
     }
 
-    fn output_as_meta_plain(resp: &AsMetaResponse) -> Response<Full<Bytes>> {
-        let plain = format!(
-            "{} | {} | {}",
-            resp.as_number, resp.as_country_code, resp.as_description
-        );
-        let mut response = Response::new(Full::new(Bytes::from(plain)));
+    fn plain_error(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+        let mut response = Response::new(Full::new(Bytes::from(format!("{}\n", message))));
         response.headers_mut().insert(
             CONTENT_TYPE,
             HeaderValue::from_static("text/plain; charset=utf-8"),
         );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
+        *response.status_mut() = status;
         response
     }
 
-    fn output_as_meta_html(resp: &AsMetaResponse) -> Response<Full<Bytes>> {
-        let html = html! {
-            head {
-                title : "iptoasn lookup";
-                meta(name="viewport", content="width=device-width, initial-scale=1");
-                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
-                style : "body { margin: 1em 4em }";
-            }
-            body(class="container-fluid") {
-                header {
-                    h1 : format_args!("Information for AS number: AS{}", resp.as_number);
-                }
-                table {
-                    tr {
-                        th : "AS Number";
-                        td : format_args!("AS{}", resp.as_number);
-                    }
-                    tr {
-                        th : "AS Country Code";
-                        td : &resp.as_country_code;
-                    }
-                    tr {
-                        th : "AS Description";
-                        td : &resp.as_description;
-                    }
-                }
-                footer {
-                    p { small {
-                        : "Powered by ";
-                        a(href="https://iptoasn.com") : "iptoasn.com";
-                    } }
-                }
-            }
+    // Start of the uptime reported by /admin/status, at the first call
+    pub fn record_start() {
+        STARTED.get_or_init(SystemTime::now);
+    }
+
+    // Maximum size of request bodies as sent
+    pub fn set_max_body(bytes: u64) {
+        MAX_BODY.store(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn max_body() -> u64 {
+        MAX_BODY.load(Ordering::Relaxed)
+    }
+
+    // Maximum number of addresses of a bulk lookup
+    pub fn set_max_bulk_ips(count: usize) {
+        MAX_BULK_IPS.store(count, Ordering::Relaxed);
+    }
+
+    // Maximum ?limit= of the list routes
+    pub fn set_max_page_size(count: usize) {
+        MAX_PAGE_SIZE.store(count, Ordering::Relaxed);
+    }
+
+    // Maximum size of compressed request bodies once decompressed
+    pub fn set_max_decoded_body(bytes: u64) {
+        MAX_DECODED_BODY.store(bytes, Ordering::Relaxed);
+    }
+
+    // Maximum size of snapshots pushed by the primary to a standby
+    pub fn set_max_replication_body(bytes: u64) {
+        MAX_REPLICATION_BODY.store(bytes, Ordering::Relaxed);
+    }
+
+    // Upper bound for X-Request-Timeout (--max-request-timeout)
+    pub fn set_max_request_timeout(timeout: Duration) {
+        MAX_REQUEST_TIMEOUT_MS.store(timeout.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // Deadline from the X-Request-Timeout header (seconds, fractions allowed),
+    // bounded by the server maximum
+    fn request_deadline(headers: &HeaderMap) -> Option<Instant> {
+        let secs = headers
+            .get("x-request-timeout")?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)?;
+        let max = Duration::from_millis(MAX_REQUEST_TIMEOUT_MS.load(Ordering::Relaxed));
+        Some(Instant::now() + Duration::from_secs_f64(secs.min(max.as_secs_f64())))
+    }
+
+    fn parse_as_number(input: &str) -> Option<u32> {
+        let s = input.trim();
+        let s = s
+            .strip_prefix("AS")
+            .or_else(|| s.strip_prefix("as"))
+            .unwrap_or(s);
+        // asdot notation, e.g. 3.10 for AS196618
+        if let Some((high, low)) = s.split_once('.') {
+            let high = u16::from_str(high).ok()?;
+            let low = u16::from_str(low).ok()?;
+            return Some(((high as u32) << 16) | low as u32);
         }
-        .into_string()
-        .unwrap();
-        let html = format!("<!DOCTYPE html>\n<html>{html}</html>");
+        u32::from_str(s).ok()
+    }
 
-        let mut response = Response::new(Full::new(Bytes::from(html)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/html; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
+    // 400 response to an AS number that is neither AS123 nor 123
+    fn invalid_as_number(output_type: OutputType) -> Response<Full<Bytes>> {
+        let mut resp = match output_type {
+            OutputType::Plain => Response::new(Full::new(Bytes::from(
+                "Invalid AS number. Use AS123 or 123\n",
+            ))),
+            OutputType::Html => {
+                let html = "<!DOCTYPE html><html><body><p>Invalid AS number. Use AS123 or 123</p></body></html>";
+                let mut r = Response::new(Full::new(Bytes::from(html)));
+                r.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/html; charset=utf-8"),
+                );
+                r
+            }
+            _ => Response::new(Full::new(Bytes::from(
+                r#"{"error":"Invalid AS number. Use AS123 or 123"}"#,
+            ))),
+        };
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        if !resp.headers().contains_key(CONTENT_TYPE) {
+            resp.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/json; charset=utf-8"),
+            );
+        }
+        resp
     }
 
     fn as_meta_lookup(
         asn_s: &str,
         headers: &HeaderMap,
         asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
     ) -> Result<Response<Full<Bytes>>, Infallible> {
         let output_type = Self::accept_type(headers);
 
         let number = match Self::parse_as_number(asn_s) {
             Some(n) => n,
-            None => {
-                let mut resp = match output_type {
-                    OutputType::Plain => Response::new(Full::new(Bytes::from(
-                        "Invalid AS number. Use AS123 or 123\n",
-                    ))),
-                    OutputType::Html => {
-                        let html = "<!DOCTYPE html><html><body><p>Invalid AS number. Use AS123 or 123</p></body></html>";
-                        let mut r = Response::new(Full::new(Bytes::from(html)));
-                        r.headers_mut().insert(
-                            CONTENT_TYPE,
-                            HeaderValue::from_static("text/html; charset=utf-8"),
-                        );
-                        r
-                    }
-                    _ => Response::new(Full::new(Bytes::from(
-                        r#"{"error":"Invalid AS number. Use AS123 or 123"}"#,
-                    ))),
-                };
-                *resp.status_mut() = StatusCode::BAD_REQUEST;
-                if !resp.headers().contains_key(CONTENT_TYPE) {
-                    resp.headers_mut().insert(
-                        CONTENT_TYPE,
-                        HeaderValue::from_static("application/json; charset=utf-8"),
-                    );
-                }
-                return Ok(resp);
-            }
+            None => return Ok(Self::invalid_as_number(output_type)),
         };
 
-        let asns = asns_arc.read().unwrap().clone();
+        let asns = Self::current_asns(&asns_arc);
 
+        let special = asn_registry::lookup(number);
         let resp = if let Some((country, description)) = asns.lookup_meta_by_asn(number) {
             AsMetaResponse {
                 as_number: number,
                 as_country_code: country.to_string(),
                 as_description: description.to_string(),
+                as_description_ascii: ascii::fold_non_ascii(&description),
+                special_purpose: special.map(|special| special.code),
+                found: true,
+            }
+        } else if let Some(special) = special {
+            // Reserved and private AS numbers are never in the database
+            AsMetaResponse {
+                as_number: number,
+                as_country_code: "None".to_string(),
+                as_description: format!("{} ({})", special.label, special.reference),
+                as_description_ascii: None,
+                special_purpose: Some(special.code),
+                found: false,
             }
         } else {
             AsMetaResponse {
                 as_number: number,
                 as_country_code: "None".to_string(),
                 as_description: "Not found".to_string(),
+                as_description_ascii: None,
+                special_purpose: None,
+                found: false,
             }
         };
 
-        let response = match output_type {
-            OutputType::Plain => Self::output_as_meta_plain(&resp),
-            OutputType::Html => Self::output_as_meta_html(&resp),
-            _ => Self::output_as_meta_json(&resp),
-        };
-
+        let mut response = Self::output_cached(output_type, &resp, style);
+        if !resp.found && STRICT_NOT_FOUND.load(Ordering::Relaxed) {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
         Ok(response)
     }
 
-    fn output_as_meta_list_json(items: &[AsMetaResponse]) -> Response<Full<Bytes>> {
-        let json = serde_json::to_string(items).unwrap();
-        let mut response = Response::new(Full::new(Bytes::from(json)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("application/json; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
+    // GET /v1/as/n/<asn>/countries lists the registry countries of the
+    // ranges of an ASN. Unknown ASNs get an empty list, like their subnets.
+    fn as_countries_lookup(
+        asn_s: &str,
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let output_type = Self::accept_type(headers);
+        let Some(number) = Self::parse_as_number(asn_s) else {
+            return Ok(Self::invalid_as_number(output_type));
+        };
+        let asns = Self::current_asns(&asns_arc);
+        // AS0 ranges are space listed as not routed, not announced by anyone
+        let shares = if number == 0 { Vec::new() } else { asns.countries_by_asn(number) };
+        let resp = AsCountriesResponse {
+            as_number: number,
+            countries: shares
+                .into_iter()
+                .map(|share| AsCountry {
+                    country_code: share.country.to_string(),
+                    ranges: share.ranges,
+                    prefixes: share.prefixes,
+                })
+                .collect(),
+        };
+        Ok(Self::output_cached(output_type, &resp, style))
     }
 
-    fn output_as_meta_list_plain(items: &[AsMetaResponse]) -> Response<Full<Bytes>> {
-        let mut out = String::with_capacity(items.len() * 32);
-        for item in items {
-            out.push_str(&format!(
-                "{} | {} | {}\n",
-                item.as_number, item.as_country_code, item.as_description
+    // GET /v1/as/prefix/<prefix> returns the announced ranges overlapping a
+    // CIDR prefix, their AS numbers, and whether the prefix is fully,
+    // partially or not announced
+    fn prefix_lookup(
+        prefix_s: &str,
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let (first, last, len) = match cidr::prefix_bounds(prefix_s) {
+            Ok(bounds) => bounds,
+            Err(e) => return Ok(Self::plain_error(StatusCode::BAD_REQUEST, &e)),
+        };
+        let resp = PrefixLookupResponse {
+            prefix: format!("{}/{}", first, len),
+            announcements: Self::announcements(&Self::current_asns(&asns_arc), first, last),
+        };
+        let mut response = render::response(Self::accept_type(headers), &resp, style);
+        Self::ip_cache_headers(response.headers_mut(), resp.announcements.coverage == "full");
+        Ok(response)
+    }
+
+    // GET /v1/as/range?first=<ip>&last=<ip> describes an arbitrary inclusive
+    // range like a prefix, with its minimal CIDR decomposition, e.g. for
+    // acquisitions and IP broker listings given as ranges
+    fn range_lookup(
+        first_s: Option<&str>,
+        last_s: Option<&str>,
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let (Some(first_s), Some(last_s)) = (first_s, last_s) else {
+            return Ok(Self::plain_error(
+                StatusCode::BAD_REQUEST,
+                "Missing range. Use /v1/as/range?first=<ip>&last=<ip>",
+            ));
+        };
+        let (first, last) = match (IpAddr::from_str(first_s.trim()), IpAddr::from_str(last_s.trim())) {
+            (Ok(first), Ok(last)) => (first, last),
+            (Err(_), _) => {
+                return Ok(Self::plain_error(StatusCode::BAD_REQUEST, &format!("Invalid IP address [{}]", first_s)))
+            }
+            (_, Err(_)) => {
+                return Ok(Self::plain_error(StatusCode::BAD_REQUEST, &format!("Invalid IP address [{}]", last_s)))
+            }
+        };
+        if first.is_ipv4() != last.is_ipv4() {
+            return Ok(Self::plain_error(
+                StatusCode::BAD_REQUEST,
+                "The first and last addresses must both be IPv4 or both IPv6",
             ));
         }
-        let mut response = Response::new(Full::new(Bytes::from(out)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
+        if first > last {
+            return Ok(Self::plain_error(
+                StatusCode::BAD_REQUEST,
+                "The first address must not be after the last one",
+            ));
+        }
+        let resp = RangeLookupResponse {
+            first_ip: first.to_string(),
+            last_ip: last.to_string(),
+            cidrs: cidr::range_to_cidrs(&first.to_string(), &last.to_string()),
+            announcements: Self::announcements(&Self::current_asns(&asns_arc), first, last),
+        };
+        let mut response = render::response(Self::accept_type(headers), &resp, style);
+        Self::ip_cache_headers(response.headers_mut(), resp.announcements.coverage == "full");
+        Ok(response)
     }
 
-    fn output_as_meta_list_html(items: &[AsMetaResponse]) -> Response<Full<Bytes>> {
-        let html = html! {
-            head {
-                title : "iptoasn AS list";
-                meta(name="viewport", content="width=device-width, initial-scale=1");
-                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
-                style : "body { margin: 1em 4em } table { width: 100%; } th, td { padding: .25em .5em; }";
-            }
-            body(class="container-fluid") {
-                header {
-                    h1 : "All ASNs";
-                }
-                table(class="table table-sm table-striped") {
-                    thead {
-                        tr {
-                            th : "AS Number";
-                            th : "AS Country Code";
-                            th : "AS Description";
-                        }
-                    }
-                    tbody {
-                        @ for item in items {
-                            tr {
-                                td : format_args!("AS{}", item.as_number);
-                                td : &item.as_country_code;
-                                td : &item.as_description;
-                            }
-                        }
-                    }
-                }
-                footer {
-                    p { small {
-                        : "Powered by ";
-                        a(href="https://iptoasn.com") : "iptoasn.com";
-                    } }
-                }
+    // Announced ranges overlapping the addresses from `first` to `last`, of
+    // the same family, and how much of them each AS number announces
+    fn announcements(asns: &Asns, first: IpAddr, last: IpAddr) -> Announcements {
+        let ranges = asns.ranges_overlapping(first, last);
+
+        // Walk the ranges, which are sorted and don't overlap, looking for gaps
+        let to_u128 = |ip: IpAddr| match ip {
+            IpAddr::V4(v4) => u32::from(v4) as u128,
+            IpAddr::V6(v6) => u128::from(v6),
+        };
+        let (start, end) = (to_u128(first), to_u128(last));
+        let mut next = Some(start);
+        let mut gap = false;
+        for range in &ranges {
+            let Some(expected) = next else { break };
+            if to_u128(range.first_ip) > expected {
+                gap = true;
             }
-        }.into_string().unwrap();
-        let html = format!("<!DOCTYPE html>\n<html>{html}</html>");
+            next = to_u128(range.last_ip).checked_add(1).filter(|n| *n <= end);
+        }
+        let coverage = if ranges.is_empty() {
+            "none"
+        } else if gap || next.is_some() {
+            "partial"
+        } else {
+            "full"
+        };
 
-        let mut response = Response::new(Full::new(Bytes::from(html)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/html; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
+        let mut as_numbers: Vec<u32> = ranges.iter().map(|range| range.number).collect();
+        as_numbers.sort_unstable();
+        as_numbers.dedup();
+
+        // Addresses announced by each AS number, from all the ranges, listed
+        // or not. There are (end - start) + 1 addresses, which overflows for
+        // ::/0.
+        let mut announced: BTreeMap<u32, u128> = BTreeMap::new();
+        for range in &ranges {
+            let clipped = to_u128(range.last_ip).min(end) - to_u128(range.first_ip).max(start) + 1;
+            *announced.entry(range.number).or_default() += clipped;
+        }
+        let total = (end - start) as f64 + 1.0;
+        let percent = |addresses: f64| addresses / total * 100.0;
+        let announced_total: u128 = announced.values().sum();
+        let unannounced = match announced_total.checked_sub(1) {
+            Some(announced_minus_one) => (end - start - announced_minus_one).to_string(),
+            None => (end - start)
+                .checked_add(1)
+                .map_or_else(|| "340282366920938463463374607431768211456".to_string(), |n| n.to_string()),
+        };
+        let unannounced_percent = percent(total - announced_total as f64);
+        let mut ownership: Vec<(u32, u128)> = announced.into_iter().collect();
+        ownership.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        Announcements {
+            coverage,
+            as_numbers,
+            ownership: ownership
+                .into_iter()
+                .map(|(as_number, addresses)| PrefixShare {
+                    as_number,
+                    addresses: addresses.to_string(),
+                    percent: percent(addresses as f64),
+                })
+                .collect(),
+            unannounced_addresses: unannounced,
+            unannounced_percent,
+            truncated: ranges.len() > PREFIX_MAX_RANGES,
+            ranges: ranges
+                .iter()
+                .take(PREFIX_MAX_RANGES)
+                .map(|range| PrefixRange {
+                    first_ip: range.first_ip.to_string(),
+                    last_ip: range.last_ip.to_string(),
+                    as_number: range.number,
+                    as_country_code: range.country.to_string(),
+                    as_description: range.description.to_string(),
+                    as_description_ascii: ascii::fold_non_ascii(&range.description),
+                })
+                .collect(),
+        }
     }
 
+    // GET /v1/as/ns lists all known AS numbers, by ascending AS number, or
+    // descending with ?sort=-asn
     fn as_meta_list(
+        sort: Option<&str>,
+        page: Option<Page>,
         headers: &HeaderMap,
         asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
     ) -> Result<Response<Full<Bytes>>, Infallible> {
         let output_type = Self::accept_type(headers);
+        let descending = match sort {
+            None | Some("asn") => false,
+            Some("-asn") => true,
+            Some(sort) => {
+                return Ok(Self::plain_error(
+                    StatusCode::BAD_REQUEST,
+                    &format!("Unsupported sort order \"{}\", expected asn or -asn", sort),
+                ))
+            }
+        };
 
-        let asns = asns_arc.read().unwrap().clone();
-        let all = asns.enumerate_asn_meta();
+        let asns = Self::current_asns(&asns_arc);
+        let mut all = asns.enumerate_asn_meta();
+        if descending {
+            all.reverse();
+        }
 
         let items: Vec<AsMetaResponse> = all
             .into_iter()
             .map(|(n, cc, desc)| AsMetaResponse {
                 as_number: n,
                 as_country_code: cc.to_string(),
+                as_description_ascii: ascii::fold_non_ascii(&desc),
                 as_description: desc.to_string(),
+                special_purpose: asn_registry::lookup(n).map(|special| special.code),
+                found: true,
             })
             .collect();
 
-        let response = match output_type {
-            OutputType::Plain => Self::output_as_meta_list_plain(&items),
-            OutputType::Html => Self::output_as_meta_list_html(&items),
-            _ => Self::output_as_meta_list_json(&items),
+        let (items, page) = Page::apply(page, items);
+        let list = AsMetaList {
+            heading: "All ASNs".to_string(),
+            items: &items,
         };
+        let mut response = Self::output_cached(output_type, &list, style);
+        Page::total_count_header(&mut response, page.map(|info| info.total));
+        Ok(response)
+    }
+
+    // GET /v1/as/search?q=<text> lists the AS numbers whose description
+    // contains the text, case-insensitively
+    fn as_search(
+        query: Option<&str>,
+        page: Option<Page>,
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let text = Self::percent_decode(&query.unwrap_or("").replace('+', " "));
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(Self::plain_error(
+                StatusCode::BAD_REQUEST,
+                "Missing search text. Use /v1/as/search?q=<text>",
+            ));
+        }
+
+        let asns = Self::current_asns(&asns_arc);
+        let items: Vec<AsMetaResponse> = asns
+            .search_asn_meta(text)
+            .into_iter()
+            .map(|(n, cc, desc)| AsMetaResponse {
+                as_number: n,
+                as_country_code: cc.to_string(),
+                as_description_ascii: ascii::fold_non_ascii(&desc),
+                as_description: desc.to_string(),
+                special_purpose: asn_registry::lookup(n).map(|special| special.code),
+                found: true,
+            })
+            .collect();
 
+        let (items, page) = Page::apply(page, items);
+        let list = AsMetaList {
+            heading: format!("ASNs matching \"{}\"", text),
+            items: &items,
+        };
+        let mut response = Self::output_cached(Self::accept_type(headers), &list, style);
+        Page::total_count_header(&mut response, page.map(|info| info.total));
         Ok(response)
     }
 
     fn as_subnets_lookup(
         asn_s: &str,
+        format: Option<&str>,
+        sort: Option<&str>,
+        page: Option<Page>,
         headers: &HeaderMap,
         asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
     ) -> Result<Response<Full<Bytes>>, Infallible> {
         let output_type = Self::accept_type(headers);
 
-        let number = match Self::parse_as_number(asn_s) {
-            Some(n) => n,
-            None => {
-                let mut resp = match output_type {
-                    OutputType::Plain => Response::new(Full::new(Bytes::from(
-                        "Invalid AS number. Use AS123 or 123\n",
-                    ))),
-                    OutputType::Html => {
-                        let html = "<!DOCTYPE html><html><body><p>Invalid AS number. Use AS123 or 123</p></body></html>";
-                        let mut r = Response::new(Full::new(Bytes::from(html)));
-                        r.headers_mut().insert(
-                            CONTENT_TYPE,
-                            HeaderValue::from_static("text/html; charset=utf-8"),
-                        );
-                        r
-                    }
-                    _ => Response::new(Full::new(Bytes::from(
-                        r#"{"error":"Invalid AS number. Use AS123 or 123"}"#,
-                    ))),
-                };
+        // ?format=bird|frr|junos|ios renders router configuration instead
+        let format = match format.map(PrefixListFormat::from_str).transpose() {
+            Ok(format) => format,
+            Err(e) => {
+                let mut resp = Response::new(Full::new(Bytes::from(format!("{}\n", e))));
                 *resp.status_mut() = StatusCode::BAD_REQUEST;
-                if !resp.headers().contains_key(CONTENT_TYPE) {
-                    resp.headers_mut().insert(
-                        CONTENT_TYPE,
-                        HeaderValue::from_static("application/json; charset=utf-8"),
-                    );
-                }
+                resp.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; charset=utf-8"),
+                );
                 return Ok(resp);
             }
         };
-
-        // For AS0 (all not routed ranges) return an empty subnet list to avoid
-        // trying to enumerate the complement of the routing table.
-        if number == 0 {
-            let subnets: Vec<String> = Vec::new();
-            let response = match output_type {
-                OutputType::Plain => Self::output_as_subnets_plain(&subnets),
-                OutputType::Html => Self::output_as_subnets_html(number, &subnets),
-                _ => {
-                    let resp = AsSubnetsResponse { as_number: number, subnets };
-                    Self::output_as_subnets_json(&resp)
-                }
-            };
-            return Ok(response);
-        }
-
-        let asns = asns_arc.read().unwrap().clone();
-
-        // If ASN is not found, return 200 with empty subnets.
-        if asns.lookup_meta_by_asn(number).is_none() {
-            let subnets: Vec<String> = Vec::new();
-            let response = match output_type {
-                OutputType::Plain => Self::output_as_subnets_plain(&subnets),
-                OutputType::Html => Self::output_as_subnets_html(number, &subnets),
-                _ => {
-                    let resp = AsSubnetsResponse { as_number: number, subnets };
-                    Self::output_as_subnets_json(&resp)
-                }
-            };
-            return Ok(response);
-        }
-
-        // Collect ranges on-demand and deaggregate to minimal CIDR set
-        let ranges = asns.collect_ranges_by_asn(number);
-        let mut subnets: Vec<String> = Vec::new();
-        for (first, last) in ranges {
-            let first_s = first.to_string();
-            let last_s = last.to_string();
-            let mut parts = Self::range_to_cidrs(&first_s, &last_s);
-            subnets.append(&mut parts);
-        }
-
-        let response = match output_type {
-            OutputType::Plain => Self::output_as_subnets_plain(&subnets),
-            OutputType::Html => Self::output_as_subnets_html(number, &subnets),
-            _ => {
-                let resp = AsSubnetsResponse { as_number: number, subnets };
-                Self::output_as_subnets_json(&resp)
-            }
+        let order = match Self::subnet_order(sort) {
+            Ok(order) => order,
+            Err(e) => return Ok(Self::plain_error(StatusCode::BAD_REQUEST, &e)),
         };
 
-        Ok(response)
-    }
-
-    fn normalize_country_code(input: &str) -> Option<String> {
-        let cc = input.trim();
-        if cc.len() != 2 {
-            return None;
-        }
-        let cc_u = cc.to_ascii_uppercase();
-        if !cc_u.chars().all(|c| c.is_ascii_alphabetic()) {
-            return None;
-        }
-        Some(cc_u)
-    }
-
-    fn output_country_asns_json(resp: &CountryAsnsResponse) -> Response<Full<Bytes>> {
-        let json = serde_json::to_string(resp).unwrap();
-        let mut response = Response::new(Full::new(Bytes::from(json)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("application/json; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
-    }
-
-    fn output_country_asns_plain(as_numbers: &[u32]) -> Response<Full<Bytes>> {
-        let mut out = String::new();
-        for n in as_numbers {
-            out.push_str(&format!("{n}\n"));
-        }
-        let mut response = Response::new(Full::new(Bytes::from(out)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
-    }
+        let number = match Self::parse_as_number(asn_s) {
+            Some(n) => n,
+            None => return Ok(Self::invalid_as_number(output_type)),
+        };
 
-    fn output_country_asns_html(resp: &CountryAsnsResponse) -> Response<Full<Bytes>> {
-        let html = html! {
-            head {
-                title : "iptoasn country AS list";
-                meta(name="viewport", content="width=device-width, initial-scale=1");
-                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
-                style : "body { margin: 1em 4em }";
-            }
-            body(class="container-fluid") {
-                header {
-                    h1 : format_args!("ASNs for {}", resp.country_code);
-                }
-                @ if resp.as_numbers.is_empty() {
-                    p : "No ASNs found";
-                } else {
-                    ul {
-                        @ for n in &resp.as_numbers {
-                            li : format_args!("AS{}", n);
-                        }
-                    }
-                }
-                footer {
-                    p { small {
-                        : "Powered by ";
-                        a(href="https://iptoasn.com") : "iptoasn.com";
-                    } }
-                }
-            }
+        // For AS0 (all not routed ranges) return an empty subnet list to avoid
+        // trying to enumerate the complement of the routing table.
+        if number == 0 {
+            return Ok(Self::output_as_subnets(output_type, format, number, page, Vec::new(), style));
         }
-        .into_string()
-        .unwrap();
-        let html = format!("<!DOCTYPE html>\n<html>{html}</html>");
 
-        let mut response = Response::new(Full::new(Bytes::from(html)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/html; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
-    }
+        let asns = Self::current_asns(&asns_arc);
 
-    fn output_country_subnets_json(resp: &CountrySubnetsResponse) -> Response<Full<Bytes>> {
-        let json = serde_json::to_string(resp).unwrap();
-        let mut response = Response::new(Full::new(Bytes::from(json)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("application/json; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
-    }
+        // If ASN is not found, return 200 with empty subnets.
+        if asns.lookup_meta_by_asn(number).is_none() {
+            return Ok(Self::output_as_subnets(output_type, format, number, page, Vec::new(), style));
+        }
 
-    fn output_country_subnets_plain(subnets: &[String]) -> Response<Full<Bytes>> {
-        let mut out = String::new();
-        for s in subnets {
-            out.push_str(s);
-            out.push('\n');
+        // Collect ranges on-demand and deaggregate to minimal CIDR set
+        let ranges = asns.collect_ranges_by_asn(number);
+        let mut subnets: Vec<String> = Vec::new();
+        for (first, last) in ranges {
+            let first_s = first.to_string();
+            let last_s = last.to_string();
+            let mut parts = cidr::range_to_cidrs(&first_s, &last_s);
+            subnets.append(&mut parts);
         }
-        let mut response = Response::new(Full::new(Bytes::from(out)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
+        cidr::sort_subnets(&mut subnets, order);
+
+        Ok(Self::output_as_subnets(output_type, format, number, page, subnets, style))
     }
 
-    fn output_country_subnets_html(resp: &CountrySubnetsResponse) -> Response<Full<Bytes>> {
-        let body_text = if resp.subnets.is_empty() {
-            String::new()
-        } else {
-            resp.subnets.join("\n")
+    fn output_as_subnets(
+        output_type: OutputType,
+        format: Option<PrefixListFormat>,
+        number: u32,
+        page: Option<Page>,
+        subnets: Vec<String>,
+        style: &Style,
+    ) -> Response<Full<Bytes>> {
+        let (subnets, page) = Page::apply(page, subnets);
+        let total = page.as_ref().map(|info| info.total);
+        let mut response = match format {
+            Some(format) => Self::output_as_subnets_prefix_list(format, number, &subnets),
+            None => {
+                let resp = AsSubnetsResponse {
+                    as_number: number,
+                    subnets,
+                    page,
+                };
+                Self::output_cached(output_type, &resp, style)
+            }
         };
+        Page::total_count_header(&mut response, total);
+        response
+    }
 
-        let html = html! {
-            head {
-                title : "iptoasn country subnets";
-                meta(name="viewport", content="width=device-width, initial-scale=1");
-                link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
-                style : "body { margin: 1em 4em }";
-            }
-            body(class="container-fluid") {
-                header {
-                    h1 : format_args!("Subnets for {}", resp.country_code);
-                }
-                pre : body_text;
-                footer {
-                    p { small {
-                        : "Powered by ";
-                        a(href="https://iptoasn.com") : "iptoasn.com";
-                    } }
-                }
-            }
-        }
-        .into_string()
-        .unwrap();
-        let html = format!("<!DOCTYPE html>\n<html>{html}</html>");
+    // ?sort=prefix|size of the subnet routes, prefix by default
+    fn subnet_order(sort: Option<&str>) -> Result<SubnetOrder, String> {
+        sort.map_or(Ok(SubnetOrder::default()), SubnetOrder::from_str)
+    }
 
-        let mut response = Response::new(Full::new(Bytes::from(html)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/html; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
+    fn normalize_country_code(input: &str) -> Option<String> {
+        let cc = input.trim();
+        if cc.len() != 2 {
+            return None;
+        }
+        let cc_u = cc.to_ascii_uppercase();
+        if !cc_u.chars().all(|c| c.is_ascii_alphabetic()) {
+            return None;
+        }
+        Some(cc_u)
     }
 
     fn country_asns_lookup(
         cc_s: &str,
+        page: Option<Page>,
         headers: &HeaderMap,
         asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
     ) -> Result<Response<Full<Bytes>>, Infallible> {
         let output_type = Self::accept_type(headers);
 
@@ -1107,29 +4236,34 @@ impl WebService {
             }
         };
 
-        let asns = asns_arc.read().unwrap().clone();
-        let list = asns.enumerate_asns_by_country(&cc);
+        let asns = Self::current_asns(&asns_arc);
+        let (list, page) = Page::apply(page, asns.enumerate_asns_by_country(&cc));
+        let total = page.as_ref().map(|info| info.total);
 
         let resp = CountryAsnsResponse {
             country_code: cc,
             as_numbers: list,
+            page,
         };
 
-        let response = match output_type {
-            OutputType::Plain => Self::output_country_asns_plain(&resp.as_numbers),
-            OutputType::Html => Self::output_country_asns_html(&resp),
-            _ => Self::output_country_asns_json(&resp),
-        };
-
+        let mut response = Self::output_cached(output_type, &resp, style);
+        Page::total_count_header(&mut response, total);
         Ok(response)
     }
 
     fn country_subnets_lookup(
         cc_s: &str,
+        sort: Option<&str>,
+        page: Option<Page>,
         headers: &HeaderMap,
         asns_arc: Arc<RwLock<Arc<Asns>>>,
+        style: &Style,
     ) -> Result<Response<Full<Bytes>>, Infallible> {
         let output_type = Self::accept_type(headers);
+        let order = match Self::subnet_order(sort) {
+            Ok(order) => order,
+            Err(e) => return Ok(Self::plain_error(StatusCode::BAD_REQUEST, &e)),
+        };
 
         let cc = match Self::normalize_country_code(cc_s) {
             Some(x) => x,
@@ -1154,7 +4288,7 @@ impl WebService {
             }
         };
 
-        let asns = asns_arc.read().unwrap().clone();
+        let asns = Self::current_asns(&asns_arc);
         let ranges = asns.collect_ranges_by_country(&cc);
 
         // Merge overlapping/adjacent ranges, then re-aggregate to largest CIDR blocks.
@@ -1170,185 +4304,193 @@ impl WebService {
         }
 
         let mut subnets: Vec<String> = Vec::new();
-        for (s, e) in Self::merge_ranges_u32(&mut v4) {
+        for (s, e) in cidr::merge_ranges_u32(&mut v4) {
             let first = IpAddr::V4(Ipv4Addr::from(s.to_be_bytes()));
             let last = IpAddr::V4(Ipv4Addr::from(e.to_be_bytes()));
-            let mut parts = Self::range_to_cidrs(&first.to_string(), &last.to_string());
+            let mut parts = cidr::range_to_cidrs(&first.to_string(), &last.to_string());
             subnets.append(&mut parts);
         }
-        for (s, e) in Self::merge_ranges_u128(&mut v6) {
+        for (s, e) in cidr::merge_ranges_u128(&mut v6) {
             let first = IpAddr::V6(Ipv6Addr::from(s.to_be_bytes()));
             let last = IpAddr::V6(Ipv6Addr::from(e.to_be_bytes()));
-            let mut parts = Self::range_to_cidrs(&first.to_string(), &last.to_string());
+            let mut parts = cidr::range_to_cidrs(&first.to_string(), &last.to_string());
             subnets.append(&mut parts);
         }
+        cidr::sort_subnets(&mut subnets, order);
+        let (subnets, page) = Page::apply(page, subnets);
+        let total = page.as_ref().map(|info| info.total);
 
         let resp = CountrySubnetsResponse {
             country_code: cc,
             subnets,
+            page,
         };
 
-        let response = match output_type {
-            OutputType::Plain => Self::output_country_subnets_plain(&resp.subnets),
-            OutputType::Html => Self::output_country_subnets_html(&resp),
-            _ => Self::output_country_subnets_json(&resp),
-        };
-
+        let mut response = Self::output_cached(output_type, &resp, style);
+        Page::total_count_header(&mut response, total);
         Ok(response)
     }
 
-    fn merge_ranges_u32(ranges: &mut Vec<(u32, u32)>) -> Vec<(u32, u32)> {
-        if ranges.is_empty() {
-            return Vec::new();
-        }
-        ranges.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
-        let mut out: Vec<(u32, u32)> = Vec::new();
-        let mut cur = ranges[0];
-
-        for &(s, e) in &ranges[1..] {
-            let adj = cur.1 != u32::MAX && s <= cur.1.saturating_add(1);
-            let ovl = s <= cur.1;
-            if ovl || adj {
-                cur.1 = cur.1.max(e);
-            } else {
-                out.push(cur);
-                cur = (s, e);
-            }
-        }
-        out.push(cur);
-        out
+    fn output_as_subnets_prefix_list(
+        format: PrefixListFormat,
+        number: u32,
+        subnets: &[String],
+    ) -> Response<Full<Bytes>> {
+        let text = format.render(&format!("AS{}", number), subnets);
+        let mut response = Response::new(Full::new(Bytes::from(text)));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        Self::cache_headers(response.headers_mut());
+        *response.status_mut() = StatusCode::OK;
+        response
     }
 
-    fn merge_ranges_u128(ranges: &mut Vec<(u128, u128)>) -> Vec<(u128, u128)> {
-        if ranges.is_empty() {
-            return Vec::new();
-        }
-        ranges.sort_unstable_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
-        let mut out: Vec<(u128, u128)> = Vec::new();
-        let mut cur = ranges[0];
-
-        for &(s, e) in &ranges[1..] {
-            let adj = cur.1 != u128::MAX && s <= cur.1.saturating_add(1);
-            let ovl = s <= cur.1;
-            if ovl || adj {
-                cur.1 = cur.1.max(e);
-            } else {
-                out.push(cur);
-                cur = (s, e);
-            }
-        }
-        out.push(cur);
-        out
+    // GET /v1/limits returns the limits enforced on requests, and the quota
+    // of the tenant of the API key, for clients sizing their batches
+    fn limits(tenant: Option<&Tenant>) -> Response<Full<Bytes>> {
+        let quota = tenant.and_then(Tenant::monthly_quota);
+        let resp = LimitsResponse {
+            limits: Limits::current(),
+            max_in_flight_per_connection: load::max_in_flight_per_connection(),
+            tenant: tenant.map(|tenant| tenant.name().to_string()),
+            monthly_quota: quota,
+            remaining: tenant.and_then(Tenant::remaining),
+            quota_reset_seconds: quota.map(|_| usage::until_next_month().as_secs()),
+        };
+        let mut response = render::json_response(&resp);
+        response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        response
     }
 
-    // Deaggregate an arbitrary inclusive range into minimal CIDR set
-    fn range_to_cidrs(first_s: &str, last_s: &str) -> Vec<String> {
-        let first = IpAddr::from_str(first_s).ok();
-        let last = IpAddr::from_str(last_s).ok();
-
-        match (first, last) {
-            (Some(IpAddr::V4(f)), Some(IpAddr::V4(l))) => {
-                let mut start = u32::from_be_bytes(f.octets());
-                let end = u32::from_be_bytes(l.octets());
-                if start > end {
-                    return vec![];
-                }
-                if start == 0 && end == u32::MAX {
-                    return vec!["0.0.0.0/0".to_string()];
+    fn db_status(
+        headers: &HeaderMap,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        refresher: &Refresher,
+    ) -> Result<Response<Full<Bytes>>, Infallible> {
+        let asns = Self::current_asns(&asns_arc);
+        let status = Self::db_status_of(&asns, refresher);
+
+        let mut response = match Self::accept_type(headers) {
+            OutputType::Plain => {
+                let mut plain = format!(
+                    "source: {}\ndb_source: {}\nentries: {}\ngeneration: {}\nupdated: {}\n",
+                    status.source, status.db_source, status.entries, status.generation, status.updated
+                );
+                for (code, count) in &status.unmapped_country_codes {
+                    plain.push_str(&format!("unmapped country code: {:?} ({} ranges)\n", code, count));
                 }
-                let mut res = Vec::new();
-                while start <= end {
-                    let mut block: u32 = if start == 0 {
-                        1u32 << 31
-                    } else {
-                        1u32 << start.trailing_zeros().min(31)
-                    };
-
-                    let remaining = end - start + 1;
-                    while block > remaining {
-                        block >>= 1;
-                    }
-
-                    let prefix_len = 32 - block.trailing_zeros() as u8;
-                    let net_ip = Ipv4Addr::from(start.to_be_bytes());
-                    res.push(format!("{}/{}", net_ip, prefix_len));
-
-                    start = start.saturating_add(block);
-                    if block == 0 {
-                        break; // safety, shouldn't happen
+                if let Some(download) = status.download {
+                    match (download.percent, download.total_bytes) {
+                        (Some(percent), Some(total)) => plain.push_str(&format!(
+                            "download: {}% ({} of {} bytes)\n",
+                            percent, download.downloaded_bytes, total
+                        )),
+                        _ => plain.push_str(&format!(
+                            "download: {} bytes\n",
+                            download.downloaded_bytes
+                        )),
                     }
                 }
-                res
+                let mut r = Response::new(Full::new(Bytes::from(plain)));
+                r.headers_mut().insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; charset=utf-8"),
+                );
+                r
             }
-            (Some(IpAddr::V6(f)), Some(IpAddr::V6(l))) => {
-                let mut start = u128::from_be_bytes(f.octets());
-                let end = u128::from_be_bytes(l.octets());
-                if start > end {
-                    return vec![];
-                }
-                if start == 0 && end == u128::MAX {
-                    return vec!["::/0".to_string()];
-                }
-                let mut res = Vec::new();
-                while start <= end {
-                    let mut block: u128 = if start == 0 {
-                        1u128 << 127
-                    } else {
-                        1u128 << start.trailing_zeros().min(127)
-                    };
-
-                    let remaining = end - start + 1;
-                    while block > remaining {
-                        block >>= 1;
-                    }
-
-                    let prefix_len = 128 - block.trailing_zeros() as u8;
-                    let net_ip = Ipv6Addr::from(start.to_be_bytes());
-                    res.push(format!("{}/{}", net_ip, prefix_len));
-
-                    start = start.saturating_add(block);
-                    if block == 0 {
-                        break; // safety, shouldn't happen
-                    }
+            OutputType::Html if Self::explicit_html(headers) => match Self::db_status_html(&status, asns.db_source()) {
+                Ok(html) => {
+                    let mut r = Response::new(Full::new(Bytes::from(html)));
+                    r.headers_mut().insert(
+                        CONTENT_TYPE,
+                        HeaderValue::from_static("text/html; charset=utf-8"),
+                    );
+                    r
                 }
-                res
-            }
-            _ => vec![],
-        }
-    }
-
-    fn output_as_subnets_json(resp: &AsSubnetsResponse) -> Response<Full<Bytes>> {
-        let json = serde_json::to_string(resp).unwrap();
-        let mut response = Response::new(Full::new(Bytes::from(json)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("application/json; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
+                Err(e) => render::internal_error(&e.to_string()),
+            },
+            _ => render::json_response(&status),
+        };
         response
+            .headers_mut()
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        Ok(response)
     }
 
-    fn output_as_subnets_html(as_number: u32, subnets: &[String]) -> Response<Full<Bytes>> {
-        // Empty list renders as an empty <pre> content
-        let body_text = if subnets.is_empty() {
-            String::new()
-        } else {
-            subnets.join("\n")
-        };
+    // Status dashboard, warning when the data comes from the cache file
+    // because all database sources failed
+    fn db_status_of(asns: &Asns, refresher: &Refresher) -> DbStatusResponse {
+        DbStatusResponse {
+            source: asns.source().to_string(),
+            db_source: asns.db_source().as_str(),
+            entries: asns.len(),
+            generation: asns.generation().to_string(),
+            updated: httpdate::fmt_http_date(asns.updated()),
+            download: refresher.progress(),
+            unmapped_country_codes: asns
+                .unmapped_country_codes()
+                .iter()
+                .map(|(code, count)| (code.to_string(), *count))
+                .collect(),
+        }
+    }
 
+    fn db_status_html(status: &DbStatusResponse, db_source: DbSource) -> Result<String, horrorshow::Error> {
         let html = html! {
             head {
-                title : "iptoasn subnets";
+                title : "iptoasn database status";
                 meta(name="viewport", content="width=device-width, initial-scale=1");
                 link(rel="stylesheet", href="https://maxcdn.bootstrapcdn.com/bootstrap/4.0.0-alpha.5/css/bootstrap.min.css", integrity="sha384-AysaV+vQoT3kOAXZkl02PThvDr8HYKPZhNT5h/CXfBThSRXQ6jW5DO2ekP5ViFdi", crossorigin="anonymous");
-                style : "body { margin: 1em 4em }";
+                style : "body { margin: 1em 4em } th { padding-right: 1em }";
             }
             body(class="container-fluid") {
                 header {
-                    h1 : format_args!("Subnets for AS{}", as_number);
+                    h1 : "Database status";
+                }
+                @ if db_source == DbSource::Cache {
+                    div(class="alert alert-warning", role="alert") {
+                        strong : "Serving cached data. ";
+                        : "The database could not be loaded from any source, the data may be out of date.";
+                    }
+                }
+                table {
+                    tr {
+                        th : "Source";
+                        td : &status.source;
+                    }
+                    tr {
+                        th : "Loaded From";
+                        td : status.db_source;
+                    }
+                    tr {
+                        th : "Entries";
+                        td : status.entries;
+                    }
+                    tr {
+                        th : "Generation";
+                        td : &status.generation;
+                    }
+                    tr {
+                        th : "Updated";
+                        td : &status.updated;
+                    }
+                    @ if let Some(download) = &status.download {
+                        tr {
+                            th : "Download";
+                            td : format_args!("{} bytes", download.downloaded_bytes);
+                        }
+                    }
+                    @ for (code, count) in &status.unmapped_country_codes {
+                        tr {
+                            th : "Unmapped Country Code";
+                            td : format_args!("{:?} ({} ranges)", code, count);
+                        }
+                    }
                 }
-                pre : body_text;
                 footer {
                     p { small {
                         : "Powered by ";
@@ -1356,76 +4498,273 @@ impl WebService {
                     } }
                 }
             }
-        }.into_string().unwrap();
-        let html = format!("<!DOCTYPE html>\n<html>{html}</html>");
-
-        let mut response = Response::new(Full::new(Bytes::from(html)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/html; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
-    }
-
-    fn output_as_subnets_plain(subnets: &[String]) -> Response<Full<Bytes>> {
-        let text = if subnets.is_empty() {
-            String::new()
-        } else {
-            let mut s = String::new();
-            for cidr in subnets {
-                s.push_str(cidr);
-                s.push('\n');
-            }
-            s
-        };
-        let mut response = Response::new(Full::new(Bytes::from(text)));
-        response.headers_mut().insert(
-            CONTENT_TYPE,
-            HeaderValue::from_static("text/plain; charset=utf-8"),
-        );
-        Self::cache_headers(response.headers_mut());
-        *response.status_mut() = StatusCode::OK;
-        response
+        }.into_string()?;
+        Ok(format!("<!DOCTYPE html>\n<html>{html}</html>"))
     }
 
-    pub async fn start(asns_arc: Arc<RwLock<Arc<Asns>>>, listen_addr: &str) {
-        let addr: SocketAddr = listen_addr.parse().expect("Could not parse socket address");
-        let listener = match TcpListener::bind(addr).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                log::error!("Failed to bind to {}: {}", addr, e);
-                return;
-            }
+    pub async fn start(
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        refresher: Arc<Refresher>,
+        listen: net::Listen,
+        backlog: u32,
+    ) -> Result<(), String> {
+        Self::record_start();
+        let listener = net::bind_waiting(listen, backlog)
+            .await
+            .map_err(|e| format!("Failed to bind to {}: {}", listen, e))?;
+
+        let tls_acceptor = match TLS_CERTIFICATE.get() {
+            Some(certificate) => match certificate.acceptor() {
+                Ok(acceptor) => {
+                    certificate.clone().watch();
+                    Some(acceptor)
+                }
+                Err(e) => return Err(format!("Unable to set up TLS: {}", e)),
+            },
+            None => None,
         };
 
-        log::info!("webservice ready");
+        log::info!(
+            "webservice ready ({}) on {}",
+            if tls_acceptor.is_some() { "https" } else { "http" },
+            listener
+        );
 
         loop {
             let (tcp, remote_addr) = match listener.accept().await {
                 Ok(conn) => conn,
                 Err(e) => {
                     log::error!("Failed to accept connection: {}", e);
+                    net::accept_error_backoff(&e).await;
                     continue;
                 }
             };
-            let io = TokioIo::new(tcp);
+            // Counted from here, so that pending TLS handshakes are capped too
+            let Some(connection) = load::accept(&tcp, tls_acceptor.is_some()).await else {
+                log::debug!("Too many connections, closed the one from {}", remote_addr);
+                continue;
+            };
             let asns_arc = asns_arc.clone();
+            let refresher = refresher.clone();
+            let tls_acceptor = tls_acceptor.clone();
 
             tokio::task::spawn(async move {
-                let service = service_fn(move |req| {
-                    let asns_arc = asns_arc.clone();
-                    async move { Self::handle_request(req, asns_arc, remote_addr).await }
-                });
-
-                if let Err(err) = auto::Builder::new(TokioExecutor::new())
-                    .serve_connection(io, service)
-                    .await
-                {
-                    log::error!("Error serving connection: {:?}", err);
+                let Some(tls_acceptor) = tls_acceptor else {
+                    Self::serve_connection(tcp, asns_arc, refresher, remote_addr, connection).await;
+                    return;
+                };
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(tcp)).await {
+                    Ok(Ok(stream)) => {
+                        Self::serve_connection(stream, asns_arc, refresher, remote_addr, connection).await
+                    }
+                    Ok(Err(e)) => log::debug!("TLS handshake with {} failed: {}", remote_addr, e),
+                    Err(_) => log::debug!("TLS handshake with {} timed out", remote_addr),
                 }
             });
         }
     }
+
+    async fn serve_connection<I>(
+        io: I,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        refresher: Arc<Refresher>,
+        remote_addr: SocketAddr,
+        connection: Arc<load::Connection>,
+    ) where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let service = service_fn(move |req| {
+            let asns_arc = asns_arc.clone();
+            let refresher = refresher.clone();
+            let connection = connection.clone();
+            async move {
+                let response = Self::handle_request_guarded(req, asns_arc, refresher, remote_addr, connection).await;
+                response.map(sse::into_body)
+            }
+        });
+
+        if let Err(err) = auto::Builder::new(TokioExecutor::new())
+            .serve_connection(TokioIo::new(io), service)
+            .await
+        {
+            log::error!("Error serving connection: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::Renderer;
+
+    const DESCRIPTION: &str = r#"<script>alert("x")</script>, "Q" & Co"#;
+
+    fn style(sep: &str, asn_notation: AsnNotation) -> Style<'_> {
+        Style { sep, asn_notation }
+    }
+
+    fn found(number: u32, description: &str) -> IpLookupResponse {
+        let asn = Asn {
+            first_ip: "1.0.0.0".parse().unwrap(),
+            last_ip: "1.0.0.255".parse().unwrap(),
+            number,
+            country: Arc::from("US"),
+            description: Arc::from(description),
+        };
+        IpLookupResponse::found("1.0.0.1".to_string(), &asn)
+    }
+
+    fn adversarial() -> IpLookupResponse {
+        found(64500, DESCRIPTION)
+    }
+
+    #[test]
+    fn html_escapes_descriptions() {
+        let html = adversarial().html(&style(" | ", AsnNotation::Asplain)).unwrap();
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt;, &quot;Q&quot; &amp; Co"));
+    }
+
+    #[test]
+    fn json_escapes_descriptions() {
+        let json = render::Json::render(&adversarial(), &style("\t", AsnNotation::Asplain)).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(value["as_description"], DESCRIPTION);
+    }
+
+    #[test]
+    fn plain_keeps_one_line() {
+        let plain = adversarial().plain(&style("\t", AsnNotation::Asplain));
+        assert_eq!(plain.lines().count(), 1);
+        assert!(plain.ends_with(&format!("\t{}\n", DESCRIPTION)));
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn csv_quotes_descriptions() {
+        let csv = String::from_utf8(render::Csv::render(&adversarial(), &style(",", AsnNotation::Asplain)).unwrap()).unwrap();
+        assert!(csv.contains(r#","<script>alert(""x"")</script>, ""Q"" & Co","#));
+    }
+
+    #[test]
+    fn csv_fields_are_not_formulas() {
+        let response = found(64500, "=HYPERLINK(\"http://example.com\")");
+        let csv = String::from_utf8(render::Csv::render(&response, &style(",", AsnNotation::Asplain)).unwrap()).unwrap();
+        assert!(csv.contains(r#","'=HYPERLINK(""http://example.com"")"#));
+        let response = found(64500, "@SUM(A1)");
+        let csv = String::from_utf8(render::Csv::render(&response, &style(",", AsnNotation::Asplain)).unwrap()).unwrap();
+        assert!(csv.contains(",'@SUM(A1)"));
+    }
+
+    fn bulk_database() -> Asns {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder
+            .write_all(b"1.0.0.0\t1.0.0.255\t64500\tUS\tFIRST\n2001:db8::\t2001:db8::ffff\t64501\tDE\tSECOND\n")
+            .unwrap();
+        Asns::from_gzip_bytes(encoder.finish().unwrap(), "test.tsv.gz").unwrap()
+    }
+
+    fn bulk_input() -> Vec<String> {
+        ["2001:db8::1", "bogus", "1.0.0.1", "9.9.9.9", "1.0.0.1", "", "1.0.0.0"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    fn order(results: &[IpLookupResponse]) -> Vec<(usize, &str, bool)> {
+        results.iter().map(|r| (r.index.unwrap(), r.ip.as_str(), r.announced)).collect()
+    }
+
+    #[test]
+    fn bulk_results_keep_input_order() {
+        let ids = (0..7).map(|i| Some(serde_json::Value::from(i))).collect();
+        let (results, truncated) = WebService::lookup_ips(bulk_input(), ids, &bulk_database(), false, None);
+        assert!(!truncated);
+        assert_eq!(
+            order(&results),
+            [
+                (0, "2001:db8::1", true),
+                (1, "bogus", false),
+                (2, "1.0.0.1", true),
+                (3, "9.9.9.9", false),
+                (4, "1.0.0.1", true),
+                (5, "", false),
+                (6, "1.0.0.0", true),
+            ]
+        );
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result.id, Some(serde_json::Value::from(i)));
+        }
+    }
+
+    #[test]
+    fn bulk_results_sorted_by_address() {
+        let (results, _) = WebService::lookup_ips(bulk_input(), Vec::new(), &bulk_database(), true, None);
+        assert_eq!(
+            order(&results),
+            [
+                (6, "1.0.0.0", true),
+                (2, "1.0.0.1", true),
+                (4, "1.0.0.1", true),
+                (3, "9.9.9.9", false),
+                (0, "2001:db8::1", true),
+                (1, "bogus", false),
+                (5, "", false),
+            ]
+        );
+        assert!(results.iter().all(|r| r.id.is_none()));
+    }
+
+    #[test]
+    fn plain_ids_stay_on_their_line() {
+        let ids = ["plain", "two\nlines", "a\tb"].map(|id| Some(serde_json::Value::from(id))).to_vec();
+        let input = ["1.0.0.1", "1.0.0.1", "1.0.0.1"].map(String::from).to_vec();
+        let (results, _) = WebService::lookup_ips(input, ids, &bulk_database(), false, None);
+        let plain = results.plain(&style(",", AsnNotation::Asplain));
+        let ids: Vec<&str> = plain.lines().map(|line| line.rsplit(',').next().unwrap()).collect();
+        assert_eq!(ids, ["plain", "\"two\\nlines\"", "\"a\\tb\""]);
+    }
+
+    #[test]
+    fn asdot_in_every_format() {
+        let asdot = style(",", AsnNotation::Asdot);
+        let response = found(196618, "EXAMPLE");
+        let json: serde_json::Value =
+            serde_json::from_slice(&render::Json::render(&response, &asdot).unwrap()).unwrap();
+        assert_eq!(json["as_number"], "3.10");
+        assert_eq!(response.plain(&asdot), "3.10,1.0.0.0-1.0.0.255,US,EXAMPLE\n");
+        assert!(response.html(&asdot).unwrap().contains("AS3.10"));
+        #[cfg(feature = "csv")]
+        {
+            let csv = String::from_utf8(render::Csv::render(&response, &asdot).unwrap()).unwrap();
+            assert!(csv.contains(",3.10,US,EXAMPLE,"));
+        }
+        #[cfg(feature = "msgpack")]
+        {
+            let msgpack = render::Msgpack::render(&response, &asdot).unwrap();
+            let value = rmpv::decode::read_value(&mut msgpack.as_slice()).unwrap();
+            let as_number = value.as_map().unwrap().iter().find(|(k, _)| k.as_str() == Some("as_number"));
+            assert_eq!(as_number.unwrap().1.as_str(), Some("3.10"));
+        }
+        // Only the response being rendered is affected
+        let json = render::Json::render(&response, &style(",", AsnNotation::Asplain)).unwrap();
+        assert!(String::from_utf8(json).unwrap().contains(r#""as_number":196618"#));
+    }
+
+    #[test]
+    fn asdot_keeps_2_byte_numbers() {
+        let asdot = style(" | ", AsnNotation::Asdot);
+        let response = found(64500, "EXAMPLE");
+        let json = render::Json::render(&response, &asdot).unwrap();
+        assert!(String::from_utf8(json).unwrap().contains(r#""as_number":64500"#));
+        let list = CountryAsnsResponse {
+            country_code: "US".to_string(),
+            as_numbers: vec![15169, 196618],
+            page: None,
+        };
+        assert_eq!(list.plain(&asdot), "15169\n3.10\n");
+        let json = render::Json::render(&list, &asdot).unwrap();
+        assert!(String::from_utf8(json).unwrap().contains(r#""as_numbers":[15169,"3.10"]"#));
+    }
 }
@@ -0,0 +1,372 @@
+use crate::asns::Asns;
+use crate::auth;
+use crate::cidr;
+use crate::ip_list::{PlainIpList, MAX_LINE_BYTES};
+use crate::load::{self, Connection};
+use crate::usage::{self, Tenant};
+use log::{debug, error, info};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+// How long a connection may stay idle between lines
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Answer to a query before an accepted API key, with API keys configured
+const KEY_REQUIRED: &[u8] = b"Error: an API key is required, as `key <api-key>` before the first query\n";
+
+// Columns of the answers, set by the options of a bulk session or the flags
+// of a single query. The registry and allocation date columns are left
+// empty, the database doesn't have them, but are kept for scripts splitting
+// the answers on '|'.
+#[derive(Clone, Copy)]
+struct Columns {
+    header: bool,
+    prefix: bool,
+    country: bool,
+    registry: bool,
+    allocated: bool,
+    as_name: bool,
+}
+
+impl Columns {
+    fn new(header: bool) -> Self {
+        Columns {
+            header,
+            prefix: false,
+            country: false,
+            registry: false,
+            allocated: false,
+            as_name: true,
+        }
+    }
+
+    fn verbose(&mut self) {
+        self.prefix = true;
+        self.country = true;
+        self.registry = true;
+        self.allocated = true;
+        self.as_name = true;
+    }
+
+    // Option line of a bulk session, false if the line isn't one
+    fn apply_option(&mut self, option: &str) -> bool {
+        match option {
+            "verbose" => self.verbose(),
+            "header" | "noheader" => self.header = option == "header",
+            "prefix" | "noprefix" => self.prefix = option == "prefix",
+            "countrycode" | "nocountrycode" => self.country = option == "countrycode",
+            "registry" | "noregistry" => self.registry = option == "registry",
+            "allocdate" | "noallocdate" => self.allocated = option == "allocdate",
+            "asname" | "noasname" => self.as_name = option == "asname",
+            _ => return false,
+        }
+        true
+    }
+
+    // Flags of a single query, e.g. "v" of " -v 8.8.8.8", false for an
+    // unknown one
+    fn apply_flags(&mut self, flags: &str) -> bool {
+        for flag in flags.chars() {
+            match flag {
+                'v' => self.verbose(),
+                'p' => self.prefix = true,
+                'c' => self.country = true,
+                'r' => self.registry = true,
+                'u' => self.allocated = true,
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    fn ip_header(&self) -> String {
+        self.ip_row(["AS", "IP", "BGP Prefix"], ["CC", "Registry", "Allocated", "AS Name"])
+    }
+
+    fn as_header(&self) -> String {
+        self.as_row("AS", ["CC", "Registry", "Allocated", "AS Name"])
+    }
+
+    // Row of an address, from its AS number, address and prefix, and the
+    // fields of its AS
+    fn ip_row(&self, [asn, ip, prefix]: [&str; 3], as_fields: [&str; 4]) -> String {
+        let mut fields = vec![format!("{:<8}", asn), format!("{:<16}", ip)];
+        if self.prefix {
+            fields.push(format!("{:<19}", prefix));
+        }
+        fields.append(&mut self.as_fields(as_fields));
+        fields.join(" | ").trim_end().to_string()
+    }
+
+    fn as_row(&self, asn: &str, as_fields: [&str; 4]) -> String {
+        let mut fields = vec![format!("{:<8}", asn)];
+        fields.append(&mut self.as_fields(as_fields));
+        fields.join(" | ").trim_end().to_string()
+    }
+
+    // Country code, registry, allocation date and name of an AS, as far as
+    // they are selected
+    fn as_fields(&self, [country, registry, allocated, as_name]: [&str; 4]) -> Vec<String> {
+        let mut fields = Vec::new();
+        if self.country {
+            fields.push(format!("{:<2}", country));
+        }
+        if self.registry {
+            fields.push(format!("{:<8}", registry));
+        }
+        if self.allocated {
+            fields.push(format!("{:<10}", allocated));
+        }
+        if self.as_name {
+            fields.push(as_name.to_string());
+        }
+        fields
+    }
+}
+
+// Team Cymru style WHOIS interface (https://team-cymru.com/community-services/ip-asn-mapping/)
+// for `whois -h` and netcat scripts. A connection either sends a single
+// query line, e.g. " -v 8.8.8.8" or "AS15169", or a bulk session:
+//   begin
+//   verbose
+//   8.8.8.8
+//   AS15169
+//   end
+// whose addresses are tokenized like the plain text bodies of PUT /v1/as/ips,
+// with at most --max-bulk-ips of them. With API keys, a line starts with
+// `key <api-key>` before the first query, and the addresses are charged to
+// the quota of the key's tenant.
+pub struct WhoisServer {
+    listener: TcpListener,
+}
+
+impl WhoisServer {
+    // Bound before the server is started, so that a listen address that
+    // can't be used fails the startup
+    pub async fn bind(listen_addr: SocketAddr) -> io::Result<Self> {
+        Ok(WhoisServer {
+            listener: TcpListener::bind(listen_addr).await?,
+        })
+    }
+
+    pub async fn serve(self, asns_arc: Arc<RwLock<Arc<Asns>>>, max_ips: usize) {
+        if let Ok(addr) = self.listener.local_addr() {
+            info!("WHOIS server ready on {}", addr);
+        }
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => {
+                    // Connections count against max_connections, as on HTTP
+                    let Some(connection) = load::open().await else {
+                        let _ = stream.try_write(b"Error: too many connections\n");
+                        continue;
+                    };
+                    tokio::spawn(Self::handle_connection(stream, connection, asns_arc.clone(), max_ips));
+                }
+                Err(e) => error!("Failed to accept WHOIS connection: {}", e),
+            }
+        }
+    }
+
+    async fn handle_connection(
+        stream: TcpStream,
+        _connection: Arc<Connection>,
+        asns_arc: Arc<RwLock<Arc<Asns>>>,
+        max_ips: usize,
+    ) {
+        let (reader, writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        if let Err(e) = Self::session(&mut reader, &mut writer, &asns_arc, max_ips).await {
+            debug!("WHOIS connection failed: {}", e);
+            return;
+        }
+        let _ = writer.flush().await;
+        let _ = writer.shutdown().await;
+    }
+
+    // Answer the lines of a connection until its single query, the end of
+    // its bulk session, an error or an idle timeout
+    async fn session<R, W>(
+        reader: &mut BufReader<R>,
+        writer: &mut W,
+        asns_arc: &RwLock<Arc<Asns>>,
+        max_ips: usize,
+    ) -> std::io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut list = PlainIpList::new(max_ips);
+        let mut columns = Columns::new(false);
+        let mut bulk = false;
+        let mut header_written = false;
+        let mut line = Vec::with_capacity(MAX_LINE_BYTES);
+        let mut line_number = 0;
+        let mut authorized = !auth::is_enabled();
+        let mut tenant: Option<&'static Tenant> = None;
+        loop {
+            // Answers so far are sent before waiting for more lines
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+            line.clear();
+            // Bounded, so that a line without end costs no more than one
+            // overlong line
+            let mut bounded = (&mut *reader).take(MAX_LINE_BYTES as u64 + 1);
+            match tokio::time::timeout(IDLE_TIMEOUT, bounded.read_until(b'\n', &mut line)).await {
+                Ok(Ok(0)) | Err(_) => return Ok(()),
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(e),
+            }
+            line_number += 1;
+            let (key, query) = Self::split_key(line.trim_ascii());
+            if let Some(key) = key {
+                let key = std::str::from_utf8(key).ok();
+                if !auth::accepts_key(key) {
+                    return writer.write_all(b"Error: invalid API key\n").await;
+                }
+                authorized = true;
+                tenant = key.and_then(usage::tenant_by_key);
+                if let Some(tenant) = tenant {
+                    tenant.record_request();
+                }
+            }
+            let text = String::from_utf8_lossy(query).to_ascii_lowercase();
+            if text.is_empty() {
+                continue;
+            }
+            // The database may be refreshed during a long session
+            let asns = asns_arc.read().unwrap_or_else(PoisonError::into_inner).clone();
+
+            if !bulk {
+                if text != "begin" {
+                    if !authorized {
+                        return writer.write_all(KEY_REQUIRED).await;
+                    }
+                    let answer = Self::single_query(&asns, &text, &mut list, tenant);
+                    return writer.write_all(answer.as_bytes()).await;
+                }
+                bulk = true;
+                let updated = httpdate::fmt_http_date(asns.updated());
+                writer
+                    .write_all(format!("Bulk mode; iptoasn-webservice [{}]\n", updated).as_bytes())
+                    .await?;
+                continue;
+            }
+            if text == "end" {
+                return Ok(());
+            }
+            if columns.apply_option(&text) {
+                continue;
+            }
+            if !authorized {
+                return writer.write_all(KEY_REQUIRED).await;
+            }
+
+            let mut answer = String::new();
+            if let Some(number) = Self::as_query(&text) {
+                if columns.header && !header_written {
+                    answer.push_str(&columns.as_header());
+                    answer.push('\n');
+                }
+                answer.push_str(&Self::as_answer(&asns, &columns, number));
+            } else {
+                if let Err((_, e)) = list.feed(query).and_then(|_| list.feed(b"\n")) {
+                    return writer.write_all(format!("Error: {}\n", e).as_bytes()).await;
+                }
+                let ips = list.take_ips();
+                if let Some(tenant) = tenant.filter(|tenant| !tenant.charge_ips(ips.len() as u64)) {
+                    let message = tenant.quota_message(Some(ips.len() as u64));
+                    return writer.write_all(format!("Error: {}\n", message).as_bytes()).await;
+                }
+                if columns.header && !header_written {
+                    answer.push_str(&columns.ip_header());
+                    answer.push('\n');
+                }
+                for ip in ips {
+                    answer.push_str(&Self::ip_answer(&asns, &columns, &ip, line_number));
+                }
+            }
+            header_written |= columns.header;
+            answer.push('\n');
+            writer.write_all(answer.as_bytes()).await?;
+        }
+    }
+
+    // `key <api-key>` at the start of a line, and the rest of the line
+    fn split_key(line: &[u8]) -> (Option<&[u8]>, &[u8]) {
+        match line.get(..4) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(b"key ") => {
+                let rest = line[4..].trim_ascii_start();
+                let end = rest.iter().position(u8::is_ascii_whitespace).unwrap_or(rest.len());
+                (Some(&rest[..end]), rest[end..].trim_ascii_start())
+            }
+            _ => (None, line),
+        }
+    }
+
+    // Answer to a single query line, flags followed by an IP address or an
+    // AS number, always with a header. An address is charged to the tenant.
+    fn single_query(asns: &Asns, text: &str, list: &mut PlainIpList, tenant: Option<&Tenant>) -> String {
+        let mut columns = Columns::new(true);
+        let mut query = None;
+        for token in text.split_whitespace() {
+            match token.strip_prefix('-') {
+                Some(flags) if !columns.apply_flags(flags) => {
+                    return format!("Error: unknown flag -{}\n", flags);
+                }
+                Some(_) => {}
+                None => query = Some(token),
+            }
+        }
+        let Some(query) = query else {
+            return "Error: no ASN or IP address in query\n".to_string();
+        };
+        if let Some(number) = Self::as_query(query) {
+            return format!("{}\n{}\n", columns.as_header(), Self::as_answer(asns, &columns, number));
+        }
+        let ip = match list.feed(query.as_bytes()).and_then(|_| list.feed(b"\n")) {
+            Ok(()) => list.take_ips().pop().unwrap_or_default(),
+            Err((_, e)) => return format!("Error: {}\n", e),
+        };
+        if let Some(tenant) = tenant.filter(|tenant| !tenant.charge_ips(1)) {
+            return format!("Error: {}\n", tenant.quota_message(None));
+        }
+        format!("{}\n{}\n", columns.ip_header(), Self::ip_answer(asns, &columns, &ip, 1))
+    }
+
+    // AS number of an "AS15169" query
+    fn as_query(text: &str) -> Option<u32> {
+        text.strip_prefix("as").and_then(|number| u32::from_str(number).ok())
+    }
+
+    fn as_answer(asns: &Asns, columns: &Columns, number: u32) -> String {
+        match asns.lookup_meta_by_asn(number) {
+            Some((country, description)) => columns.as_row(&number.to_string(), [&country, "", "", &description]),
+            None => columns.as_row(&number.to_string(), ["", "", "", "NA"]),
+        }
+    }
+
+    // Answer row of an address, NA for an unannounced one
+    fn ip_answer(asns: &Asns, columns: &Columns, ip_s: &str, line_number: usize) -> String {
+        let Ok(ip) = IpAddr::from_str(ip_s) else {
+            return format!("Error: no ASN or IP match on line {}.", line_number);
+        };
+        match asns.lookup_by_ip(ip) {
+            Some(found) => {
+                let prefix = cidr::containing_prefix(found.first_ip, found.last_ip, ip).unwrap_or_default();
+                columns.ip_row(
+                    [&found.number.to_string(), &ip.to_string(), &prefix],
+                    [&found.country, "", "", &found.description],
+                )
+            }
+            None => columns.ip_row(["NA", &ip.to_string(), "NA"], ["", "", "", "NA"]),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::asns::Asns;
+use crate::usage;
+use log::{error, info};
+use std::ffi::OsString;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use std::{env, io};
+use tokio::sync::Notify;
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::{define_windows_service, service_dispatcher};
+
+// Name the service is registered with, e.g. `sc create iptoasn-webservice`
+pub const SERVICE_NAME: &str = "iptoasn-webservice";
+
+// Time given to the server to wind down after a stop request
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Server = fn() -> Pin<Box<dyn Future<Output = ()>>>;
+
+static SERVER: OnceLock<Server> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+// Run `server` as a Windows service, until the service control manager
+// stops it. Blocks the calling thread, which must be the main thread of a
+// process started by the service control manager.
+pub fn run(server: Server) -> windows_service::Result<()> {
+    let _ = SERVER.set(server);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        error!("Windows service failed: {}", e);
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let stop = Arc::new(Notify::new());
+    let stop_handler = stop.clone();
+    let status_handle = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            stop_handler.notify_one();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    let status = |current_state, controls_accepted, exit_code| ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint: STOP_TIMEOUT,
+        process_id: None,
+    };
+
+    // Services start in the system directory; relative paths (cache file,
+    // config file) are relative to the executable instead
+    if let Some(dir) = env::current_exe().ok().and_then(|exe| exe.parent().map(|d| d.to_path_buf())) {
+        if let Err(e) = env::set_current_dir(&dir) {
+            error!("Unable to change to directory {}: {}", dir.display(), e);
+        }
+    }
+    let runtime = tokio::runtime::Runtime::new().map_err(windows_service::Error::Winapi)?;
+    let Some(server) = SERVER.get() else {
+        return Err(windows_service::Error::Winapi(io::Error::other("no server to run")));
+    };
+    status_handle.set_service_status(status(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ServiceExitCode::NO_ERROR,
+    ))?;
+
+    let stopped = runtime.block_on(async {
+        tokio::select! {
+            _ = server() => false,
+            _ = stop.notified() => true,
+        }
+    });
+    status_handle.set_service_status(status(
+        ServiceState::StopPending,
+        ServiceControlAccept::empty(),
+        ServiceExitCode::NO_ERROR,
+    ))?;
+    info!("Stopping the service");
+    Asns::cancel_loads();
+    usage::save();
+    runtime.shutdown_timeout(STOP_TIMEOUT);
+
+    // The server only returns by itself when it couldn't start
+    let exit_code = if stopped {
+        ServiceExitCode::NO_ERROR
+    } else {
+        ServiceExitCode::ServiceSpecific(1)
+    };
+    status_handle.set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty(), exit_code))
+}
@@ -0,0 +1,93 @@
+// Runs the `iptoasn selftest` checks against a webservice instance serving
+// a small generated database.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use iptoasn_webservice::selftest::SelfTest;
+use std::io::Write;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+const DATABASE: &[&str] = &[
+    "1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET",
+    "1.0.4.0\t1.0.7.255\t38803\tAU\tGTELECOM-AUSTRALIA",
+    "8.8.8.0\t8.8.8.255\t15169\tUS\tGOOGLE",
+    "9.9.9.0\t9.9.9.255\t19281\tUS\tQUAD9-AS-1",
+    "2001:4860::\t2001:4860:ffff:ffff:ffff:ffff:ffff:ffff\t15169\tUS\tGOOGLE",
+];
+
+// The server process, killed with its directory removed when dropped
+struct Instance {
+    child: Child,
+    dir: PathBuf,
+    url: String,
+}
+
+impl Instance {
+    fn start() -> Self {
+        let dir = std::env::temp_dir().join(format!("iptoasn-selftest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let database = dir.join("ip2asn.tsv.gz");
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        for line in DATABASE {
+            writeln!(encoder, "{}", line).unwrap();
+        }
+        std::fs::write(&database, encoder.finish().unwrap()).unwrap();
+
+        // A port that was free a moment ago
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_iptoasn-webservice"))
+            .arg("--dburl")
+            .arg(format!("file://{}", database.display()))
+            .arg("--cache-file")
+            .arg(dir.join("cache.tsv.gz"))
+            .args(["--refresh", "0", "--listen", &format!("127.0.0.1:{}", port)])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Instance {
+            child,
+            dir,
+            url: format!("http://127.0.0.1:{}", port),
+        }
+    }
+
+    async fn wait_ready(&mut self, client: &reqwest::Client) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            if client.get(format!("{}/version", self.url)).send().await.is_ok() {
+                return;
+            }
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("The webservice exited with {}", status);
+            }
+            assert!(Instant::now() < deadline, "The webservice didn't start");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+impl Drop for Instance {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[tokio::test]
+async fn selftest_passes() {
+    let client = reqwest::Client::new();
+    let mut instance = Instance::start();
+    instance.wait_ready(&client).await;
+
+    let mut selftest = SelfTest::new(client, &instance.url);
+    selftest.run().await;
+    let passed = selftest.print_report();
+    // Lookups are skipped without a sample of the database
+    assert!(selftest.checks().iter().any(|check| check.name == "PUT /v1/as/ips"));
+    assert!(passed, "some selftest checks failed");
+}